@@ -0,0 +1,454 @@
+use std::fmt;
+
+use crate::bus;
+use crate::cpu::Cpu;
+use crate::elf::{self, Elf, ElfError};
+use crate::error::Error;
+use crate::fdt::FdtConfig;
+
+// OpenSBIのようなファームウェアからカーネルを起動する時のメモリの置き方
+// QEMUのvirtマシンに合わせ、ファームウェアはRAMの先頭、カーネルはその4MiB先に置く
+pub const RAM_BASE: u32 = 0x8000_0000;
+pub const RAM_SIZE: u32 = 128 << 20;
+const KERNEL_OFFSET: u32 = 0x40_0000;
+const PAGE_SIZE: u32 = 0x1000;
+
+// fw_dynamicに渡すstruct fw_dynamic_info (RV32ではunsigned longが4バイト)
+const FW_DYNAMIC_MAGIC: u32 = 0x4942_534F;
+const FW_DYNAMIC_VERSION: u32 = 2;
+// 次に起動するもの (カーネル) をS-modeで動かす
+const NEXT_MODE_S: u32 = 1;
+
+// リセットベクタに置くROM (QEMUのvirtとほぼ同じ)
+// a0にhartid、a1にDTB、a2にfw_dynamic_infoのアドレスを入れて入口に飛ぶ
+// 入口とDTBのアドレスは0x50から並べ、その後にfw_dynamic_infoを置く
+const ROM_ENTRY: usize = 0x50;
+const ROM_DYNAMIC_INFO: u32 = 0x58;
+const ROM_PROLOGUE: [u32; 5] = [
+    0x0000_0297, // auipc t0, 0
+    0x0582_8613, // addi  a2, t0, 0x58
+    0xF140_2573, // csrr  a0, mhartid
+    0x0542_A583, // lw    a1, 0x54(t0)
+    0x0502_A283, // lw    t0, 0x50(t0)
+];
+// ファームウェアにはM-modeのまま飛ぶ
+const ROM_JUMP: [u32; 1] = [
+    0x0002_8067, // jr    t0
+];
+// カーネルには例外と割り込みをS-modeに任せ、mstatusのMPPをS-modeにしてmretで入る
+// リセットし直した時も同じ状態から始められるようにROMで設定する
+// medelegにはS-modeとM-modeからのecall以外の例外 (0xB1FF)、midelegにはS-modeの割り込み (0x222)
+// mcounterenではS-modeからcycle, time, instretを読めるようにする
+const ROM_ENTER_S: [u32; 12] = [
+    0x0000_B337, // lui   t1, 0xb
+    0x1FF3_0313, // addi  t1, t1, 0x1ff
+    0x3023_1073, // csrw  medeleg, t1
+    0x2220_0313, // li    t1, 0x222
+    0x3033_1073, // csrw  mideleg, t1
+    0x0070_0313, // li    t1, 7
+    0x3063_1073, // csrw  mcounteren, t1
+    0x3412_9073, // csrw  mepc, t0
+    0x0000_1337, // lui   t1, 1
+    0x8003_0313, // addi  t1, t1, -0x800
+    0x3003_2073, // csrs  mstatus, t1
+    0x3020_0073, // mret
+];
+
+// LinuxのImageのヘッダ (text_offsetとマジック)
+const IMAGE_TEXT_OFFSET: usize = 8;
+const IMAGE_MAGIC: &[u8] = b"RISCV\0\0\0";
+const IMAGE_MAGIC_OFFSET: usize = 48;
+const IMAGE_MAGIC2: &[u8] = b"RSC\x05";
+const IMAGE_MAGIC2_OFFSET: usize = 56;
+
+#[derive(Debug)]
+pub enum BootError {
+    Elf(ElfError),
+    // RAMに置けない
+    Load(&'static str, Error),
+    DoesNotFit(&'static str),
+    // RAMがROMの位置と重なっている
+    Rom,
+}
+
+pub type Result<T> = std::result::Result<T, BootError>;
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootError::Elf(e) => write!(f, "{}", e),
+            BootError::Load(what, e) => write!(f, "cannot load {}: {}", what, e),
+            BootError::DoesNotFit(what) => write!(f, "{} does not fit in RAM", what),
+            BootError::Rom => write!(f, "RAM overlaps the boot ROM at {:08x}", bus::ROM_BASE),
+        }
+    }
+}
+
+impl std::error::Error for BootError {}
+
+impl From<ElfError> for BootError {
+    fn from(e: ElfError) -> Self {
+        BootError::Elf(e)
+    }
+}
+
+// ファームウェアとカーネルはELFか生のバイナリ
+// ファームウェアが無ければエミュレータのSBIでカーネルをS-modeから直接始める
+pub struct BootConfig<'a> {
+    pub firmware: Option<&'a [u8]>,
+    pub kernel: Option<&'a [u8]>,
+    pub initrd: Option<&'a [u8]>,
+    pub fdt: FdtConfig,
+}
+
+// 各イメージを置いたアドレス
+#[derive(Debug, Clone)]
+pub struct BootInfo {
+    pub firmware: Option<u32>,
+    pub kernel: u32,
+    pub initrd: Option<(u32, u32)>,
+    pub fdt: u32,
+    pub dynamic_info: Option<u32>,
+}
+
+impl Cpu {
+    // イメージとDTBを置き、リセットしてROMから始める
+    // ROMはa0にhartid、a1にDTB、a2にfw_dynamic_infoのアドレスを入れ、M-modeでファームウェアの入口に飛ぶ (fw_jumpはa2を見ない)
+    // ファームウェアが無ければカーネルの入口にS-modeで入る
+    pub fn boot(&mut self, config: &BootConfig) -> Result<BootInfo> {
+        let base = self.bus().ram_base();
+        self.boot_images_mut().clear();
+        let firmware = match config.firmware {
+            Some(data) => Some(self.load_image("firmware", data, base)?),
+            None => None,
+        };
+
+        // Imageならヘッダのtext_offset、それ以外は既定の位置に置く
+        let kernel = match config.kernel {
+            Some(data) => {
+                let offset = image_text_offset(data).unwrap_or(KERNEL_OFFSET);
+                self.load_image("kernel", data, base.wrapping_add(offset))?
+            }
+            None => base.wrapping_add(KERNEL_OFFSET),
+        };
+
+        let mut fdt = config.fdt.clone();
+        if let Some(data) = config.initrd {
+            fdt.initrd = Some(
+                self.load_initrd(data)
+                    .ok_or(BootError::DoesNotFit("initrd"))?,
+            );
+        }
+        let fdt_addr = self.load_fdt(&fdt).ok_or(BootError::DoesNotFit("dtb"))?;
+        if let Some((start, end)) = fdt.initrd {
+            self.keep_image(start, end - start);
+        }
+        let dtb_len = self.fdt(&fdt).len() as u32;
+        self.keep_image(fdt_addr, dtb_len);
+
+        let rom = match firmware {
+            Some(firmware) => reset_rom(&ROM_JUMP, firmware, fdt_addr, Some(kernel)),
+            None => reset_rom(&ROM_ENTER_S, kernel, fdt_addr, None),
+        };
+        if !self.bus_mut().set_rom(&rom) {
+            return Err(BootError::Rom);
+        }
+        // ハートは1つだけなので、それをROMから始める
+        self.set_reset_vector(bus::ROM_BASE);
+        self.reset();
+        // ファームウェアは未実装のCSRを例外で調べるので、エラーでは止めずにゲストに返す
+        self.set_trap_on_error(true);
+
+        if firmware.is_none() {
+            self.set_sbi(true);
+        }
+
+        Ok(BootInfo {
+            firmware,
+            kernel,
+            initrd: fdt.initrd,
+            fdt: fdt_addr,
+            dynamic_info: firmware.map(|_| bus::ROM_BASE + ROM_DYNAMIC_INFO),
+        })
+    }
+
+    // initrdをRAMの最後にページ境界で置き、範囲を返す
+    // 入りきらなければNone
+    pub fn load_initrd(&mut self, data: &[u8]) -> Option<(u32, u32)> {
+        let end = self.bus().ram_base() + self.bus().ram_size();
+        let start = end.checked_sub(data.len() as u32)? & !(PAGE_SIZE - 1);
+        self.write_phys(start, data).ok()?;
+        Some((start, start + data.len() as u32))
+    }
+
+    // ELFならセグメントを置いてエントリポイントを、それ以外はaddrに置いてaddrを返す
+    fn load_image(&mut self, what: &'static str, data: &[u8], addr: u32) -> Result<u32> {
+        if elf::is_elf(data) {
+            self.load_elf(data)?;
+            let elf = Elf::parse(data)?;
+            for segment in elf.segments() {
+                self.keep_image(segment.paddr, segment.memsz.max(segment.filesz));
+            }
+            return Ok(elf.entry());
+        }
+        self.write_phys(addr, data)
+            .map_err(|e| BootError::Load(what, e))?;
+        self.keep_image(addr, data.len() as u32);
+        Ok(addr)
+    }
+
+    // 置いた直後のRAMの中身を再起動用に取っておく
+    fn keep_image(&mut self, addr: u32, len: u32) {
+        if len == 0 {
+            return;
+        }
+        let mut data = vec![0; len as usize];
+        self.read_phys(addr, &mut data).unwrap();
+        self.boot_images_mut().push((addr, data));
+    }
+}
+
+// tailで入口に飛ぶROMを作る
+// kernelがあればその後にfw_dynamic_infoを置く
+fn reset_rom(tail: &[u32], entry: u32, fdt: u32, kernel: Option<u32>) -> Vec<u8> {
+    let mut rom: Vec<u8> = ROM_PROLOGUE
+        .iter()
+        .chain(tail)
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    rom.resize(ROM_ENTRY, 0);
+    rom.extend_from_slice(&entry.to_le_bytes());
+    rom.extend_from_slice(&fdt.to_le_bytes());
+    if let Some(kernel) = kernel {
+        let info = [
+            FW_DYNAMIC_MAGIC,
+            FW_DYNAMIC_VERSION,
+            kernel,
+            NEXT_MODE_S,
+            // options
+            0,
+            // boot_hart (-1は指定なし)
+            u32::MAX,
+        ];
+        rom.extend(info.iter().flat_map(|v| v.to_le_bytes()));
+    }
+    rom
+}
+
+// LinuxのImageならRAMの先頭からのオフセットを返す
+fn image_text_offset(data: &[u8]) -> Option<u32> {
+    let magic = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+    if !magic(IMAGE_MAGIC_OFFSET, IMAGE_MAGIC) && !magic(IMAGE_MAGIC2_OFFSET, IMAGE_MAGIC2) {
+        return None;
+    }
+    let offset = data.get(IMAGE_TEXT_OFFSET..IMAGE_TEXT_OFFSET + 8)?;
+    u64::from_le_bytes(offset.try_into().unwrap())
+        .try_into()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BootConfig, BootError, KERNEL_OFFSET, RAM_BASE, RAM_SIZE};
+    use crate::bus::{self, Bus};
+    use crate::cpu::{Cpu, HaltReason, Privilege};
+    use crate::fdt::FdtConfig;
+
+    // 自分の中の0x40の値を1増やし、SBIでコールドリブートする
+    const KERNEL: [u32; 10] = [
+        0x0000_0297, // auipc t0, 0
+        0x0402_A303, // lw    t1, 0x40(t0)
+        0x0013_0313, // addi  t1, t1, 1
+        0x0462_A023, // sw    t1, 0x40(t0)
+        0x5352_58B7, // lui   a7, 0x53525
+        0x3548_8893, // addi  a7, a7, 0x354
+        0x0010_0513, // li    a0, 1
+        0x0000_0593, // li    a1, 0
+        0x0000_0813, // li    a6, 0
+        0x0000_0073, // ecall
+    ];
+
+    fn kernel() -> Vec<u8> {
+        let mut data: Vec<u8> = KERNEL.iter().flat_map(|i| i.to_le_bytes()).collect();
+        data.resize(0x44, 0);
+        data
+    }
+
+    fn counter(cpu: &Cpu, kernel: u32) -> u32 {
+        let mut buf = [0; 4];
+        cpu.read_phys(kernel + 0x40, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    // 再起動すると書き換えられたイメージを置き直して始めから動く
+    #[test]
+    fn reboot_reloads_images() {
+        let mut cpu = Cpu::new(Bus::with_ram(RAM_BASE, RAM_SIZE).unwrap());
+        let data = kernel();
+        let config = BootConfig {
+            firmware: None,
+            kernel: Some(&data),
+            initrd: Some(b"initrd"),
+            fdt: FdtConfig::default(),
+        };
+        let info = cpu.boot(&config).unwrap();
+        let (start, _) = info.initrd.unwrap();
+
+        assert_eq!(cpu.step(100), HaltReason::SystemReset(1, 0));
+        assert_eq!(counter(&cpu, info.kernel), 1);
+        cpu.write_phys(start, b"xxxxxx").unwrap();
+        cpu.write_phys(info.fdt, &[0; 8]).unwrap();
+
+        cpu.reboot();
+        assert_eq!(cpu.pc(), bus::ROM_BASE);
+        assert_eq!(counter(&cpu, info.kernel), 0);
+        let mut buf = [0; 6];
+        cpu.read_phys(start, &mut buf).unwrap();
+        assert_eq!(&buf, b"initrd");
+        let mut magic = [0; 4];
+        cpu.read_phys(info.fdt, &mut magic).unwrap();
+        assert_eq!(magic, [0xD0, 0x0D, 0xFE, 0xED]);
+
+        // 2回目も同じように動く
+        assert_eq!(cpu.step(100), HaltReason::SystemReset(1, 0));
+        assert_eq!(counter(&cpu, info.kernel), 1);
+    }
+
+    fn config<'a>(
+        firmware: Option<&'a [u8]>,
+        kernel: Option<&'a [u8]>,
+        initrd: Option<&'a [u8]>,
+    ) -> BootConfig<'a> {
+        BootConfig {
+            firmware,
+            kernel,
+            initrd,
+            fdt: FdtConfig::default(),
+        }
+    }
+
+    fn word(cpu: &Cpu, addr: u32) -> u32 {
+        let mut buf = [0; 4];
+        cpu.read_phys(addr, &mut buf).unwrap();
+        u32::from_le_bytes(buf)
+    }
+
+    // ファームウェアにはM-modeでa0からa2を渡して入る
+    #[test]
+    fn firmware_gets_dynamic_info() {
+        let mut cpu = Cpu::new(Bus::with_ram(RAM_BASE, RAM_SIZE).unwrap());
+        let firmware = 0x0000_006Fu32.to_le_bytes(); // j .
+        let data = kernel();
+        let info = cpu
+            .boot(&config(Some(&firmware), Some(&data), Some(b"initrd")))
+            .unwrap();
+        assert_eq!(info.firmware, Some(RAM_BASE));
+        assert_eq!(info.kernel, RAM_BASE + KERNEL_OFFSET);
+        let dynamic_info = info.dynamic_info.unwrap();
+        assert_eq!(dynamic_info, bus::ROM_BASE + 0x58);
+
+        // initrdはRAMの最後のページ
+        let (start, end) = info.initrd.unwrap();
+        assert_eq!(start, RAM_BASE + RAM_SIZE - 0x1000);
+        assert_eq!(end, start + 6);
+
+        let info_words: Vec<u32> = (0..6).map(|i| word(&cpu, dynamic_info + 4 * i)).collect();
+        assert_eq!(info_words, [0x4942_534F, 2, info.kernel, 1, 0, u32::MAX]);
+
+        assert_eq!(cpu.pc(), bus::ROM_BASE);
+        assert_eq!(cpu.run_until_pc(RAM_BASE), HaltReason::Pc(RAM_BASE));
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        assert_eq!(cpu.get_x(10), 0);
+        assert_eq!(cpu.get_x(11), info.fdt);
+        assert_eq!(cpu.get_x(12), dynamic_info);
+        assert!(!cpu.sbi());
+    }
+
+    // Imageはヘッダのtext_offsetに置く
+    #[test]
+    fn image_text_offset() {
+        let mut image = kernel();
+        image.resize(64, 0);
+        image[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        image[48..56].copy_from_slice(b"RISCV\0\0\0");
+        let mut cpu = Cpu::new(Bus::with_ram(RAM_BASE, RAM_SIZE).unwrap());
+        let info = cpu.boot(&config(None, Some(&image), None)).unwrap();
+        assert_eq!(info.kernel, RAM_BASE + 0x20_0000);
+        assert_eq!(info.initrd, None);
+        assert_eq!(word(&cpu, info.kernel), KERNEL[0]);
+
+        // 2つ目のマジックだけでもよい
+        image[48..56].fill(0);
+        image[56..60].copy_from_slice(b"RSC\x05");
+        let info = cpu.boot(&config(None, Some(&image), None)).unwrap();
+        assert_eq!(info.kernel, RAM_BASE + 0x20_0000);
+
+        // マジックが無ければ既定の位置
+        image[56..60].fill(0);
+        let info = cpu.boot(&config(None, Some(&image), None)).unwrap();
+        assert_eq!(info.kernel, RAM_BASE + KERNEL_OFFSET);
+    }
+
+    #[test]
+    fn images_that_do_not_fit() {
+        let data = kernel();
+        let mut cpu = Cpu::new(Bus::with_ram(RAM_BASE, 1 << 20).unwrap());
+        assert!(matches!(
+            cpu.boot(&config(None, Some(&data), None)),
+            Err(BootError::Load("kernel", _))
+        ));
+        let initrd = vec![0; 2 << 20];
+        assert!(matches!(
+            cpu.boot(&config(None, None, Some(&initrd))),
+            Err(BootError::DoesNotFit("initrd"))
+        ));
+
+        // ROMと重なるRAMでは起動できない
+        let mut cpu = Cpu::new(Bus::with_ram(0, 1 << 20).unwrap());
+        assert!(matches!(
+            cpu.boot(&config(None, None, None)),
+            Err(BootError::Rom)
+        ));
+    }
+
+    // ファームウェアが無ければROMで委譲を設定し、S-modeでカーネルに入る
+    #[test]
+    fn rom_enters_kernel_in_supervisor() {
+        let mut cpu = Cpu::new(Bus::with_ram(RAM_BASE, RAM_SIZE).unwrap());
+        let data = kernel();
+        let info = cpu.boot(&config(None, Some(&data), None)).unwrap();
+        assert_eq!(info.firmware, None);
+        assert_eq!(info.dynamic_info, None);
+        assert_eq!(cpu.reset_vector(), bus::ROM_BASE);
+        assert_eq!(cpu.bus().rom().len(), 0x58);
+        assert_eq!(word(&cpu, bus::ROM_BASE + 0x50), info.kernel);
+        assert_eq!(word(&cpu, bus::ROM_BASE + 0x54), info.fdt);
+        assert!(cpu.sbi());
+
+        assert_eq!(cpu.run_until_pc(info.kernel), HaltReason::Pc(info.kernel));
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.get_x(10), 0);
+        assert_eq!(cpu.get_x(11), info.fdt);
+        assert_eq!(cpu.read_csr(0x302), Ok(0xB1FF));
+        assert_eq!(cpu.read_csr(0x303), Ok(0x222));
+        assert_eq!(cpu.read_csr(0x306), Ok(7));
+
+        // リセットすると委譲も消え、ROMからやり直す
+        cpu.reset();
+        assert_eq!(cpu.read_csr(0x302), Ok(0));
+        assert_eq!(cpu.run_until_pc(info.kernel), HaltReason::Pc(info.kernel));
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.read_csr(0x302), Ok(0xB1FF));
+    }
+
+    #[test]
+    fn rom_limits() {
+        let mut bus = Bus::with_ram(RAM_BASE, 1 << 20).unwrap();
+        assert!(bus.set_rom(&[0; bus::ROM_SIZE as usize]));
+        assert!(!bus.set_rom(&[0; bus::ROM_SIZE as usize + 1]));
+        assert_eq!(bus.rom().len(), bus::ROM_SIZE as usize);
+        let mut bus = Bus::with_ram(0x8000, 1 << 20).unwrap();
+        assert!(bus.set_rom(&[0; 0x7000]));
+        assert!(!bus.set_rom(&[0; 0x7004]));
+    }
+}
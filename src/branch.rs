@@ -0,0 +1,825 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::symbols::Symbols;
+
+// 条件分岐の向きを予測する
+pub trait Predictor {
+    // targetは分岐先
+    fn predict(&self, pc: u32, target: u32) -> bool;
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+}
+
+// 後ろへの分岐は成立、前への分岐は不成立と予測する
+pub struct Btfn;
+
+impl Predictor for Btfn {
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        target < pc
+    }
+
+    fn update(&mut self, _pc: u32, _target: u32, _taken: bool) {}
+}
+
+// 2ビットの飽和カウンタ
+fn counter_update(counter: &mut u8, taken: bool) {
+    if taken {
+        *counter = (*counter + 1).min(3);
+    } else {
+        *counter = counter.saturating_sub(1);
+    }
+}
+
+// pcで引く2ビットカウンタの表
+pub struct Bimodal {
+    table: Vec<u8>,
+    mask: u32,
+}
+
+impl Bimodal {
+    pub fn new(bits: u32) -> Self {
+        Self {
+            // 弱く成立
+            table: vec![2; 1 << bits],
+            mask: (1 << bits) - 1,
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        ((pc >> 2) & self.mask) as usize
+    }
+}
+
+impl Predictor for Bimodal {
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.table[self.index(pc)] >= 2
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        let i = self.index(pc);
+        counter_update(&mut self.table[i], taken);
+    }
+}
+
+// pcと分岐の履歴のxorで引く2ビットカウンタの表
+pub struct Gshare {
+    table: Vec<u8>,
+    mask: u32,
+    history: u32,
+}
+
+impl Gshare {
+    pub fn new(bits: u32) -> Self {
+        Self {
+            table: vec![2; 1 << bits],
+            mask: (1 << bits) - 1,
+            history: 0,
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        (((pc >> 2) ^ self.history) & self.mask) as usize
+    }
+}
+
+impl Predictor for Gshare {
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.table[self.index(pc)] >= 2
+    }
+
+    fn update(&mut self, pc: u32, _target: u32, taken: bool) {
+        let i = self.index(pc);
+        counter_update(&mut self.table[i], taken);
+        self.history = ((self.history << 1) | taken as u32) & self.mask;
+    }
+}
+
+// TAGEの履歴の長さ
+const TAGE_HISTORY: [u32; 4] = [5, 11, 22, 44];
+const TAGE_TAG_BITS: u32 = 8;
+
+#[derive(Clone, Copy, Default)]
+struct TageEntry {
+    valid: bool,
+    tag: u16,
+    // 3ビットの符号付きカウンタ (0以上なら成立)
+    ctr: i8,
+    // 2ビットの有用さ
+    useful: u8,
+}
+
+// 小さなTAGE
+// 履歴の長さの違う4つのタグ付きの表と、何も当たらない時のbimodal
+pub struct TageLite {
+    base: Bimodal,
+    tables: [Vec<TageEntry>; 4],
+    bits: u32,
+    history: u64,
+    // 新しく載せる表を選ぶ乱数
+    seed: u32,
+}
+
+impl TageLite {
+    pub fn new(bits: u32) -> Self {
+        // タグ付きの表はbimodalの1/4の大きさにする
+        let tagged = bits.saturating_sub(2).max(1);
+        Self {
+            base: Bimodal::new(bits),
+            tables: std::array::from_fn(|_| vec![TageEntry::default(); 1 << tagged]),
+            bits: tagged,
+            history: 0,
+            seed: 0x2545_F491,
+        }
+    }
+
+    // 履歴の下位lenビットをwidthビットに畳み込む
+    fn fold(&self, len: u32, width: u32) -> u32 {
+        let mut h = self.history & ((1u64 << len) - 1);
+        let mut folded = 0;
+        while h != 0 {
+            folded ^= h as u32 & ((1 << width) - 1);
+            h >>= width;
+        }
+        folded
+    }
+
+    fn index(&self, table: usize, pc: u32) -> usize {
+        let len = TAGE_HISTORY[table];
+        let mask = (1 << self.bits) - 1;
+        (((pc >> 2) ^ (pc >> (2 + self.bits)) ^ self.fold(len, self.bits)) & mask) as usize
+    }
+
+    fn tag(&self, table: usize, pc: u32) -> u16 {
+        let len = TAGE_HISTORY[table];
+        let mask = (1 << TAGE_TAG_BITS) - 1;
+        let tag =
+            (pc >> 2) ^ self.fold(len, TAGE_TAG_BITS) ^ (self.fold(len, TAGE_TAG_BITS - 1) << 1);
+        (tag & mask) as u16
+    }
+
+    // 当たった一番長い表と、その次に長い表 (無ければbimodal) の予測
+    fn lookup(&self, pc: u32) -> (Option<usize>, bool, bool) {
+        let mut provider = None;
+        let mut alt = self.base.predict(pc, 0);
+        let mut pred = alt;
+        for t in 0..self.tables.len() {
+            let entry = &self.tables[t][self.index(t, pc)];
+            if entry.valid && entry.tag == self.tag(t, pc) {
+                if provider.is_some() {
+                    alt = pred;
+                }
+                provider = Some(t);
+                pred = entry.ctr >= 0;
+            }
+        }
+        (provider, pred, alt)
+    }
+
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+impl Predictor for TageLite {
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        self.lookup(pc).1
+    }
+
+    fn update(&mut self, pc: u32, target: u32, taken: bool) {
+        let (provider, pred, alt) = self.lookup(pc);
+        match provider {
+            Some(t) => {
+                let i = self.index(t, pc);
+                let entry = &mut self.tables[t][i];
+                entry.ctr = if taken {
+                    (entry.ctr + 1).min(3)
+                } else {
+                    (entry.ctr - 1).max(-4)
+                };
+                if pred != alt {
+                    entry.useful = if pred == taken {
+                        (entry.useful + 1).min(3)
+                    } else {
+                        entry.useful.saturating_sub(1)
+                    };
+                }
+            }
+            None => self.base.update(pc, target, taken),
+        }
+
+        // 外れたら長い表に載せる。空きが無ければ有用さを下げる
+        if pred != taken {
+            let start = provider.map_or(0, |t| t + 1);
+            let free: Vec<usize> = (start..self.tables.len())
+                .filter(|&t| self.tables[t][self.index(t, pc)].useful == 0)
+                .collect();
+            if free.is_empty() {
+                for t in start..self.tables.len() {
+                    let i = self.index(t, pc);
+                    let entry = &mut self.tables[t][i];
+                    entry.useful = entry.useful.saturating_sub(1);
+                }
+            } else {
+                // 短い方を選びやすくする
+                let t = free[if self.random() & 3 == 0 && free.len() > 1 {
+                    1
+                } else {
+                    0
+                }];
+                let (i, tag) = (self.index(t, pc), self.tag(t, pc));
+                self.tables[t][i] = TageEntry {
+                    valid: true,
+                    tag,
+                    ctr: if taken { 0 } else { -1 },
+                    useful: 0,
+                };
+            }
+        }
+
+        self.history = (self.history << 1) | taken as u64;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PredictorKind {
+    Btfn,
+    Bimodal,
+    Gshare,
+    Tage,
+}
+
+impl PredictorKind {
+    fn name(self) -> &'static str {
+        match self {
+            PredictorKind::Btfn => "btfn",
+            PredictorKind::Bimodal => "bimodal",
+            PredictorKind::Gshare => "gshare",
+            PredictorKind::Tage => "tage",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchConfig {
+    pub kind: PredictorKind,
+    // 表の大きさ (エントリ数の2の対数)
+    pub bits: u32,
+    // リターンアドレススタックの深さ (0なら無し)
+    pub ras: usize,
+    // BTBのエントリ数 (0なら無し。無い時は直接分岐の飛び先は分かっているものとする)
+    pub btb: usize,
+    // 予測が外れた時に足すサイクル数
+    pub penalty: u64,
+}
+
+impl Default for BranchConfig {
+    fn default() -> Self {
+        Self {
+            kind: PredictorKind::Bimodal,
+            bits: 10,
+            ras: 8,
+            btb: 0,
+            penalty: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid predictor: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl BranchConfig {
+    // "gshare,bits=12,ras=16,btb=512,penalty=3" のような指定
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        let mut config = Self::default();
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let err = || ParseError(item.to_string());
+            let Some((name, val)) = item.split_once('=') else {
+                config.kind = match item {
+                    "btfn" => PredictorKind::Btfn,
+                    "bimodal" => PredictorKind::Bimodal,
+                    "gshare" => PredictorKind::Gshare,
+                    "tage" => PredictorKind::Tage,
+                    _ => return Err(err()),
+                };
+                continue;
+            };
+            match name {
+                "bits" => match val.parse() {
+                    Ok(bits @ 1..=24) => config.bits = bits,
+                    _ => return Err(err()),
+                },
+                "ras" => config.ras = val.parse().map_err(|_| err())?,
+                "btb" => match val.parse::<usize>() {
+                    Ok(n) if n == 0 || n.is_power_of_two() => config.btb = n,
+                    _ => return Err(err()),
+                },
+                "penalty" => config.penalty = val.parse().map_err(|_| err())?,
+                _ => return Err(err()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+// 制御を移す命令の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BranchKind {
+    Branch,
+    Call,
+    Return,
+    Jump,
+    // jalrによるリターン以外のジャンプ
+    Indirect,
+}
+
+impl BranchKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            BranchKind::Branch => "branch",
+            BranchKind::Call => "call",
+            BranchKind::Return => "return",
+            BranchKind::Jump => "jump",
+            BranchKind::Indirect => "indirect",
+        }
+    }
+}
+
+// 分岐命令ごとの集計
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchStats {
+    pub kind: BranchKind,
+    pub count: u64,
+    pub taken: u64,
+    pub mispredicts: u64,
+}
+
+impl BranchStats {
+    fn new(kind: BranchKind) -> Self {
+        Self {
+            kind,
+            count: 0,
+            taken: 0,
+            mispredicts: 0,
+        }
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        match self.count {
+            0 => 1.0,
+            n => 1.0 - self.mispredicts as f64 / n as f64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct BtbEntry {
+    pc: u32,
+    target: u32,
+    valid: bool,
+}
+
+// 分岐予測器、RASとBTBをまとめたフロントエンド
+// 次のpcの予測が実際と違えば予測ミスとする
+pub struct BranchUnit {
+    config: BranchConfig,
+    predictor: Box<dyn Predictor>,
+    ras: Vec<u32>,
+    btb: Vec<BtbEntry>,
+    sites: HashMap<u32, BranchStats>,
+}
+
+impl BranchUnit {
+    pub fn new(config: BranchConfig) -> Self {
+        let predictor: Box<dyn Predictor> = match config.kind {
+            PredictorKind::Btfn => Box::new(Btfn),
+            PredictorKind::Bimodal => Box::new(Bimodal::new(config.bits)),
+            PredictorKind::Gshare => Box::new(Gshare::new(config.bits)),
+            PredictorKind::Tage => Box::new(TageLite::new(config.bits)),
+        };
+        Self::with_predictor(config, predictor)
+    }
+
+    // 自前の予測器を使う (config.kindは表示にだけ使う)
+    pub fn with_predictor(config: BranchConfig, predictor: Box<dyn Predictor>) -> Self {
+        Self {
+            btb: vec![BtbEntry::default(); config.btb],
+            ras: Vec::with_capacity(config.ras),
+            config,
+            predictor,
+            sites: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &BranchConfig {
+        &self.config
+    }
+
+    pub fn sites(&self) -> &HashMap<u32, BranchStats> {
+        &self.sites
+    }
+
+    pub fn mispredicts(&self) -> u64 {
+        self.sites.values().map(|s| s.mispredicts).sum()
+    }
+
+    // 予測ミスで失ったサイクル数
+    pub fn penalty_cycles(&self) -> u64 {
+        self.mispredicts() * self.config.penalty
+    }
+
+    // 実行した命令を予測と突き合わせる
+    // 制御を移す命令でなければNone、そうなら予測が外れたかを返す
+    pub fn on_inst(&mut self, pc: u32, ir: u32, next_pc: u32) -> Option<bool> {
+        let rd = (ir >> 7) & 0x1F;
+        let rs1 = (ir >> 15) & 0x1F;
+        let fallthrough = pc.wrapping_add(4);
+        let (kind, predicted) = match ir & 0x7F {
+            0x63 => {
+                let imm = ((ir as i32) >> 31 << 12)
+                    | (((ir >> 7) & 1) << 11) as i32
+                    | (((ir >> 25) & 0x3F) << 5) as i32
+                    | (((ir >> 8) & 0xF) << 1) as i32;
+                let target = pc.wrapping_add(imm as u32);
+                let taken = next_pc != fallthrough;
+                let predicted = if !self.predictor.predict(pc, target) {
+                    fallthrough
+                } else if self.btb.is_empty() {
+                    target
+                } else {
+                    self.btb_lookup(pc).unwrap_or(fallthrough)
+                };
+                self.predictor.update(pc, target, taken);
+                (BranchKind::Branch, predicted)
+            }
+            0x6F => {
+                let predicted = if self.btb.is_empty() {
+                    next_pc
+                } else {
+                    self.btb_lookup(pc).unwrap_or(fallthrough)
+                };
+                if is_link(rd) {
+                    self.push_ras(fallthrough);
+                    (BranchKind::Call, predicted)
+                } else {
+                    (BranchKind::Jump, predicted)
+                }
+            }
+            0x67 => {
+                // rs1とrdが同じリンクレジスタならpushだけ、違えばpopしてpush
+                let ret = is_link(rs1) && !(is_link(rd) && rd == rs1);
+                let btb = self.btb_lookup(pc).unwrap_or(fallthrough);
+                let predicted = match ret {
+                    true if self.config.ras > 0 => self.ras.pop().unwrap_or(btb),
+                    _ => btb,
+                };
+                let kind = if is_link(rd) {
+                    self.push_ras(fallthrough);
+                    BranchKind::Call
+                } else if ret {
+                    BranchKind::Return
+                } else {
+                    BranchKind::Indirect
+                };
+                (kind, predicted)
+            }
+            _ => return None,
+        };
+
+        let taken = next_pc != fallthrough;
+        if taken && !self.btb.is_empty() {
+            let i = self.btb_index(pc);
+            self.btb[i] = BtbEntry {
+                pc,
+                target: next_pc,
+                valid: true,
+            };
+        }
+        let mispredicted = predicted != next_pc;
+        let site = self.sites.entry(pc).or_insert(BranchStats::new(kind));
+        site.count += 1;
+        site.taken += taken as u64;
+        site.mispredicts += mispredicted as u64;
+        Some(mispredicted)
+    }
+
+    fn btb_index(&self, pc: u32) -> usize {
+        (pc >> 2) as usize & (self.btb.len() - 1)
+    }
+
+    fn btb_lookup(&self, pc: u32) -> Option<u32> {
+        if self.btb.is_empty() {
+            return None;
+        }
+        let entry = &self.btb[self.btb_index(pc)];
+        (entry.valid && entry.pc == pc).then_some(entry.target)
+    }
+
+    // 一杯なら一番古いものを捨てる
+    fn push_ras(&mut self, addr: u32) {
+        if self.config.ras == 0 {
+            return;
+        }
+        if self.ras.len() == self.config.ras {
+            self.ras.remove(0);
+        }
+        self.ras.push(addr);
+    }
+
+    // 全体、種類ごと、関数ごとと、外れの多い分岐をtop個書き出す
+    pub fn write_table<W: Write>(
+        &self,
+        symbols: &Symbols,
+        top: usize,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let c = &self.config;
+        writeln!(
+            out,
+            "predictor        {} (bits {}, ras {}, btb {}, penalty {})",
+            c.kind.name(),
+            c.bits,
+            c.ras,
+            c.btb,
+            c.penalty
+        )?;
+        let total = self.sum(|_| true);
+        writeln!(
+            out,
+            "branches         {} (mispredicts {}, accuracy {:.2}%)",
+            total.count,
+            total.mispredicts,
+            total.accuracy() * 100.0
+        )?;
+        writeln!(out, "penalty cycles   {}", self.penalty_cycles())?;
+
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<32} {:>10} {:>11} {:>9}",
+            "kind", "count", "mispredicts", "accuracy"
+        )?;
+        for kind in [
+            BranchKind::Branch,
+            BranchKind::Call,
+            BranchKind::Return,
+            BranchKind::Jump,
+            BranchKind::Indirect,
+        ] {
+            let s = self.sum(|s| s.kind == kind);
+            if s.count > 0 {
+                write_row(out, kind.name(), &s)?;
+            }
+        }
+
+        // 関数ごと
+        let mut funcs: HashMap<String, BranchStats> = HashMap::new();
+        for (&pc, site) in &self.sites {
+            let name = match symbols.symbolize(pc) {
+                Some((name, _)) => name.to_string(),
+                None => "?".to_string(),
+            };
+            let f = funcs
+                .entry(name)
+                .or_insert(BranchStats::new(BranchKind::Branch));
+            f.count += site.count;
+            f.taken += site.taken;
+            f.mispredicts += site.mispredicts;
+        }
+        let mut funcs: Vec<(String, BranchStats)> = funcs.into_iter().collect();
+        funcs.sort_by(|a, b| b.1.mispredicts.cmp(&a.1.mispredicts).then(a.0.cmp(&b.0)));
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<32} {:>10} {:>11} {:>9}",
+            "function", "count", "mispredicts", "accuracy"
+        )?;
+        for (name, s) in funcs.iter().take(top) {
+            write_row(out, name, s)?;
+        }
+
+        // 分岐命令ごと
+        let mut sites: Vec<(&u32, &BranchStats)> = self.sites.iter().collect();
+        sites.sort_by(|a, b| b.1.mispredicts.cmp(&a.1.mispredicts).then(a.0.cmp(b.0)));
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<8} {:<8} {:>10} {:>7} {:>11} {:>9}  location",
+            "pc", "kind", "count", "taken%", "mispredicts", "accuracy"
+        )?;
+        for (&pc, s) in sites.iter().take(top) {
+            writeln!(
+                out,
+                "{:08x} {:<8} {:>10} {:>6.1}% {:>11} {:>8.2}%  {}",
+                pc,
+                s.kind.name(),
+                s.count,
+                s.taken as f64 * 100.0 / s.count as f64,
+                s.mispredicts,
+                s.accuracy() * 100.0,
+                symbols.describe(pc).unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn sum<F: Fn(&BranchStats) -> bool>(&self, f: F) -> BranchStats {
+        let mut total = BranchStats::new(BranchKind::Branch);
+        for s in self.sites.values().filter(|s| f(s)) {
+            total.count += s.count;
+            total.taken += s.taken;
+            total.mispredicts += s.mispredicts;
+        }
+        total
+    }
+}
+
+fn write_row<W: Write>(out: &mut W, name: &str, s: &BranchStats) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<32} {:>10} {:>11} {:>8.2}%",
+        name,
+        s.count,
+        s.mispredicts,
+        s.accuracy() * 100.0
+    )
+}
+
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BranchConfig, BranchKind, BranchStats, BranchUnit, PredictorKind};
+    use crate::symbols::Symbols;
+
+    // 0x10にある後ろ向きの分岐 (0x00へ)
+    const BNEZ: u32 = 0xFE05_18E3; // bnez a0, -16
+    const JAL: u32 = 0x1000_00EF; // jal  ra, +0x100
+    const J: u32 = 0x1000_006F; // j    +0x100
+    const RET: u32 = 0x0000_8067; // ret
+    const JR: u32 = 0x0003_0067; // jr   t1
+
+    fn new_unit(spec: &str) -> BranchUnit {
+        BranchUnit::new(BranchConfig::parse(spec).unwrap())
+    }
+
+    // 分岐の結果を順に与えて外れた回数を返す
+    fn run(unit: &mut BranchUnit, pattern: impl IntoIterator<Item = bool>) -> u64 {
+        let mut mispredicts = 0;
+        for taken in pattern {
+            let next = if taken { 0x00 } else { 0x14 };
+            mispredicts += unit.on_inst(0x10, BNEZ, next).unwrap() as u64;
+        }
+        mispredicts
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = BranchConfig::parse("gshare,bits=12,ras=16,btb=512,penalty=5").unwrap();
+        assert_eq!(
+            config,
+            BranchConfig {
+                kind: PredictorKind::Gshare,
+                bits: 12,
+                ras: 16,
+                btb: 512,
+                penalty: 5,
+            }
+        );
+        assert_eq!(BranchConfig::parse("").unwrap(), BranchConfig::default());
+        for spec in [
+            "perceptron",
+            "bits=0",
+            "bits=25",
+            "btb=100",
+            "ras=-1",
+            "x=1",
+        ] {
+            assert!(BranchConfig::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    // 9回回って抜けるループを2回
+    #[test]
+    fn loop_branch() {
+        let pattern = || (0..2).flat_map(|_| (0..10).map(|i| i < 9));
+        for kind in ["btfn", "bimodal", "gshare", "tage"] {
+            let mut unit = new_unit(kind);
+            let mispredicts = run(&mut unit, pattern());
+            assert!(mispredicts <= 3, "{} {}", kind, mispredicts);
+            let site = unit.sites()[&0x10];
+            assert_eq!(
+                (site.kind, site.count, site.taken),
+                (BranchKind::Branch, 20, 18)
+            );
+        }
+        let mut unit = new_unit("btfn,penalty=3");
+        assert_eq!(run(&mut unit, pattern()), 2);
+        assert_eq!(unit.penalty_cycles(), 6);
+        assert_eq!(unit.sites()[&0x10].accuracy(), 0.9);
+        // 前向きの分岐は不成立と予測する
+        assert_eq!(unit.on_inst(0x10, 0x0005_1863, 0x20), Some(true));
+    }
+
+    // TAGEは長い履歴でループを抜ける所も覚える
+    #[test]
+    fn tage_learns_loop_exit() {
+        let mut unit = new_unit("tage");
+        let pattern = || (0..10).map(|i| i < 9);
+        for _ in 0..20 {
+            run(&mut unit, pattern());
+        }
+        assert_eq!(run(&mut unit, (0..5).flat_map(|_| pattern())), 0);
+        let mut unit = new_unit("bimodal");
+        assert_eq!(run(&mut unit, (0..5).flat_map(|_| pattern())), 5);
+    }
+
+    // 成立と不成立を交互に繰り返すと、履歴を使う予測器だけが当てられる
+    #[test]
+    fn alternating_branch() {
+        let pattern = || (0..200).map(|i| i % 2 == 0);
+        assert!(run(&mut new_unit("bimodal"), pattern()) >= 100);
+        assert!(run(&mut new_unit("gshare"), pattern()) <= 5);
+        assert!(run(&mut new_unit("tage"), pattern()) <= 20);
+    }
+
+    #[test]
+    fn return_address_stack() {
+        // 0x100から0x200、0x200から0x300、0x300から0x400を呼んで戻る
+        let calls = |unit: &mut BranchUnit| {
+            let mut mispredicts = Vec::new();
+            for pc in [0x100, 0x200, 0x300] {
+                assert_eq!(unit.on_inst(pc, JAL, pc + 0x100), Some(false));
+            }
+            for (pc, ret) in [(0x404, 0x304), (0x308, 0x204), (0x208, 0x104)] {
+                mispredicts.push(unit.on_inst(pc, RET, ret).unwrap());
+            }
+            mispredicts
+        };
+        assert_eq!(calls(&mut new_unit("ras=8")), [false, false, false]);
+        // 溢れた一番古い戻り先は当たらない
+        assert_eq!(calls(&mut new_unit("ras=2")), [false, false, true]);
+        assert_eq!(calls(&mut new_unit("ras=0")), [true, true, true]);
+
+        let mut unit = new_unit("ras=8");
+        calls(&mut unit);
+        assert_eq!(unit.sites()[&0x100].kind, BranchKind::Call);
+        assert_eq!(unit.sites()[&0x404].kind, BranchKind::Return);
+    }
+
+    #[test]
+    fn branch_target_buffer() {
+        let mut unit = new_unit("btb=4");
+        // 飛び先を覚えるまでは外れる
+        assert_eq!(unit.on_inst(0x100, J, 0x200), Some(true));
+        assert_eq!(unit.on_inst(0x100, J, 0x200), Some(false));
+        // 同じインデックスの別の分岐で追い出される
+        assert_eq!(unit.on_inst(0x110, J, 0x210), Some(true));
+        assert_eq!(unit.on_inst(0x100, J, 0x200), Some(true));
+
+        // 間接ジャンプは前回の飛び先を予測する
+        assert_eq!(unit.on_inst(0x120, JR, 0x500), Some(true));
+        assert_eq!(unit.on_inst(0x120, JR, 0x500), Some(false));
+        assert_eq!(unit.on_inst(0x120, JR, 0x600), Some(true));
+        assert_eq!(unit.sites()[&0x120].kind, BranchKind::Indirect);
+        assert_eq!(unit.on_inst(0x124, 0x0000_0013, 0x128), None);
+    }
+
+    #[test]
+    fn table() {
+        let mut unit = new_unit("btfn,penalty=3");
+        run(&mut unit, (0..10).map(|i| i < 9));
+        unit.on_inst(0x100, JAL, 0x200);
+        let mut out = Vec::new();
+        unit.write_table(&Symbols::default(), 10, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        for line in [
+            "predictor        btfn (bits 10, ras 8, btb 0, penalty 3)\n",
+            "branches         11 (mispredicts 1, accuracy 90.91%)\n",
+            "penalty cycles   3\n",
+            "00000010 branch           10   90.0%           1    90.00%",
+        ] {
+            assert!(out.contains(line), "{:?} in {:?}", line, out);
+        }
+        let total = BranchStats {
+            kind: BranchKind::Branch,
+            count: 0,
+            taken: 0,
+            mispredicts: 0,
+        };
+        assert_eq!(total.accuracy(), 1.0);
+    }
+}
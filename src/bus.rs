@@ -1,47 +1,615 @@
+use crate::cache::Caches;
+use crate::device::clint::{self, Clint};
+use crate::device::plic::{self, Plic};
+use crate::device::uart::{self, Uart};
+use crate::device::virtio::{self, VirtioMmio};
+use crate::error::{Access, Error, Result};
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+
+// Bus::newのRAM
+const RAM_BASE: u32 = 0;
 const RAM_SIZE: usize = 0x4000;
+const PAGE_SHIFT: usize = 12;
+
+// リセット直後に実行するROM (QEMUのvirtと同じ位置)
+pub const ROM_BASE: u32 = 0x1000;
+pub const ROM_SIZE: u32 = 0xF000;
+
+// デバイスのアドレス
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const UART_BASE: u32 = 0x1000_0000;
+// virtio-mmioはここからvirtio::SIZEごとに並べる
+pub const VIRTIO_BASE: u32 = 0x1000_1000;
+pub const VIRTIO_SLOTS: usize = 8;
+
+// PLICでのUARTの割り込み番号
+pub const UART_IRQ: usize = 10;
+// virtio-mmioの割り込み番号 (スロットの順に続く)
+pub const VIRTIO_IRQ: usize = 1;
 
+// mipのビット
+const MIP_MSIP: u32 = 1 << 3;
+const MIP_MTIP: u32 = 1 << 7;
+const MIP_SEIP: u32 = 1 << 9;
+const MIP_MEIP: u32 = 1 << 11;
+
+#[derive(Clone)]
 pub struct Bus {
-    ram: [u8; RAM_SIZE], // TODO: RAMサイズ後で調べる
+    ram: Vec<u8>,
+    ram_base: u32,
+    // ホストが置く読み出し専用の領域 (空なら無い)
+    rom: Vec<u8>,
+
+    clint: Clint,
+    plic: Plic,
+    uart: Uart,
+    virtio: Vec<VirtioMmio>,
+    // 前回割り込みを更新してからデバイスにアクセスがあった
+    device_accessed: bool,
+    // デバイスへのアクセスを副作用なしで失敗させる (JITの実行中)
+    ram_only: bool,
+
+    // キャッシュのモデル (Noneならキャッシュを考えない)
+    caches: Option<Box<Caches>>,
+    // キャッシュで待ってまだサイクル数に足していない分
+    stall: u64,
+
+    // デコードキャッシュに載っている命令を含むページ
+    code_pages: Vec<bool>,
+    // 命令を含むページへの書き込みがあったページ
+    dirty_code: Vec<u32>,
+    // 書き込みがあったページと書き込む前の中身 (JITの検証中だけ取る)
+    #[cfg(feature = "jit")]
+    journal: Option<Vec<(usize, Vec<u8>)>>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
     pub fn new() -> Self {
-        Self { ram: [0; RAM_SIZE] }
+        Self::with_ram(RAM_BASE, RAM_SIZE as u32).unwrap()
+    }
+
+    // RAMの位置と大きさを決める (Linuxなどを動かす時は0x80000000から広く取る)
+    // デバイスと重なる時やアドレスの終わりを越える時はNone
+    pub fn with_ram(base: u32, size: u32) -> Option<Self> {
+        let end = base.checked_add(size)?;
+        let devices = [
+            (CLINT_BASE, clint::SIZE),
+            (PLIC_BASE, plic::SIZE),
+            (UART_BASE, uart::SIZE),
+            (VIRTIO_BASE, VIRTIO_SLOTS as u32 * virtio::SIZE),
+        ];
+        if devices
+            .iter()
+            .any(|&(start, len)| base < start + len && start < end)
+        {
+            return None;
+        }
+        Some(Self {
+            ram: vec![0; size as usize],
+            ram_base: base,
+            rom: Vec::new(),
+            clint: Clint::new(),
+            plic: Plic::new(),
+            uart: Uart::new(),
+            virtio: Vec::new(),
+            device_accessed: false,
+            ram_only: false,
+            caches: None,
+            stall: 0,
+            code_pages: vec![false; (size as usize).div_ceil(1 << PAGE_SHIFT)],
+            dirty_code: Vec::new(),
+            #[cfg(feature = "jit")]
+            journal: None,
+        })
+    }
+
+    // RAMに収まるアクセスならインデックスを返す
+    fn index(&self, addr: u32, width: u8) -> Option<usize> {
+        let start = addr.wrapping_sub(self.ram_base) as usize;
+        match start.checked_add(width as usize) {
+            Some(end) if end <= self.ram.len() => Some(start),
+            _ => None,
+        }
     }
 
-    pub fn read8(&self, addr: u32) -> u8 {
-        self.ram[addr as usize]
+    // addrからlenバイトが全部RAMに収まるか
+    pub(crate) fn in_ram(&self, addr: u32, len: u32) -> bool {
+        let start = addr.wrapping_sub(self.ram_base) as u64;
+        start + len as u64 <= self.ram.len() as u64
     }
 
-    pub fn write8(&mut self, addr: u32, val: u8) {
-        self.ram[addr as usize] = val
+    // RAMだけを読む (デバイスの状態は変えない)
+    pub fn read_bytes(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            let addr = addr.wrapping_add(i as u32);
+            *b = match self.index(addr, 1) {
+                Some(i) => self.ram[i],
+                None => self.read_rom(addr, 1).ok_or(Error::BusFault {
+                    addr,
+                    width: 1,
+                    access: Access::Load,
+                })? as u8,
+            };
+        }
+        Ok(())
     }
 
-    pub fn read16(&self, addr: u32) -> u16 {
-        let low = self.read8(addr) as u16;
-        let high = self.read8(addr + 1) as u16;
+    pub fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        for (i, b) in data.iter().enumerate() {
+            self.write8(addr.wrapping_add(i as u32), *b)?;
+        }
+        Ok(())
+    }
+
+    // JITの検証用に、RAMを持たない複製を作る
+    #[cfg(feature = "jit")]
+    pub(crate) fn clone_without_ram(&mut self) -> Bus {
+        let ram = std::mem::take(&mut self.ram);
+        let bus = self.clone();
+        self.ram = ram;
+        bus
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn swap_ram(&mut self, other: &mut Bus) {
+        std::mem::swap(&mut self.ram, &mut other.ram);
+    }
+
+    // 書き込みのあったページを覚え始める
+    #[cfg(feature = "jit")]
+    pub(crate) fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    // 書き込みのあったページ (RAMの先頭からの番号) と書き込む前の中身
+    #[cfg(feature = "jit")]
+    pub(crate) fn take_journal(&mut self) -> Vec<(usize, Vec<u8>)> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn page(&self, page: usize) -> &[u8] {
+        &self.ram[page_range(page, self.ram.len())]
+    }
+
+    // デコードキャッシュやJITの記録を通さずにページを書き戻す
+    #[cfg(feature = "jit")]
+    pub(crate) fn set_page(&mut self, page: usize, data: &[u8]) {
+        let range = page_range(page, self.ram.len());
+        self.ram[range].copy_from_slice(data);
+    }
+
+    pub fn ram_base(&self) -> u32 {
+        self.ram_base
+    }
 
-        low | (high << 8)
+    pub fn ram_size(&self) -> u32 {
+        self.ram.len() as u32
     }
 
-    pub fn write16(&mut self, addr: u32, val: u16) {
-        self.ram[addr as usize] = val as u8;
-        self.ram[(addr + 1) as usize] = (val >> 8) as u8;
+    // ROMの中身を置き換える (RAMと重なるか大きすぎればfalse)
+    pub fn set_rom(&mut self, data: &[u8]) -> bool {
+        if data.len() > ROM_SIZE as usize {
+            return false;
+        }
+        let end = ROM_BASE + data.len() as u32;
+        let ram_end = self.ram_base as u64 + self.ram.len() as u64;
+        if !data.is_empty() && self.ram_base < end && (ROM_BASE as u64) < ram_end {
+            return false;
+        }
+        // 前のROMの命令をデコードキャッシュから捨てる
+        for page in (0..self.rom.len().max(data.len())).step_by(1 << PAGE_SHIFT) {
+            self.dirty_code.push(ROM_BASE + page as u32);
+        }
+        self.rom = data.to_vec();
+        true
     }
 
-    pub fn read32(&self, addr: u32) -> u32 {
-        let lowest = self.read8(addr) as u32;
-        let lower = self.read8(addr + 1) as u32;
-        let higher = self.read8(addr + 2) as u32;
-        let highest = self.read8(addr + 3) as u32;
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u32, width: u8) -> Option<u32> {
+        let start = addr.wrapping_sub(ROM_BASE) as usize;
+        let bytes = self.rom.get(start..start.checked_add(width as usize)?)?;
+        Some(bytes.iter().rev().fold(0, |val, &b| (val << 8) | b as u32))
+    }
+
+    pub fn clint(&self) -> &Clint {
+        &self.clint
+    }
+
+    // タイマーの設定が変わるのでブロックを区切らせる
+    pub(crate) fn clint_mut(&mut self) -> &mut Clint {
+        self.device_accessed = true;
+        &mut self.clint
+    }
+
+    pub fn plic(&self) -> &Plic {
+        &self.plic
+    }
+
+    pub fn uart(&self) -> &Uart {
+        &self.uart
+    }
+
+    // ホストからの入力で割り込みが変わるので、次の命令の前に割り込みを更新させる
+    pub fn uart_mut(&mut self) -> &mut Uart {
+        self.device_accessed = true;
+        &mut self.uart
+    }
+
+    // 空いているスロットにvirtioデバイスをつなぎ、そのアドレスを返す
+    pub fn add_virtio(&mut self, device: virtio::Device) -> Option<u32> {
+        if self.virtio.len() == VIRTIO_SLOTS {
+            return None;
+        }
+        self.virtio.push(VirtioMmio::new(device));
+        Some(VIRTIO_BASE + (self.virtio.len() as u32 - 1) * virtio::SIZE)
+    }
+
+    pub fn virtio(&self) -> &[VirtioMmio] {
+        &self.virtio
+    }
+
+    pub fn virtio_mut(&mut self) -> &mut [VirtioMmio] {
+        &mut self.virtio
+    }
+
+    pub fn set_caches(&mut self, caches: Option<Caches>) {
+        self.caches = caches.map(Box::new);
+        self.stall = 0;
+    }
+
+    pub fn caches(&self) -> Option<&Caches> {
+        self.caches.as_deref()
+    }
+
+    pub fn caches_mut(&mut self) -> Option<&mut Caches> {
+        self.caches.as_deref_mut()
+    }
+
+    // スナップショットから戻す時に別のBusへ移す
+    pub(crate) fn take_caches(&mut self) -> Option<Box<Caches>> {
+        self.caches.take()
+    }
+
+    pub(crate) fn put_caches(&mut self, caches: Option<Box<Caches>>) {
+        self.caches = caches;
+    }
 
-        lowest | (lower << 8) | (higher << 16) | (highest << 24)
+    #[inline(always)]
+    pub(crate) fn caching(&self) -> bool {
+        self.caches.is_some()
     }
 
-    pub fn write32(&mut self, addr: u32, val: u32) {
-        self.ram[addr as usize] = val as u8;
-        self.ram[(addr + 1) as usize] = (val >> 8) as u8;
-        self.ram[(addr + 2) as usize] = (val >> 16) as u8;
-        self.ram[(addr + 3) as usize] = (val >> 24) as u8;
+    // CPUからのRAMへのアクセスをキャッシュに通す
+    // デバッガからの読み書きやデバイスへのアクセスは通さない
+    pub(crate) fn cache_access(&mut self, addr: u32, width: u8, access: Access) {
+        let ram = self.index(addr, width).is_some();
+        if let (Some(caches), true) = (&mut self.caches, ram) {
+            self.stall += caches.access(access, addr, width);
+        }
     }
+
+    pub(crate) fn take_stall(&mut self) -> u64 {
+        std::mem::take(&mut self.stall)
+    }
+
+    pub(crate) fn max_stall(&self) -> u64 {
+        self.caches.as_ref().map_or(0, |c| c.max_stall())
+    }
+
+    // 命令を実行した分だけ時間を進める
+    #[inline(always)]
+    pub(crate) fn advance(&mut self, n: u64) {
+        self.clint.advance(n);
+    }
+
+    pub(crate) fn device_accessed(&self) -> bool {
+        self.device_accessed
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn set_ram_only(&mut self, enable: bool) {
+        self.ram_only = enable;
+    }
+
+    // デバイスの割り込み線をPLICに伝える
+    pub(crate) fn update_irq(&mut self) {
+        self.device_accessed = false;
+        self.plic.set_level(UART_IRQ, self.uart.irq());
+        for (i, virtio) in self.virtio.iter().enumerate() {
+            self.plic.set_level(VIRTIO_IRQ + i, virtio.irq());
+        }
+    }
+
+    // デバイスが立てているmipのビット
+    pub fn interrupts(&self) -> u32 {
+        let mut mip = 0;
+        if self.clint.msip() {
+            mip |= MIP_MSIP;
+        }
+        if self.clint.mtip() {
+            mip |= MIP_MTIP;
+        }
+        if self.plic.irq(0) {
+            mip |= MIP_MEIP;
+        }
+        if self.plic.irq(1) {
+            mip |= MIP_SEIP;
+        }
+        mip
+    }
+
+    fn fault(&self, addr: u32, width: u8, access: Access) -> Error {
+        Error::BusFault {
+            addr,
+            width,
+            access,
+        }
+    }
+
+    #[inline(never)]
+    fn read_device(&mut self, addr: u32, width: u8) -> Result<u32> {
+        // ROMは読んでも副作用が無い
+        if let Some(val) = self.read_rom(addr, width) {
+            return Ok(val);
+        }
+        if self.ram_only {
+            return Err(self.fault(addr, width, Access::Load));
+        }
+        let val = match addr {
+            a if a.wrapping_sub(CLINT_BASE) < clint::SIZE => self.clint.read(a - CLINT_BASE, width),
+            a if a.wrapping_sub(PLIC_BASE) < plic::SIZE => self.plic.read(a - PLIC_BASE, width),
+            a if a.wrapping_sub(UART_BASE) < uart::SIZE => self.uart.read(a - UART_BASE, width),
+            a if a.wrapping_sub(VIRTIO_BASE) < VIRTIO_SLOTS as u32 * virtio::SIZE => {
+                let offset = a - VIRTIO_BASE;
+                let slot = (offset / virtio::SIZE) as usize;
+                self.virtio
+                    .get_mut(slot)
+                    .and_then(|v| v.read(offset % virtio::SIZE, width))
+            }
+            _ => None,
+        };
+        self.device_accessed = true;
+        val.ok_or(self.fault(addr, width, Access::Load))
+    }
+
+    #[inline(never)]
+    fn write_device(&mut self, addr: u32, width: u8, val: u32) -> Result<()> {
+        if self.ram_only {
+            return Err(self.fault(addr, width, Access::Store));
+        }
+        let ok = match addr {
+            a if a.wrapping_sub(CLINT_BASE) < clint::SIZE => {
+                self.clint.write(a - CLINT_BASE, width, val)
+            }
+            a if a.wrapping_sub(PLIC_BASE) < plic::SIZE => {
+                self.plic.write(a - PLIC_BASE, width, val)
+            }
+            a if a.wrapping_sub(UART_BASE) < uart::SIZE => {
+                self.uart.write(a - UART_BASE, width, val)
+            }
+            a if a.wrapping_sub(VIRTIO_BASE) < VIRTIO_SLOTS as u32 * virtio::SIZE => {
+                let offset = a - VIRTIO_BASE;
+                self.write_virtio(
+                    (offset / virtio::SIZE) as usize,
+                    offset % virtio::SIZE,
+                    width,
+                    val,
+                )
+            }
+            _ => false,
+        };
+        self.device_accessed = true;
+        if ok {
+            Ok(())
+        } else {
+            Err(self.fault(addr, width, Access::Store))
+        }
+    }
+
+    // ホストから届いたデータを (スロット, ポート, データ) で集める
+    // ゲストから見える状態は変えないので、記録してからdeliverで渡す
+    pub(crate) fn receive(&mut self) -> Vec<(u32, u32, Vec<u8>)> {
+        let mut inputs = Vec::new();
+        for (slot, v) in self.virtio.iter_mut().enumerate() {
+            for (port, data) in v.receive() {
+                inputs.push((slot as u32, port, data));
+            }
+        }
+        inputs
+    }
+
+    // receiveで集めたデータをデバイスに渡す (受け取れないデバイスならfalse)
+    pub(crate) fn deliver(&mut self, slot: u32, port: u32, data: &[u8]) -> bool {
+        if slot as usize >= self.virtio.len() {
+            return false;
+        }
+        let mut virtio = std::mem::take(&mut self.virtio);
+        let ok = virtio[slot as usize].deliver(port, data, self);
+        self.virtio = virtio;
+        self.device_accessed = true;
+        ok
+    }
+
+    // virtioはキューの処理でRAMを読み書きするので、一度Busから外して渡す
+    fn write_virtio(&mut self, slot: usize, offset: u32, width: u8, val: u32) -> bool {
+        if slot >= self.virtio.len() {
+            return false;
+        }
+        let mut virtio = std::mem::take(&mut self.virtio);
+        let ok = virtio[slot].write(offset, width, val, self);
+        self.virtio = virtio;
+        ok
+    }
+
+    // デバイスからRAMへの書き込み (デバイスには書かない)
+    pub(crate) fn dma_write(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let start = addr.wrapping_sub(self.ram_base) as usize;
+        if start + data.len() > self.ram.len() {
+            return Err(self.fault(addr, 1, Access::Store));
+        }
+        for page in (start..start + data.len()).step_by(1 << PAGE_SHIFT) {
+            self.note_write(page, 1);
+        }
+        if !data.is_empty() {
+            self.note_write(start + data.len() - 1, 1);
+        }
+        self.ram[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    // 命令をデコードキャッシュに載せたページを覚えておく
+    pub(crate) fn mark_code(&mut self, addr: u32) {
+        let i = addr.wrapping_sub(self.ram_base) as usize;
+        if let Some(page) = self.code_pages.get_mut(i >> PAGE_SHIFT) {
+            *page = true;
+        }
+    }
+
+    pub(crate) fn has_dirty_code(&self) -> bool {
+        !self.dirty_code.is_empty()
+    }
+
+    pub(crate) fn take_dirty_code(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.dirty_code)
+    }
+
+    fn note_write(&mut self, i: usize, width: usize) {
+        for page in [i >> PAGE_SHIFT, (i + width - 1) >> PAGE_SHIFT] {
+            #[cfg(feature = "jit")]
+            if let Some(journal) = &mut self.journal {
+                if !journal.iter().any(|(p, _)| *p == page) {
+                    journal.push((page, self.ram[page_range(page, self.ram.len())].to_vec()));
+                }
+            }
+            if self.code_pages[page] {
+                self.code_pages[page] = false;
+                let addr = self.ram_base.wrapping_add((page << PAGE_SHIFT) as u32);
+                self.dirty_code.push(addr);
+            }
+        }
+    }
+
+    // 命令フェッチはRAMとROMからだけ行う
+    pub(crate) fn fetch32(&self, addr: u32) -> Result<u32> {
+        match self.index(addr, 4) {
+            Some(i) => Ok(u32::from_le_bytes(self.ram[i..i + 4].try_into().unwrap())),
+            None => self
+                .read_rom(addr, 4)
+                .ok_or(self.fault(addr, 4, Access::Fetch)),
+        }
+    }
+
+    pub fn read8(&mut self, addr: u32) -> Result<u8> {
+        let Some(i) = self.index(addr, 1) else {
+            return self.read_device(addr, 1).map(|v| v as u8);
+        };
+        Ok(self.ram[i])
+    }
+
+    pub fn write8(&mut self, addr: u32, val: u8) -> Result<()> {
+        let Some(i) = self.index(addr, 1) else {
+            return self.write_device(addr, 1, val as u32);
+        };
+        self.note_write(i, 1);
+        self.ram[i] = val;
+        Ok(())
+    }
+
+    pub fn read16(&mut self, addr: u32) -> Result<u16> {
+        let Some(i) = self.index(addr, 2) else {
+            return self.read_device(addr, 2).map(|v| v as u16);
+        };
+        let low = self.ram[i] as u16;
+        let high = self.ram[i + 1] as u16;
+
+        Ok(low | (high << 8))
+    }
+
+    pub fn write16(&mut self, addr: u32, val: u16) -> Result<()> {
+        let Some(i) = self.index(addr, 2) else {
+            return self.write_device(addr, 2, val as u32);
+        };
+        self.note_write(i, 2);
+        self.ram[i] = val as u8;
+        self.ram[i + 1] = (val >> 8) as u8;
+        Ok(())
+    }
+
+    pub fn read32(&mut self, addr: u32) -> Result<u32> {
+        let Some(i) = self.index(addr, 4) else {
+            return self.read_device(addr, 4);
+        };
+        let lowest = self.ram[i] as u32;
+        let lower = self.ram[i + 1] as u32;
+        let higher = self.ram[i + 2] as u32;
+        let highest = self.ram[i + 3] as u32;
+
+        Ok(lowest | (lower << 8) | (higher << 16) | (highest << 24))
+    }
+
+    pub fn write32(&mut self, addr: u32, val: u32) -> Result<()> {
+        let Some(i) = self.index(addr, 4) else {
+            return self.write_device(addr, 4, val);
+        };
+        self.note_write(i, 4);
+        self.ram[i] = val as u8;
+        self.ram[i + 1] = (val >> 8) as u8;
+        self.ram[i + 2] = (val >> 16) as u8;
+        self.ram[i + 3] = (val >> 24) as u8;
+        Ok(())
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u32(self.ram_base);
+        w.bytes(&self.ram);
+        self.clint.save(w);
+        self.plic.save(w);
+        self.uart.save(w);
+        w.u32(self.virtio.len() as u32);
+        for virtio in &self.virtio {
+            virtio.save(w);
+        }
+    }
+
+    // virtioのデバイスとROMはhostのものを引き継ぐ (ディスクイメージなどはスナップショットに含めない)
+    pub(crate) fn load(r: &mut Reader, host: &Bus) -> snapshot::Result<Self> {
+        let mut bus = Self::with_ram(host.ram_base, host.ram_size()).unwrap();
+        let base = r.u32()?;
+        let ram = r.bytes()?;
+        if base != host.ram_base || ram.len() != host.ram.len() {
+            return Err(SnapshotError::Invalid("ram size"));
+        }
+        bus.ram.copy_from_slice(ram);
+        bus.rom = host.rom.clone();
+        bus.clint = Clint::load(r)?;
+        bus.plic = Plic::load(r)?;
+        bus.uart = Uart::load(r)?;
+        if r.u32()? as usize != host.virtio.len() {
+            return Err(SnapshotError::Invalid("virtio devices"));
+        }
+        for virtio in &host.virtio {
+            let mut virtio = virtio.clone();
+            virtio.load(r)?;
+            bus.virtio.push(virtio);
+        }
+        Ok(bus)
+    }
+}
+
+// page番目のページのRAMでの範囲 (RAMの最後は半端なこともある)
+#[cfg(feature = "jit")]
+fn page_range(page: usize, len: usize) -> std::ops::Range<usize> {
+    let start = page << PAGE_SHIFT;
+    start..(start + (1 << PAGE_SHIFT)).min(len)
 }
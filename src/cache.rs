@@ -0,0 +1,659 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::error::Access;
+
+// 置き換える行の選び方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    // 書き込みで行を確保し、追い出す時に書き戻す
+    WriteBack,
+    // 常に次のレベルへ書き、外れても行を確保しない
+    WriteThrough,
+}
+
+// 1つのレベルのキャッシュの構成
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub size: usize,
+    pub ways: usize,
+    pub line: usize,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    // このレベルを引いた時に足すサイクル数
+    // L1の分は普通タイミングモデルの命令のレイテンシに含めるので0にしておく
+    pub latency: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cache config: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl CacheConfig {
+    pub fn l1() -> Self {
+        Self {
+            size: 4096,
+            ways: 2,
+            line: 32,
+            replacement: Replacement::Lru,
+            write: WritePolicy::WriteBack,
+            latency: 0,
+        }
+    }
+
+    pub fn l2() -> Self {
+        Self {
+            size: 16384,
+            ways: 8,
+            line: 64,
+            replacement: Replacement::Lru,
+            write: WritePolicy::WriteBack,
+            latency: 10,
+        }
+    }
+
+    // "size=8k,ways=4,line=32,policy=lru,write=back,latency=2" のような指定で上書きする
+    pub fn parse(mut self, spec: &str) -> Result<Self, ConfigError> {
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let err = || ConfigError(item.to_string());
+            let (name, val) = item.split_once('=').ok_or_else(err)?;
+            match name {
+                "size" => self.size = parse_size(val).ok_or_else(err)?,
+                "ways" => self.ways = val.parse().map_err(|_| err())?,
+                "line" => self.line = parse_size(val).ok_or_else(err)?,
+                "latency" => self.latency = val.parse().map_err(|_| err())?,
+                "policy" => {
+                    self.replacement = match val {
+                        "lru" => Replacement::Lru,
+                        "fifo" => Replacement::Fifo,
+                        "random" => Replacement::Random,
+                        _ => return Err(err()),
+                    }
+                }
+                "write" => {
+                    self.write = match val {
+                        "back" => WritePolicy::WriteBack,
+                        "through" => WritePolicy::WriteThrough,
+                        _ => return Err(err()),
+                    }
+                }
+                _ => return Err(err()),
+            }
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    // 行の大きさとセット数は2のべき
+    fn validate(&self) -> Result<(), ConfigError> {
+        let sets = self.size / (self.ways.max(1) * self.line.max(1));
+        if self.ways == 0
+            || !self.line.is_power_of_two()
+            || self.line < 4
+            || sets == 0
+            || !sets.is_power_of_two()
+            || sets * self.ways * self.line != self.size
+        {
+            return Err(ConfigError(format!(
+                "size={},ways={},line={}",
+                self.size, self.ways, self.line
+            )));
+        }
+        Ok(())
+    }
+}
+
+// "16k" や "1m" のような大きさ
+fn parse_size(s: &str) -> Option<usize> {
+    let (num, unit) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 1024),
+        b'm' | b'M' => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (s, 1),
+    };
+    num.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+// キャッシュの階層の構成
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachesConfig {
+    pub l1i: CacheConfig,
+    pub l1d: CacheConfig,
+    pub l2: Option<CacheConfig>,
+    // メモリまで行った時に足すサイクル数
+    pub memory_latency: u64,
+}
+
+impl Default for CachesConfig {
+    fn default() -> Self {
+        Self {
+            l1i: CacheConfig::l1(),
+            l1d: CacheConfig::l1(),
+            l2: None,
+            memory_latency: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    // 行の番号 (アドレスを行の大きさで割ったもの)
+    tag: u32,
+    valid: bool,
+    dirty: bool,
+    // LRUなら最後に使った時刻、FIFOなら載せた時刻
+    stamp: u64,
+}
+
+// 1回のアクセスの結果
+struct Outcome {
+    hit: bool,
+    // 次のレベルから行を読む
+    fill: bool,
+    // 次のレベルへ書く (ライトスルー)
+    write_through: bool,
+    // 追い出して書き戻す行のアドレス
+    writeback: Option<u32>,
+}
+
+// タグだけを持つキャッシュ (データはRAMにある)
+#[derive(Clone)]
+struct Cache {
+    config: CacheConfig,
+    line_shift: u32,
+    set_mask: u32,
+    lines: Vec<Line>,
+    clock: u64,
+    seed: u32,
+}
+
+impl Cache {
+    fn new(config: CacheConfig) -> Self {
+        let sets = config.size / (config.ways * config.line);
+        Self {
+            line_shift: config.line.trailing_zeros(),
+            set_mask: sets as u32 - 1,
+            lines: vec![Line::default(); sets * config.ways],
+            clock: 0,
+            seed: 0x1234_5678,
+            config,
+        }
+    }
+
+    fn access(&mut self, addr: u32, write: bool) -> Outcome {
+        self.clock += 1;
+        let tag = addr >> self.line_shift;
+        let ways = self.config.ways;
+        let set = (tag & self.set_mask) as usize * ways;
+        let through = self.config.write == WritePolicy::WriteThrough;
+        let lines = &mut self.lines[set..set + ways];
+
+        if let Some(line) = lines.iter_mut().find(|l| l.valid && l.tag == tag) {
+            if self.config.replacement == Replacement::Lru {
+                line.stamp = self.clock;
+            }
+            line.dirty |= write && !through;
+            return Outcome {
+                hit: true,
+                fill: false,
+                write_through: write && through,
+                writeback: None,
+            };
+        }
+        if write && through {
+            return Outcome {
+                hit: false,
+                fill: false,
+                write_through: true,
+                writeback: None,
+            };
+        }
+
+        // 空いている行か、置き換え方で選んだ行に載せる
+        let victim = match lines.iter().position(|l| !l.valid) {
+            Some(i) => i,
+            None if self.config.replacement == Replacement::Random => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                self.seed as usize % ways
+            }
+            None => (0..ways).min_by_key(|&i| lines[i].stamp).unwrap(),
+        };
+        let line = &mut self.lines[set + victim];
+        let writeback = (line.valid && line.dirty).then_some(line.tag << self.line_shift);
+        *line = Line {
+            tag,
+            valid: true,
+            dirty: write,
+            stamp: self.clock,
+        };
+        Outcome {
+            hit: false,
+            fill: true,
+            write_through: false,
+            writeback,
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.lines.fill(Line::default());
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn miss_rate(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            n => self.misses as f64 / n as f64,
+        }
+    }
+
+    fn count(&mut self, outcome: &Outcome) {
+        if outcome.hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        if outcome.writeback.is_some() {
+            self.writebacks += 1;
+        }
+    }
+}
+
+// アドレスの範囲ごとの集計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionStats {
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+    pub l1i: CacheStats,
+    pub l1d: CacheStats,
+    pub l2: CacheStats,
+    // キャッシュとメモリを待ったサイクル数
+    pub stall_cycles: u64,
+}
+
+impl RegionStats {
+    fn new(name: &str, start: u32, end: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            end,
+            l1i: CacheStats::default(),
+            l1d: CacheStats::default(),
+            l2: CacheStats::default(),
+            stall_cycles: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Level {
+    L1i,
+    L1d,
+    L2,
+}
+
+// L1命令キャッシュ、L1データキャッシュと共有のL2
+// RAMへのアクセスだけをBusから通す (デバイスはキャッシュしない)
+#[derive(Clone)]
+pub struct Caches {
+    l1i: Cache,
+    l1d: Cache,
+    l2: Option<Cache>,
+    memory_latency: u64,
+    // 最後は "other" (どの範囲にも入らないアクセス)
+    regions: Vec<RegionStats>,
+    total: RegionStats,
+}
+
+impl Caches {
+    pub fn new(config: CachesConfig) -> Result<Self, ConfigError> {
+        config.l1i.validate()?;
+        config.l1d.validate()?;
+        if let Some(l2) = &config.l2 {
+            l2.validate()?;
+        }
+        Ok(Self {
+            l1i: Cache::new(config.l1i),
+            l1d: Cache::new(config.l1d),
+            l2: config.l2.map(Cache::new),
+            memory_latency: config.memory_latency,
+            regions: vec![RegionStats::new("other", 0, 0)],
+            total: RegionStats::new("total", 0, 0),
+        })
+    }
+
+    // 集計する範囲 [start, end) を足す。重なっていれば先に足した方に数える
+    pub fn add_region(&mut self, name: &str, start: u32, end: u32) {
+        let other = self.regions.len() - 1;
+        self.regions
+            .insert(other, RegionStats::new(name, start, end));
+    }
+
+    pub fn regions(&self) -> &[RegionStats] {
+        &self.regions
+    }
+
+    pub fn total(&self) -> &RegionStats {
+        &self.total
+    }
+
+    // 中身を捨てる (集計はそのまま)
+    pub fn invalidate(&mut self) {
+        self.l1i.invalidate();
+        self.l1d.invalidate();
+        if let Some(l2) = &mut self.l2 {
+            l2.invalidate();
+        }
+    }
+
+    // アクセスして待つサイクル数を返す。行をまたぐアクセスは2回引く
+    pub fn access(&mut self, access: Access, addr: u32, width: u8) -> u64 {
+        let shift = match access {
+            Access::Fetch => self.l1i.line_shift,
+            _ => self.l1d.line_shift,
+        };
+        let last = addr.wrapping_add(width as u32 - 1);
+        let mut cycles = self.access_line(access, addr);
+        if last >> shift != addr >> shift {
+            cycles += self.access_line(access, last);
+        }
+        cycles
+    }
+
+    // 1回のアクセスで待ちうる最大のサイクル数
+    pub fn max_stall(&self) -> u64 {
+        let next = match &self.l2 {
+            Some(l2) => l2.config.latency + 2 * self.memory_latency,
+            None => self.memory_latency,
+        };
+        let l1 = self.l1i.config.latency.max(self.l1d.config.latency);
+        2 * (l1 + 2 * next)
+    }
+
+    fn access_line(&mut self, access: Access, addr: u32) -> u64 {
+        let region = self.region(addr);
+        let write = access == Access::Store;
+        let (level, l1) = match access {
+            Access::Fetch => (Level::L1i, &mut self.l1i),
+            _ => (Level::L1d, &mut self.l1d),
+        };
+        let mut cycles = l1.config.latency;
+        let outcome = l1.access(addr, write);
+        self.count(region, level, &outcome);
+        if outcome.fill {
+            cycles += self.next_level(region, addr, false);
+        }
+        if outcome.write_through {
+            cycles += self.next_level(region, addr, true);
+        }
+        if let Some(victim) = outcome.writeback {
+            cycles += self.next_level(region, victim, true);
+        }
+        self.regions[region].stall_cycles += cycles;
+        self.total.stall_cycles += cycles;
+        cycles
+    }
+
+    // L1から出たアクセスをL2かメモリへ送る
+    fn next_level(&mut self, region: usize, addr: u32, write: bool) -> u64 {
+        let Some(l2) = &mut self.l2 else {
+            return self.memory_latency;
+        };
+        let mut cycles = l2.config.latency;
+        let outcome = l2.access(addr, write);
+        self.count(region, Level::L2, &outcome);
+        if outcome.fill || outcome.write_through {
+            cycles += self.memory_latency;
+        }
+        if outcome.writeback.is_some() {
+            cycles += self.memory_latency;
+        }
+        cycles
+    }
+
+    fn region(&self, addr: u32) -> usize {
+        let other = self.regions.len() - 1;
+        self.regions[..other]
+            .iter()
+            .position(|r| r.start <= addr && addr < r.end)
+            .unwrap_or(other)
+    }
+
+    fn count(&mut self, region: usize, level: Level, outcome: &Outcome) {
+        for stats in [&mut self.regions[region], &mut self.total] {
+            match level {
+                Level::L1i => stats.l1i.count(outcome),
+                Level::L1d => stats.l1d.count(outcome),
+                Level::L2 => stats.l2.count(outcome),
+            }
+        }
+    }
+
+    pub fn write_table<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<6} {:>8} {:>5} {:>5} {:<7} {:<8} {:>8}",
+            "cache", "size", "ways", "line", "policy", "write", "latency"
+        )?;
+        let levels = [("l1i", Some(&self.l1i)), ("l1d", Some(&self.l1d))];
+        for (name, cache) in levels.into_iter().chain([("l2", self.l2.as_ref())]) {
+            let Some(cache) = cache else {
+                continue;
+            };
+            let c = &cache.config;
+            let policy = match c.replacement {
+                Replacement::Lru => "lru",
+                Replacement::Fifo => "fifo",
+                Replacement::Random => "random",
+            };
+            let write = match c.write {
+                WritePolicy::WriteBack => "back",
+                WritePolicy::WriteThrough => "through",
+            };
+            writeln!(
+                out,
+                "{:<6} {:>8} {:>5} {:>5} {:<7} {:<8} {:>8}",
+                name, c.size, c.ways, c.line, policy, write, c.latency
+            )?;
+        }
+        writeln!(out, "memory latency {}", self.memory_latency)?;
+
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<16} {:<17} {:<5} {:>10} {:>10} {:>7} {:>10} {:>12}",
+            "region", "range", "level", "accesses", "misses", "miss%", "writebacks", "stall cycles"
+        )?;
+        for r in self.regions.iter().chain([&self.total]) {
+            if r.l1i.accesses() + r.l1d.accesses() == 0 {
+                continue;
+            }
+            let range = if r.start < r.end {
+                format!("{:08x}-{:08x}", r.start, r.end)
+            } else {
+                String::new()
+            };
+            let mut levels = vec![("l1i", &r.l1i), ("l1d", &r.l1d)];
+            if self.l2.is_some() {
+                levels.push(("l2", &r.l2));
+            }
+            for (i, (level, s)) in levels.into_iter().enumerate() {
+                let (name, range, stall) = if i == 0 {
+                    (r.name.as_str(), range.as_str(), r.stall_cycles.to_string())
+                } else {
+                    ("", "", String::new())
+                };
+                writeln!(
+                    out,
+                    "{:<16} {:<17} {:<5} {:>10} {:>10} {:>6.2}% {:>10} {:>12}",
+                    name,
+                    range,
+                    level,
+                    s.accesses(),
+                    s.misses,
+                    s.miss_rate() * 100.0,
+                    s.writebacks,
+                    stall
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheConfig, CacheStats, Caches, CachesConfig, Replacement, WritePolicy};
+    use crate::error::Access;
+
+    // 2ウェイ、2セット、16バイトの行 (0x00, 0x20, 0x40は同じセット)
+    fn tiny(spec: &str) -> CacheConfig {
+        CacheConfig::l1()
+            .parse(&format!("size=64,ways=2,line=16,{}", spec))
+            .unwrap()
+    }
+
+    fn hierarchy(l1d: CacheConfig, l2: Option<CacheConfig>) -> Caches {
+        Caches::new(CachesConfig {
+            l1i: tiny(""),
+            l1d,
+            l2,
+            memory_latency: 50,
+        })
+        .unwrap()
+    }
+
+    fn stats(hits: u64, misses: u64, writebacks: u64) -> CacheStats {
+        CacheStats {
+            hits,
+            misses,
+            writebacks,
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = CacheConfig::l1()
+            .parse("size=8k,ways=4,line=32,policy=fifo,write=through,latency=2")
+            .unwrap();
+        assert_eq!(
+            config,
+            CacheConfig {
+                size: 8192,
+                ways: 4,
+                line: 32,
+                replacement: Replacement::Fifo,
+                write: WritePolicy::WriteThrough,
+                latency: 2,
+            }
+        );
+        for spec in [
+            "size=3000",
+            "ways=0",
+            "line=2",
+            "line=24",
+            "ways=3",
+            "policy=plru",
+            "write=around",
+            "size",
+            "color=red",
+        ] {
+            assert!(CacheConfig::l1().parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn replacement_policies() {
+        let order = [0x00, 0x20, 0x00, 0x40, 0x00, 0x20];
+        for (policy, expected) in [("lru", stats(2, 4, 0)), ("fifo", stats(1, 5, 0))] {
+            let mut caches = hierarchy(tiny(&format!("policy={}", policy)), None);
+            for addr in order {
+                caches.access(Access::Load, addr, 4);
+            }
+            assert_eq!(caches.total().l1d, expected, "{}", policy);
+            assert_eq!(caches.total().l1i, stats(0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut caches = hierarchy(tiny("write=back"), None);
+        assert_eq!(caches.access(Access::Store, 0x00, 4), 50);
+        assert_eq!(caches.access(Access::Load, 0x20, 4), 50);
+        // 汚れた0x00を追い出して書き戻す
+        assert_eq!(caches.access(Access::Load, 0x40, 4), 100);
+        assert_eq!(caches.total().l1d, stats(0, 3, 1));
+        assert_eq!(caches.total().stall_cycles, 200);
+
+        // ライトスルーは外れても行を確保しない
+        let mut caches = hierarchy(tiny("write=through"), None);
+        assert_eq!(caches.access(Access::Store, 0x00, 4), 50);
+        assert_eq!(caches.access(Access::Load, 0x00, 4), 50);
+        assert_eq!(caches.access(Access::Store, 0x00, 4), 50);
+        assert_eq!(caches.total().l1d, stats(1, 2, 0));
+    }
+
+    #[test]
+    fn l2_and_regions() {
+        let l2 = CacheConfig::l2().parse("size=256,ways=2,line=16").unwrap();
+        let mut caches = hierarchy(tiny(""), Some(l2));
+        caches.add_region("data", 0x1000, 0x2000);
+        assert_eq!(caches.max_stall(), 2 * 2 * (10 + 2 * 50));
+
+        // L1とL2の両方で外れる
+        assert_eq!(caches.access(Access::Fetch, 0x100, 4), 60);
+        assert_eq!(caches.access(Access::Fetch, 0x104, 4), 0);
+        // 行をまたぐので2行分
+        assert_eq!(caches.access(Access::Load, 0x100E, 4), 120);
+        caches.invalidate();
+        // invalidateはL2の中身も捨てる
+        assert_eq!(caches.access(Access::Load, 0x1010, 4), 60);
+
+        let regions = caches.regions();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(
+            (regions[0].name.as_str(), regions[1].name.as_str()),
+            ("data", "other")
+        );
+        assert_eq!(regions[0].l1d, stats(0, 3, 0));
+        assert_eq!(regions[0].l2, stats(0, 3, 0));
+        assert_eq!(regions[0].stall_cycles, 180);
+        assert_eq!(regions[1].l1i, stats(1, 1, 0));
+        assert_eq!(regions[1].l2, stats(0, 1, 0));
+        assert_eq!(regions[1].stall_cycles, 60);
+        assert_eq!(caches.total().stall_cycles, 240);
+
+        let mut out = Vec::new();
+        caches.write_table(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("memory latency 50\n"));
+        assert!(out.contains("data             00001000-00002000 l1i"));
+    }
+}
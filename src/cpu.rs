@@ -1,11 +1,135 @@
-use anyhow::{bail, Result};
+use std::collections::BTreeSet;
 
 use crate::bus::Bus;
+use crate::decode_cache::{Block, DecodeCache};
+use crate::error::{Access, Error, Result};
+use crate::hooks::{CsrAccess, Hooks, MemAccess, TrapEvent};
+use crate::mmu::{self, Context};
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+use crate::symbols::Symbols;
+use crate::timing::{Class, InstEvent, TimingModel};
+
+mod counters;
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(feature = "jit")]
+pub use jit::JitMode;
+mod replay;
+mod reverse;
+mod sbi;
+#[cfg(test)]
+pub(crate) mod testing;
+pub use counters::{
+    EVENT_BRANCH, EVENT_BRANCH_TAKEN, EVENT_LOAD, EVENT_NONE, EVENT_STORE, EVENT_TLB_MISS,
+    EVENT_TRAP,
+};
+pub use replay::{Event, ReplayLog};
+
+const MSTATUS_SIE: u32 = 1 << 1;
+const MSTATUS_MIE: u32 = 1 << 3;
+const MSTATUS_SPIE: u32 = 1 << 5;
+const MSTATUS_MPIE: u32 = 1 << 7;
+const MSTATUS_SPP: u32 = 1 << 8;
+const MSTATUS_MPP: u32 = 0b11 << 11;
+const MSTATUS_MPRV: u32 = 1 << 17;
+const MSTATUS_SUM: u32 = 1 << 18;
+const MSTATUS_MXR: u32 = 1 << 19;
+const MSTATUS_TVM: u32 = 1 << 20;
+const MSTATUS_TW: u32 = 1 << 21;
+const MSTATUS_TSR: u32 = 1 << 22;
+
+const MIP_STIP: u32 = 1 << 5;
+const MIP_MTIP: u32 = 1 << 7;
+
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// S-modeに委譲できる割り込み (SSIP, STIP, SEIP)
+const MIDELEG_MASK: u32 = 0x222;
+
+// 1ブロックに詰める命令数の上限
+const MAX_BLOCK_LEN: usize = 64;
+
+// 1命令でキャッシュを引く最大の回数
+// (フェッチとそのページテーブルの読み書き、ページをまたぐロード/ストアと2ページ分のページテーブルの読み書き)
+const MAX_CACHE_ACCESSES: u64 = 12;
+
+// 特権モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+// アラインされていないロード/ストアの扱い
+// AMOとLR/SCは常に例外にする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+    // アドレス不整列例外を起こす
+    Trap,
+    // 1回のアクセスとして行う (ページをまたぐ時は両方のページを先に変換する)
+    Emulate,
+    // 1バイトずつのアクセスに分ける (途中のページで例外が起きると前半だけ書かれる)
+    Split,
+}
+
+// 実行が止まった理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    // 指定した命令数を実行した
+    Step,
+    // ブレークポイントに到達した
+    Breakpoint(u32),
+    // ウォッチポイントに書き込んだ (書き込んだアドレス)
+    Watchpoint(u32),
+    // 指定したpcに到達した
+    Pc(u32),
+    // 条件を満たした
+    Condition,
+    // ホストに返るエラーが起きた
+    Error(Error),
+    // 再生中にログと違う動きをした (その時の命令数)
+    Diverged(u64),
+    // 巻き戻せる記録の先頭に着いた
+    HistoryStart,
+    // 巻き戻し用のチェックポイントを戻せなかった (その命令数)
+    BadCheckpoint(u64),
+    // SBIでシステムのリセットを求められた (reset_type, reset_reason)
+    SystemReset(u32, u32),
+    // JITの検証モードでインタプリタと結果が違った (違っていた内容)
+    JitMismatch(String),
+}
 
 pub struct Cpu {
     // 汎用レジスタ
     xr: [u32; 32],
     pc: u32,
+    // 浮動小数点レジスタ (F/D拡張の命令は未実装)
+    fr: [u64; 32],
+    // 実行中の命令と次のpc
+    ir: u32,
+    next_pc: u32,
+    prv: Privilege,
+    instret: u64,
+    // 実行した命令数 (例外になった命令も含む)
+    icount: u64,
+    // タイミングモデルで数えたサイクル数 (モデルが無ければ1命令1サイクル)
+    cycles: u64,
+    // 実行中の命令のフェッチでキャッシュを待ったサイクル数
+    fetch_stall: u64,
+    // mtimeを1進めるサイクル数と、まだmtimeに反映していないサイクル数
+    timebase: u64,
+    time_rem: u64,
 
     // CSRレジスタ
     ustatus: u32,
@@ -17,6 +141,57 @@ pub struct Cpu {
     utval: u32,
     uip: u32,
 
+    stvec: u32,
+    sscratch: u32,
+    sepc: u32,
+    scause: u32,
+    stval: u32,
+    satp: u32,
+
+    mstatus: u32,
+    medeleg: u32,
+    mideleg: u32,
+    mie: u32,
+    mtvec: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    mip: u32,
+    counters: counters::Counters,
+
+    // エラーをホストに返さずゲストの例外として扱う
+    trap_on_error: bool,
+    misaligned: MisalignedPolicy,
+    // S-modeからのecallをSBIとして処理する
+    sbi: bool,
+    // SBIで求められたリセット (次の命令の後で止まる)
+    reset_request: Option<(u32, u32)>,
+    // 実行中の命令がecallかebreakで例外を起こした (リタイアしない)
+    raised: bool,
+    // resetで始めるアドレス
+    reset_vector: u32,
+    // 再起動で置き直すイメージ (アドレス, 中身)
+    boot_images: Vec<(u32, Vec<u8>)>,
+
+    breakpoints: BTreeSet<u32>,
+    // 書き込みを監視する仮想アドレスの範囲 (先頭, 長さ)
+    watchpoints: Vec<(u32, u32)>,
+    watch_hit: Option<u32>,
+    halt_reason: Option<HaltReason>,
+    hooks: Hooks,
+
+    decode_cache: DecodeCache,
+    // 命令フェッチ用のアドレス変換 (仮想ページ, 特権レベル, 物理ページ)
+    fetch_tlb: Option<(u32, Privilege, u32)>,
+    #[cfg(feature = "jit")]
+    jit: Option<Box<jit::Jit>>,
+    // 記録か再生の途中
+    replay: Option<Box<replay::Session>>,
+    timing: Option<Box<dyn TimingModel>>,
+    // 読み込んだELFのシンボルと行番号
+    symbols: Symbols,
+
     bus: Bus,
 }
 
@@ -25,6 +200,16 @@ impl Cpu {
         Self {
             xr: [0; 32],
             pc: 0,
+            fr: [0; 32],
+            ir: 0,
+            next_pc: 0,
+            prv: Privilege::Machine,
+            instret: 0,
+            icount: 0,
+            cycles: 0,
+            fetch_stall: 0,
+            timebase: 1,
+            time_rem: 0,
             bus,
             ustatus: 0,
             uie: 0,
@@ -34,22 +219,258 @@ impl Cpu {
             ucause: 0,
             utval: 0,
             uip: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            mstatus: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mip: 0,
+            counters: counters::Counters::new(),
+            trap_on_error: false,
+            misaligned: MisalignedPolicy::Emulate,
+            sbi: false,
+            reset_request: None,
+            raised: false,
+            reset_vector: 0,
+            boot_images: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            halt_reason: None,
+            hooks: Hooks::default(),
+            decode_cache: DecodeCache::new(),
+            fetch_tlb: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            replay: None,
+            timing: None,
+            symbols: Symbols::default(),
+        }
+    }
+
+    pub fn set_trap_on_error(&mut self, enable: bool) {
+        self.trap_on_error = enable;
+    }
+
+    pub fn set_misaligned(&mut self, policy: MisalignedPolicy) {
+        self.misaligned = policy;
+    }
+
+    pub fn misaligned(&self) -> MisalignedPolicy {
+        self.misaligned
+    }
+
+    // ファームウェア無しでS-modeのカーネルを動かす時に使う
+    pub fn set_sbi(&mut self, enable: bool) {
+        self.sbi = enable;
+    }
+
+    pub fn sbi(&self) -> bool {
+        self.sbi
+    }
+
+    pub fn set_reset_vector(&mut self, addr: u32) {
+        self.reset_vector = addr;
+    }
+
+    pub fn reset_vector(&self) -> u32 {
+        self.reset_vector
+    }
+
+    // ハートをリセット直後の状態にしてリセットベクタから始める
+    // 仕様で決まっているのはM-mode、mstatusのMIEとMPRVが0、mcauseだけなので、それ以外も0にする
+    // mcycleとminstretは不定なので数え続け、RAMとデバイスはそのまま残す
+    pub fn reset(&mut self) {
+        self.xr = [0; 32];
+        self.fr = [0; 32];
+        self.ir = 0;
+        self.pc = self.reset_vector;
+        self.next_pc = self.reset_vector;
+        for csr in self.csrs_mut() {
+            *csr = 0;
+        }
+        self.counters = counters::Counters::new();
+        self.set_privilege(Privilege::Machine);
+        self.fetch_tlb = None;
+        self.reset_request = None;
+        self.watch_hit = None;
+        self.halt_reason = None;
+        // 記録や再生はリセットを越えて続けない
+        self.replay = None;
+    }
+
+    // bootで置いたイメージを置き直してからリセットする
+    // ファームウェアやカーネルが書き換えた所 (bssや再配置したコード) を起動した時の中身に戻す
+    pub fn reboot(&mut self) {
+        let images = std::mem::take(&mut self.boot_images);
+        for (addr, data) in &images {
+            // bootで置けた所なので失敗しない
+            self.write_phys(*addr, data).unwrap();
         }
+        self.boot_images = images;
+        self.reset();
+    }
+
+    pub(crate) fn boot_images_mut(&mut self) -> &mut Vec<(u32, Vec<u8>)> {
+        &mut self.boot_images
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.prv
+    }
+
+    pub fn set_privilege(&mut self, prv: Privilege) {
+        let from = self.prv;
+        self.prv = prv;
+        if from != prv {
+            if let Some(f) = &mut self.hooks.privilege {
+                f(from, prv);
+            }
+        }
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    // リタイアした命令数
+    pub fn instret(&self) -> u64 {
+        self.instret
+    }
+
+    pub fn icount(&self) -> u64 {
+        self.icount
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // 命令ごとのサイクル数を決めるモデル
+    // Noneなら1命令1サイクル。モデルがある間はJITを使わない
+    pub fn set_timing_model(&mut self, model: Option<Box<dyn TimingModel>>) {
+        self.timing = model;
+    }
+
+    pub fn timing_model(&self) -> Option<&dyn TimingModel> {
+        self.timing.as_deref()
+    }
+
+    pub fn timing_model_mut(&mut self) -> Option<&mut (dyn TimingModel + 'static)> {
+        self.timing.as_deref_mut()
+    }
+
+    // mtimeを1進めるのにかかるサイクル数
+    pub fn set_timebase(&mut self, cycles: u64) {
+        self.timebase = cycles.max(1);
+        self.time_rem = 0;
+    }
+
+    pub fn timebase(&self) -> u64 {
+        self.timebase
+    }
+
+    // mtimeに足していないサイクル数
+    pub(crate) fn time_rem(&self) -> u64 {
+        self.time_rem
     }
 
-    fn get_x(&self, i: usize) -> u32 {
+    pub fn get_x(&self, i: usize) -> u32 {
         match i {
             0 => 0,
             x => self.xr[x],
         }
     }
 
-    fn set_x(&mut self, i: usize, val: u32) {
-        self.xr[i] = val
+    // x0への書き込みは捨てる
+    pub fn set_x(&mut self, i: usize, val: u32) {
+        if i != 0 {
+            self.xr[i] = val
+        }
     }
 
-    fn get_csr(&self, no: u16) -> u32 {
-        match no {
+    pub fn get_f(&self, i: usize) -> u64 {
+        self.fr[i]
+    }
+
+    pub fn set_f(&mut self, i: usize, val: u64) {
+        self.fr[i] = val
+    }
+
+    fn illegal(&self) -> Error {
+        Error::IllegalInstruction {
+            ir: self.ir,
+            pc: self.pc,
+        }
+    }
+
+    fn check_csr(&self, no: u16) -> Result<()> {
+        // CSR番号の[9:8]はアクセスに必要な特権レベル
+        if (no >> 8) & 0b11 > self.prv as u16 {
+            return Err(self.illegal());
+        }
+        // TVMが立っているとS-modeからsatpを触れない
+        if no == 0x180 && self.prv == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0 {
+            return Err(self.illegal());
+        }
+        if !self.counter_accessible(no) {
+            return Err(self.illegal());
+        }
+        Ok(())
+    }
+
+    fn get_csr(&self, no: u16) -> Result<u32> {
+        self.check_csr(no)?;
+        self.read_csr(no)
+    }
+
+    fn set_csr(&mut self, no: u16, val: u32) -> Result<()> {
+        self.check_csr(no)?;
+        // CSR番号の[11:10]が0b11なら読み取り専用
+        if no >> 10 == 0b11 {
+            self.read_csr(no)?;
+            return Err(self.illegal());
+        }
+        self.write_csr(no, val)
+    }
+
+    // 特権チェック無しでCSRを読む
+    pub fn read_csr(&self, no: u16) -> Result<u32> {
+        let val = match no {
             0x000 => self.ustatus,
             0x004 => self.uie,
             0x005 => self.utvec,
@@ -58,11 +479,40 @@ impl Cpu {
             0x042 => self.ucause,
             0x043 => self.utval,
             0x044 => self.uip,
-            _ => panic!("unknown csr no {:04X}", no),
-        }
-    }
-
-    fn set_csr(&mut self, no: u16, val: u32) {
+            0x100 => self.mstatus & SSTATUS_MASK,
+            0x104 => self.mie & self.mideleg,
+            0x105 => self.stvec,
+            0x140 => self.sscratch,
+            0x141 => self.sepc,
+            0x142 => self.scause,
+            0x143 => self.stval,
+            0x144 => self.pending_interrupts() & self.mideleg,
+            0x180 => self.satp,
+            0x300 => self.mstatus,
+            // RV32IMASU
+            0x301 => (1 << 30) | (1 << 0) | (1 << 8) | (1 << 12) | (1 << 18) | (1 << 20),
+            0x302 => self.medeleg,
+            0x303 => self.mideleg,
+            0x304 => self.mie,
+            0x305 => self.mtvec,
+            0x340 => self.mscratch,
+            0x341 => self.mepc,
+            0x342 => self.mcause,
+            0x343 => self.mtval,
+            0x344 => self.pending_interrupts(),
+            // mvendorid, marchid, mimpid, mhartid
+            0xF11..=0xF14 => 0,
+            _ => match self.read_counter_csr(no) {
+                Some(val) => val,
+                None => return Err(Error::UnimplementedCsr { no, pc: self.pc }),
+            },
+        };
+        Ok(val)
+    }
+
+    // 特権チェック無しでCSRに書く
+    // 読み取り専用のCSRへの書き込みは無視する
+    pub fn write_csr(&mut self, no: u16, val: u32) -> Result<()> {
         match no {
             0x000 => {
                 self.ustatus = val;
@@ -88,242 +538,1106 @@ impl Cpu {
             0x044 => {
                 self.uip = val;
             }
-            _ => panic!("unknown csr no {:04X}", no),
+            0x100 => {
+                self.mstatus = (self.mstatus & !SSTATUS_MASK) | (val & SSTATUS_MASK);
+            }
+            0x104 => {
+                self.mie = (self.mie & !self.mideleg) | (val & self.mideleg);
+            }
+            0x105 => {
+                self.stvec = val;
+            }
+            0x140 => {
+                self.sscratch = val;
+            }
+            0x141 => {
+                self.sepc = val & !0b11;
+            }
+            0x142 => {
+                self.scause = val;
+            }
+            0x143 => {
+                self.stval = val;
+            }
+            // SSIPだけ書ける
+            0x144 => {
+                let mask = self.mideleg & 0x002;
+                self.mip = (self.mip & !mask) | (val & mask);
+            }
+            0x180 => {
+                // ASIDは実装しない
+                self.satp = val & 0x803F_FFFF;
+                self.fetch_tlb = None;
+            }
+            0x300 => {
+                let mask = SSTATUS_MASK
+                    | MSTATUS_MIE
+                    | MSTATUS_MPIE
+                    | MSTATUS_MPP
+                    | MSTATUS_MPRV
+                    | MSTATUS_TVM
+                    | MSTATUS_TW
+                    | MSTATUS_TSR;
+                self.mstatus = (self.mstatus & !mask) | (val & mask);
+                // MPPの0b10は予約なのでUとして扱う
+                if (self.mstatus & MSTATUS_MPP) >> 11 == 0b10 {
+                    self.mstatus &= !MSTATUS_MPP;
+                }
+            }
+            // misaは書き込みを無視する
+            0x301 => {}
+            // M-modeからのecallは委譲できない
+            0x302 => {
+                self.medeleg = val & 0xFFFF & !(1 << 11);
+            }
+            0x303 => {
+                self.mideleg = val & MIDELEG_MASK;
+            }
+            0x304 => {
+                self.mie = val & 0xAAA;
+            }
+            0x305 => {
+                self.mtvec = val;
+            }
+            0x340 => {
+                self.mscratch = val;
+            }
+            0x341 => {
+                self.mepc = val & !0b11;
+            }
+            0x342 => {
+                self.mcause = val;
+            }
+            0x343 => {
+                self.mtval = val;
+            }
+            // S-modeの割り込みだけソフトウェアから立てられる
+            0x344 => {
+                self.mip = (self.mip & !MIDELEG_MASK) | (val & MIDELEG_MASK);
+            }
+            0xF11..=0xF14 => {}
+            _ => {
+                if !self.write_counter_csr(no, val) {
+                    return Err(Error::UnimplementedCsr { no, pc: self.pc });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // addrからlenバイトへの書き込みで止める
+    pub fn add_watchpoint(&mut self, addr: u32, len: u32) {
+        self.watchpoints.push((addr, len));
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|(a, _)| *a != addr);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    // 最後に実行が止まった理由
+    pub fn halt_reason(&self) -> Option<&HaltReason> {
+        self.halt_reason.as_ref()
+    }
+
+    fn halt(&mut self, reason: HaltReason) -> HaltReason {
+        self.halt_reason = Some(reason.clone());
+        reason
+    }
+
+    // n命令実行する
+    pub fn step(&mut self, n: u64) -> HaltReason {
+        let reason = self.exec(n, false, true, |_| false);
+        self.halt(reason)
+    }
+
+    // ブレークポイントかエラーで止まるまで実行する
+    pub fn run(&mut self) -> HaltReason {
+        let reason = self.exec(u64::MAX, true, true, |_| false);
+        self.halt(reason)
+    }
+
+    pub fn run_until_pc(&mut self, pc: u32) -> HaltReason {
+        let reason = self.run_until(|cpu| cpu.pc == pc);
+        match reason {
+            HaltReason::Condition => self.halt(HaltReason::Pc(pc)),
+            reason => reason,
+        }
+    }
+
+    // 命令を実行するごとにcondを評価し、真になったら止まる
+    // 再開直後の命令ではブレークポイントを無視する
+    pub fn run_until<F: FnMut(&Cpu) -> bool>(&mut self, cond: F) -> HaltReason {
+        let reason = self.exec(u64::MAX, true, false, cond);
+        self.halt(reason)
+    }
+
+    // 最大limit命令をブロック単位で実行する
+    // 停止の判定は各命令の実行後に行う
+    // nativeの時は命令ごとの判定がいらなければJITを使う
+    fn exec<F: FnMut(&Cpu) -> bool>(
+        &mut self,
+        limit: u64,
+        breakpoints: bool,
+        native: bool,
+        mut cond: F,
+    ) -> HaltReason {
+        // フックは実行中に登録できないので最初に一度だけ見る
+        let hooked = self.hooks.before_inst.is_some() || self.hooks.after_inst.is_some();
+        #[cfg(feature = "jit")]
+        let native = native
+            && !hooked
+            && self.breakpoints.is_empty()
+            && self.watchpoints.is_empty()
+            && self.jit.is_some();
+        #[cfg(not(feature = "jit"))]
+        let _ = native;
+
+        let mut count = 0;
+        while count < limit {
+            let pc = self.pc;
+            if let Err(reason) = self.deliver_events() {
+                return reason;
+            }
+            // 割り込みで入ったハンドラの先頭でも止める
+            if self.pc != pc {
+                if let Some(reason) = self.check_stop(breakpoints, &mut cond) {
+                    return reason;
+                }
+            }
+            // タイマー割り込みが立つ命令と再生するイベントの命令でブロックを区切る
+            let until = self.until_timer().min(self.until_event());
+            let end = limit.min(count.saturating_add(until));
+
+            // イベントやサイクルを数える時はインタプリタで実行する
+            #[cfg(feature = "jit")]
+            if native && !self.counters.active && self.timing.is_none() && !self.bus.caching() {
+                let (executed, interpret) = match self.run_native(end - count) {
+                    Ok(result) => result,
+                    Err(reason) => return reason,
+                };
+                count += executed;
+                if !interpret || count == end {
+                    continue;
+                }
+            }
+
+            let block = match self.fetch_block() {
+                Ok(block) => block,
+                Err(e) => {
+                    if let Err(e) = self.fetch_fault(e) {
+                        return HaltReason::Error(e);
+                    }
+                    count += 1;
+                    if let Some(reason) = self.check_stop(breakpoints, &mut cond) {
+                        return reason;
+                    }
+                    continue;
+                }
+            };
+
+            for decoded in block.iter() {
+                let (pc, prv) = (self.pc, self.prv);
+                let result = if hooked {
+                    self.execute(decoded)
+                } else {
+                    self.execute_plain(decoded)
+                };
+                if let Err(e) = result {
+                    return HaltReason::Error(e);
+                }
+                count += 1;
+                if let Some(reason) = self.check_stop(breakpoints, &mut cond) {
+                    return reason;
+                }
+                // トラップやコードの書き換え、デバイスへのアクセスがあればブロックを引き直す
+                if count == end
+                    || self.pc != pc.wrapping_add(4)
+                    || self.prv != prv
+                    || self.bus.has_dirty_code()
+                    || self.bus.device_accessed()
+                {
+                    break;
+                }
+            }
+        }
+        HaltReason::Step
+    }
+
+    fn check_stop<F: FnMut(&Cpu) -> bool>(
+        &mut self,
+        breakpoints: bool,
+        cond: &mut F,
+    ) -> Option<HaltReason> {
+        let watch_hit = self.watch_hit.take();
+        if let Some((kind, reason)) = self.reset_request.take() {
+            Some(HaltReason::SystemReset(kind, reason))
+        } else if cond(self) {
+            Some(HaltReason::Condition)
+        } else if let (true, Some(addr)) = (breakpoints, watch_hit) {
+            Some(HaltReason::Watchpoint(addr))
+        } else if breakpoints && !self.breakpoints.is_empty() && self.breakpoints.contains(&self.pc)
+        {
+            Some(HaltReason::Breakpoint(self.pc))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn save_state(&self, w: &mut Writer) {
+        for x in self.xr {
+            w.u32(x);
+        }
+        w.u32(self.pc);
+        for f in self.fr {
+            w.u64(f);
+        }
+        w.u8(self.prv as u8);
+        w.u64(self.instret);
+        w.u64(self.icount);
+        w.u64(self.cycles);
+        w.u64(self.time_rem);
+        for csr in self.csrs() {
+            w.u32(*csr);
+        }
+        self.counters.save(w);
+    }
+
+    pub(crate) fn load_state(&mut self, r: &mut Reader) -> snapshot::Result<()> {
+        for x in &mut self.xr {
+            *x = r.u32()?;
+        }
+        self.xr[0] = 0;
+        self.pc = r.u32()?;
+        for f in &mut self.fr {
+            *f = r.u64()?;
+        }
+        self.prv = match r.u8()? {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            3 => Privilege::Machine,
+            _ => return Err(SnapshotError::Invalid("privilege")),
+        };
+        self.instret = r.u64()?;
+        self.icount = r.u64()?;
+        self.cycles = r.u64()?;
+        self.time_rem = r.u64()?;
+        for csr in self.csrs_mut() {
+            *csr = r.u32()?;
+        }
+        self.counters.load(r)
+    }
+
+    // スナップショットに含めるCSR
+    fn csrs(&self) -> [&u32; 24] {
+        [
+            &self.ustatus,
+            &self.uie,
+            &self.utvec,
+            &self.uscratch,
+            &self.uepc,
+            &self.ucause,
+            &self.utval,
+            &self.uip,
+            &self.stvec,
+            &self.sscratch,
+            &self.sepc,
+            &self.scause,
+            &self.stval,
+            &self.satp,
+            &self.mstatus,
+            &self.medeleg,
+            &self.mideleg,
+            &self.mie,
+            &self.mtvec,
+            &self.mscratch,
+            &self.mepc,
+            &self.mcause,
+            &self.mtval,
+            &self.mip,
+        ]
+    }
+
+    fn csrs_mut(&mut self) -> [&mut u32; 24] {
+        [
+            &mut self.ustatus,
+            &mut self.uie,
+            &mut self.utvec,
+            &mut self.uscratch,
+            &mut self.uepc,
+            &mut self.ucause,
+            &mut self.utval,
+            &mut self.uip,
+            &mut self.stvec,
+            &mut self.sscratch,
+            &mut self.sepc,
+            &mut self.scause,
+            &mut self.stval,
+            &mut self.satp,
+            &mut self.mstatus,
+            &mut self.medeleg,
+            &mut self.mideleg,
+            &mut self.mie,
+            &mut self.mtvec,
+            &mut self.mscratch,
+            &mut self.mepc,
+            &mut self.mcause,
+            &mut self.mtval,
+            &mut self.mip,
+        ]
+    }
+
+    // otherのマシンの状態に置き換える
+    // ホスト側の設定は残し、キャッシュは捨てる
+    // 記録や再生は続けない
+    pub(crate) fn replace_state(&mut self, other: Cpu) {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.flush();
+        }
+        // キャッシュの中身はスナップショットに含めないので空にして引き継ぐ
+        let mut other = other;
+        other.bus.put_caches(self.bus.take_caches());
+        if let Some(caches) = other.bus.caches_mut() {
+            caches.invalidate();
+        }
+        *self = Cpu {
+            trap_on_error: self.trap_on_error,
+            misaligned: self.misaligned,
+            sbi: self.sbi,
+            reset_vector: self.reset_vector,
+            boot_images: std::mem::take(&mut self.boot_images),
+            breakpoints: std::mem::take(&mut self.breakpoints),
+            watchpoints: std::mem::take(&mut self.watchpoints),
+            hooks: std::mem::take(&mut self.hooks),
+            symbols: std::mem::take(&mut self.symbols),
+            timing: self.timing.take(),
+            timebase: self.timebase,
+            #[cfg(feature = "jit")]
+            jit: self.jit.take(),
+            ..other
+        };
+    }
+
+    pub fn read_phys(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        self.bus.read_bytes(addr, buf)
+    }
+
+    pub fn write_phys(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.bus.write_bytes(addr, data)
+    }
+
+    // 現在の特権レベルとsatpで仮想アドレスを読み書きする
+    pub fn read_virt(&mut self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            let paddr = self.translate(addr.wrapping_add(i as u32), Access::Load, true)?;
+            *b = self.bus.read8(paddr)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_virt(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        for (i, b) in data.iter().enumerate() {
+            let paddr = self.translate(addr.wrapping_add(i as u32), Access::Store, true)?;
+            self.bus.write8(paddr, *b)?;
+        }
+        Ok(())
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        // tickはずれを返せないので再生をやめる
+        if self.deliver_events().is_err() {
+            self.stop_replay();
+        }
+        match self.fetch_block() {
+            Ok(block) => self.execute(&block[0]),
+            Err(e) => self.fetch_fault(e),
+        }
+    }
+
+    #[inline(always)]
+    fn execute(&mut self, decoded: &Decoded) -> Result<()> {
+        let pc = self.pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.ir = decoded.inst.ir;
+
+        if let Some(mut f) = self.hooks.before_inst.take() {
+            f(self, pc, self.ir);
+            self.hooks.before_inst = Some(f);
+        }
+        self.icount += 1;
+        if self.timing.is_none() {
+            self.advance_cycles(1);
+        }
+        if self.bus.caching() {
+            self.fetch_cache();
+        }
+        let trapped = match (decoded.handler)(self, &decoded.inst) {
+            Ok(()) if std::mem::take(&mut self.raised) => true,
+            Ok(()) => {
+                self.instret += 1;
+                false
+            }
+            Err(e) => {
+                self.raise(e)?;
+                true
+            }
+        };
+        if self.counters.active {
+            self.count_events();
+        }
+        if self.timing.is_some() || self.bus.caching() {
+            self.charge(pc, trapped);
+        }
+        self.retire(pc);
+        Ok(())
+    }
+
+    // 命令フックがない時のexecute
+    #[inline(always)]
+    fn execute_plain(&mut self, decoded: &Decoded) -> Result<()> {
+        self.next_pc = self.pc.wrapping_add(4);
+        self.ir = decoded.inst.ir;
+        self.icount += 1;
+        if self.timing.is_none() {
+            self.advance_cycles(1);
+        }
+        if self.bus.caching() {
+            self.fetch_cache();
+        }
+        let trapped = match (decoded.handler)(self, &decoded.inst) {
+            Ok(()) if std::mem::take(&mut self.raised) => true,
+            Ok(()) => {
+                self.instret += 1;
+                false
+            }
+            Err(e) => {
+                self.raise(e)?;
+                true
+            }
+        };
+        if self.counters.active {
+            self.count_events();
+        }
+        if self.timing.is_some() || self.bus.caching() {
+            self.charge(self.pc, trapped);
+        }
+        self.pc = self.next_pc;
+        Ok(())
+    }
+
+    fn fetch_fault(&mut self, e: Error) -> Result<()> {
+        let pc = self.pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.icount += 1;
+        if self.timing.is_none() {
+            self.advance_cycles(1);
+        }
+        self.raise(e)?;
+        self.retire(pc);
+        Ok(())
+    }
+
+    // サイクル数を進め、timebaseごとにmtimeを進める
+    #[inline(always)]
+    fn advance_cycles(&mut self, n: u64) {
+        self.cycles += n;
+        if self.timebase == 1 {
+            self.bus.advance(n);
+        } else {
+            self.time_rem += n;
+            self.bus.advance(self.time_rem / self.timebase);
+            self.time_rem %= self.timebase;
+        }
+    }
+
+    // 命令フェッチをキャッシュに通す (実行中の命令のページはfetch_tlbにある)
+    fn fetch_cache(&mut self) {
+        if let Some((_, _, ppage)) = self.fetch_tlb {
+            self.bus
+                .cache_access(ppage | (self.pc & 0xFFF), 4, Access::Fetch);
+        }
+        self.fetch_stall = self.bus.take_stall();
+    }
+
+    // タイミングモデルとキャッシュで命令のサイクル数を数える (トラップの分はtrapで数える)
+    fn charge(&mut self, pc: u32, trapped: bool) {
+        let fetch_stall = std::mem::take(&mut self.fetch_stall);
+        let mem_stall = self.bus.take_stall();
+        let cycles = match &mut self.timing {
+            Some(model) => model.inst(&InstEvent {
+                pc,
+                ir: self.ir,
+                class: Class::of(self.ir),
+                next_pc: self.next_pc,
+                trapped,
+                fetch_stall,
+                mem_stall,
+            }),
+            None => fetch_stall + mem_stall,
+        };
+        self.advance_cycles(cycles);
+    }
+
+    // タイマー割り込みが立つ前に実行し終えられる命令数
+    fn until_timer(&self) -> u64 {
+        let ticks = self.bus.clint().until_timer();
+        let per_inst = self.timing.as_ref().map_or(1, |m| m.max_cycles()).max(1)
+            + MAX_CACHE_ACCESSES * self.bus.max_stall();
+        if ticks == u64::MAX || (self.timebase == 1 && per_inst == 1) {
+            return ticks;
+        }
+        let cycles = ticks.saturating_mul(self.timebase) - self.time_rem;
+        (cycles / per_inst).max(1)
+    }
+
+    // ゲストの例外にできるエラーはトラップする
+    fn raise(&mut self, e: Error) -> Result<()> {
+        if !self.trap_on_error && !matches!(e, Error::PageFault { .. } | Error::Misaligned { .. }) {
+            return Err(e);
+        }
+        let tval = match e {
+            Error::IllegalInstruction { ir, .. } => ir,
+            Error::BusFault { addr, .. }
+            | Error::PageFault { addr, .. }
+            | Error::Misaligned { addr, .. } => addr,
+            Error::UnimplementedCsr { .. } => self.ir,
+        };
+        self.trap(e.cause(), tval);
+        Ok(())
+    }
+
+    fn retire(&mut self, pc: u32) {
+        self.pc = self.next_pc;
+
+        if let Some(mut f) = self.hooks.after_inst.take() {
+            f(self, pc, self.ir);
+            self.hooks.after_inst = Some(f);
+        }
+    }
+
+    fn translate(&mut self, addr: u32, access: Access, debug: bool) -> Result<u32> {
+        // MPRVが立っているとM-modeのロード/ストアはMPPの権限で行う
+        let prv = if access != Access::Fetch
+            && self.prv == Privilege::Machine
+            && self.mstatus & MSTATUS_MPRV != 0
+        {
+            Privilege::from_bits(self.mstatus >> 11)
+        } else {
+            self.prv
+        };
+        let ctx = Context {
+            prv,
+            sum: self.mstatus & MSTATUS_SUM != 0,
+            mxr: self.mstatus & MSTATUS_MXR != 0,
+        };
+        if !debug && self.satp >> 31 != 0 && prv != Privilege::Machine {
+            self.counters.count(counters::EVENT_TLB_MISS);
+        }
+        mmu::translate(&mut self.bus, self.satp, addr, access, ctx, debug)
+    }
+
+    fn translate_fetch(&mut self) -> Result<u32> {
+        let vpage = self.pc & !0xFFF;
+        match self.fetch_tlb {
+            Some((v, prv, ppage)) if v == vpage && prv == self.prv => Ok(ppage | (self.pc & 0xFFF)),
+            _ => {
+                let paddr = self.translate(self.pc, Access::Fetch, false)?;
+                self.fetch_tlb = Some((vpage, self.prv, paddr & !0xFFF));
+                Ok(paddr)
+            }
+        }
+    }
+
+    // pcから始まるブロックをキャッシュから取り出す
+    #[inline(always)]
+    fn fetch_block(&mut self) -> Result<Block> {
+        // 同じページでキャッシュに当たる時の近道
+        if let Some((vpage, prv, ppage)) = self.fetch_tlb {
+            if vpage == self.pc & !0xFFF && prv == self.prv && !self.bus.has_dirty_code() {
+                if let Some(block) = self.decode_cache.get(ppage | (self.pc & 0xFFF)) {
+                    return Ok(block.clone());
+                }
+            }
+        }
+        self.fetch_block_slow()
+    }
+
+    #[inline(never)]
+    fn fetch_block_slow(&mut self) -> Result<Block> {
+        if self.pc & 0b11 != 0 {
+            return Err(Error::Misaligned {
+                addr: self.pc,
+                access: Access::Fetch,
+            });
+        }
+        let paddr = self.translate_fetch()?;
+        self.invalidate_dirty_code();
+        if let Some(block) = self.decode_cache.get(paddr) {
+            return Ok(block.clone());
+        }
+
+        let block = self.decode_block(paddr)?;
+        self.decode_cache.insert(paddr, block.clone());
+        self.bus.mark_code(paddr);
+        Ok(block)
+    }
+
+    // 書き換えられたページのブロックを捨てる
+    fn invalidate_dirty_code(&mut self) {
+        if !self.bus.has_dirty_code() {
+            return;
+        }
+        for page in self.bus.take_dirty_code() {
+            self.decode_cache.invalidate_page(page);
+            #[cfg(feature = "jit")]
+            if let Some(jit) = &mut self.jit {
+                jit.invalidate_page(page);
+            }
+        }
+    }
+
+    // 分岐かシステム命令、ページの終わりまでをデコードする
+    // 先頭以外でデコードできない命令があればその手前で切る
+    fn decode_block(&self, paddr: u32) -> Result<Block> {
+        let mut block = Vec::new();
+        let mut addr = paddr;
+        loop {
+            let ir = match self.bus.fetch32(addr) {
+                Ok(ir) => ir,
+                Err(e) if block.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            match Self::decode(ir) {
+                Some(decoded) => block.push(decoded),
+                None if block.is_empty() => {
+                    return Err(Error::IllegalInstruction { ir, pc: self.pc })
+                }
+                None => break,
+            }
+
+            addr = addr.wrapping_add(4);
+            if ends_block(ir) || addr & 0xFFF == 0 || block.len() == MAX_BLOCK_LEN {
+                break;
+            }
+        }
+        Ok(block.into())
+    }
+
+    fn load8(&mut self, addr: u32) -> Result<u8> {
+        let paddr = self.translate(addr, Access::Load, false)?;
+        self.bus.cache_access(paddr, 1, Access::Load);
+        let val = self.bus.read8(paddr)?;
+        self.hook_mem_read(addr, paddr, 1, val as u32);
+        Ok(val)
+    }
+
+    fn load16(&mut self, addr: u32) -> Result<u16> {
+        if addr & 0b1 != 0 {
+            return self.load_misaligned(addr, 2).map(|v| v as u16);
+        }
+        let paddr = self.translate(addr, Access::Load, false)?;
+        self.bus.cache_access(paddr, 2, Access::Load);
+        let val = self.bus.read16(paddr)?;
+        self.hook_mem_read(addr, paddr, 2, val as u32);
+        Ok(val)
+    }
+
+    fn load32(&mut self, addr: u32) -> Result<u32> {
+        if addr & 0b11 != 0 {
+            return self.load_misaligned(addr, 4);
+        }
+        let paddr = self.translate(addr, Access::Load, false)?;
+        self.bus.cache_access(paddr, 4, Access::Load);
+        let val = self.bus.read32(paddr)?;
+        self.hook_mem_read(addr, paddr, 4, val);
+        Ok(val)
+    }
+
+    fn store8(&mut self, addr: u32, val: u8) -> Result<()> {
+        let paddr = self.translate(addr, Access::Store, false)?;
+        self.bus.cache_access(paddr, 1, Access::Store);
+        self.bus.write8(paddr, val)?;
+        self.hook_mem_write(addr, paddr, 1, val as u32);
+        Ok(())
+    }
+
+    fn store16(&mut self, addr: u32, val: u16) -> Result<()> {
+        if addr & 0b1 != 0 {
+            return self.store_misaligned(addr, 2, val as u32);
+        }
+        let paddr = self.translate(addr, Access::Store, false)?;
+        self.bus.cache_access(paddr, 2, Access::Store);
+        self.bus.write16(paddr, val)?;
+        self.hook_mem_write(addr, paddr, 2, val as u32);
+        Ok(())
+    }
+
+    fn store32(&mut self, addr: u32, val: u32) -> Result<()> {
+        if addr & 0b11 != 0 {
+            return self.store_misaligned(addr, 4, val);
+        }
+        let paddr = self.translate(addr, Access::Store, false)?;
+        self.bus.cache_access(paddr, 4, Access::Store);
+        self.bus.write32(paddr, val)?;
+        self.hook_mem_write(addr, paddr, 4, val);
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn load_misaligned(&mut self, addr: u32, width: u8) -> Result<u32> {
+        match self.misaligned {
+            MisalignedPolicy::Trap => Err(Error::Misaligned {
+                addr,
+                access: Access::Load,
+            }),
+            MisalignedPolicy::Emulate => {
+                let paddrs = self.translate_span(addr, width, Access::Load)?;
+                let mut val = 0;
+                for i in 0..width as u32 {
+                    val |= (self.bus.read8(paddrs[i as usize])? as u32) << (8 * i);
+                }
+                self.hook_mem_read(addr, paddrs[0], width, val);
+                Ok(val)
+            }
+            MisalignedPolicy::Split => {
+                let mut val = 0;
+                for i in 0..width as u32 {
+                    val |= (self.load8(addr.wrapping_add(i))? as u32) << (8 * i);
+                }
+                Ok(val)
+            }
+        }
+    }
+
+    #[inline(never)]
+    fn store_misaligned(&mut self, addr: u32, width: u8, val: u32) -> Result<()> {
+        match self.misaligned {
+            MisalignedPolicy::Trap => Err(Error::Misaligned {
+                addr,
+                access: Access::Store,
+            }),
+            MisalignedPolicy::Emulate => {
+                let paddrs = self.translate_span(addr, width, Access::Store)?;
+                for i in 0..width as u32 {
+                    self.bus
+                        .write8(paddrs[i as usize], (val >> (8 * i)) as u8)?;
+                }
+                self.hook_mem_write(addr, paddrs[0], width, val);
+                Ok(())
+            }
+            MisalignedPolicy::Split => {
+                for i in 0..width as u32 {
+                    self.store8(addr.wrapping_add(i), (val >> (8 * i)) as u8)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // widthバイトのアクセスを1バイトごとの物理アドレスにする
+    // ページをまたぐなら後ろのページも変換してからキャッシュに通す
+    fn translate_span(&mut self, addr: u32, width: u8, access: Access) -> Result<[u32; 4]> {
+        let first = self.translate(addr, access, false)?;
+        let head = (0x1000 - (addr & 0xFFF)).min(width as u32);
+        let second = if head < width as u32 {
+            self.translate(addr.wrapping_add(head), access, false)?
+        } else {
+            first.wrapping_add(head)
+        };
+        self.bus.cache_access(first, head as u8, access);
+        if head < width as u32 {
+            self.bus.cache_access(second, width - head as u8, access);
+        }
+        let mut paddrs = [0; 4];
+        for (i, paddr) in paddrs.iter_mut().enumerate().take(width as usize) {
+            let i = i as u32;
+            *paddr = if i < head {
+                first.wrapping_add(i)
+            } else {
+                second.wrapping_add(i - head)
+            };
+        }
+        Ok(paddrs)
+    }
+
+    fn hook_mem_read(&mut self, vaddr: u32, paddr: u32, width: u8, val: u32) {
+        if let Some(f) = &mut self.hooks.mem_read {
+            f(MemAccess {
+                vaddr,
+                paddr,
+                width,
+                val,
+            });
+        }
+    }
+
+    fn hook_mem_write(&mut self, vaddr: u32, paddr: u32, width: u8, val: u32) {
+        if !self.watchpoints.is_empty() {
+            let end = vaddr as u64 + width as u64;
+            if self.watchpoints.iter().any(|&(addr, len)| {
+                (vaddr as u64) < addr as u64 + len as u64 && (addr as u64) < end
+            }) {
+                self.watch_hit = Some(vaddr);
+            }
+        }
+        if let Some(f) = &mut self.hooks.mem_write {
+            f(MemAccess {
+                vaddr,
+                paddr,
+                width,
+                val,
+            });
         }
     }
 
-    pub fn tick(&mut self) -> Result<()> {
-        self.pc = self.pc.wrapping_add(4);
+    fn hook_csr(&mut self, no: u16, read: u32, write: Option<u32>) {
+        if let Some(f) = &mut self.hooks.csr {
+            f(CsrAccess { no, read, write });
+        }
+    }
+
+    // ソフトウェアから立てたビットとデバイスの割り込みを合わせたmip
+    fn pending_interrupts(&self) -> u32 {
+        let pending = self.mip | self.bus.interrupts();
+        // SBIをエミュレータで処理する時はCLINTのタイマーをS-modeのタイマー割り込みにする
+        if self.sbi && pending & MIP_MTIP != 0 {
+            return pending & !MIP_MTIP | MIP_STIP;
+        }
+        pending
+    }
+
+    // 受け付けられる割り込みがあればトラップし、その原因を返す
+    fn take_interrupt(&mut self) -> Option<u32> {
+        if self.bus.device_accessed() {
+            self.bus.update_irq();
+        }
+        if self.mie == 0 {
+            return None;
+        }
+        let pending = self.pending_interrupts() & self.mie;
+        if pending == 0 {
+            return None;
+        }
 
-        let ir = self.bus.read32(self.pc);
+        let m_enabled = self.prv != Privilege::Machine || self.mstatus & MSTATUS_MIE != 0;
+        let s_enabled = match self.prv {
+            Privilege::User => true,
+            Privilege::Supervisor => self.mstatus & MSTATUS_SIE != 0,
+            Privilege::Machine => false,
+        };
+        let enabled = match (pending & !self.mideleg, pending & self.mideleg) {
+            (m, _) if m != 0 && m_enabled => m,
+            (_, s) if s != 0 && s_enabled => s,
+            _ => return None,
+        };
+
+        // MEI, MSI, MTI, SEI, SSI, STIの順で優先する
+        let code = [11, 3, 7, 9, 1, 5]
+            .into_iter()
+            .find(|code| enabled & (1 << code) != 0)?;
+        let cause = 1 << 31 | code;
+        self.trap(cause, 0);
+        self.pc = self.next_pc;
+        Some(cause)
+    }
+
+    // 例外を発生させてトラップベクタへ飛ぶ
+    fn trap(&mut self, cause: u32, tval: u32) {
+        self.counters.count(counters::EVENT_TRAP);
+        if let Some(model) = &mut self.timing {
+            let cycles = model.trap(cause);
+            self.advance_cycles(cycles);
+        }
+        let from = self.prv;
+        let interrupt = cause >> 31 != 0;
+        let code = cause & 0x7FFF_FFFF;
+        let deleg = if interrupt {
+            self.mideleg
+        } else {
+            self.medeleg
+        };
+
+        if self.prv != Privilege::Machine && (deleg >> code) & 1 != 0 {
+            self.sepc = self.pc;
+            self.scause = cause;
+            self.stval = tval;
+
+            let spie = if self.mstatus & MSTATUS_SIE != 0 {
+                MSTATUS_SPIE
+            } else {
+                0
+            };
+            let spp = if self.prv == Privilege::Supervisor {
+                MSTATUS_SPP
+            } else {
+                0
+            };
+            self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            self.mstatus |= spie | spp;
+            self.set_privilege(Privilege::Supervisor);
+
+            self.next_pc = trap_vector(self.stvec, cause);
+        } else {
+            self.mepc = self.pc;
+            self.mcause = cause;
+            self.mtval = tval;
+
+            let mpie = if self.mstatus & MSTATUS_MIE != 0 {
+                MSTATUS_MPIE
+            } else {
+                0
+            };
+            self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+            self.mstatus |= mpie | ((self.prv as u32) << 11);
+            self.set_privilege(Privilege::Machine);
+
+            self.next_pc = trap_vector(self.mtvec, cause);
+        }
 
-        self.do_mnemonic(ir)
+        if let Some(f) = &mut self.hooks.trap {
+            f(TrapEvent {
+                cause,
+                tval,
+                epc: self.pc,
+                from,
+                to: self.prv,
+            });
+        }
     }
 
-    fn do_mnemonic(&mut self, ir: u32) -> Result<()> {
+    #[allow(clippy::unusual_byte_groupings)]
+    fn decode(ir: u32) -> Option<Decoded> {
         let opecode = ir & 0x7F;
         match opecode {
             // 000系
-            0b00_000_11 => self.load(Inst::from_i(ir)),
-            0b01_000_11 => self.store(Inst::from_s(ir)),
-            0b11_000_11 => self.branch(Inst::from_b(ir)),
+            0b00_000_11 => Self::load(Inst::from_i(ir)),
+            0b01_000_11 => Self::store(Inst::from_s(ir)),
+            0b11_000_11 => Self::branch(Inst::from_b(ir)),
             // 001系
-            0b11_001_11 => self.jalr(Inst::from_i(ir)),
+            0b11_001_11 => Some(Decoded {
+                handler: |cpu, i| cpu.jalr(*i),
+                inst: Inst::from_i(ir),
+            }),
             // 011系
-            0b00_011_11 => self.misc_mem(Inst::from_i(ir)),
-            0b01_011_11 => self.amo(Inst::from_r(ir)),
-            0b11_011_11 => self.jal(Inst::from_j(ir)),
+            0b00_011_11 => Self::misc_mem(Inst::from_i(ir)),
+            0b01_011_11 => Self::amo(Inst::from_r(ir)),
+            0b11_011_11 => Some(Decoded {
+                handler: |cpu, i| cpu.jal(*i),
+                inst: Inst::from_j(ir),
+            }),
             // 100系
-            0b00_100_11 => self.opimm(Inst::from_i(ir)),
-            0b01_100_11 => self.op(Inst::from_r(ir)),
-            0b11_100_11 => self.system(Inst::from_i(ir)),
+            0b00_100_11 => Self::opimm(Inst::from_i(ir)),
+            0b01_100_11 => Self::op(Inst::from_r(ir)),
+            0b11_100_11 => Self::system(Inst::from_i(ir)),
             // 101系
-            0b00_101_11 => self.auipc(Inst::from_u(ir)),
-            0b01_101_11 => self.lui(Inst::from_u(ir)),
-            _ => bail!("unknown instruction {:08X}", ir),
-        }
-    }
-
-    fn opimm(&mut self, inst: Inst) -> Result<()> {
-        match inst {
-            Inst {
-                funct3: 0b000,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.addi(rd, rs1, imm12),
-            Inst {
-                funct3: 0b001,
-                funct7: 0b000000,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.slli(rd, rs1, imm12),
-            Inst {
-                funct3: 0b010,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.slti(rd, rs1, imm12),
-            Inst {
-                funct3: 0b011,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.sltiu(rd, rs1, imm12),
-            Inst {
-                funct3: 0b100,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.xori(rd, rs1, imm12),
-            Inst {
-                funct3: 0b101,
-                funct7: 0b000000,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.srli(rd, rs1, imm12),
-            Inst {
-                funct3: 0b101,
-                funct7: 0b010000,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.srai(rd, rs1, imm12),
-            Inst {
-                funct3: 0b110,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.ori(rd, rs1, imm12),
-            Inst {
-                funct3: 0b111,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.andi(rd, rs1, imm12),
-            _ => bail!("unknown OP-IMM {:?}", inst),
+            0b00_101_11 => Some(Decoded {
+                handler: |cpu, i| cpu.auipc(*i),
+                inst: Inst::from_u(ir),
+            }),
+            0b01_101_11 => Some(Decoded {
+                handler: |cpu, i| cpu.lui(*i),
+                inst: Inst::from_u(ir),
+            }),
+            _ => None,
         }
     }
 
-    fn branch(&mut self, inst: Inst) -> Result<()> {
-        match inst {
-            Inst {
-                funct3: 0b000,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.beq(rs1, rs2, imm12),
+    fn opimm(inst: Inst) -> Option<Decoded> {
+        let handler: Handler = match inst {
+            Inst { funct3: 0b000, .. } => |cpu, i| cpu.addi(i.rd, i.rs1, i.imm12),
             Inst {
                 funct3: 0b001,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.bne(rs1, rs2, imm12),
-            Inst {
-                funct3: 0b100,
-                rs1,
-                rs2,
-                imm12,
+                funct7: 0b0000000,
                 ..
-            } => self.blt(rs1, rs2, imm12),
+            } => |cpu, i| cpu.slli(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b010, .. } => |cpu, i| cpu.slti(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b011, .. } => |cpu, i| cpu.sltiu(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b100, .. } => |cpu, i| cpu.xori(i.rd, i.rs1, i.imm12),
             Inst {
                 funct3: 0b101,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.bge(rs1, rs2, imm12),
-            Inst {
-                funct3: 0b110,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.bltu(rs1, rs2, imm12),
-            Inst {
-                funct3: 0b111,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.bgeu(rs1, rs2, imm12),
-            _ => bail!("unknown Branch {:?}", inst),
-        }
-    }
-
-    fn load(&mut self, inst: Inst) -> Result<()> {
-        match inst {
-            Inst {
-                funct3: 0b000,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.lb(rd, rs1, imm12),
-            Inst {
-                funct3: 0b001,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.lh(rd, rs1, imm12),
-            Inst {
-                funct3: 0b010,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.lw(rd, rs1, imm12),
-            Inst {
-                funct3: 0b100,
-                rd,
-                rs1,
-                imm12,
+                funct7: 0b0000000,
                 ..
-            } => self.lbu(rd, rs1, imm12),
+            } => |cpu, i| cpu.srli(i.rd, i.rs1, i.imm12),
             Inst {
                 funct3: 0b101,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.lhu(rd, rs1, imm12),
-            _ => bail!("unknown Load {:?}", inst),
-        }
-    }
-
-    fn store(&mut self, inst: Inst) -> Result<()> {
-        match inst {
-            Inst {
-                funct3: 0b000,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.sb(rs1, rs2, imm12),
-            Inst {
-                funct3: 0b001,
-                rs1,
-                rs2,
-                imm12,
-                ..
-            } => self.sh(rs1, rs2, imm12),
-            Inst {
-                funct3: 0b010,
-                rs1,
-                rs2,
-                imm12,
+                funct7: 0b0100000,
                 ..
-            } => self.sw(rs1, rs2, imm12),
-            _ => bail!("unknown Store {:?}", inst),
-        }
+            } => |cpu, i| cpu.srai(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b110, .. } => |cpu, i| cpu.ori(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b111, .. } => |cpu, i| cpu.andi(i.rd, i.rs1, i.imm12),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst })
+    }
+
+    fn branch(inst: Inst) -> Option<Decoded> {
+        let handler: Handler = match inst {
+            Inst { funct3: 0b000, .. } => |cpu, i| cpu.beq(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b001, .. } => |cpu, i| cpu.bne(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b100, .. } => |cpu, i| cpu.blt(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b101, .. } => |cpu, i| cpu.bge(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b110, .. } => |cpu, i| cpu.bltu(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b111, .. } => |cpu, i| cpu.bgeu(i.rs1, i.rs2, i.imm12),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst })
+    }
+
+    fn load(inst: Inst) -> Option<Decoded> {
+        let handler: Handler = match inst {
+            Inst { funct3: 0b000, .. } => |cpu, i| cpu.lb(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b001, .. } => |cpu, i| cpu.lh(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b010, .. } => |cpu, i| cpu.lw(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b100, .. } => |cpu, i| cpu.lbu(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b101, .. } => |cpu, i| cpu.lhu(i.rd, i.rs1, i.imm12),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst })
+    }
+
+    fn store(inst: Inst) -> Option<Decoded> {
+        let handler: Handler = match inst {
+            Inst { funct3: 0b000, .. } => |cpu, i| cpu.sb(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b001, .. } => |cpu, i| cpu.sh(i.rs1, i.rs2, i.imm12),
+            Inst { funct3: 0b010, .. } => |cpu, i| cpu.sw(i.rs1, i.rs2, i.imm12),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst })
     }
 
     fn andi(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, self.get_x(rs1) & ((imm12 & 0x0FFF) as u32));
+        self.set_x(rd, self.get_x(rs1) & (imm12 as i32 as u32));
         Ok(())
     }
 
     fn addi(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, ((self.get_x(rs1) as i32) + imm12 as i32) as u32);
+        self.set_x(
+            rd,
+            (self.get_x(rs1) as i32).wrapping_add(imm12 as i32) as u32,
+        );
         Ok(())
     }
 
     fn slli(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, self.get_x(rs1) << imm12);
+        self.set_x(rd, self.get_x(rs1) << (imm12 & 0x1F));
         Ok(())
     }
 
@@ -333,42 +1647,57 @@ impl Cpu {
     }
 
     fn sltiu(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, (self.get_x(rs1) < ((imm12 & 0x0FFF) as u32)) as u32);
+        self.set_x(rd, (self.get_x(rs1) < (imm12 as i32 as u32)) as u32);
         Ok(())
     }
 
     fn xori(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, self.get_x(rs1) ^ ((imm12 & 0x0FFF) as u32));
+        self.set_x(rd, self.get_x(rs1) ^ (imm12 as i32 as u32));
         Ok(())
     }
 
     fn srli(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, self.get_x(rs1) >> imm12);
+        self.set_x(rd, self.get_x(rs1) >> (imm12 & 0x1F));
         Ok(())
     }
 
     fn srai(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, ((self.get_x(rs1) as i32) >> imm12) as u32);
+        self.set_x(rd, ((self.get_x(rs1) as i32) >> (imm12 & 0x1F)) as u32);
         Ok(())
     }
 
     fn ori(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        self.set_x(rd, self.get_x(rs1) | ((imm12 & 0x0FFF) as u32));
+        self.set_x(rd, self.get_x(rs1) | (imm12 as i32 as u32));
         Ok(())
     }
 
     fn jal(&mut self, ir: Inst) -> Result<()> {
         let Inst { rd, imm32, .. } = ir;
+        let target = (self.pc as i32).wrapping_add(imm32) as u32;
+        self.jump_to(target)?;
         self.set_x(rd, self.pc.wrapping_add(4));
-        self.pc = (self.pc as i32).wrapping_add(imm32) as u32;
         Ok(())
     }
 
     fn jalr(&mut self, ir: Inst) -> Result<()> {
-        let Inst { rd, rs1, imm32, .. } = ir;
+        let Inst { rd, rs1, imm12, .. } = ir;
         let base_addr = self.get_x(rs1);
+        let target = (base_addr as i32).wrapping_add(imm12 as i32) as u32 & !1;
+        self.jump_to(target)?;
         self.set_x(rd, self.pc.wrapping_add(4));
-        self.pc = (base_addr as i32).wrapping_add(imm32) as u32;
+        Ok(())
+    }
+
+    // 飛び先がアラインされていなければジャンプした命令で例外にする
+    #[inline(always)]
+    fn jump_to(&mut self, target: u32) -> Result<()> {
+        if target & 0b11 != 0 {
+            return Err(Error::Misaligned {
+                addr: target,
+                access: Access::Fetch,
+            });
+        }
+        self.next_pc = target;
         Ok(())
     }
 
@@ -376,7 +1705,7 @@ impl Cpu {
         let left = self.get_x(rs1) as i32;
         let right = self.get_x(rs2) as i32;
         if left == right {
-            self.pc = (self.pc as i32).wrapping_add(imm12 as i32) as u32;
+            self.jump_to((self.pc as i32).wrapping_add(imm12 as i32) as u32)?;
         }
         Ok(())
     }
@@ -385,7 +1714,7 @@ impl Cpu {
         let left = self.get_x(rs1) as i32;
         let right = self.get_x(rs2) as i32;
         if left != right {
-            self.pc = (self.pc as i32).wrapping_add(imm12 as i32) as u32;
+            self.jump_to((self.pc as i32).wrapping_add(imm12 as i32) as u32)?;
         }
         Ok(())
     }
@@ -394,7 +1723,7 @@ impl Cpu {
         let left = self.get_x(rs1) as i32;
         let right = self.get_x(rs2) as i32;
         if left < right {
-            self.pc = (self.pc as i32).wrapping_add(imm12 as i32) as u32;
+            self.jump_to((self.pc as i32).wrapping_add(imm12 as i32) as u32)?;
         }
         Ok(())
     }
@@ -403,7 +1732,7 @@ impl Cpu {
         let left = self.get_x(rs1) as i32;
         let right = self.get_x(rs2) as i32;
         if left >= right {
-            self.pc = (self.pc as i32).wrapping_add(imm12 as i32) as u32;
+            self.jump_to((self.pc as i32).wrapping_add(imm12 as i32) as u32)?;
         }
         Ok(())
     }
@@ -412,7 +1741,7 @@ impl Cpu {
         let left = self.get_x(rs1);
         let right = self.get_x(rs2);
         if left < right {
-            self.pc = (self.pc as i32).wrapping_add(imm12 as i32) as u32;
+            self.jump_to((self.pc as i32).wrapping_add(imm12 as i32) as u32)?;
         }
         Ok(())
     }
@@ -421,355 +1750,384 @@ impl Cpu {
         let left = self.get_x(rs1);
         let right = self.get_x(rs2);
         if left >= right {
-            self.pc = (self.pc as i32).wrapping_add(imm12 as i32) as u32;
+            self.jump_to((self.pc as i32).wrapping_add(imm12 as i32) as u32)?;
         }
         Ok(())
     }
 
     fn lb(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.set_x(
-            rd,
-            self.bus
-                .read8((base_addr as i32).wrapping_add(imm12 as i32) as u32) as i8
-                as i32 as u32,
-        );
+        let val = self.load8((base_addr as i32).wrapping_add(imm12 as i32) as u32)?;
+        self.set_x(rd, val as i8 as i32 as u32);
         Ok(())
     }
 
     fn lh(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.set_x(
-            rd,
-            self.bus
-                .read16((base_addr as i32).wrapping_add(imm12 as i32) as u32) as i16
-                as i32 as u32,
-        );
+        let val = self.load16((base_addr as i32).wrapping_add(imm12 as i32) as u32)?;
+        self.set_x(rd, val as i16 as i32 as u32);
         Ok(())
     }
 
     fn lw(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.set_x(
-            rd,
-            self.bus
-                .read32((base_addr as i32).wrapping_add(imm12 as i32) as u32),
-        );
+        let val = self.load32((base_addr as i32).wrapping_add(imm12 as i32) as u32)?;
+        self.set_x(rd, val);
         Ok(())
     }
 
     fn lbu(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.set_x(
-            rd,
-            self.bus
-                .read8((base_addr as i32).wrapping_add(imm12 as i32) as u32) as u32,
-        );
+        let val = self.load8((base_addr as i32).wrapping_add(imm12 as i32) as u32)?;
+        self.set_x(rd, val as u32);
         Ok(())
     }
 
     fn lhu(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.set_x(
-            rd,
-            self.bus
-                .read16((base_addr as i32).wrapping_add(imm12 as i32) as u32) as u32,
-        );
+        let val = self.load16((base_addr as i32).wrapping_add(imm12 as i32) as u32)?;
+        self.set_x(rd, val as u32);
         Ok(())
     }
 
     fn sb(&mut self, rs1: usize, rs2: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.bus.write8(
+        self.store8(
             (base_addr as i32).wrapping_add(imm12 as i32) as u32,
             self.get_x(rs2) as u8,
-        );
+        )?;
         Ok(())
     }
 
     fn sh(&mut self, rs1: usize, rs2: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.bus.write16(
+        self.store16(
             (base_addr as i32).wrapping_add(imm12 as i32) as u32,
             self.get_x(rs2) as u16,
-        );
+        )?;
         Ok(())
     }
 
     fn sw(&mut self, rs1: usize, rs2: usize, imm12: i16) -> Result<()> {
         let base_addr = self.get_x(rs1);
-        self.bus.write32(
+        self.store32(
             (base_addr as i32).wrapping_add(imm12 as i32) as u32,
             self.get_x(rs2),
-        );
+        )?;
         Ok(())
     }
 
-    fn misc_mem(&mut self, ir: Inst) -> Result<()> {
-        match ir {
-            Inst {
-                funct3: 0b000,
-                imm12,
-                ..
-            } => self.fence(imm12),
-            Inst { funct3: 0b001, .. } => self.fencei(),
-            _ => bail!("unknown MISC-MEM {:?}", ir),
-        }
+    fn misc_mem(ir: Inst) -> Option<Decoded> {
+        let handler: Handler = match ir {
+            Inst { funct3: 0b000, .. } => |cpu, i| cpu.fence(i.imm12),
+            Inst { funct3: 0b001, .. } => |cpu, _| cpu.fencei(),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst: ir })
     }
 
     fn fence(&self, _: i16) -> Result<()> {
-        todo!()
+        // メモリアクセスは逐次実行されるので何もしない
+        Ok(())
     }
 
-    fn fencei(&self) -> Result<()> {
-        // hartが一つなので何もしない
+    fn fencei(&mut self) -> Result<()> {
+        self.decode_cache.flush();
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.flush();
+        }
         Ok(())
     }
 
-    fn system(&mut self, ir: Inst) -> Result<()> {
-        match ir {
-            Inst {
-                funct3: 0b001,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.csrrw(rd, rs1, imm12),
-            Inst {
-                funct3: 0b010,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.csrrs(rd, rs1, imm12),
-            Inst {
-                funct3: 0b011,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.csrrc(rd, rs1, imm12),
-            Inst {
-                funct3: 0b101,
-                rd,
-                rs1,
-                imm12,
-                ..
-            } => self.csrrwi(rd, rs1, imm12),
+    fn system(ir: Inst) -> Option<Decoded> {
+        let handler: Handler = match ir {
             Inst {
-                funct3: 0b110,
-                rd,
-                rs1,
-                imm12,
+                funct3: 0b000,
+                funct7: 0b0001001,
+                rd: 0,
                 ..
-            } => self.csrrsi(rd, rs1, imm12),
+            } => |cpu, _| cpu.sfence_vma(),
             Inst {
-                funct3: 0b111,
-                rd,
-                rs1,
-                imm12,
+                funct3: 0b000,
+                rd: 0,
+                rs1: 0,
                 ..
-            } => self.csrrci(rd, rs1, imm12),
-            _ => bail!("unknown SYSTEM {:?}", ir),
-        }
+            } => match ir.imm12 {
+                0x000 => |cpu, _| cpu.ecall(),
+                0x001 => |cpu, _| cpu.ebreak(),
+                0x102 => |cpu, _| cpu.sret(),
+                0x302 => |cpu, _| cpu.mret(),
+                0x105 => |cpu, _| cpu.wfi(),
+                _ => return None,
+            },
+            Inst { funct3: 0b001, .. } => |cpu, i| cpu.csrrw(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b010, .. } => |cpu, i| cpu.csrrs(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b011, .. } => |cpu, i| cpu.csrrc(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b101, .. } => |cpu, i| cpu.csrrwi(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b110, .. } => |cpu, i| cpu.csrrsi(i.rd, i.rs1, i.imm12),
+            Inst { funct3: 0b111, .. } => |cpu, i| cpu.csrrci(i.rd, i.rs1, i.imm12),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst: ir })
     }
 
     fn csrrw(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        let csr_val = self.get_csr(imm12 as u16);
+        let no = csr_no(imm12);
+        let csr_val = self.get_csr(no)?;
         let src_val = self.get_x(rs1);
+        self.set_csr(no, src_val)?;
+        self.hook_csr(no, csr_val, Some(src_val));
         self.set_x(rd, csr_val);
-        self.set_csr(imm12 as u16, src_val);
         Ok(())
     }
 
     fn csrrs(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        let csr_val = self.get_csr(imm12 as u16);
+        let no = csr_no(imm12);
+        let csr_val = self.get_csr(no)?;
         let src_val = self.get_x(rs1);
+        // rs1がx0なら書き込まない
+        let write = if rs1 != 0 {
+            let val = csr_val | src_val;
+            self.set_csr(no, val)?;
+            Some(val)
+        } else {
+            None
+        };
+        self.hook_csr(no, csr_val, write);
         self.set_x(rd, csr_val);
-        self.set_csr(imm12 as u16, src_val | csr_val);
         Ok(())
     }
 
     fn csrrc(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        let csr_val = self.get_csr(imm12 as u16);
+        let no = csr_no(imm12);
+        let csr_val = self.get_csr(no)?;
         let src_val = self.get_x(rs1);
+        let write = if rs1 != 0 {
+            let val = csr_val & !src_val;
+            self.set_csr(no, val)?;
+            Some(val)
+        } else {
+            None
+        };
+        self.hook_csr(no, csr_val, write);
         self.set_x(rd, csr_val);
-        self.set_csr(imm12 as u16, src_val & !csr_val);
         Ok(())
     }
 
     fn csrrwi(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        let csr_val = self.get_csr(imm12 as u16);
+        let no = csr_no(imm12);
+        let csr_val = self.get_csr(no)?;
         let src_val = rs1 as u32;
+        self.set_csr(no, src_val)?;
+        self.hook_csr(no, csr_val, Some(src_val));
         self.set_x(rd, csr_val);
-        self.set_csr(imm12 as u16, src_val);
         Ok(())
     }
 
     fn csrrsi(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        let csr_val = self.get_csr(imm12 as u16);
+        let no = csr_no(imm12);
+        let csr_val = self.get_csr(no)?;
         let src_val = rs1 as u32;
+        let write = if src_val != 0 {
+            let val = csr_val | src_val;
+            self.set_csr(no, val)?;
+            Some(val)
+        } else {
+            None
+        };
+        self.hook_csr(no, csr_val, write);
         self.set_x(rd, csr_val);
-        self.set_csr(imm12 as u16, src_val | csr_val);
         Ok(())
     }
 
     fn csrrci(&mut self, rd: usize, rs1: usize, imm12: i16) -> Result<()> {
-        let csr_val = self.get_csr(imm12 as u16);
+        let no = csr_no(imm12);
+        let csr_val = self.get_csr(no)?;
         let src_val = rs1 as u32;
+        let write = if src_val != 0 {
+            let val = csr_val & !src_val;
+            self.set_csr(no, val)?;
+            Some(val)
+        } else {
+            None
+        };
+        self.hook_csr(no, csr_val, write);
         self.set_x(rd, csr_val);
-        self.set_csr(imm12 as u16, src_val & !csr_val);
         Ok(())
     }
 
-    fn op(&mut self, ir: Inst) -> Result<()> {
-        match ir {
+    fn ecall(&mut self) -> Result<()> {
+        if self.sbi && self.prv == Privilege::Supervisor {
+            self.sbi_call();
+            return Ok(());
+        }
+        // U=8, S=9, M=11
+        self.trap(8 + self.prv as u32, 0);
+        self.raised = true;
+        Ok(())
+    }
+
+    fn ebreak(&mut self) -> Result<()> {
+        self.trap(3, self.pc);
+        self.raised = true;
+        Ok(())
+    }
+
+    fn sret(&mut self) -> Result<()> {
+        if self.prv == Privilege::User
+            || (self.prv == Privilege::Supervisor && self.mstatus & MSTATUS_TSR != 0)
+        {
+            return Err(self.illegal());
+        }
+        self.set_privilege(if self.mstatus & MSTATUS_SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        });
+        let sie = if self.mstatus & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+        self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        self.mstatus |= sie | MSTATUS_SPIE;
+        self.next_pc = self.sepc;
+        Ok(())
+    }
+
+    fn mret(&mut self) -> Result<()> {
+        if self.prv != Privilege::Machine {
+            return Err(self.illegal());
+        }
+        self.set_privilege(Privilege::from_bits(self.mstatus >> 11));
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        self.mstatus |= mie | MSTATUS_MPIE;
+        if self.prv != Privilege::Machine {
+            self.mstatus &= !MSTATUS_MPRV;
+        }
+        self.next_pc = self.mepc;
+        Ok(())
+    }
+
+    fn wfi(&mut self) -> Result<()> {
+        if self.prv != Privilege::Machine && self.mstatus & MSTATUS_TW != 0 {
+            return Err(self.illegal());
+        }
+        // 割り込みはブロックの先頭で受け付けるので何もしない
+        Ok(())
+    }
+
+    fn sfence_vma(&mut self) -> Result<()> {
+        if self.prv == Privilege::User
+            || (self.prv == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0)
+        {
+            return Err(self.illegal());
+        }
+        self.fetch_tlb = None;
+        Ok(())
+    }
+
+    fn op(ir: Inst) -> Option<Decoded> {
+        let handler: Handler = match ir {
             Inst {
                 funct3: 0b000,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.add(rd, rs1, rs2),
+            } => |cpu, i| cpu.add(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b000,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.mul(rd, rs1, rs2),
+            } => |cpu, i| cpu.mul(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b000,
                 funct7: 0b0100000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.sub(rd, rs1, rs2),
+            } => |cpu, i| cpu.sub(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b001,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.sll(rd, rs1, rs2),
+            } => |cpu, i| cpu.sll(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b001,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.mulh(rd, rs1, rs2),
+            } => |cpu, i| cpu.mulh(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b010,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.slt(rd, rs1, rs2),
+            } => |cpu, i| cpu.slt(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b010,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.mulhsu(rd, rs1, rs2),
+            } => |cpu, i| cpu.mulhsu(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b011,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.sltu(rd, rs1, rs2),
+            } => |cpu, i| cpu.sltu(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b011,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.mulhu(rd, rs1, rs2),
+            } => |cpu, i| cpu.mulhu(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b100,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.xor(rd, rs1, rs2),
+            } => |cpu, i| cpu.xor(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b100,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.div(rd, rs1, rs2),
+            } => |cpu, i| cpu.div(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b101,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.srl(rd, rs1, rs2),
+            } => |cpu, i| cpu.srl(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b101,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.divu(rd, rs1, rs2),
+            } => |cpu, i| cpu.divu(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b101,
                 funct7: 0b0100000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.sra(rd, rs1, rs2),
+            } => |cpu, i| cpu.sra(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b110,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.or(rd, rs1, rs2),
+            } => |cpu, i| cpu.or(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b110,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.rem(rd, rs1, rs2),
+            } => |cpu, i| cpu.rem(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b111,
                 funct7: 0b0000000,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.and(rd, rs1, rs2),
+            } => |cpu, i| cpu.and(i.rd, i.rs1, i.rs2),
             Inst {
                 funct3: 0b111,
                 funct7: 0b0000001,
-                rd,
-                rs1,
-                rs2,
                 ..
-            } => self.remu(rd, rs1, rs2),
-            _ => bail!("unknown OP {:?}", ir),
-        }
+            } => |cpu, i| cpu.remu(i.rd, i.rs1, i.rs2),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst: ir })
     }
 
     fn lui(&mut self, ir: Inst) -> Result<()> {
@@ -801,7 +2159,7 @@ impl Cpu {
     fn sll(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1);
         let right = self.get_x(rs2);
-        self.set_x(rd, (left << right) as u32);
+        self.set_x(rd, left << (right & 0x1F));
         Ok(())
     }
 
@@ -829,14 +2187,14 @@ impl Cpu {
     fn srl(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1);
         let right = self.get_x(rs2);
-        self.set_x(rd, (left >> right) as u32);
+        self.set_x(rd, left >> (right & 0x1F));
         Ok(())
     }
 
     fn sra(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1) as i32;
-        let right = self.get_x(rs2) as i32;
-        self.set_x(rd, (left >> right) as u32);
+        let right = self.get_x(rs2);
+        self.set_x(rd, (left >> (right & 0x1F)) as u32);
         Ok(())
     }
 
@@ -862,15 +2220,15 @@ impl Cpu {
     }
 
     fn mulh(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
-        let left = self.get_x(rs1) as i64;
-        let right = self.get_x(rs2) as i64;
+        let left = self.get_x(rs1) as i32 as i64;
+        let right = self.get_x(rs2) as i32 as i64;
         self.set_x(rd, (left.wrapping_mul(right) >> 32) as u32);
         Ok(())
     }
 
     fn mulhsu(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
-        let left = self.get_x(rs1) as i64;
-        let right = self.get_x(rs2) as u64 as i64;
+        let left = self.get_x(rs1) as i32 as i64;
+        let right = self.get_x(rs2) as i64;
         self.set_x(rd, (left.wrapping_mul(right) >> 32) as u32);
         Ok(())
     }
@@ -885,217 +2243,216 @@ impl Cpu {
     fn div(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1) as i32;
         let right = self.get_x(rs2) as i32;
-        self.set_x(rd, left.wrapping_div(right) as u32);
+        // ゼロ除算は例外にならず全ビット1
+        let val = if right == 0 {
+            -1
+        } else {
+            left.wrapping_div(right)
+        };
+        self.set_x(rd, val as u32);
         Ok(())
     }
 
     fn divu(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1);
         let right = self.get_x(rs2);
-        self.set_x(rd, left.wrapping_div(right));
+        let val = if right == 0 {
+            u32::MAX
+        } else {
+            left.wrapping_div(right)
+        };
+        self.set_x(rd, val);
         Ok(())
     }
 
     fn rem(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1) as i32;
         let right = self.get_x(rs2) as i32;
-        self.set_x(rd, left.wrapping_rem(right) as u32);
+        // ゼロ除算の剰余は被除数
+        let val = if right == 0 {
+            left
+        } else {
+            left.wrapping_rem(right)
+        };
+        self.set_x(rd, val as u32);
         Ok(())
     }
 
     fn remu(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let left = self.get_x(rs1);
         let right = self.get_x(rs2);
-        self.set_x(rd, left.wrapping_rem(right));
+        let val = if right == 0 {
+            left
+        } else {
+            left.wrapping_rem(right)
+        };
+        self.set_x(rd, val);
         Ok(())
     }
 
-    fn amo(&mut self, ir: Inst) -> Result<()> {
+    fn amo(ir: Inst) -> Option<Decoded> {
         // NOTE: AMO系はaq/rlを無視する
-        match ir {
+        let handler: Handler = match ir {
             Inst {
-                funct5: 0b00010,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.lrw(rd, rs1, rs2),
+                funct5: 0b00010, ..
+            } => |cpu, i| cpu.lrw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b00011,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.scw(rd, rs1, rs2),
+                funct5: 0b00011, ..
+            } => |cpu, i| cpu.scw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b00001,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amoswapw(rd, rs1, rs2),
+                funct5: 0b00001, ..
+            } => |cpu, i| cpu.amoswapw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b00000,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amoaddw(rd, rs1, rs2),
+                funct5: 0b00000, ..
+            } => |cpu, i| cpu.amoaddw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b00100,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amoxorw(rd, rs1, rs2),
+                funct5: 0b00100, ..
+            } => |cpu, i| cpu.amoxorw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b01100,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amoandw(rd, rs1, rs2),
+                funct5: 0b01100, ..
+            } => |cpu, i| cpu.amoandw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b01000,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amoorw(rd, rs1, rs2),
+                funct5: 0b01000, ..
+            } => |cpu, i| cpu.amoorw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b10000,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amominw(rd, rs1, rs2),
+                funct5: 0b10000, ..
+            } => |cpu, i| cpu.amominw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b10100,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amomaxw(rd, rs1, rs2),
+                funct5: 0b10100, ..
+            } => |cpu, i| cpu.amomaxw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b11000,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amominuw(rd, rs1, rs2),
+                funct5: 0b11000, ..
+            } => |cpu, i| cpu.amominuw(i.rd, i.rs1, i.rs2),
             Inst {
-                funct5: 0b11100,
-                rd,
-                rs1,
-                rs2,
-                ..
-            } => self.amomaxuw(rd, rs1, rs2),
-            _ => bail!("unknown AMO {:?}", ir),
-        }
+                funct5: 0b11100, ..
+            } => |cpu, i| cpu.amomaxuw(i.rd, i.rs1, i.rs2),
+            _ => return None,
+        };
+        Some(Decoded { handler, inst: ir })
     }
 
-    fn lrw(&mut self, rd: usize, rs1: usize, _: usize) -> Result<(), anyhow::Error> {
+    fn lrw(&mut self, rd: usize, rs1: usize, _: usize) -> Result<()> {
         let addr = self.get_x(rs1);
-        let val = self.bus.read32(addr);
+        self.check_atomic(addr, Access::Load)?;
+        let val = self.load32(addr)?;
         self.set_x(rd, val);
         Ok(())
     }
 
-    fn scw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
+    fn scw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
         let addr = self.get_x(rs1);
+        self.check_atomic(addr, Access::Store)?;
         let val = self.get_x(rs2);
-        self.bus.write32(addr, val);
+        self.store32(addr, val)?;
         self.set_x(rd, 0);
         Ok(())
     }
 
-    fn amoswapw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus.write32(addr, right);
+    // AMOとLR/SCはポリシーによらずアラインされていなければ例外にする
+    fn check_atomic(&self, addr: u32, access: Access) -> Result<()> {
+        if addr & 0b11 != 0 {
+            return Err(Error::Misaligned { addr, access });
+        }
         Ok(())
     }
 
-    fn amoaddw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
+    // 読み出し、演算、書き戻しを一つのストアとして行う
+    fn amo_rmw(
+        &mut self,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+        op: impl FnOnce(u32, u32) -> u32,
+    ) -> Result<()> {
+        let vaddr = self.get_x(rs1);
+        self.check_atomic(vaddr, Access::Store)?;
+        let paddr = self.translate(vaddr, Access::Store, false)?;
+        self.bus.cache_access(paddr, 4, Access::Load);
+        let left = self.bus.read32(paddr)?;
+        self.hook_mem_read(vaddr, paddr, 4, left);
         let right = self.get_x(rs2);
+        let val = op(left, right);
+        self.bus.cache_access(paddr, 4, Access::Store);
+        self.bus.write32(paddr, val)?;
+        self.hook_mem_write(vaddr, paddr, 4, val);
         self.set_x(rd, left);
-        self.bus
-            .write32(addr, (left as i32).wrapping_add(right as i32) as u32);
         Ok(())
     }
 
-    fn amoxorw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus.write32(addr, left ^ right);
-        Ok(())
+    fn amoswapw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |_, right| right)
     }
 
-    fn amoandw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus.write32(addr, left & right);
-        Ok(())
+    fn amoaddw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |left, right| {
+            (left as i32).wrapping_add(right as i32) as u32
+        })
     }
 
-    fn amoorw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus.write32(addr, left | right);
-        Ok(())
+    fn amoxorw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |left, right| left ^ right)
     }
 
-    fn amominw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus
-            .write32(addr, std::cmp::min(left as i32, right as i32) as u32);
-        Ok(())
+    fn amoandw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |left, right| left & right)
     }
 
-    fn amomaxw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus
-            .write32(addr, std::cmp::max(left as i32, right as i32) as u32);
-        Ok(())
+    fn amoorw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |left, right| left | right)
     }
 
-    fn amominuw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus.write32(addr, std::cmp::min(left, right));
-        Ok(())
+    fn amominw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |left, right| {
+            std::cmp::min(left as i32, right as i32) as u32
+        })
     }
 
-    fn amomaxuw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<(), anyhow::Error> {
-        let addr = self.get_x(rs1);
-        let left = self.bus.read32(addr);
-        let right = self.get_x(rs2);
-        self.set_x(rd, left);
-        self.bus.write32(addr, std::cmp::max(left, right));
-        Ok(())
+    fn amomaxw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, |left, right| {
+            std::cmp::max(left as i32, right as i32) as u32
+        })
+    }
+
+    fn amominuw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, std::cmp::min)
     }
+
+    fn amomaxuw(&mut self, rd: usize, rs1: usize, rs2: usize) -> Result<()> {
+        self.amo_rmw(rd, rs1, rs2, std::cmp::max)
+    }
+}
+
+// ベクタモードでは割り込みだけがBASE+4*causeに飛ぶ
+fn trap_vector(tvec: u32, cause: u32) -> u32 {
+    let base = tvec & !0b11;
+    if tvec & 0b11 == 1 && cause >> 31 != 0 {
+        base.wrapping_add(4 * (cause & 0x7FFF_FFFF))
+    } else {
+        base
+    }
+}
+
+fn csr_no(imm12: i16) -> u16 {
+    (imm12 as u16) & 0x0FFF
+}
+
+// 制御が移りうる命令 (分岐, ジャンプ, FENCE系, SYSTEM) でブロックを終える
+fn ends_block(ir: u32) -> bool {
+    matches!(ir & 0x7F, 0x63 | 0x67 | 0x6F | 0x0F | 0x73)
+}
+
+// デコード済みの命令を実行する関数
+type Handler = fn(&mut Cpu, &Inst) -> Result<()>;
+
+#[derive(Clone, Copy)]
+pub(crate) struct Decoded {
+    handler: Handler,
+    inst: Inst,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Inst {
+    ir: u32,
     funct7: u8,
     funct5: u8,
     rs2: usize,
@@ -1107,11 +2464,11 @@ struct Inst {
 }
 
 pub trait IntoI12 {
-    fn into_i12(&self) -> i16;
+    fn into_i12(self) -> i16;
 }
 
 impl IntoI12 for u16 {
-    fn into_i12(&self) -> i16 {
+    fn into_i12(self) -> i16 {
         let mut result = self & 0x0FFF;
 
         if self & 0x0800 > 0 {
@@ -1125,6 +2482,7 @@ impl IntoI12 for u16 {
 impl Inst {
     fn from_r(ir: u32) -> Self {
         Self {
+            ir,
             funct7: ((ir >> 25) & 0b1111111) as u8,
             funct5: ((ir >> 27) & 0b11111) as u8,
             rs2: ((ir >> 20) & 0b11111) as usize,
@@ -1138,6 +2496,7 @@ impl Inst {
 
     fn from_i(ir: u32) -> Self {
         Self {
+            ir,
             imm12: (((ir >> 20) & 0b111111111111) as u16).into_i12(),
             rs1: ((ir >> 15) & 0b11111) as usize,
             funct3: ((ir >> 12) & 0b111) as u8,
@@ -1151,10 +2510,11 @@ impl Inst {
 
     fn from_s(ir: u32) -> Self {
         Self {
+            ir,
             rs2: ((ir >> 20) & 0b11111) as usize,
             rs1: ((ir >> 15) & 0b11111) as usize,
             funct3: ((ir >> 12) & 0b111) as u8,
-            imm12: ((((ir >> 7) & 0b11111) | ((ir >> 25) & 0b1111111)) as u16).into_i12(),
+            imm12: ((((ir >> 7) & 0b11111) | (((ir >> 25) & 0b1111111) << 5)) as u16).into_i12(),
             funct7: 0,
             funct5: 0,
             rd: 0,
@@ -1164,6 +2524,7 @@ impl Inst {
 
     fn from_u(ir: u32) -> Self {
         Self {
+            ir,
             rd: ((ir >> 7) & 0b11111) as usize,
             funct7: 0,
             funct5: 0,
@@ -1181,13 +2542,15 @@ impl Inst {
         imm12 |= (((ir >> 8) & 0b1111) << 1) as u16; // imm[4:1]
         imm12 |= (((ir >> 25) & 0b111111) << 5) as u16; // imm[10:5]
         imm12 |= (((ir >> 7) & 0b1) << 11) as u16; // imm[11]
-        imm12 |= (((ir >> 31) & 0b1) << 31) as u16; // imm[12]
+        imm12 |= (((ir >> 31) & 0b1) << 12) as u16; // imm[12]
 
         Self {
+            ir,
             rs2: ((ir >> 20) & 0b11111) as usize,
             rs1: ((ir >> 15) & 0b11111) as usize,
             funct3: ((ir >> 12) & 0b111) as u8,
-            imm12: imm12.into_i12(),
+            // imm[12]が符号ビット
+            imm12: ((imm12 << 3) as i16) >> 3,
             funct7: 0,
             funct5: 0,
             rd: 0,
@@ -1198,12 +2561,13 @@ impl Inst {
     fn from_j(ir: u32) -> Self {
         let mut imm32: u32 = 0;
 
-        imm32 |= (((ir >> 21) & 0b1111111111) << 1) as u32; // imm[10:1]
-        imm32 |= (((ir >> 20) & 0b1) << 11) as u32; // imm[11]
-        imm32 |= (((ir >> 12) & 0b11111111) << 12) as u32; // imm[19:12]
-        imm32 |= (((ir >> 31) & 0b1) << 20) as u32; // imm[20]
+        imm32 |= ((ir >> 21) & 0b1111111111) << 1; // imm[10:1]
+        imm32 |= ((ir >> 20) & 0b1) << 11; // imm[11]
+        imm32 |= ((ir >> 12) & 0b11111111) << 12; // imm[19:12]
+        imm32 |= ((ir >> 31) & 0b1) << 20; // imm[20]
 
         Self {
+            ir,
             rd: ((ir >> 7) & 0b11111) as usize,
             funct7: 0,
             funct5: 0,
@@ -1211,7 +2575,297 @@ impl Inst {
             rs1: 0,
             funct3: 0,
             imm12: 0,
-            imm32: imm32 as i32,
+            // imm[20]が符号ビット
+            imm32: ((imm32 << 11) as i32) >> 11,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{machine, TIMER_LOOP};
+    use super::{Cpu, HaltReason, MisalignedPolicy, Privilege};
+    use crate::error::{Access, Error};
+
+    // 不正命令とバスエラーを起こし、ハンドラで次の命令に進める
+    const FAULTS: [u32; 12] = [
+        0x0000_0297, // auipc t0, 0
+        0x0202_8293, // addi  t0, t0, 0x20
+        0x3052_9073, // csrw  mtvec, t0
+        0x5000_0437, // lui   s0, 0x50000
+        0xFFFF_FFFF, // (不正命令)
+        0x0004_2303, // lw    t1, 0(s0)
+        0x0000_006F, // j     .
+        0x0000_0013, // nop
+        0x3410_23F3, // csrr  t2, mepc
+        0x0043_8393, // addi  t2, t2, 4
+        0x3413_9073, // csrw  mepc, t2
+        0x3020_0073, // mret
+    ];
+
+    // 既定ではエラーをホストに返し、その命令の所で止まる
+    #[test]
+    fn error_stops_at_faulting_instruction() {
+        let mut cpu = machine(&FAULTS);
+        let reason = cpu.step(10);
+        assert_eq!(
+            reason,
+            HaltReason::Error(Error::IllegalInstruction {
+                ir: 0xFFFF_FFFF,
+                pc: 0x10
+            })
+        );
+        assert_eq!(cpu.pc(), 0x10);
+        assert_eq!(cpu.read_csr(0x342), Ok(0));
+    }
+
+    // ゲストの例外にするとM-modeのトラップベクタに飛び、mepc、mcause、mtvalを設定する
+    #[test]
+    fn error_traps_to_machine_mode() {
+        let mut cpu = machine(&FAULTS);
+        cpu.set_trap_on_error(true);
+        assert_eq!(cpu.step(5), HaltReason::Step);
+        assert_eq!(cpu.pc(), 0x20);
+        assert_eq!(cpu.read_csr(0x341), Ok(0x10));
+        assert_eq!(cpu.read_csr(0x342), Ok(2));
+        assert_eq!(cpu.read_csr(0x343), Ok(0xFFFF_FFFF));
+
+        assert_eq!(cpu.step(5), HaltReason::Step);
+        assert_eq!(cpu.pc(), 0x20);
+        assert_eq!(cpu.read_csr(0x341), Ok(0x14));
+        assert_eq!(cpu.read_csr(0x342), Ok(5));
+        assert_eq!(cpu.read_csr(0x343), Ok(0x5000_0000));
+
+        cpu.step(4);
+        assert_eq!(cpu.pc(), 0x18);
+    }
+
+    // 3命令足して先頭に戻るループ (a0に回数が入る)
+    const COUNT_LOOP: [u32; 4] = [
+        0x0015_0513, // addi a0, a0, 1
+        0x0015_0513, // addi a0, a0, 1
+        0x0015_0513, // addi a0, a0, 1
+        0xFF5F_F06F, // j    0
+    ];
+
+    #[test]
+    fn registers_and_csrs() {
+        let mut cpu = machine(&[]);
+        cpu.set_x(0, 1);
+        cpu.set_x(5, 0x1234);
+        assert_eq!(cpu.get_x(0), 0);
+        assert_eq!(cpu.get_x(5), 0x1234);
+        cpu.set_f(3, 0x4000_0000_0000_0000);
+        assert_eq!(cpu.get_f(3), 0x4000_0000_0000_0000);
+        cpu.set_pc(0x100);
+        assert_eq!(cpu.pc(), 0x100);
+
+        cpu.write_csr(0x340, 7).unwrap();
+        assert_eq!(cpu.read_csr(0x340), Ok(7));
+        // mepcの下位2ビットは0
+        cpu.write_csr(0x341, 0x103).unwrap();
+        assert_eq!(cpu.read_csr(0x341), Ok(0x100));
+        assert_eq!(
+            cpu.read_csr(0x7C0),
+            Err(Error::UnimplementedCsr {
+                no: 0x7C0,
+                pc: 0x100
+            })
+        );
+    }
+
+    // ブレークポイントはその命令を実行する前に止まり、続けるとその命令から実行する
+    #[test]
+    fn run_control() {
+        let mut cpu = machine(&COUNT_LOOP);
+        assert_eq!(cpu.step(1), HaltReason::Step);
+        assert_eq!(cpu.get_x(10), 1);
+
+        cpu.add_breakpoint(8);
+        assert_eq!(cpu.run(), HaltReason::Breakpoint(8));
+        assert_eq!(cpu.get_x(10), 2);
+        assert_eq!(cpu.run(), HaltReason::Breakpoint(8));
+        assert_eq!(cpu.get_x(10), 5);
+        assert!(cpu.remove_breakpoint(8));
+
+        assert_eq!(cpu.run_until_pc(4), HaltReason::Pc(4));
+        assert_eq!(cpu.get_x(10), 7);
+        assert_eq!(
+            cpu.run_until(|cpu| cpu.get_x(10) == 20),
+            HaltReason::Condition
+        );
+        assert_eq!(cpu.halt_reason(), Some(&HaltReason::Condition));
+        assert_eq!(cpu.icount(), 26);
+    }
+
+    // 仮想アドレスの読み書きは今の特権レベルとsatpで変換する
+    #[test]
+    fn virtual_memory_access() {
+        let mut cpu = machine(&[]);
+        // 0x40000000のページを物理アドレス0x3000にする
+        cpu.write_phys(0x1400, &(2 << 10 | 1u32).to_le_bytes())
+            .unwrap();
+        cpu.write_phys(0x2000, &(3 << 10 | 0b111u32).to_le_bytes())
+            .unwrap();
+        cpu.write_csr(0x180, 1 << 31 | 1).unwrap();
+
+        // M-modeでは変換しない
+        cpu.write_virt(0x3010, b"phys").unwrap();
+        cpu.set_privilege(Privilege::Supervisor);
+        cpu.write_virt(0x4000_0014, b"virt").unwrap();
+        let mut buf = [0; 8];
+        cpu.read_virt(0x4000_0010, &mut buf).unwrap();
+        assert_eq!(&buf, b"physvirt");
+        cpu.read_phys(0x3010, &mut buf).unwrap();
+        assert_eq!(&buf, b"physvirt");
+        assert_eq!(
+            cpu.read_virt(0x5000_0000, &mut buf),
+            Err(Error::PageFault {
+                addr: 0x5000_0000,
+                access: Access::Load
+            })
+        );
+    }
+
+    #[test]
+    fn breakpoint_on_interrupt_handler() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.add_breakpoint(0x90);
+        assert_eq!(cpu.run(), HaltReason::Breakpoint(0x90));
+        // ハンドラの命令はまだ実行していない
+        assert_eq!(cpu.get_x(19), 0);
+        assert!(cpu.remove_breakpoint(0x90));
+        assert_eq!(cpu.run_until_pc(0x90), HaltReason::Pc(0x90));
+        assert_eq!(cpu.get_x(19), 1);
+    }
+
+    const SW: u32 = 0x00B5_2023; // sw       a1, 0(a0)
+    const LW: u32 = 0x0005_2603; // lw       a2, 0(a0)
+    const LH: u32 = 0x0005_1603; // lh       a2, 0(a0)
+    const AMOADD: u32 = 0x00B5_262F; // amoadd.w a2, a1, (a0)
+
+    // 1命令だけ実行する。例外なら0x40のハンドラに来る
+    fn misaligned(policy: MisalignedPolicy, inst: u32, addr: u32) -> Cpu {
+        let mut cpu = machine(&[inst]);
+        cpu.write_phys(0x40, &0x0000_006Fu32.to_le_bytes()).unwrap();
+        cpu.write_csr(0x305, 0x40).unwrap();
+        cpu.set_misaligned(policy);
+        cpu.set_x(10, addr);
+        cpu.set_x(11, 0x4433_2211);
+        cpu.step(1);
+        cpu
+    }
+
+    fn phys(cpu: &Cpu, addr: u32) -> [u8; 4] {
+        let mut buf = [0; 4];
+        cpu.read_phys(addr, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn misaligned_trap() {
+        for (inst, cause) in [(LW, 4), (LH, 4), (SW, 6)] {
+            let cpu = misaligned(MisalignedPolicy::Trap, inst, 0x101);
+            assert_eq!(cpu.pc(), 0x40);
+            assert_eq!(cpu.read_csr(0x342), Ok(cause));
+            assert_eq!(cpu.read_csr(0x343), Ok(0x101));
         }
+        assert_eq!(
+            phys(&misaligned(MisalignedPolicy::Trap, SW, 0x101), 0x100),
+            [0; 4]
+        );
+        // 半語の境界に揃っていればlhはそのまま
+        let cpu = misaligned(MisalignedPolicy::Trap, LH, 0x102);
+        assert_eq!(cpu.pc(), 4);
+    }
+
+    #[test]
+    fn misaligned_emulate_and_split() {
+        for policy in [MisalignedPolicy::Emulate, MisalignedPolicy::Split] {
+            let cpu = misaligned(policy, SW, 0x101);
+            assert_eq!(cpu.pc(), 4);
+            assert_eq!(phys(&cpu, 0x100), [0, 0x11, 0x22, 0x33]);
+            assert_eq!(phys(&cpu, 0x104), [0x44, 0, 0, 0]);
+
+            let mut cpu = machine(&[LW]);
+            cpu.write_phys(0x103, &[0x78, 0x56, 0x34, 0x12]).unwrap();
+            cpu.set_misaligned(policy);
+            cpu.set_x(10, 0x103);
+            cpu.step(1);
+            assert_eq!(cpu.get_x(12), 0x1234_5678);
+        }
+        // AMOはいつも例外にする
+        for policy in [MisalignedPolicy::Emulate, MisalignedPolicy::Split] {
+            let cpu = misaligned(policy, AMOADD, 0x102);
+            assert_eq!(cpu.read_csr(0x342), Ok(6));
+            assert_eq!(cpu.read_csr(0x343), Ok(0x102));
+        }
+    }
+
+    // ページをまたぐストアで後ろのページが無い時
+    // Emulateは何も書かず、Splitは前のページの分だけ書いてから例外になる
+    #[test]
+    fn misaligned_across_pages() {
+        for (policy, written) in [
+            (MisalignedPolicy::Emulate, [0, 0]),
+            (MisalignedPolicy::Split, [0x11, 0x22]),
+        ] {
+            let mut cpu = machine(&[SW]);
+            cpu.write_phys(0x40, &0x0000_006Fu32.to_le_bytes()).unwrap();
+            cpu.write_csr(0x305, 0x40).unwrap();
+            // 0から4MiBは同じアドレス、0x40000000は物理アドレス0x3000で、その次のページは無い
+            cpu.write_phys(0x1000, &0b1111u32.to_le_bytes()).unwrap();
+            cpu.write_phys(0x1400, &(2 << 10 | 1u32).to_le_bytes())
+                .unwrap();
+            cpu.write_phys(0x2000, &(3 << 10 | 0b111u32).to_le_bytes())
+                .unwrap();
+            cpu.write_csr(0x180, 1 << 31 | 1).unwrap();
+            cpu.set_privilege(Privilege::Supervisor);
+            cpu.set_misaligned(policy);
+            cpu.set_x(10, 0x4000_0FFE);
+            cpu.set_x(11, 0x4433_2211);
+            cpu.step(1);
+
+            assert_eq!(cpu.pc(), 0x40, "{:?}", policy);
+            assert_eq!(cpu.read_csr(0x342), Ok(15));
+            assert_eq!(cpu.read_csr(0x343), Ok(0x4000_1000));
+            assert_eq!(phys(&cpu, 0x3FFC)[2..], written, "{:?}", policy);
+        }
+    }
+
+    // リセットベクタの命令から始め、ハートの状態だけを戻す
+    #[test]
+    fn reset_restarts_at_vector() {
+        let mut cpu = machine(&[
+            0x0010_0513, // li    a0, 1
+            0x0000_006F, // j     .
+        ]);
+        cpu.write_phys(0x40, &0x0050_0513u32.to_le_bytes()).unwrap(); // li a0, 5
+        cpu.step(1);
+        assert_eq!(cpu.get_x(10), 1);
+
+        cpu.set_x(5, 0x1234);
+        cpu.set_f(1, 0x5678);
+        cpu.write_csr(0x340, 0x9ABC).unwrap();
+        cpu.write_csr(0x305, 0x100).unwrap();
+        cpu.set_privilege(Privilege::User);
+        cpu.set_reset_vector(0x40);
+        assert_eq!(cpu.reset_vector(), 0x40);
+        cpu.reset();
+        assert_eq!(cpu.pc(), 0x40);
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        assert_eq!(cpu.get_x(5), 0);
+        assert_eq!(cpu.get_f(1), 0);
+        assert_eq!(cpu.read_csr(0x340), Ok(0));
+        assert_eq!(cpu.read_csr(0x305), Ok(0));
+        assert_eq!(cpu.halt_reason(), None);
+
+        cpu.step(1);
+        assert_eq!(cpu.get_x(10), 5);
+        assert_eq!(cpu.pc(), 0x44);
+        // RAMはそのまま
+        let mut buf = [0; 4];
+        cpu.read_phys(0, &mut buf).unwrap();
+        assert_eq!(u32::from_le_bytes(buf), 0x0010_0513);
     }
 }
@@ -0,0 +1,371 @@
+use super::{Cpu, Privilege};
+use crate::snapshot::{self, Reader, Writer};
+
+// mhpmeventに書くイベント番号
+pub const EVENT_NONE: u32 = 0;
+// ロード (AMOも含む)
+pub const EVENT_LOAD: u32 = 1;
+// ストア (AMOとscも含む)
+pub const EVENT_STORE: u32 = 2;
+// 条件分岐
+pub const EVENT_BRANCH: u32 = 3;
+// 成立した条件分岐
+pub const EVENT_BRANCH_TAKEN: u32 = 4;
+// ページテーブルを引いた回数 (データ側にTLBはないので変換のたびに数える)
+pub const EVENT_TLB_MISS: u32 = 5;
+// 例外と割り込み
+pub const EVENT_TRAP: u32 = 6;
+const EVENTS: usize = 7;
+
+// mcountinhibitのTMは予約
+const INHIBIT_MASK: u32 = !(1 << 1);
+
+// Zicntr/Zihpmのカウンタ
+// 0番はサイクル、2番は命令数、3から31番はmhpmcounter (1番のtimeはCLINTのmtime)
+// 毎命令数えずに、元になる数 (命令数やイベント数) との差を持っておく
+#[derive(Clone)]
+pub(super) struct Counters {
+    offset: [u64; 32],
+    // mcountinhibitで止めている間の値
+    frozen: [u64; 32],
+    mhpmevent: [u32; 32],
+    mcountinhibit: u32,
+    mcounteren: u32,
+    scounteren: u32,
+    events: [u64; EVENTS],
+    // イベントを数えるmhpmcounterがある
+    pub active: bool,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            offset: [0; 32],
+            frozen: [0; 32],
+            mhpmevent: [0; 32],
+            mcountinhibit: 0,
+            mcounteren: 0,
+            scounteren: 0,
+            events: [0; EVENTS],
+            active: false,
+        }
+    }
+
+    #[inline(always)]
+    pub fn count(&mut self, event: u32) {
+        if self.active {
+            self.events[event as usize] += 1;
+        }
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        for i in 0..32 {
+            w.u64(self.offset[i]);
+            w.u64(self.frozen[i]);
+            w.u32(self.mhpmevent[i]);
+        }
+        w.u32(self.mcountinhibit);
+        w.u32(self.mcounteren);
+        w.u32(self.scounteren);
+        for e in self.events {
+            w.u64(e);
+        }
+    }
+
+    pub fn load(&mut self, r: &mut Reader) -> snapshot::Result<()> {
+        for i in 0..32 {
+            self.offset[i] = r.u64()?;
+            self.frozen[i] = r.u64()?;
+            self.mhpmevent[i] = r.u32()?;
+        }
+        self.mcountinhibit = r.u32()?;
+        self.mcounteren = r.u32()?;
+        self.scounteren = r.u32()?;
+        for e in &mut self.events {
+            *e = r.u64()?;
+        }
+        self.update_active();
+        Ok(())
+    }
+
+    fn update_active(&mut self) {
+        self.active = self.mhpmevent[3..].iter().any(|&e| e != EVENT_NONE);
+    }
+}
+
+impl Cpu {
+    // カウンタの元になる数
+    fn counter_base(&self, i: usize) -> u64 {
+        match i {
+            0 => self.cycles,
+            2 => self.instret,
+            _ => match self.counters.mhpmevent[i] as usize {
+                e if e != 0 && e < EVENTS => self.counters.events[e],
+                _ => 0,
+            },
+        }
+    }
+
+    fn counter(&self, i: usize) -> u64 {
+        if self.counters.mcountinhibit & (1 << i) != 0 {
+            self.counters.frozen[i]
+        } else {
+            self.counter_base(i).wrapping_add(self.counters.offset[i])
+        }
+    }
+
+    fn set_counter(&mut self, i: usize, val: u64) {
+        if self.counters.mcountinhibit & (1 << i) != 0 {
+            self.counters.frozen[i] = val;
+        } else {
+            self.counters.offset[i] = val.wrapping_sub(self.counter_base(i));
+        }
+    }
+
+    // 下位か上位の32ビットを書き換える
+    fn set_counter_half(&mut self, i: usize, high: bool, val: u32) {
+        let old = self.counter(i);
+        let new = if high {
+            (old & 0xFFFF_FFFF) | (val as u64) << 32
+        } else {
+            (old & !0xFFFF_FFFF) | val as u64
+        };
+        self.set_counter(i, new);
+    }
+
+    fn set_mcountinhibit(&mut self, val: u32) {
+        let val = val & INHIBIT_MASK;
+        for i in (0..32).filter(|&i| i != 1) {
+            let val_before = self.counter(i);
+            let bit = 1 << i;
+            self.counters.mcountinhibit = (self.counters.mcountinhibit & !bit) | (val & bit);
+            self.set_counter(i, val_before);
+        }
+    }
+
+    fn set_mhpmevent(&mut self, i: usize, val: u32) {
+        // 数え始めの値を保ったまま元になるイベントを変える
+        let val_before = self.counter(i);
+        self.counters.mhpmevent[i] = if (val as usize) < EVENTS {
+            val
+        } else {
+            EVENT_NONE
+        };
+        self.set_counter(i, val_before);
+        self.counters.update_active();
+    }
+
+    // 実行した命令のイベントを数える
+    pub(super) fn count_events(&mut self) {
+        match self.ir & 0x7F {
+            0x03 => self.counters.count(EVENT_LOAD),
+            0x23 => self.counters.count(EVENT_STORE),
+            0x2F => match self.ir >> 27 {
+                // lr.w
+                0b00010 => self.counters.count(EVENT_LOAD),
+                // sc.w
+                0b00011 => self.counters.count(EVENT_STORE),
+                _ => {
+                    self.counters.count(EVENT_LOAD);
+                    self.counters.count(EVENT_STORE);
+                }
+            },
+            0x63 => {
+                self.counters.count(EVENT_BRANCH);
+                if self.next_pc != self.pc.wrapping_add(4) {
+                    self.counters.count(EVENT_BRANCH_TAKEN);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // ユーザーレベルのカウンタ (cycle, time, instret, hpmcounter) を読めるか
+    // mcounterenとscounterenで下の特権レベルから隠せる
+    pub(super) fn counter_accessible(&self, no: u16) -> bool {
+        if !matches!(no, 0xC00..=0xC1F | 0xC80..=0xC9F) {
+            return true;
+        }
+        let bit = 1 << (no & 0x1F);
+        match self.prv {
+            Privilege::Machine => true,
+            Privilege::Supervisor => self.counters.mcounteren & bit != 0,
+            Privilege::User => {
+                self.counters.mcounteren & bit != 0 && self.counters.scounteren & bit != 0
+            }
+        }
+    }
+
+    pub(super) fn read_counter_csr(&self, no: u16) -> Option<u32> {
+        let i = (no & 0x1F) as usize;
+        let val = match no {
+            0x106 => self.counters.scounteren,
+            0x306 => self.counters.mcounteren,
+            0x320 => self.counters.mcountinhibit,
+            0x323..=0x33F => self.counters.mhpmevent[i],
+            // time, timeh
+            0xC01 => self.bus.clint().mtime() as u32,
+            0xC81 => (self.bus.clint().mtime() >> 32) as u32,
+            // mcycle, minstret, mhpmcounter (1番は無い)
+            0xB01 | 0xB81 => return None,
+            0xB00..=0xB1F | 0xC00..=0xC1F => self.counter(i) as u32,
+            0xB80..=0xB9F | 0xC80..=0xC9F => (self.counter(i) >> 32) as u32,
+            _ => return None,
+        };
+        Some(val)
+    }
+
+    // 書けるCSRでなければfalseを返す
+    pub(super) fn write_counter_csr(&mut self, no: u16, val: u32) -> bool {
+        let i = (no & 0x1F) as usize;
+        match no {
+            0x106 => self.counters.scounteren = val,
+            0x306 => self.counters.mcounteren = val,
+            0x320 => self.set_mcountinhibit(val),
+            0x323..=0x33F => self.set_mhpmevent(i, val),
+            0xB01 | 0xB81 => return false,
+            0xB00..=0xB1F => self.set_counter_half(i, false, val),
+            0xB80..=0xB9F => self.set_counter_half(i, true, val),
+            // 読み取り専用
+            0xC00..=0xC1F | 0xC80..=0xC9F => {}
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EVENT_BRANCH, EVENT_BRANCH_TAKEN, EVENT_LOAD, EVENT_STORE, EVENT_TRAP};
+    use crate::cpu::testing::machine;
+    use crate::cpu::Privilege;
+
+    // ecallとebreakは例外を起こすのでminstretに数えない
+    #[test]
+    fn ecall_and_ebreak_do_not_retire() {
+        let mut cpu = machine(&[
+            0x0000_0297, // auipc t0, 0
+            0x0202_8293, // addi  t0, t0, 0x20
+            0x3052_9073, // csrw  mtvec, t0
+            0x0000_0073, // ecall
+            0x0010_0073, // ebreak
+            0x0000_0013, // nop
+            0x0000_006F, // j     .
+            0x0000_0013, // nop
+            0x3410_2373, // csrr  t1, mepc
+            0x0043_0313, // addi  t1, t1, 4
+            0x3413_1073, // csrw  mepc, t1
+            0x3020_0073, // mret
+        ]);
+        cpu.step(14);
+        assert_eq!(cpu.pc(), 0x18);
+        assert_eq!(cpu.icount(), 14);
+        assert_eq!(cpu.instret(), 12);
+        assert_eq!(cpu.read_csr(0xB02), Ok(12));
+    }
+
+    // 0x40のハンドラに来たら読めなかった (不正命令例外)
+    const READ_COUNTERS: [u32; 3] = [
+        0xC020_2573, // csrr  a0, instret
+        0xC030_25F3, // csrr  a1, hpmcounter3
+        0x0000_006F, // j     .
+    ];
+
+    // (特権レベル, mcounteren, scounteren, 読めるか)
+    #[test]
+    fn counter_enables() {
+        let cases = [
+            (Privilege::Machine, 0, 0, true),
+            (Privilege::Supervisor, 0, 0, false),
+            (Privilege::Supervisor, 1 << 2, 0, true),
+            (Privilege::Supervisor, 1 << 3, 1 << 2, false),
+            (Privilege::User, 1 << 2, 0, false),
+            (Privilege::User, 0, 1 << 2, false),
+            (Privilege::User, 1 << 2, 1 << 2, true),
+        ];
+        for (prv, mcounteren, scounteren, readable) in cases {
+            let mut cpu = machine(&READ_COUNTERS);
+            cpu.write_phys(0x40, &0x0000_006Fu32.to_le_bytes()).unwrap();
+            cpu.set_trap_on_error(true);
+            cpu.write_csr(0x305, 0x40).unwrap();
+            cpu.write_csr(0x306, mcounteren).unwrap();
+            cpu.write_csr(0x106, scounteren).unwrap();
+            cpu.set_privilege(prv);
+            cpu.step(1);
+            let case = (prv, mcounteren, scounteren);
+            if readable {
+                assert_eq!(cpu.pc(), 4, "{:?}", case);
+                assert_eq!(cpu.get_x(10), 0, "{:?}", case);
+            } else {
+                assert_eq!(cpu.pc(), 0x40, "{:?}", case);
+                assert_eq!(cpu.read_csr(0x342), Ok(2), "{:?}", case);
+                assert_eq!(cpu.read_csr(0x343), Ok(READ_COUNTERS[0]), "{:?}", case);
+            }
+        }
+
+        // hpmcounter3はmcounterenのビット3で決まる
+        let mut cpu = machine(&READ_COUNTERS);
+        cpu.set_trap_on_error(true);
+        cpu.write_csr(0x305, 0x40).unwrap();
+        cpu.write_csr(0x306, 1 << 2).unwrap();
+        cpu.set_privilege(Privilege::Supervisor);
+        cpu.step(2);
+        assert_eq!(cpu.pc(), 0x40);
+        assert_eq!(cpu.read_csr(0x343), Ok(READ_COUNTERS[1]));
+    }
+
+    // 3回回るループで、ロードとストアと分岐を数える
+    const LOOP: [u32; 10] = [
+        0x0030_0293, // li    t0, 3
+        0x1000_0313, // li    t1, 0x100
+        0x0053_0023, // sb    t0, 0(t1)
+        0x0053_1123, // sh    t0, 2(t1)
+        0x0003_2383, // lw    t2, 0(t1)
+        0x0253_83B3, // mul   t2, t2, t0
+        0xFFF2_8293, // addi  t0, t0, -1
+        0xFE02_96E3, // bnez  t0, 0x8
+        0x0000_0073, // ecall
+        0x0000_006F, // j     .
+    ];
+
+    #[test]
+    fn mhpmevent_counts() {
+        let mut cpu = machine(&LOOP);
+        cpu.write_csr(0x305, 0x24).unwrap();
+        let events = [
+            EVENT_LOAD,
+            EVENT_STORE,
+            EVENT_BRANCH,
+            EVENT_BRANCH_TAKEN,
+            EVENT_TRAP,
+            99,
+        ];
+        for (i, &event) in events.iter().enumerate() {
+            cpu.write_csr(0x323 + i as u16, event).unwrap();
+        }
+        // 知らないイベントは何も数えない
+        assert_eq!(cpu.read_csr(0x328), Ok(0));
+        // 書いた値から数え始める
+        cpu.write_csr(0xB03, 100).unwrap();
+        cpu.write_csr(0xB83, 1).unwrap();
+
+        cpu.step(22);
+        assert_eq!(cpu.pc(), 0x24);
+        let counts: Vec<u32> = (0..6).map(|i| cpu.read_csr(0xB03 + i).unwrap()).collect();
+        assert_eq!(counts, [103, 6, 3, 2, 1, 0]);
+        assert_eq!(cpu.read_csr(0xB83), Ok(1));
+        // ユーザーレベルの名前でも同じ値
+        assert_eq!(cpu.read_csr(0xC04), Ok(6));
+
+        // mcountinhibitで止めている間は増えない
+        cpu.write_csr(0x320, 1 << 4).unwrap();
+        cpu.set_pc(0);
+        cpu.step(22);
+        assert_eq!(cpu.read_csr(0xB03), Ok(106));
+        assert_eq!(cpu.read_csr(0xB04), Ok(6));
+        cpu.write_csr(0x320, 0).unwrap();
+        cpu.set_pc(0);
+        cpu.step(22);
+        assert_eq!(cpu.read_csr(0xB04), Ok(12));
+    }
+}
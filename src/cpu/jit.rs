@@ -0,0 +1,759 @@
+use std::collections::HashMap;
+use std::mem::offset_of;
+use std::ptr;
+
+use super::{Cpu, HaltReason, Inst};
+
+mod x86;
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs an x86_64 Linux host");
+
+use x86::{Alu, Asm, Cond, Reg, Shift};
+
+const CODE_SIZE: usize = 16 << 20;
+// インタプリタでこの回数実行されたブロックをコンパイルする
+const HOT_THRESHOLD: u32 = 16;
+// 1回のネイティブ実行で進める命令数の上限
+// 間で割り込みなどを見られるように戻ってくる
+const MAX_BUDGET: u64 = 1 << 16;
+const MAX_BLOCK_LEN: usize = 256;
+
+// ネイティブコードから戻った時の指示
+const STATUS_CONTINUE: u32 = 0;
+const STATUS_INTERPRET: u32 = 1;
+
+// ヘルパーの返り値
+const LOAD_FAULT: u64 = 1 << 32;
+const STORE_OK: u32 = 0;
+const STORE_FAULT: u32 = 1;
+const STORE_DIRTY: u32 = 2;
+
+// JITの動作モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitMode {
+    Off,
+    On,
+    // ネイティブで実行するたびにインタプリタでも実行して結果を突き合わせる
+    Check,
+}
+
+// ネイティブコードとやり取りする領域 (rbxが指す)
+#[repr(C)]
+struct Context {
+    xr: *mut u32,
+    cpu: *mut Cpu,
+    entry: usize,
+    // 残りの命令数
+    budget: u64,
+    pc: u32,
+    status: u32,
+    // 未接続のチェインのスロット
+    link: usize,
+}
+
+const PC: usize = offset_of!(Context, pc);
+const BUDGET: usize = offset_of!(Context, budget);
+const STATUS: usize = offset_of!(Context, status);
+const LINK: usize = offset_of!(Context, link);
+
+enum Entry {
+    Native { vpc: u32, code: usize },
+    // 先頭の命令がコンパイルできない
+    Interpret,
+}
+
+enum Lookup {
+    Native(usize),
+    Interpret,
+    Cold,
+    Hot,
+}
+
+pub(super) struct Jit {
+    check: bool,
+    mem: ExecMem,
+    trampoline_len: usize,
+    used: usize,
+    exit: usize,
+    // 物理pcをキーにしたコンパイル済みブロック
+    blocks: HashMap<u32, Entry>,
+    heat: HashMap<u32, u32>,
+    // 前回のネイティブ実行の最後に通った未接続のスロットと飛び先
+    link: Option<(usize, u32)>,
+}
+
+impl Jit {
+    pub fn new(mode: JitMode) -> Self {
+        let mut mem = ExecMem::new(CODE_SIZE);
+        let mut asm = Asm::new(mem.addr());
+        let exit = asm.trampoline(offset_of!(Context, xr), offset_of!(Context, entry));
+        let code = asm.finish();
+        mem.write(0, &code);
+
+        Self {
+            check: mode == JitMode::Check,
+            mem,
+            trampoline_len: code.len(),
+            used: code.len(),
+            exit,
+            blocks: HashMap::new(),
+            heat: HashMap::new(),
+            link: None,
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.heat.clear();
+        self.link = None;
+        self.used = self.trampoline_len;
+    }
+
+    // ブロック間のチェインはページ内に限るので、ページごと捨てれば足りる
+    pub fn invalidate_page(&mut self, page: u32) {
+        self.blocks.retain(|paddr, _| paddr & !0xFFF != page);
+        self.heat.retain(|paddr, _| paddr & !0xFFF != page);
+        self.link = None;
+    }
+
+    fn lookup(&mut self, paddr: u32, vpc: u32) -> Lookup {
+        match self.blocks.get(&paddr) {
+            Some(Entry::Native { vpc: v, code }) if *v == vpc => return Lookup::Native(*code),
+            Some(Entry::Interpret) => return Lookup::Interpret,
+            _ => {}
+        }
+        let heat = self.heat.entry(paddr).or_insert(0);
+        *heat += 1;
+        if *heat >= HOT_THRESHOLD {
+            Lookup::Hot
+        } else {
+            Lookup::Cold
+        }
+    }
+
+    fn compile(&mut self, paddr: u32, vpc: u32, words: &[u32]) -> Option<usize> {
+        self.heat.remove(&paddr);
+
+        let mut code = assemble(self.mem.addr() + self.used, self.exit, vpc, words);
+        if matches!(&code, Some(bytes) if self.used + bytes.len() > CODE_SIZE) {
+            // 領域が尽きたら全部捨ててやり直す
+            self.flush();
+            code = assemble(self.mem.addr() + self.used, self.exit, vpc, words);
+        }
+        let Some(bytes) = code else {
+            self.blocks.insert(paddr, Entry::Interpret);
+            return None;
+        };
+
+        let entry = self.mem.addr() + self.used;
+        self.mem.write(self.used, &bytes);
+        self.used += bytes.len();
+        self.blocks
+            .insert(paddr, Entry::Native { vpc, code: entry });
+        Some(entry)
+    }
+
+    // 前回の出口のスロットを今のブロックにつなぐ
+    fn chain(&mut self, vpc: u32, code: usize) {
+        if let Some((slot, target)) = self.link.take() {
+            if target == vpc {
+                self.mem
+                    .write(slot - self.mem.addr(), &(code as u64).to_le_bytes());
+            }
+        }
+    }
+
+    fn entry_fn(&self) -> extern "sysv64" fn(*mut Context) {
+        // SAFETY: 先頭にはtrampolineが置いてある
+        unsafe { std::mem::transmute::<usize, extern "sysv64" fn(*mut Context)>(self.mem.addr()) }
+    }
+}
+
+impl Cpu {
+    pub fn set_jit(&mut self, mode: JitMode) {
+        self.jit = match mode {
+            JitMode::Off => None,
+            mode => Some(Box::new(Jit::new(mode))),
+        };
+    }
+
+    // pcのブロックがコンパイル済みならネイティブで実行する
+    // 実行した命令数と、次をインタプリタで実行すべきかを返す
+    // 検証モードでインタプリタと結果が違えばJitMismatchで止める
+    pub(super) fn run_native(&mut self, budget: u64) -> Result<(u64, bool), HaltReason> {
+        let Ok(paddr) = self.translate_fetch() else {
+            return Ok((0, true));
+        };
+        self.invalidate_dirty_code();
+
+        let Some(lookup) = self.jit.as_mut().map(|jit| jit.lookup(paddr, self.pc)) else {
+            return Ok((0, true));
+        };
+        let code = match lookup {
+            Lookup::Native(code) => code,
+            Lookup::Interpret | Lookup::Cold => return Ok((0, true)),
+            Lookup::Hot => {
+                let words = self.block_words(paddr);
+                match self.jit.as_mut().unwrap().compile(paddr, self.pc, &words) {
+                    Some(code) => {
+                        self.bus.mark_code(paddr);
+                        code
+                    }
+                    None => return Ok((0, true)),
+                }
+            }
+        };
+
+        let jit = self.jit.as_mut().unwrap();
+        jit.chain(self.pc, code);
+        let check = jit.check;
+        let enter = jit.entry_fn();
+
+        let mut shadow = check.then(|| self.shadow());
+        if check {
+            self.bus.start_journal();
+        }
+        let start = self.pc;
+        let budget = budget.min(MAX_BUDGET);
+
+        let cpu: *mut Cpu = self;
+        let mut ctx = Context {
+            // SAFETY: cpuは有効なポインタ
+            xr: unsafe { ptr::addr_of_mut!((*cpu).xr) } as *mut u32,
+            cpu,
+            entry: code,
+            budget,
+            pc: start,
+            status: STATUS_CONTINUE,
+            link: 0,
+        };
+        // デバイスへのアクセスはインタプリタでやり直す
+        self.bus.set_ram_only(true);
+        enter(&mut ctx);
+        self.bus.set_ram_only(false);
+
+        let executed = budget - ctx.budget;
+        self.pc = ctx.pc;
+        self.instret += executed;
+        self.icount += executed;
+        self.advance_cycles(executed);
+        if ctx.link != 0 {
+            self.jit.as_mut().unwrap().link = Some((ctx.link, ctx.pc));
+        }
+
+        if let Some(shadow) = &mut shadow {
+            if let Some(diff) = self.check_native(shadow, executed) {
+                return Err(HaltReason::JitMismatch(format!(
+                    "after {} instructions from {:08X}: {}",
+                    executed, start, diff
+                )));
+            }
+        }
+
+        Ok((executed, ctx.status == STATUS_INTERPRET))
+    }
+
+    // paddrからページの終わりまでの命令
+    fn block_words(&self, paddr: u32) -> Vec<u32> {
+        let len = ((0x1000 - (paddr & 0xFFF)) / 4) as usize;
+        (0..len.min(MAX_BLOCK_LEN))
+            .map_while(|i| self.bus.fetch32(paddr + 4 * i as u32).ok())
+            .collect()
+    }
+
+    // 差分チェック用にJITもRAMも持たない複製を作る (RAMは実行する時に貸す)
+    fn shadow(&mut self) -> Cpu {
+        Cpu {
+            xr: self.xr,
+            pc: self.pc,
+            fr: self.fr,
+            prv: self.prv,
+            instret: self.instret,
+            // mtimeはサイクル数で進むので、時間に関わるものも揃える
+            icount: self.icount,
+            cycles: self.cycles,
+            timebase: self.timebase,
+            time_rem: self.time_rem,
+            counters: self.counters.clone(),
+            ustatus: self.ustatus,
+            uie: self.uie,
+            utvec: self.utvec,
+            uscratch: self.uscratch,
+            uepc: self.uepc,
+            ucause: self.ucause,
+            utval: self.utval,
+            uip: self.uip,
+            stvec: self.stvec,
+            sscratch: self.sscratch,
+            sepc: self.sepc,
+            scause: self.scause,
+            stval: self.stval,
+            satp: self.satp,
+            mstatus: self.mstatus,
+            medeleg: self.medeleg,
+            mideleg: self.mideleg,
+            mie: self.mie,
+            mtvec: self.mtvec,
+            mscratch: self.mscratch,
+            mepc: self.mepc,
+            mcause: self.mcause,
+            mtval: self.mtval,
+            mip: self.mip,
+            trap_on_error: self.trap_on_error,
+            misaligned: self.misaligned,
+            sbi: self.sbi,
+            ..Cpu::new(self.bus.clone_without_ram())
+        }
+    }
+
+    // ネイティブで書いたページを書く前に戻したRAMを複製に貸し、インタプリタで実行して比べる
+    // RAMはどちらかが書いたページだけを比べ、最後はネイティブで実行した結果にしておく
+    fn check_native(&mut self, shadow: &mut Cpu, executed: u64) -> Option<String> {
+        let written = self.bus.take_journal();
+        let mut pages: Vec<(usize, Vec<u8>)> = written
+            .iter()
+            .map(|(page, _)| (*page, self.bus.page(*page).to_vec()))
+            .collect();
+        for (page, before) in &written {
+            self.bus.set_page(*page, before);
+        }
+
+        self.bus.swap_ram(&mut shadow.bus);
+        shadow.bus.start_journal();
+        shadow.step(executed);
+        let shadow_written = shadow.bus.take_journal();
+        self.bus.swap_ram(&mut shadow.bus);
+
+        for (page, before) in shadow_written {
+            if !pages.iter().any(|(p, _)| *p == page) {
+                pages.push((page, before));
+            }
+        }
+        let mut diff = self.diff(shadow);
+        for (page, native) in &pages {
+            if diff.is_none() {
+                let interp = self.bus.page(*page);
+                if let Some(i) = (0..native.len()).find(|&i| native[i] != interp[i]) {
+                    diff = Some(format!(
+                        "memory at {:08X} is {:02X}, interpreter has {:02X}",
+                        self.bus
+                            .ram_base()
+                            .wrapping_add(*page as u32 * 0x1000 + i as u32),
+                        native[i],
+                        interp[i]
+                    ));
+                }
+            }
+            self.bus.set_page(*page, native);
+        }
+        diff
+    }
+
+    fn diff(&self, other: &Cpu) -> Option<String> {
+        if let Some(i) = (1..32).find(|&i| self.xr[i] != other.xr[i]) {
+            return Some(format!(
+                "x{} is {:08X}, interpreter has {:08X}",
+                i, self.xr[i], other.xr[i]
+            ));
+        }
+        if self.pc != other.pc {
+            return Some(format!(
+                "pc is {:08X}, interpreter has {:08X}",
+                self.pc, other.pc
+            ));
+        }
+        if self.instret != other.instret {
+            return Some(format!(
+                "instret is {}, interpreter has {}",
+                self.instret, other.instret
+            ));
+        }
+        None
+    }
+}
+
+// 命令がネイティブコードにできるか
+#[derive(PartialEq, Eq)]
+enum Kind {
+    Plain,
+    // ブロックの最後に置く制御命令
+    Jump,
+    Unsupported,
+}
+
+fn kind(ir: u32) -> Kind {
+    if Cpu::decode(ir).is_none() {
+        return Kind::Unsupported;
+    }
+    let funct3 = (ir >> 12) & 0b111;
+    let funct7 = ir >> 25;
+    match ir & 0x7F {
+        0x37 | 0x17 | 0x13 | 0x03 | 0x23 => Kind::Plain,
+        // 除算はインタプリタに任せる
+        0x33 if funct7 == 1 && funct3 >= 4 => Kind::Unsupported,
+        0x33 => Kind::Plain,
+        // 飛び先がアラインされていない分岐は例外になるのでインタプリタに任せる
+        0x63 if Inst::from_b(ir).imm12 & 0b10 != 0 => Kind::Unsupported,
+        0x6F if Inst::from_j(ir).imm32 & 0b10 != 0 => Kind::Unsupported,
+        0x63 | 0x6F | 0x67 => Kind::Jump,
+        _ => Kind::Unsupported,
+    }
+}
+
+// vpcから始まるブロックをbaseに置く機械語にする
+// 先頭の命令がコンパイルできなければNone
+fn assemble(base: usize, exit: usize, vpc: u32, words: &[u32]) -> Option<Vec<u8>> {
+    let mut len = 0;
+    let mut stop = Kind::Plain;
+    for &ir in words {
+        stop = kind(ir);
+        if stop == Kind::Unsupported {
+            break;
+        }
+        len += 1;
+        if stop == Kind::Jump {
+            break;
+        }
+    }
+    if len == 0 {
+        return None;
+    }
+
+    let mut e = Emitter {
+        asm: Asm::new(base),
+        exit,
+        vpc,
+        len,
+    };
+
+    // 残りの命令数が足りなければインタプリタに任せる
+    e.asm.ctx_alu64_imm(Alu::Cmp, BUDGET, len as u32);
+    let short = e.asm.jcc_fwd(Cond::B);
+    e.asm.ctx_alu64_imm(Alu::Sub, BUDGET, len as u32);
+
+    for (i, &ir) in words[..len].iter().enumerate() {
+        e.inst(i, ir);
+    }
+    let next = vpc.wrapping_add(4 * len as u32);
+    match stop {
+        Kind::Jump => {}
+        Kind::Unsupported => e.exit_interpret(next),
+        Kind::Plain => e.exit_to(next),
+    }
+
+    e.asm.bind(short);
+    e.exit_interpret(vpc);
+
+    Some(e.asm.finish())
+}
+
+struct Emitter {
+    asm: Asm,
+    exit: usize,
+    vpc: u32,
+    len: usize,
+}
+
+impl Emitter {
+    fn inst(&mut self, i: usize, ir: u32) {
+        let pc = self.vpc.wrapping_add(4 * i as u32);
+        match ir & 0x7F {
+            0x37 => {
+                let inst = Inst::from_u(ir);
+                self.asm.store_x_imm(inst.rd, inst.imm32 as u32);
+            }
+            0x17 => {
+                let inst = Inst::from_u(ir);
+                self.asm
+                    .store_x_imm(inst.rd, pc.wrapping_add(inst.imm32 as u32));
+            }
+            0x13 => self.opimm(Inst::from_i(ir)),
+            0x33 => self.op(Inst::from_r(ir)),
+            0x03 => self.load(i, Inst::from_i(ir)),
+            0x23 => self.store(i, Inst::from_s(ir)),
+            0x63 => self.branch(pc, Inst::from_b(ir)),
+            0x6F => {
+                let inst = Inst::from_j(ir);
+                self.asm.store_x_imm(inst.rd, pc.wrapping_add(4));
+                self.exit_to(pc.wrapping_add(inst.imm32 as u32));
+            }
+            0x67 => {
+                let inst = Inst::from_i(ir);
+                self.asm.load_x(Reg::Eax, inst.rs1);
+                self.asm
+                    .alu_imm(Alu::Add, Reg::Eax, inst.imm12 as i32 as u32);
+                self.asm.alu_imm(Alu::And, Reg::Eax, !1);
+                // 飛び先がアラインされていなければインタプリタで例外にする
+                self.asm.mov(Reg::Ecx, Reg::Eax);
+                self.asm.alu_imm(Alu::And, Reg::Ecx, 0b10);
+                let aligned = self.asm.jcc_fwd(Cond::E);
+                self.exit_fault(i);
+                self.asm.bind(aligned);
+                self.asm.store_x_imm(inst.rd, pc.wrapping_add(4));
+                self.asm.ctx_store(PC, Reg::Eax);
+                self.asm.jmp_abs(self.exit);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn opimm(&mut self, inst: Inst) {
+        let imm = inst.imm12 as i32 as u32;
+        let shamt = (inst.imm12 & 0x1F) as u8;
+        self.asm.load_x(Reg::Eax, inst.rs1);
+        match (inst.funct3, inst.funct7) {
+            (0b000, _) => self.asm.alu_imm(Alu::Add, Reg::Eax, imm),
+            (0b001, _) => self.asm.shift_imm(Shift::Shl, Reg::Eax, shamt),
+            (0b010, _) => {
+                self.asm.alu_imm(Alu::Cmp, Reg::Eax, imm);
+                self.asm.set_eax(Cond::L);
+            }
+            (0b011, _) => {
+                self.asm.alu_imm(Alu::Cmp, Reg::Eax, imm);
+                self.asm.set_eax(Cond::B);
+            }
+            (0b100, _) => self.asm.alu_imm(Alu::Xor, Reg::Eax, imm),
+            (0b101, 0b0000000) => self.asm.shift_imm(Shift::Shr, Reg::Eax, shamt),
+            (0b101, _) => self.asm.shift_imm(Shift::Sar, Reg::Eax, shamt),
+            (0b110, _) => self.asm.alu_imm(Alu::Or, Reg::Eax, imm),
+            _ => self.asm.alu_imm(Alu::And, Reg::Eax, imm),
+        }
+        self.asm.store_x(inst.rd, Reg::Eax);
+    }
+
+    fn op(&mut self, inst: Inst) {
+        self.asm.load_x(Reg::Eax, inst.rs1);
+        self.asm.load_x(Reg::Ecx, inst.rs2);
+        match (inst.funct3, inst.funct7) {
+            (0b000, 0b0000000) => self.asm.alu(Alu::Add, Reg::Eax, Reg::Ecx),
+            (0b000, 0b0100000) => self.asm.alu(Alu::Sub, Reg::Eax, Reg::Ecx),
+            (0b001, 0b0000000) => self.asm.shift_cl(Shift::Shl, Reg::Eax),
+            (0b010, 0b0000000) => {
+                self.asm.alu(Alu::Cmp, Reg::Eax, Reg::Ecx);
+                self.asm.set_eax(Cond::L);
+            }
+            (0b011, 0b0000000) => {
+                self.asm.alu(Alu::Cmp, Reg::Eax, Reg::Ecx);
+                self.asm.set_eax(Cond::B);
+            }
+            (0b100, 0b0000000) => self.asm.alu(Alu::Xor, Reg::Eax, Reg::Ecx),
+            (0b101, 0b0000000) => self.asm.shift_cl(Shift::Shr, Reg::Eax),
+            (0b101, 0b0100000) => self.asm.shift_cl(Shift::Sar, Reg::Eax),
+            (0b110, 0b0000000) => self.asm.alu(Alu::Or, Reg::Eax, Reg::Ecx),
+            (0b111, 0b0000000) => self.asm.alu(Alu::And, Reg::Eax, Reg::Ecx),
+            (0b000, _) => self.asm.imul(),
+            (0b001, _) => self.asm.mul_high(true, true),
+            (0b010, _) => self.asm.mul_high(true, false),
+            (0b011, _) => self.asm.mul_high(false, false),
+            _ => unreachable!(),
+        }
+        self.asm.store_x(inst.rd, Reg::Eax);
+    }
+
+    fn load(&mut self, i: usize, inst: Inst) {
+        self.asm.load_x(Reg::Eax, inst.rs1);
+        self.asm
+            .alu_imm(Alu::Add, Reg::Eax, inst.imm12 as i32 as u32);
+        self.asm.mov(Reg::Esi, Reg::Eax);
+        self.asm.mov_imm(Reg::Edx, inst.funct3 as u32);
+        self.asm.mov_rdi_ctx();
+        self.asm.call_abs(load as *const () as usize);
+
+        self.asm.test_bit32_rax();
+        let ok = self.asm.jcc_fwd(Cond::Ae);
+        self.exit_fault(i);
+        self.asm.bind(ok);
+        self.asm.store_x(inst.rd, Reg::Eax);
+    }
+
+    fn store(&mut self, i: usize, inst: Inst) {
+        self.asm.load_x(Reg::Eax, inst.rs1);
+        self.asm
+            .alu_imm(Alu::Add, Reg::Eax, inst.imm12 as i32 as u32);
+        self.asm.mov(Reg::Esi, Reg::Eax);
+        self.asm.load_x(Reg::Edx, inst.rs2);
+        self.asm.mov_imm(Reg::Ecx, inst.funct3 as u32);
+        self.asm.mov_rdi_ctx();
+        self.asm.call_abs(store as *const () as usize);
+
+        self.asm.test_eax();
+        let ok = self.asm.jcc_fwd(Cond::E);
+        self.asm.alu_imm(Alu::Cmp, Reg::Eax, STORE_FAULT);
+        let dirty = self.asm.jcc_fwd(Cond::Ne);
+        self.exit_fault(i);
+
+        // 命令を含むページに書き込んだらブロックを抜けて捨てさせる
+        self.asm.bind(dirty);
+        let rest = (self.len - i - 1) as u32;
+        if rest != 0 {
+            self.asm.ctx_alu64_imm(Alu::Add, BUDGET, rest);
+        }
+        let next = self.vpc.wrapping_add(4 * (i as u32 + 1));
+        self.asm.ctx_store_imm(PC, next);
+        self.asm.jmp_abs(self.exit);
+
+        self.asm.bind(ok);
+    }
+
+    fn branch(&mut self, pc: u32, inst: Inst) {
+        let cond = match inst.funct3 {
+            0b000 => Cond::E,
+            0b001 => Cond::Ne,
+            0b100 => Cond::L,
+            0b101 => Cond::Ge,
+            0b110 => Cond::B,
+            _ => Cond::Ae,
+        };
+        self.asm.load_x(Reg::Eax, inst.rs1);
+        self.asm.load_x(Reg::Ecx, inst.rs2);
+        self.asm.alu(Alu::Cmp, Reg::Eax, Reg::Ecx);
+        let taken = self.asm.jcc_fwd(cond);
+        self.exit_to(pc.wrapping_add(4));
+        self.asm.bind(taken);
+        self.exit_to(pc.wrapping_add(inst.imm12 as i32 as u32));
+    }
+
+    // 同じページ内ならスロット経由で次のブロックに直接飛ぶ
+    fn exit_to(&mut self, target: u32) {
+        self.asm.ctx_store_imm(PC, target);
+        if target & !0xFFF == self.vpc & !0xFFF {
+            let slot = self.asm.new_slot();
+            self.asm.load_slot(slot);
+            self.asm.test_rax();
+            let unlinked = self.asm.jcc_fwd(Cond::E);
+            self.asm.jmp_rax();
+            self.asm.bind(unlinked);
+            self.asm.lea_slot(slot);
+            self.asm.ctx_store_rax(LINK);
+        }
+        self.asm.jmp_abs(self.exit);
+    }
+
+    fn exit_interpret(&mut self, pc: u32) {
+        self.asm.ctx_store_imm(PC, pc);
+        self.asm.ctx_store_imm(STATUS, STATUS_INTERPRET);
+        self.asm.jmp_abs(self.exit);
+    }
+
+    // i番目の命令をインタプリタでやり直して例外を起こさせる
+    fn exit_fault(&mut self, i: usize) {
+        self.asm
+            .ctx_alu64_imm(Alu::Add, BUDGET, (self.len - i) as u32);
+        self.exit_interpret(self.vpc.wrapping_add(4 * i as u32));
+    }
+}
+
+// ネイティブコードから呼ばれるロード
+// 値はbit0-31, フォールトならbit32を立てる
+extern "sysv64" fn load(ctx: *mut Context, addr: u32, funct3: u32) -> u64 {
+    // SAFETY: ネイティブコードの実行中はこのCpuを他から触らない
+    let cpu = unsafe { &mut *(*ctx).cpu };
+    let val = match funct3 {
+        0b000 => cpu.load8(addr).map(|v| v as i8 as i32 as u32),
+        0b001 => cpu.load16(addr).map(|v| v as i16 as i32 as u32),
+        0b010 => cpu.load32(addr),
+        0b100 => cpu.load8(addr).map(u32::from),
+        _ => cpu.load16(addr).map(u32::from),
+    };
+    val.map_or(LOAD_FAULT, u64::from)
+}
+
+// ネイティブコードから呼ばれるストア
+extern "sysv64" fn store(ctx: *mut Context, addr: u32, val: u32, funct3: u32) -> u32 {
+    // SAFETY: ネイティブコードの実行中はこのCpuを他から触らない
+    let cpu = unsafe { &mut *(*ctx).cpu };
+    let result = match funct3 {
+        0b000 => cpu.store8(addr, val as u8),
+        0b001 => cpu.store16(addr, val as u16),
+        _ => cpu.store32(addr, val),
+    };
+    match result {
+        Err(_) => STORE_FAULT,
+        Ok(()) if cpu.bus.has_dirty_code() => STORE_DIRTY,
+        Ok(()) => STORE_OK,
+    }
+}
+
+const PROT_RWX: i32 = 0x7;
+const MAP_PRIVATE_ANONYMOUS: i32 = 0x22;
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+// 書き込みと実行ができるメモリ
+struct ExecMem {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecMem {
+    fn new(len: usize) -> Self {
+        // SAFETY: 新しい無名マッピングを作るだけ
+        let ptr = unsafe { mmap(ptr::null_mut(), len, PROT_RWX, MAP_PRIVATE_ANONYMOUS, -1, 0) };
+        assert!(ptr as isize != -1, "failed to map executable memory");
+        Self { ptr, len }
+    }
+
+    fn addr(&self) -> usize {
+        self.ptr as usize
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) {
+        assert!(offset + bytes.len() <= self.len);
+        // SAFETY: 範囲はマッピングの中
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(offset), bytes.len()) }
+    }
+}
+
+impl Drop for ExecMem {
+    fn drop(&mut self) {
+        // SAFETY: newでマップした領域
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JitMode;
+    use crate::cpu::testing::{assert_same, machine, TIMER_LOOP};
+    use crate::cpu::HaltReason;
+
+    // タイマー割り込みが入るループを検証モードで実行し、インタプリタだけの実行とも突き合わせる
+    #[test]
+    fn check_mode_matches_interpreter() {
+        for timebase in [1, 3] {
+            let mut jit = machine(&TIMER_LOOP);
+            jit.set_timebase(timebase);
+            jit.set_jit(JitMode::Check);
+            assert_eq!(jit.step(200_000), HaltReason::Step);
+
+            let mut interp = machine(&TIMER_LOOP);
+            interp.set_timebase(timebase);
+            assert_eq!(interp.step(200_000), HaltReason::Step);
+            assert!(interp.get_x(19) > 0);
+            assert_same(&jit, &interp);
+        }
+    }
+
+    // ネイティブ側だけが書いたメモリは違いとして見つけ、RAMはネイティブの結果のままにする
+    #[test]
+    fn check_finds_memory_difference() {
+        let mut cpu = machine(&TIMER_LOOP);
+        let mut shadow = cpu.shadow();
+        cpu.bus.start_journal();
+        cpu.write_phys(0x1404, &[0xAB]).unwrap();
+        let diff = cpu.check_native(&mut shadow, 0);
+        assert_eq!(
+            diff.as_deref(),
+            Some("memory at 00001404 is AB, interpreter has 00")
+        );
+        let mut b = [0];
+        cpu.read_phys(0x1404, &mut b).unwrap();
+        assert_eq!(b, [0xAB]);
+    }
+}
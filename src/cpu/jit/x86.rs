@@ -0,0 +1,301 @@
+// JITで使うx86_64の命令だけをエンコードする
+// rbxがContext, r12がゲストのレジスタ配列を指す前提
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Reg {
+    Eax = 0,
+    Ecx = 1,
+    Edx = 2,
+    Esi = 6,
+}
+
+// 2オペランドのALU命令 (値は reg, r/m 形式のオペコード)
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+impl Alu {
+    // 即値版の /digit
+    fn digit(self) -> u8 {
+        match self {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Cond {
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    L = 0xC,
+    Ge = 0xD,
+}
+
+// 後から飛び先を埋めるrel32の位置
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Label(usize);
+
+pub(super) struct Asm {
+    buf: Vec<u8>,
+    // buf[0]が置かれるアドレス
+    base: usize,
+    // rip相対でスロットを参照するdisp32の位置とスロット番号
+    slot_refs: Vec<(usize, usize)>,
+    slots: usize,
+}
+
+impl Asm {
+    pub fn new(base: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            base,
+            slot_refs: Vec::new(),
+            slots: 0,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.base + self.buf.len()
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn rel32_to(&mut self, target: usize) {
+        let next = self.addr() + 4;
+        let rel = (target as i64 - next as i64) as i32;
+        self.bytes(&rel.to_le_bytes());
+    }
+
+    // ゲストのレジスタを読む (x0は0)
+    pub fn load_x(&mut self, reg: Reg, x: usize) {
+        if x == 0 {
+            self.alu(Alu::Xor, reg, reg);
+        } else {
+            // mov reg, [r12 + 4*x]
+            self.bytes(&[0x41, 0x8B, 0x84 | (reg as u8) << 3, 0x24]);
+            self.imm32(4 * x as u32);
+        }
+    }
+
+    // ゲストのレジスタに書く (x0への書き込みは捨てる)
+    pub fn store_x(&mut self, x: usize, reg: Reg) {
+        if x != 0 {
+            // mov [r12 + 4*x], reg
+            self.bytes(&[0x41, 0x89, 0x84 | (reg as u8) << 3, 0x24]);
+            self.imm32(4 * x as u32);
+        }
+    }
+
+    pub fn store_x_imm(&mut self, x: usize, imm: u32) {
+        if x != 0 {
+            // mov dword [r12 + 4*x], imm
+            self.bytes(&[0x41, 0xC7, 0x84, 0x24]);
+            self.imm32(4 * x as u32);
+            self.imm32(imm);
+        }
+    }
+
+    pub fn mov_imm(&mut self, reg: Reg, imm: u32) {
+        self.bytes(&[0xB8 + reg as u8]);
+        self.imm32(imm);
+    }
+
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.bytes(&[0x89, 0xC0 | (src as u8) << 3 | dst as u8]);
+    }
+
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.bytes(&[op as u8, 0xC0 | (src as u8) << 3 | dst as u8]);
+    }
+
+    pub fn alu_imm(&mut self, op: Alu, dst: Reg, imm: u32) {
+        self.bytes(&[0x81, 0xC0 | op.digit() << 3 | dst as u8]);
+        self.imm32(imm);
+    }
+
+    pub fn shift_imm(&mut self, op: Shift, reg: Reg, n: u8) {
+        self.bytes(&[0xC1, 0xC0 | (op as u8) << 3 | reg as u8, n]);
+    }
+
+    // シフト量はcl (下位5bitだけが使われる)
+    pub fn shift_cl(&mut self, op: Shift, reg: Reg) {
+        self.bytes(&[0xD3, 0xC0 | (op as u8) << 3 | reg as u8]);
+    }
+
+    // eax = cond ? 1 : 0
+    pub fn set_eax(&mut self, cond: Cond) {
+        self.bytes(&[0x0F, 0x90 | cond as u8, 0xC0]);
+        self.bytes(&[0x0F, 0xB6, 0xC0]);
+    }
+
+    // eax = eax * ecx の下位32bit
+    pub fn imul(&mut self) {
+        self.bytes(&[0x0F, 0xAF, 0xC1]);
+    }
+
+    // eax = eax * ecx の上位32bit
+    // 64bitに広げる時の符号をそれぞれ指定する
+    pub fn mul_high(&mut self, eax_signed: bool, ecx_signed: bool) {
+        if eax_signed {
+            self.bytes(&[0x48, 0x63, 0xC0]);
+        } else {
+            self.bytes(&[0x89, 0xC0]);
+        }
+        if ecx_signed {
+            self.bytes(&[0x48, 0x63, 0xC9]);
+        } else {
+            self.bytes(&[0x89, 0xC9]);
+        }
+        self.bytes(&[0x48, 0x0F, 0xAF, 0xC1]);
+        self.bytes(&[0x48, 0xC1, 0xE8, 0x20]);
+    }
+
+    // mov dword [rbx + off], imm
+    pub fn ctx_store_imm(&mut self, off: usize, imm: u32) {
+        self.bytes(&[0xC7, 0x43, off as u8]);
+        self.imm32(imm);
+    }
+
+    // mov dword [rbx + off], reg
+    pub fn ctx_store(&mut self, off: usize, reg: Reg) {
+        self.bytes(&[0x89, 0x43 | (reg as u8) << 3, off as u8]);
+    }
+
+    // 64bitのフィールドとの演算 (add/sub/cmp qword [rbx + off], imm)
+    pub fn ctx_alu64_imm(&mut self, op: Alu, off: usize, imm: u32) {
+        self.bytes(&[0x48, 0x81, 0x43 | op.digit() << 3, off as u8]);
+        self.imm32(imm);
+    }
+
+    // mov qword [rbx + off], rax
+    pub fn ctx_store_rax(&mut self, off: usize) {
+        self.bytes(&[0x48, 0x89, 0x43, off as u8]);
+    }
+
+    pub fn jmp_abs(&mut self, target: usize) {
+        self.bytes(&[0xE9]);
+        self.rel32_to(target);
+    }
+
+    pub fn jcc_fwd(&mut self, cond: Cond) -> Label {
+        self.bytes(&[0x0F, 0x80 | cond as u8]);
+        let label = Label(self.buf.len());
+        self.imm32(0);
+        label
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        let rel = (self.buf.len() - (label.0 + 4)) as u32;
+        self.buf[label.0..label.0 + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    // ブロック末尾に置く8byteのスロットを確保する
+    pub fn new_slot(&mut self) -> usize {
+        self.slots += 1;
+        self.slots - 1
+    }
+
+    // mov rax, [rip + slot]
+    pub fn load_slot(&mut self, slot: usize) {
+        self.bytes(&[0x48, 0x8B, 0x05]);
+        self.slot_ref(slot);
+    }
+
+    // lea rax, [rip + slot]
+    pub fn lea_slot(&mut self, slot: usize) {
+        self.bytes(&[0x48, 0x8D, 0x05]);
+        self.slot_ref(slot);
+    }
+
+    fn slot_ref(&mut self, slot: usize) {
+        self.slot_refs.push((self.buf.len(), slot));
+        self.imm32(0);
+    }
+
+    pub fn test_rax(&mut self) {
+        self.bytes(&[0x48, 0x85, 0xC0]);
+    }
+
+    pub fn test_eax(&mut self) {
+        self.bytes(&[0x85, 0xC0]);
+    }
+
+    pub fn jmp_rax(&mut self) {
+        self.bytes(&[0xFF, 0xE0]);
+    }
+
+    // rdi = Context
+    pub fn mov_rdi_ctx(&mut self) {
+        self.bytes(&[0x48, 0x89, 0xDF]);
+    }
+
+    pub fn call_abs(&mut self, addr: usize) {
+        self.bytes(&[0x48, 0xB8]);
+        self.bytes(&(addr as u64).to_le_bytes());
+        self.bytes(&[0xFF, 0xD0]);
+    }
+
+    // raxのbit32を見てCFに入れる
+    pub fn test_bit32_rax(&mut self) {
+        self.bytes(&[0x48, 0x0F, 0xBA, 0xE0, 0x20]);
+    }
+
+    // 入口: Contextを受け取り [rbx + entry] に飛ぶ
+    // 出口: 退避したレジスタを戻して呼び出し元に帰る
+    pub fn trampoline(&mut self, xr_off: usize, entry_off: usize) -> usize {
+        // push rbx; push r12; sub rsp, 8
+        self.bytes(&[0x53, 0x41, 0x54, 0x48, 0x83, 0xEC, 0x08]);
+        // mov rbx, rdi; mov r12, [rbx + xr]
+        self.bytes(&[0x48, 0x89, 0xFB, 0x4C, 0x8B, 0x63, xr_off as u8]);
+        // jmp [rbx + entry]
+        self.bytes(&[0xFF, 0x63, entry_off as u8]);
+
+        let exit = self.addr();
+        // add rsp, 8; pop r12; pop rbx; ret
+        self.bytes(&[0x48, 0x83, 0xC4, 0x08, 0x41, 0x5C, 0x5B, 0xC3]);
+        exit
+    }
+
+    // スロットを末尾に並べて機械語を返す
+    pub fn finish(mut self) -> Vec<u8> {
+        while !self.addr().is_multiple_of(8) {
+            self.bytes(&[0xCC]);
+        }
+        let mut offsets = Vec::new();
+        for _ in 0..self.slots {
+            offsets.push(self.buf.len());
+            self.bytes(&[0; 8]);
+        }
+        for (pos, slot) in std::mem::take(&mut self.slot_refs) {
+            let rel = (offsets[slot] - (pos + 4)) as u32;
+            self.buf[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        self.buf
+    }
+}
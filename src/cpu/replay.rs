@@ -0,0 +1,458 @@
+use std::fs;
+use std::path::Path;
+
+use super::{Cpu, HaltReason};
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+
+// 記録ファイルの形式
+// 先頭にMAGICとVERSION, 記録を始めた時のスナップショット, イベントの順に並べる
+const MAGIC: &[u8; 8] = b"RVREPLAY";
+const VERSION: u32 = 2;
+
+const EVENT_INPUT: u8 = 0;
+const EVENT_INTERRUPT: u8 = 1;
+const EVENT_DEVICE: u8 = 2;
+
+// 巻き戻し用のチェックポイントを取る間隔 (命令数) と数の上限
+// チェックポイントはRAMを丸ごと含むので、合わせた大きさでも抑える
+const CHECKPOINT_INTERVAL: u64 = 100_000;
+const MAX_CHECKPOINTS: usize = 256;
+const MAX_CHECKPOINT_BYTES: usize = 256 << 20;
+
+// 実行結果を左右するホストからの入力と、その結果の割り込み
+// icountは記録を始めてからではなくCpuが実行した命令数
+// mtimeは命令数で進むので記録しなくても再現できる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // UARTへの入力
+    Input {
+        icount: u64,
+        data: Vec<u8>,
+    },
+    // 割り込みを受け付けた
+    Interrupt {
+        icount: u64,
+        cause: u32,
+    },
+    // virtioデバイスへの入力 (virtio-netのフレームやvirtio-consoleのポートへの入力)
+    Device {
+        icount: u64,
+        slot: u32,
+        port: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Event {
+    pub fn icount(&self) -> u64 {
+        match self {
+            Event::Input { icount, .. }
+            | Event::Interrupt { icount, .. }
+            | Event::Device { icount, .. } => *icount,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayLog {
+    snapshot: Vec<u8>,
+    events: Vec<Event>,
+}
+
+impl ReplayLog {
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.raw(MAGIC);
+        w.u32(VERSION);
+        w.bytes(&self.snapshot);
+        w.u32(self.events.len() as u32);
+        for event in &self.events {
+            match event {
+                Event::Input { icount, data } => {
+                    w.u8(EVENT_INPUT);
+                    w.u64(*icount);
+                    w.bytes(data);
+                }
+                Event::Interrupt { icount, cause } => {
+                    w.u8(EVENT_INTERRUPT);
+                    w.u64(*icount);
+                    w.u32(*cause);
+                }
+                Event::Device {
+                    icount,
+                    slot,
+                    port,
+                    data,
+                } => {
+                    w.u8(EVENT_DEVICE);
+                    w.u64(*icount);
+                    w.u32(*slot);
+                    w.u32(*port);
+                    w.bytes(data);
+                }
+            }
+        }
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> snapshot::Result<Self> {
+        let mut r = Reader::new(data);
+        if r.raw(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+        let snapshot = r.bytes()?.to_vec();
+        let mut events = Vec::new();
+        for _ in 0..r.u32()? {
+            let event = match r.u8()? {
+                EVENT_INPUT => Event::Input {
+                    icount: r.u64()?,
+                    data: r.bytes()?.to_vec(),
+                },
+                EVENT_INTERRUPT => Event::Interrupt {
+                    icount: r.u64()?,
+                    cause: r.u32()?,
+                },
+                EVENT_DEVICE => Event::Device {
+                    icount: r.u64()?,
+                    slot: r.u32()?,
+                    port: r.u32()?,
+                    data: r.bytes()?.to_vec(),
+                },
+                _ => return Err(SnapshotError::Invalid("event")),
+            };
+            // 命令数の順に並んでいないと再生できない
+            if matches!(events.last(), Some(e) if Event::icount(e) > event.icount()) {
+                return Err(SnapshotError::Invalid("event order"));
+            }
+            events.push(event);
+        }
+        if !r.is_empty() {
+            return Err(SnapshotError::Invalid("trailing data"));
+        }
+        Ok(Self { snapshot, events })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> snapshot::Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> snapshot::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+// 記録か再生の状態
+// 巻き戻した後はheadまでログを再生し、記録中ならその先で記録に戻る
+pub(super) struct Session {
+    pub log: ReplayLog,
+    // 次に再生するイベント
+    pub next: usize,
+    // 新しいイベントを記録するか (falseならログを再生するだけ)
+    pub recording: bool,
+    // これまでに実行した一番先の命令数
+    pub head: u64,
+    // 巻き戻し用のチェックポイント (命令数, スナップショット)
+    // 先頭は記録を始めた時の状態
+    pub checkpoints: Vec<(u64, Vec<u8>)>,
+    pub interval: u64,
+}
+
+impl Session {
+    fn new(log: ReplayLog, recording: bool, icount: u64) -> Self {
+        let snapshot = log.snapshot.clone();
+        Self {
+            log,
+            next: 0,
+            recording,
+            head: icount,
+            checkpoints: vec![(icount, snapshot)],
+            interval: CHECKPOINT_INTERVAL,
+        }
+    }
+
+    fn checkpoint_bytes(&self) -> usize {
+        self.checkpoints.iter().map(|(_, s)| s.len()).sum()
+    }
+
+    // ログの途中を再生しているか
+    fn replaying(&self, icount: u64) -> bool {
+        self.next < self.log.events.len() || icount < self.head
+    }
+}
+
+impl Cpu {
+    // 今の状態から記録を始める
+    // 記録中は巻き戻しもできる
+    pub fn start_recording(&mut self) -> snapshot::Result<()> {
+        let log = ReplayLog {
+            snapshot: self.snapshot()?,
+            events: Vec::new(),
+        };
+        self.replay = Some(Box::new(Session::new(log, true, self.icount)));
+        Ok(())
+    }
+
+    // 記録を終えてログを返す
+    pub fn stop_recording(&mut self) -> Option<ReplayLog> {
+        match self.replay.take() {
+            Some(session) if session.recording => Some(session.log),
+            session => {
+                self.replay = session;
+                None
+            }
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        matches!(self.replay.as_deref(), Some(session) if session.recording)
+    }
+
+    // 記録を始めた時の状態に戻し、以降の実行でログを再生する
+    pub fn start_replay(&mut self, log: ReplayLog) -> snapshot::Result<()> {
+        self.restore(&log.snapshot)?;
+        self.replay = Some(Box::new(Session::new(log, false, self.icount)));
+        Ok(())
+    }
+
+    pub fn stop_replay(&mut self) {
+        if matches!(self.replay.as_deref(), Some(session) if !session.recording) {
+            self.replay = None;
+        }
+    }
+
+    // ログの最後まで再生したか
+    pub fn replay_finished(&self) -> bool {
+        match self.replay.as_deref() {
+            Some(session) => !session.replaying(self.icount),
+            None => true,
+        }
+    }
+
+    // ホストからUARTへの入力
+    // 記録中はログに残す。巻き戻した後に入力すると、それより先の記録は捨てる
+    // 再生中はログの入力を使うので捨てる
+    pub fn push_input(&mut self, data: &[u8]) {
+        let icount = self.icount;
+        match self.replay.as_deref_mut() {
+            Some(session) if session.recording => {
+                if session.replaying(icount) {
+                    session.log.events.truncate(session.next);
+                    session.checkpoints.retain(|(i, _)| *i <= icount);
+                    session.head = icount;
+                }
+                session.log.events.push(Event::Input {
+                    icount,
+                    data: data.to_vec(),
+                });
+                session.next = session.log.events.len();
+            }
+            Some(_) => return,
+            None => {}
+        }
+        self.bus.uart_mut().push_input(data);
+    }
+
+    // ホストからデバイスへ届いたデータを受け取る (virtio-netの受信など)
+    // 記録中はログに残す。再生中や巻き戻した後はログにない入力になるので読まない
+    pub fn poll_devices(&mut self) {
+        let icount = self.icount;
+        if let Some(session) = self.replay.as_deref() {
+            if !session.recording || session.replaying(icount) {
+                return;
+            }
+        }
+        for (slot, port, data) in self.bus.receive() {
+            self.bus.deliver(slot, port, &data);
+            if let Some(session) = self.replay.as_deref_mut() {
+                session.log.events.push(Event::Device {
+                    icount,
+                    slot,
+                    port,
+                    data,
+                });
+                session.next = session.log.events.len();
+            }
+        }
+    }
+
+    // 次のイベントまでの命令数
+    pub(super) fn until_event(&self) -> u64 {
+        match self.replay.as_deref() {
+            Some(session) => session
+                .log
+                .events
+                .get(session.next)
+                .map_or(u64::MAX, |e| e.icount() - self.icount),
+            None => u64::MAX,
+        }
+    }
+
+    // ブロックの先頭でチェックポイントを取り、入力を渡して割り込みを受け付ける
+    pub(super) fn deliver_events(&mut self) -> Result<(), HaltReason> {
+        if self.replay.is_none() {
+            self.take_interrupt();
+            return Ok(());
+        }
+        self.checkpoint();
+        self.replay_inputs()?;
+        let cause = self.take_interrupt();
+        self.replay_interrupt(cause)?;
+        // 割り込みの後に記録された入力
+        self.replay_inputs()
+    }
+
+    fn checkpoint(&mut self) {
+        let icount = self.icount;
+        let Some(session) = self.replay.as_deref() else {
+            return;
+        };
+        let last = session.checkpoints.last().map_or(0, |(i, _)| *i);
+        if icount < last + session.interval {
+            return;
+        }
+        // 記録の途中でタイミングモデルやキャッシュを付けた時は取れない
+        let Ok(snapshot) = self.snapshot() else {
+            return;
+        };
+        let session = self.replay.as_deref_mut().unwrap();
+        session.checkpoints.push((icount, snapshot));
+        session.head = session.head.max(icount);
+        // 多すぎる時は先頭以外を間引いて間隔を倍にする
+        // 先頭ともう1つは大きくても残す
+        while session.checkpoints.len() > 2
+            && (session.checkpoints.len() > MAX_CHECKPOINTS
+                || session.checkpoint_bytes() > MAX_CHECKPOINT_BYTES)
+        {
+            let mut i = 0;
+            session.checkpoints.retain(|_| {
+                i += 1;
+                i == 1 || i % 2 == 0
+            });
+            session.interval *= 2;
+        }
+    }
+
+    // 今の命令数で起きる入力を渡す
+    fn replay_inputs(&mut self) -> Result<(), HaltReason> {
+        let Some(session) = self.replay.as_deref_mut() else {
+            return Ok(());
+        };
+        while let Some(event) = session.log.events.get(session.next) {
+            match event {
+                Event::Input { icount, data } if *icount == self.icount => {
+                    self.bus.uart_mut().push_input(data);
+                    session.next += 1;
+                }
+                Event::Device {
+                    icount,
+                    slot,
+                    port,
+                    data,
+                } if *icount == self.icount => {
+                    // 記録した時とデバイスの構成が違う
+                    if !self.bus.deliver(*slot, *port, data) {
+                        return Err(HaltReason::Diverged(self.icount));
+                    }
+                    session.next += 1;
+                }
+                e if e.icount() < self.icount => {
+                    return Err(HaltReason::Diverged(self.icount));
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    // 割り込みを受け付けたか (受け付けたならその原因) をログと照らし合わせる
+    // ログを再生し終えていて記録中なら新しく記録する
+    fn replay_interrupt(&mut self, cause: Option<u32>) -> Result<(), HaltReason> {
+        let icount = self.icount;
+        let Some(session) = self.replay.as_deref_mut() else {
+            return Ok(());
+        };
+        if !session.replaying(icount) {
+            if let (true, Some(cause)) = (session.recording, cause) {
+                session.log.events.push(Event::Interrupt { icount, cause });
+                session.next = session.log.events.len();
+            }
+            return Ok(());
+        }
+
+        let expected = match session.log.events.get(session.next) {
+            Some(Event::Interrupt { icount: i, cause }) if *i == icount => Some(*cause),
+            _ => None,
+        };
+        if expected != cause {
+            return Err(HaltReason::Diverged(icount));
+        }
+        if expected.is_some() {
+            session.next += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, ReplayLog};
+    use crate::cpu::testing::{assert_same, machine, TIMER_LOOP};
+    use crate::cpu::HaltReason;
+
+    // 記録したログを別のCpuで再生すると同じ状態になる
+    #[test]
+    fn replay_reproduces_recording() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.step(1_000);
+        cpu.start_recording().unwrap();
+        for (i, data) in [&b"a"[..], b"bc", b"d"].iter().enumerate() {
+            cpu.step(7_000 + 1_000 * i as u64);
+            cpu.push_input(data);
+        }
+        cpu.step(20_000);
+        let log = cpu.stop_recording().unwrap();
+        let events = log.events();
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Input { .. }))
+                .count(),
+            3
+        );
+        assert!(events.iter().any(|e| matches!(e, Event::Interrupt { .. })));
+
+        let log = ReplayLog::from_bytes(&log.to_bytes()).unwrap();
+        let mut replay = machine(&[]);
+        replay.start_replay(log).unwrap();
+        assert_eq!(replay.icount(), 1_000);
+        // 再生中のホストからの入力は使わない
+        replay.push_input(b"x");
+        assert_eq!(replay.step(cpu.icount() - 1_000), HaltReason::Step);
+        assert!(replay.replay_finished());
+        assert_same(&cpu, &replay);
+        assert_eq!(
+            replay.get_x(23),
+            b"abcd".iter().map(|&b| b as u32).sum::<u32>()
+        );
+    }
+
+    #[test]
+    fn rejects_bad_log() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.start_recording().unwrap();
+        cpu.step(1_000);
+        cpu.push_input(b"a");
+        let bytes = cpu.stop_recording().unwrap().to_bytes();
+        assert!(ReplayLog::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ReplayLog::from_bytes(b"RVSNAP\0\0").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::mem;
+
+use super::{Cpu, HaltReason};
+
+// 記録中か再生中のチェックポイントから決定的に実行し直して過去の状態に戻る
+impl Cpu {
+    // n命令前の状態に戻る
+    pub fn reverse_step(&mut self, n: u64) -> HaltReason {
+        let Some(start) = self.history_start() else {
+            return self.halt(HaltReason::HistoryStart);
+        };
+        let target = self.icount.saturating_sub(n);
+        let reason = match self.seek(target.max(start)) {
+            Err(reason) => reason,
+            Ok(()) if target < start => HaltReason::HistoryStart,
+            Ok(()) => HaltReason::Step,
+        };
+        self.halt(reason)
+    }
+
+    // 最後にブレークポイントかウォッチポイントで止まったはずの所まで戻る
+    pub fn reverse_continue(&mut self) -> HaltReason {
+        let now = self.icount;
+        let Some(session) = self.replay.as_deref() else {
+            return self.halt(HaltReason::HistoryStart);
+        };
+        let starts: Vec<u64> = session
+            .checkpoints
+            .iter()
+            .map(|(i, _)| *i)
+            .filter(|&i| i < now)
+            .collect();
+
+        // 新しい区間から順に実行し直し、区間の中で最後に止まった所を探す
+        let mut end = now;
+        for &start in starts.iter().rev() {
+            if let Err(reason) = self.seek(start) {
+                return self.halt(reason);
+            }
+            let hooks = mem::take(&mut self.hooks);
+            let mut last = None;
+            let result = loop {
+                match self.exec(end - self.icount, true, true, |_| false) {
+                    HaltReason::Step => break Ok(()),
+                    reason @ (HaltReason::Breakpoint(_) | HaltReason::Watchpoint(_)) => {
+                        if self.icount < now {
+                            last = Some((self.icount, reason));
+                        }
+                    }
+                    reason => break Err(reason),
+                }
+            };
+            self.hooks = hooks;
+            if let Err(reason) = result {
+                return self.halt(reason);
+            }
+
+            if let Some((icount, reason)) = last {
+                if let Err(reason) = self.seek(icount) {
+                    return self.halt(reason);
+                }
+                // 割り込みで入ったハンドラで止まった時は割り込みも受け直す
+                if matches!(reason, HaltReason::Breakpoint(pc) if pc != self.pc) {
+                    if let Err(reason) = self.deliver_events() {
+                        return self.halt(reason);
+                    }
+                }
+                return self.halt(reason);
+            }
+            end = start;
+        }
+
+        if let Some(start) = starts.first() {
+            if let Err(reason) = self.seek(*start) {
+                return self.halt(reason);
+            }
+        }
+        self.halt(HaltReason::HistoryStart)
+    }
+
+    // 巻き戻せる一番古い命令数
+    pub fn history_start(&self) -> Option<u64> {
+        let session = self.replay.as_deref()?;
+        session.checkpoints.first().map(|(i, _)| *i)
+    }
+
+    // 命令数がicountの時の状態にする
+    // 直前のチェックポイントから、フックやブレークポイントを無効にして実行し直す
+    fn seek(&mut self, icount: u64) -> Result<(), HaltReason> {
+        // restoreは記録を引き継がないので外しておき、失敗しても戻す
+        let Some(mut session) = self.replay.take() else {
+            return Err(HaltReason::HistoryStart);
+        };
+        let Some(i) = session.checkpoints.iter().rposition(|(i, _)| *i <= icount) else {
+            self.replay = Some(session);
+            return Err(HaltReason::HistoryStart);
+        };
+        let start = session.checkpoints[i].0;
+        session.head = session.head.max(self.icount);
+        if self.restore(&session.checkpoints[i].1).is_err() {
+            self.replay = Some(session);
+            return Err(HaltReason::BadCheckpoint(start));
+        }
+        session.next = session.log.events().partition_point(|e| e.icount() < start);
+        self.replay = Some(session);
+
+        let hooks = mem::take(&mut self.hooks);
+        let breakpoints = mem::take(&mut self.breakpoints);
+        let watchpoints = mem::take(&mut self.watchpoints);
+        let reason = self.exec(icount - start, false, true, |_| false);
+        self.hooks = hooks;
+        self.breakpoints = breakpoints;
+        self.watchpoints = watchpoints;
+
+        match reason {
+            HaltReason::Step => Ok(()),
+            reason => Err(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::testing::{assert_same, machine, TIMER_LOOP};
+    use crate::cpu::HaltReason;
+
+    // 割り込みハンドラの先頭
+    const HANDLER: u32 = 0x90;
+
+    #[test]
+    fn reverse_step_matches_forward_run() {
+        let mut cpu = machine(&TIMER_LOOP);
+        assert_eq!(cpu.reverse_step(1), HaltReason::HistoryStart);
+        cpu.step(1_000);
+        cpu.start_recording().unwrap();
+        cpu.step(250_000);
+        assert_eq!(cpu.history_start(), Some(1_000));
+
+        for back in [1, 12_345, 150_000] {
+            let target = cpu.icount() - back;
+            assert_eq!(cpu.reverse_step(back), HaltReason::Step);
+            let mut expected = machine(&TIMER_LOOP);
+            expected.step(target);
+            assert_same(&cpu, &expected);
+            // 戻った所から進めても同じ
+            cpu.step(back);
+            expected.step(back);
+            assert_same(&cpu, &expected);
+        }
+
+        // 記録より前には戻れない
+        assert_eq!(cpu.reverse_step(300_000), HaltReason::HistoryStart);
+        assert_eq!(cpu.icount(), 1_000);
+    }
+
+    #[test]
+    fn reverse_continue_finds_previous_hits() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.start_recording().unwrap();
+        cpu.add_breakpoint(HANDLER);
+        let mut hits = Vec::new();
+        loop {
+            match cpu.run_until(|cpu| cpu.icount() >= 250_000) {
+                HaltReason::Breakpoint(HANDLER) => hits.push(cpu.icount()),
+                reason => {
+                    assert_eq!(reason, HaltReason::Condition);
+                    break;
+                }
+            }
+        }
+        assert!(hits.len() >= 3);
+
+        for &hit in hits.iter().rev().take(3) {
+            assert_eq!(cpu.reverse_continue(), HaltReason::Breakpoint(HANDLER));
+            assert_eq!(cpu.icount(), hit);
+            assert_eq!(cpu.pc(), HANDLER);
+        }
+
+        cpu.remove_breakpoint(HANDLER);
+        assert_eq!(cpu.reverse_continue(), HaltReason::HistoryStart);
+        assert_eq!(cpu.icount(), 0);
+    }
+
+    // 最後にその場所へ書いた命令の直後に戻る
+    #[test]
+    fn reverse_continue_to_watchpoint() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.start_recording().unwrap();
+        cpu.step(150_000);
+        let mut last = [0; 4];
+        cpu.read_phys(0x1400, &mut last).unwrap();
+
+        cpu.add_watchpoint(0x1400, 4);
+        assert_eq!(cpu.reverse_continue(), HaltReason::Watchpoint(0x1400));
+        let at = cpu.icount();
+        assert!(at < 150_000);
+        let mut val = [0; 4];
+        cpu.read_phys(0x1400, &mut val).unwrap();
+        assert_eq!(val, last);
+        // 1命令戻ると書き込む前の値で、そこから進めると同じ所で止まる
+        cpu.remove_watchpoint(0x1400);
+        assert_eq!(cpu.reverse_step(1), HaltReason::Step);
+        cpu.read_phys(0x1400, &mut val).unwrap();
+        assert_ne!(val, last);
+        cpu.add_watchpoint(0x1400, 4);
+        assert_eq!(
+            cpu.run_until(|cpu| cpu.icount() == 150_000),
+            HaltReason::Watchpoint(0x1400)
+        );
+        assert_eq!(cpu.icount(), at);
+        assert_eq!(
+            cpu.run_until(|cpu| cpu.icount() == 150_000),
+            HaltReason::Condition
+        );
+    }
+}
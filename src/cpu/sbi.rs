@@ -0,0 +1,447 @@
+use super::{Cpu, Privilege, MSTATUS_SIE};
+
+// S-modeからのecallをファームウェアの代わりにエミュレータで処理する (SBI v2.0)
+// ハートは1つだけなので、IPIやリモートフェンスは自分自身に対するものだけ意味がある
+const EXT_BASE: u32 = 0x10;
+const EXT_TIME: u32 = 0x5449_4D45;
+const EXT_IPI: u32 = 0x0073_5049;
+const EXT_RFENCE: u32 = 0x5246_4E43;
+const EXT_HSM: u32 = 0x0048_534D;
+const EXT_SRST: u32 = 0x5352_5354;
+const EXT_DBCN: u32 = 0x4442_434E;
+// 0.1の古い関数 (拡張ごとに1つ)
+const LEGACY_SET_TIMER: u32 = 0x00;
+const LEGACY_PUTCHAR: u32 = 0x01;
+const LEGACY_GETCHAR: u32 = 0x02;
+const LEGACY_CLEAR_IPI: u32 = 0x03;
+const LEGACY_SEND_IPI: u32 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u32 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA: u32 = 0x06;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u32 = 0x07;
+const LEGACY_SHUTDOWN: u32 = 0x08;
+
+const SPEC_VERSION: u32 = 2 << 24;
+// 登録済みの実装IDと重ならない値
+const IMPL_ID: u32 = 0x6D6A;
+const IMPL_VERSION: u32 = 1;
+
+const SUCCESS: i32 = 0;
+const ERR_FAILED: i32 = -1;
+const ERR_NOT_SUPPORTED: i32 = -2;
+const ERR_INVALID_PARAM: i32 = -3;
+const ERR_ALREADY_AVAILABLE: i32 = -6;
+
+// HSMのハートの状態
+const HART_STARTED: u32 = 0;
+
+const SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+const SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
+
+const RESET_SHUTDOWN: u32 = 0;
+const RESET_WARM_REBOOT: u32 = 2;
+const REASON_SYSTEM_FAILURE: u32 = 1;
+
+const MIP_SSIP: u32 = 1 << 1;
+
+// a0とa1で返す (エラーと値)
+type Ret = std::result::Result<u32, i32>;
+
+impl Cpu {
+    pub(super) fn sbi_call(&mut self) {
+        let (eid, fid) = (self.get_x(17), self.get_x(16));
+        let args = [
+            self.get_x(10),
+            self.get_x(11),
+            self.get_x(12),
+            self.get_x(13),
+            self.get_x(14),
+            self.get_x(15),
+        ];
+        // 0.1の関数はa0だけを返す
+        if eid <= LEGACY_SHUTDOWN {
+            let ret = self.sbi_legacy(eid, args);
+            self.set_x(10, ret as u32);
+            return;
+        }
+        if eid == EXT_HSM && fid == 3 && args[0] == SUSPEND_NON_RETENTIVE {
+            self.sbi_resume(args[1], args[2]);
+            return;
+        }
+        let ret = match eid {
+            EXT_BASE => self.sbi_base(fid, args),
+            EXT_TIME if fid == 0 => self.sbi_set_timer(args[0], args[1]),
+            EXT_IPI if fid == 0 => self.sbi_send_ipi(args[0], args[1]),
+            EXT_RFENCE => self.sbi_rfence(fid, args),
+            EXT_HSM => self.sbi_hsm(fid, args),
+            EXT_SRST if fid == 0 => self.sbi_reset(args[0], args[1]),
+            EXT_DBCN => self.sbi_console(fid, args),
+            _ => Err(ERR_NOT_SUPPORTED),
+        };
+        // 成功した時だけa1に値を返す
+        match ret {
+            Ok(val) => {
+                self.set_x(10, SUCCESS as u32);
+                self.set_x(11, val);
+            }
+            Err(e) => self.set_x(10, e as u32),
+        }
+    }
+
+    fn sbi_legacy(&mut self, eid: u32, args: [u32; 6]) -> i32 {
+        match eid {
+            LEGACY_SET_TIMER => {
+                let _ = self.sbi_set_timer(args[0], args[1]);
+            }
+            LEGACY_PUTCHAR => self.bus.uart_mut().transmit(&[args[0] as u8]),
+            LEGACY_GETCHAR => {
+                return self.bus.uart_mut().receive().map_or(-1, |c| c as i32);
+            }
+            LEGACY_CLEAR_IPI => self.mip &= !MIP_SSIP,
+            // 引数はハートのマスクを指す仮想アドレス
+            LEGACY_SEND_IPI => {
+                let mut mask = [0; 4];
+                if args[0] == 0 || self.read_virt(args[0], &mut mask).is_err() {
+                    self.mip |= MIP_SSIP;
+                } else {
+                    let _ = self.sbi_send_ipi(u32::from_le_bytes(mask), 0);
+                }
+            }
+            LEGACY_REMOTE_FENCE_I => self.sbi_fence_i(),
+            LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => self.fetch_tlb = None,
+            LEGACY_SHUTDOWN => {
+                let _ = self.sbi_reset(RESET_SHUTDOWN, 0);
+            }
+            _ => return ERR_NOT_SUPPORTED,
+        }
+        SUCCESS
+    }
+
+    fn sbi_base(&mut self, fid: u32, args: [u32; 6]) -> Ret {
+        match fid {
+            0 => Ok(SPEC_VERSION),
+            1 => Ok(IMPL_ID),
+            2 => Ok(IMPL_VERSION),
+            3 => {
+                let supported = matches!(
+                    args[0],
+                    LEGACY_SET_TIMER
+                        ..=LEGACY_SHUTDOWN
+                            | EXT_BASE
+                            | EXT_TIME
+                            | EXT_IPI
+                            | EXT_RFENCE
+                            | EXT_HSM
+                            | EXT_SRST
+                            | EXT_DBCN
+                );
+                Ok(supported as u32)
+            }
+            // mvendorid, marchid, mimpid
+            4..=6 => Ok(self.read_csr(0xF11 + fid as u16 - 4).unwrap_or(0)),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    // STIPはmtimecmpから作るので、設定し直せば下がる
+    fn sbi_set_timer(&mut self, low: u32, high: u32) -> Ret {
+        let val = (high as u64) << 32 | low as u64;
+        self.bus.clint_mut().set_mtimecmp(val);
+        Ok(0)
+    }
+
+    // hart_mask_baseが-1なら全てのハート
+    fn targets_self(&self, mask: u32, base: u32) -> std::result::Result<bool, i32> {
+        let hartid = self.read_csr(0xF14).unwrap_or(0);
+        if base == u32::MAX {
+            return Ok(true);
+        }
+        match hartid.checked_sub(base) {
+            Some(bit) if bit < 32 => Ok(mask >> bit & 1 != 0),
+            // 存在しないハートを指している
+            _ if mask != 0 => Err(ERR_INVALID_PARAM),
+            _ => Ok(false),
+        }
+    }
+
+    fn sbi_send_ipi(&mut self, mask: u32, base: u32) -> Ret {
+        if self.targets_self(mask, base)? {
+            self.mip |= MIP_SSIP;
+        }
+        Ok(0)
+    }
+
+    fn sbi_fence_i(&mut self) {
+        let _ = self.fencei();
+    }
+
+    fn sbi_rfence(&mut self, fid: u32, args: [u32; 6]) -> Ret {
+        let target = self.targets_self(args[0], args[1])?;
+        match fid {
+            0 if target => self.sbi_fence_i(),
+            // ASIDは実装していないので全て捨てる
+            1 | 2 if target => self.fetch_tlb = None,
+            0..=2 => {}
+            // ハイパーバイザ拡張は無い
+            _ => return Err(ERR_NOT_SUPPORTED),
+        }
+        Ok(0)
+    }
+
+    fn sbi_hsm(&mut self, fid: u32, args: [u32; 6]) -> Ret {
+        let hartid = self.read_csr(0xF14).unwrap_or(0);
+        match fid {
+            // hart_start
+            0 if args[0] == hartid => Err(ERR_ALREADY_AVAILABLE),
+            0 => Err(ERR_INVALID_PARAM),
+            // hart_stop: 最後のハートを止めると再開させる相手がいない
+            1 => Err(ERR_FAILED),
+            // hart_get_status
+            2 if args[0] == hartid => Ok(HART_STARTED),
+            2 => Err(ERR_INVALID_PARAM),
+            // hart_suspend
+            3 => match args[0] {
+                // 割り込みはブロックの先頭で受け付けるので、wfiと同じくすぐ戻る
+                SUSPEND_RETENTIVE => Ok(0),
+                0x0000_0001..=0x0FFF_FFFF | 0x8000_0001..=0x8FFF_FFFF => Err(ERR_INVALID_PARAM),
+                _ => Err(ERR_NOT_SUPPORTED),
+            },
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+
+    // 割り込みで起きたことにして、S-modeでresume_addrから始める
+    fn sbi_resume(&mut self, addr: u32, opaque: u32) {
+        let hartid = self.read_csr(0xF14).unwrap_or(0);
+        self.set_x(10, hartid);
+        self.set_x(11, opaque);
+        self.satp = 0;
+        self.fetch_tlb = None;
+        self.mstatus &= !MSTATUS_SIE;
+        self.set_privilege(Privilege::Supervisor);
+        self.next_pc = addr;
+    }
+
+    // 実行を止めてホストに理由を返す
+    fn sbi_reset(&mut self, kind: u32, reason: u32) -> Ret {
+        if kind > RESET_WARM_REBOOT && kind < 0xF000_0000 {
+            return Err(ERR_INVALID_PARAM);
+        }
+        if kind > RESET_WARM_REBOOT {
+            return Err(ERR_NOT_SUPPORTED);
+        }
+        // 理由は「なし」と「システムの故障」しか知らない
+        if reason > REASON_SYSTEM_FAILURE {
+            return Err(ERR_INVALID_PARAM);
+        }
+        self.reset_request = Some((kind, reason));
+        Ok(0)
+    }
+
+    // アドレスは物理アドレスで、上位32bitは0でなければならない
+    fn sbi_console(&mut self, fid: u32, args: [u32; 6]) -> Ret {
+        let (len, addr) = (args[0], args[1]);
+        if fid < 2 && (args[2] != 0 || len > self.bus.ram_size()) {
+            return Err(ERR_INVALID_PARAM);
+        }
+        match fid {
+            // console_write
+            0 => {
+                let mut data = vec![0; len as usize];
+                self.read_phys(addr, &mut data)
+                    .map_err(|_| ERR_INVALID_PARAM)?;
+                self.bus.uart_mut().transmit(&data);
+                Ok(len)
+            }
+            // console_read: 書けない所なら入力を取り出す前に断る
+            1 => {
+                if !self.bus.in_ram(addr, len) {
+                    return Err(ERR_INVALID_PARAM);
+                }
+                let mut data = Vec::new();
+                while data.len() < len as usize {
+                    match self.bus.uart_mut().receive() {
+                        Some(c) => data.push(c),
+                        None => break,
+                    }
+                }
+                self.write_phys(addr, &data)
+                    .map_err(|_| ERR_INVALID_PARAM)?;
+                Ok(data.len() as u32)
+            }
+            // console_write_byte
+            2 => {
+                self.bus.uart_mut().transmit(&[args[0] as u8]);
+                Ok(0)
+            }
+            _ => Err(ERR_NOT_SUPPORTED),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::machine;
+    use super::super::{Cpu, Privilege};
+    use super::{EXT_BASE, EXT_DBCN, EXT_HSM, EXT_IPI, EXT_RFENCE, EXT_SRST, EXT_TIME, MIP_SSIP};
+
+    // a0とa1を返す
+    fn call(cpu: &mut Cpu, eid: u32, fid: u32, args: &[u32]) -> (i32, u32) {
+        cpu.set_x(11, 0xDEAD_BEEF);
+        for (i, &arg) in args.iter().enumerate() {
+            cpu.set_x(10 + i, arg);
+        }
+        cpu.set_x(16, fid);
+        cpu.set_x(17, eid);
+        cpu.sbi_call();
+        (cpu.get_x(10) as i32, cpu.get_x(11))
+    }
+
+    #[test]
+    fn base() {
+        let mut cpu = machine(&[]);
+        assert_eq!(call(&mut cpu, EXT_BASE, 0, &[]), (0, 2 << 24));
+        assert_eq!(call(&mut cpu, EXT_BASE, 3, &[EXT_DBCN]), (0, 1));
+        assert_eq!(call(&mut cpu, EXT_BASE, 3, &[0x08]), (0, 1));
+        assert_eq!(call(&mut cpu, EXT_BASE, 3, &[0x1234_5678]), (0, 0));
+        // 失敗した時はa1を変えない
+        assert_eq!(call(&mut cpu, EXT_BASE, 7, &[]), (-2, 0xDEAD_BEEF));
+        assert_eq!(call(&mut cpu, 0x1234_5678, 0, &[]), (-2, 0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn legacy() {
+        let mut cpu = machine(&[]);
+        // 0.1の関数はa1を返さない
+        assert_eq!(call(&mut cpu, 0x01, 0, &[b'x' as u32]), (0, 0xDEAD_BEEF));
+        assert_eq!(cpu.bus_mut().uart_mut().take_output(), b"x");
+        assert_eq!(call(&mut cpu, 0x02, 0, &[]).0, -1);
+        cpu.bus_mut().uart_mut().push_input(b"y");
+        assert_eq!(call(&mut cpu, 0x02, 0, &[]).0, b'y' as i32);
+
+        assert_eq!(call(&mut cpu, 0x00, 0, &[100, 0]).0, 0);
+        assert_eq!(cpu.bus().clint().until_timer(), 100);
+
+        // マスクのアドレスが0なら自分に送る
+        assert_eq!(call(&mut cpu, 0x04, 0, &[0]).0, 0);
+        assert_ne!(cpu.mip & MIP_SSIP, 0);
+        assert_eq!(call(&mut cpu, 0x03, 0, &[]).0, 0);
+        assert_eq!(cpu.mip & MIP_SSIP, 0);
+        cpu.write_phys(0x1000, &2u32.to_le_bytes()).unwrap();
+        assert_eq!(call(&mut cpu, 0x04, 0, &[0x1000]).0, 0);
+        assert_eq!(cpu.mip & MIP_SSIP, 0);
+        cpu.write_phys(0x1000, &1u32.to_le_bytes()).unwrap();
+        assert_eq!(call(&mut cpu, 0x04, 0, &[0x1000]).0, 0);
+        assert_ne!(cpu.mip & MIP_SSIP, 0);
+
+        assert_eq!(call(&mut cpu, 0x08, 0, &[]).0, 0);
+        assert_eq!(cpu.reset_request, Some((0, 0)));
+    }
+
+    #[test]
+    fn timer_ipi_and_rfence() {
+        let mut cpu = machine(&[]);
+        assert_eq!(call(&mut cpu, EXT_TIME, 0, &[5, 1]), (0, 0));
+        assert_eq!(cpu.bus().clint().until_timer(), (1 << 32) + 5);
+        assert_eq!(call(&mut cpu, EXT_TIME, 1, &[]).0, -2);
+
+        // ハートは0番だけ
+        assert_eq!(call(&mut cpu, EXT_IPI, 0, &[1, 1]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_IPI, 0, &[0, 1]).0, 0);
+        assert_eq!(call(&mut cpu, EXT_IPI, 0, &[2, 0]).0, 0);
+        assert_eq!(cpu.mip & MIP_SSIP, 0);
+        assert_eq!(call(&mut cpu, EXT_IPI, 0, &[0, u32::MAX]).0, 0);
+        assert_ne!(cpu.mip & MIP_SSIP, 0);
+
+        for fid in 0..3 {
+            assert_eq!(call(&mut cpu, EXT_RFENCE, fid, &[1, 0]).0, 0);
+        }
+        assert_eq!(call(&mut cpu, EXT_RFENCE, 0, &[1, 1]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_RFENCE, 3, &[1, 0]).0, -2);
+    }
+
+    #[test]
+    fn hsm() {
+        let mut cpu = machine(&[]);
+        assert_eq!(call(&mut cpu, EXT_HSM, 0, &[0, 0x100, 0]).0, -6);
+        assert_eq!(call(&mut cpu, EXT_HSM, 0, &[1, 0x100, 0]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_HSM, 1, &[]).0, -1);
+        assert_eq!(call(&mut cpu, EXT_HSM, 2, &[0]), (0, 0));
+        assert_eq!(call(&mut cpu, EXT_HSM, 2, &[1]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_HSM, 3, &[0, 0, 0]), (0, 0));
+        assert_eq!(call(&mut cpu, EXT_HSM, 3, &[1, 0, 0]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_HSM, 3, &[0x1000_0000, 0, 0]).0, -2);
+        assert_eq!(call(&mut cpu, EXT_HSM, 3, &[0x8000_0001, 0, 0]).0, -3);
+
+        // 戻らない中断はS-modeで再開先から始める
+        cpu.set_privilege(Privilege::Supervisor);
+        cpu.satp = 1 << 31;
+        assert_eq!(
+            call(&mut cpu, EXT_HSM, 3, &[0x8000_0000, 0x200, 42]),
+            (0, 42)
+        );
+        assert_eq!(cpu.next_pc, 0x200);
+        assert_eq!(cpu.satp, 0);
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+    }
+
+    #[test]
+    fn system_reset() {
+        let mut cpu = machine(&[]);
+        assert_eq!(call(&mut cpu, EXT_SRST, 0, &[3, 0]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_SRST, 0, &[0xF000_0000, 0]).0, -2);
+        // 知らない理由は受け付けない
+        assert_eq!(call(&mut cpu, EXT_SRST, 0, &[0, 2]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_SRST, 0, &[0, 0xF000_0000]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_SRST, 1, &[0, 0]).0, -2);
+        assert_eq!(cpu.reset_request, None);
+        assert_eq!(call(&mut cpu, EXT_SRST, 0, &[1, 1]), (0, 0));
+        assert_eq!(cpu.reset_request, Some((1, 1)));
+    }
+
+    #[test]
+    fn debug_console() {
+        let mut cpu = machine(&[]);
+        cpu.write_phys(0x1000, b"hello").unwrap();
+        assert_eq!(call(&mut cpu, EXT_DBCN, 0, &[5, 0x1000, 0]), (0, 5));
+        assert_eq!(call(&mut cpu, EXT_DBCN, 2, &[b'!' as u32]), (0, 0));
+        assert_eq!(cpu.bus_mut().uart_mut().take_output(), b"hello!");
+        // 上位32bitが0でないか、RAMの外
+        assert_eq!(call(&mut cpu, EXT_DBCN, 0, &[5, 0x1000, 1]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_DBCN, 0, &[5, 0x10_0000, 0]).0, -3);
+        assert!(cpu.bus_mut().uart_mut().take_output().is_empty());
+
+        // 書けない所を渡されても入力はなくさない
+        cpu.bus_mut().uart_mut().push_input(b"abc");
+        assert_eq!(call(&mut cpu, EXT_DBCN, 1, &[8, 0x10_0000, 0]).0, -3);
+        assert_eq!(call(&mut cpu, EXT_DBCN, 1, &[2, 0x2000, 0]), (0, 2));
+        assert_eq!(call(&mut cpu, EXT_DBCN, 1, &[8, 0x2002, 0]), (0, 1));
+        assert_eq!(call(&mut cpu, EXT_DBCN, 1, &[8, 0x2003, 0]), (0, 0));
+        let mut buf = [0; 3];
+        cpu.read_phys(0x2000, &mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        assert_eq!(call(&mut cpu, EXT_DBCN, 3, &[]).0, -2);
+    }
+
+    // S-modeのecallだけを受け持つ
+    #[test]
+    fn ecall_from_supervisor() {
+        let mut cpu = machine(&[
+            0x0010_0893, // li    a7, 1
+            0x0410_0513, // li    a0, 0x41
+            0x0000_0073, // ecall
+            0x0000_006F, // j     .
+        ]);
+        cpu.set_sbi(true);
+        cpu.set_privilege(Privilege::Supervisor);
+        cpu.step(4);
+        assert_eq!(cpu.pc(), 12);
+        assert_eq!(cpu.bus_mut().uart_mut().take_output(), b"A");
+
+        cpu.reset();
+        cpu.set_privilege(Privilege::User);
+        cpu.write_csr(0x305, 0x100).unwrap();
+        cpu.step(3);
+        assert_eq!(cpu.pc(), 0x100);
+        assert_eq!(cpu.read_csr(0x342).unwrap(), 8);
+    }
+}
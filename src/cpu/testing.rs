@@ -0,0 +1,71 @@
+use super::Cpu;
+use crate::bus::Bus;
+
+// タイマー割り込みを受けながらメモリを読み書きし、256回ごとにUARTに届いたバイトを足していく
+// UARTを見る回数を減らし、コードと別のページを読み書きしてJITでコンパイルされるようにしている
+// s2に回数、s3に割り込みの回数、s6に読んだ値の和、s7に受け取ったバイトの和が入る
+pub(crate) const TIMER_LOOP: [u32; 42] = [
+    0x0000_0297, // auipc t0, 0
+    0x0902_8293, // addi  t0, t0, 0x90
+    0x3052_9073, // csrw  mtvec, t0
+    0x0200_0437, // lui   s0, 0x2000
+    0x1000_04B7, // lui   s1, 0x10000
+    0x0000_1C37, // lui   s8, 1
+    0x0000_0097, // auipc ra, 0
+    0x0500_80E7, // jalr  0x50(ra)
+    0x0800_0293, // li    t0, 0x80
+    0x3042_9073, // csrw  mie, t0
+    0x3004_6073, // csrsi mstatus, 8
+    0x0019_0913, // addi  s2, s2, 1
+    0x012A_0A33, // add   s4, s4, s2
+    0x0FC9_7313, // andi  t1, s2, 0xfc
+    0x0183_0333, // add   t1, t1, s8
+    0x4143_2023, // sw    s4, 0x400(t1)
+    0x4003_2383, // lw    t2, 0x400(t1)
+    0x007B_0B33, // add   s6, s6, t2
+    0x0FF9_7E13, // andi  t3, s2, 0xff
+    0xFE0E_10E3, // bnez  t3, 0x2c
+    0x0054_CE03, // lbu   t3, 5(s1)
+    0x001E_7E13, // andi  t3, t3, 1
+    0xFC0E_0AE3, // beqz  t3, 0x2c
+    0x0004_CE03, // lbu   t3, 0(s1)
+    0x01CB_8BB3, // add   s7, s7, t3
+    0xFC9F_F06F, // j     0x2c
+    0x0000_C5B7, // lui   a1, 0xc
+    0xFF85_8593, // addi  a1, a1, -8
+    0x0085_85B3, // add   a1, a1, s0
+    0x0005_A503, // lw    a0, 0(a1)
+    0x3095_0513, // addi  a0, a0, 777
+    0x0000_45B7, // lui   a1, 4
+    0x0085_85B3, // add   a1, a1, s0
+    0x0005_A223, // sw    zero, 4(a1)
+    0x00A5_A023, // sw    a0, 0(a1)
+    0x0000_8067, // ret
+    0x0019_8993, // addi  s3, s3, 1
+    0x0000_8A93, // mv    s5, ra
+    0x0000_0097, // auipc ra, 0
+    0xFD00_80E7, // jalr  -0x30(ra)
+    0x000A_8093, // mv    ra, s5
+    0x3020_0073, // mret
+];
+
+pub(crate) fn machine(program: &[u32]) -> Cpu {
+    let mut cpu = Cpu::new(Bus::new());
+    let data: Vec<u8> = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    cpu.write_phys(0, &data).unwrap();
+    cpu
+}
+
+// ゲストから見える状態が同じか
+pub(crate) fn assert_same(a: &Cpu, b: &Cpu) {
+    assert_eq!(a.pc(), b.pc());
+    assert_eq!(a.icount(), b.icount());
+    for i in 0..32 {
+        assert_eq!(a.get_x(i), b.get_x(i), "x{}", i);
+    }
+    assert_eq!(a.bus().clint().mtime(), b.bus().clint().mtime());
+    let (mut ram_a, mut ram_b) = (vec![0; 0x100], vec![0; 0x100]);
+    a.read_phys(0x1400, &mut ram_a).unwrap();
+    b.read_phys(0x1400, &mut ram_b).unwrap();
+    assert_eq!(ram_a, ram_b);
+}
@@ -0,0 +1,108 @@
+use std::rc::Rc;
+
+use crate::cpu::Decoded;
+
+const ENTRIES: usize = 1 << 14;
+// 1ページ分の命令数
+const PAGE_ENTRIES: usize = 4096 / 4;
+
+// デコード済みの基本ブロック
+// 分岐かシステム命令で終わり、ページをまたがない
+pub(crate) type Block = Rc<[Decoded]>;
+
+// 先頭の物理pcをキーにしたダイレクトマップのブロックキャッシュ
+// 1ページ分のエントリは連続したインデックスに並ぶ
+pub(crate) struct DecodeCache {
+    entries: Vec<Option<(u32, Block)>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![None; ENTRIES],
+        }
+    }
+
+    fn index(paddr: u32) -> usize {
+        (paddr as usize >> 2) & (ENTRIES - 1)
+    }
+
+    pub fn get(&self, paddr: u32) -> Option<&Block> {
+        match &self.entries[Self::index(paddr)] {
+            Some((tag, block)) if *tag == paddr => Some(block),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, paddr: u32, block: Block) {
+        self.entries[Self::index(paddr)] = Some((paddr, block));
+    }
+
+    // ページ内から始まるブロックを全て捨てる
+    pub fn invalidate_page(&mut self, page: u32) {
+        let start = Self::index(page);
+        for entry in &mut self.entries[start..start + PAGE_ENTRIES] {
+            if matches!(entry, Some((tag, _)) if *tag & !0xFFF == page) {
+                *entry = None;
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, DecodeCache, ENTRIES};
+    use crate::cpu::testing::machine;
+
+    fn block() -> Block {
+        Vec::new().into()
+    }
+
+    #[test]
+    fn lookup_checks_tag() {
+        let mut cache = DecodeCache::new();
+        cache.insert(0x1000, block());
+        assert!(cache.get(0x1000).is_some());
+        // 同じインデックスの別のアドレス
+        assert!(cache.get(0x1000 + (ENTRIES as u32) * 4).is_none());
+        assert!(cache.get(0x1004).is_none());
+    }
+
+    #[test]
+    fn invalidate_only_the_page() {
+        let mut cache = DecodeCache::new();
+        cache.insert(0x1000, block());
+        cache.insert(0x1FFC, block());
+        cache.insert(0x2000, block());
+        // インデックスは同じページだが別の物理ページ
+        let alias = 0x1008 + (ENTRIES as u32) * 4;
+        cache.insert(alias, block());
+        cache.invalidate_page(0x1000);
+        assert!(cache.get(0x1000).is_none());
+        assert!(cache.get(0x1FFC).is_none());
+        assert!(cache.get(0x2000).is_some());
+        assert!(cache.get(alias).is_some());
+        cache.flush();
+        assert!(cache.get(0x2000).is_none());
+    }
+
+    // 実行済みのブロックを書き換えると次からは新しい命令を実行する
+    #[test]
+    fn self_modifying_code() {
+        let mut cpu = machine(&[
+            0x0015_0513, // addi a0, a0, 1
+            0x0105_0337, // lui  t1, 0x1050
+            0x5133_0313, // addi t1, t1, 0x513
+            0x0060_2023, // sw   t1, 0(zero)
+            0xFF1F_F06F, // j    0
+        ]);
+        cpu.step(5);
+        assert_eq!(cpu.get_x(10), 1);
+        cpu.step(1);
+        assert_eq!(cpu.get_x(10), 17);
+    }
+}
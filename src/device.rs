@@ -0,0 +1,5 @@
+// Busにつながるメモリマップドデバイス
+pub mod clint;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
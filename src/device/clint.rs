@@ -0,0 +1,114 @@
+use crate::snapshot::{Reader, Result, Writer};
+
+// CLINT (ソフトウェア割り込みとタイマー割り込み)
+// mtimeは1命令ごとに1進む
+const MSIP: u32 = 0x0000;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xBFF8;
+
+pub const SIZE: u32 = 0x10000;
+
+#[derive(Clone)]
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    mtime: u64,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: false,
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub(crate) fn advance(&mut self, n: u64) {
+        self.mtime = self.mtime.wrapping_add(n);
+    }
+
+    pub fn msip(&self) -> bool {
+        self.msip
+    }
+
+    pub fn mtip(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    // タイマー割り込みが立つまでの命令数
+    // 既に立っている時は次の変化が無いのでu64::MAX
+    pub fn until_timer(&self) -> u64 {
+        match self.mtimecmp - self.mtime.min(self.mtimecmp) {
+            0 => u64::MAX,
+            n => n,
+        }
+    }
+
+    // 32bitアクセスだけ受け付ける
+    pub fn read(&self, offset: u32, width: u8) -> Option<u32> {
+        if width != 4 {
+            return None;
+        }
+        match offset {
+            MSIP => Some(self.msip as u32),
+            MTIMECMP => Some(self.mtimecmp as u32),
+            o if o == MTIMECMP + 4 => Some((self.mtimecmp >> 32) as u32),
+            MTIME => Some(self.mtime as u32),
+            o if o == MTIME + 4 => Some((self.mtime >> 32) as u32),
+            _ => None,
+        }
+    }
+
+    // SBIのset_timer
+    pub(crate) fn set_mtimecmp(&mut self, val: u64) {
+        self.mtimecmp = val;
+    }
+
+    pub fn write(&mut self, offset: u32, width: u8, val: u32) -> bool {
+        if width != 4 {
+            return false;
+        }
+        match offset {
+            MSIP => self.msip = val & 1 != 0,
+            MTIMECMP => self.mtimecmp = set_low(self.mtimecmp, val),
+            o if o == MTIMECMP + 4 => self.mtimecmp = set_high(self.mtimecmp, val),
+            MTIME => self.mtime = set_low(self.mtime, val),
+            o if o == MTIME + 4 => self.mtime = set_high(self.mtime, val),
+            _ => return false,
+        }
+        true
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.bool(self.msip);
+        w.u64(self.mtimecmp);
+        w.u64(self.mtime);
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            msip: r.bool()?,
+            mtimecmp: r.u64()?,
+            mtime: r.u64()?,
+        })
+    }
+}
+
+fn set_low(val: u64, low: u32) -> u64 {
+    (val & !0xFFFF_FFFF) | low as u64
+}
+
+fn set_high(val: u64, high: u32) -> u64 {
+    (val & 0xFFFF_FFFF) | (high as u64) << 32
+}
@@ -0,0 +1,179 @@
+use crate::snapshot::{Reader, Result, SnapshotError, Writer};
+
+// PLIC (外部割り込みコントローラ)
+// コンテキスト0がhart0のM-mode, 1がS-mode
+// 割り込みはレベルで受け、完了通知までは同じ割り込みを保留しない
+pub const SOURCES: usize = 32;
+pub const CONTEXTS: usize = 2;
+
+const PRIORITY: u32 = 0x0000;
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+const PRIORITY_MASK: u32 = 0x7;
+
+pub const SIZE: u32 = 0x400_0000;
+
+#[derive(Clone)]
+pub struct Plic {
+    priority: [u32; SOURCES],
+    pending: u32,
+    enable: [u32; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+    // claimされて完了を待っている割り込み
+    claimed: u32,
+    // 各ソースの入力の状態
+    level: u32,
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priority: [0; SOURCES],
+            pending: 0,
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+            claimed: 0,
+            level: 0,
+        }
+    }
+
+    pub fn set_level(&mut self, source: usize, high: bool) {
+        let bit = 1 << source;
+        if high {
+            self.level |= bit;
+            if self.claimed & bit == 0 {
+                self.pending |= bit;
+            }
+        } else {
+            self.level &= !bit;
+        }
+    }
+
+    // コンテキストに通知すべき割り込みのうち最も優先度が高いもの
+    fn best(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending & self.enable[context];
+        if candidates == 0 {
+            return None;
+        }
+        // 同じ優先度ならIDが小さい方
+        (1..SOURCES)
+            .filter(|&i| candidates & (1 << i) != 0)
+            .filter(|&i| self.priority[i] > self.threshold[context])
+            .min_by_key(|&i| std::cmp::Reverse(self.priority[i]))
+    }
+
+    pub fn irq(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(i) => {
+                self.pending &= !(1 << i);
+                self.claimed |= 1 << i;
+                i as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, source: u32) {
+        let bit = 1u32.checked_shl(source).unwrap_or(0);
+        self.claimed &= !bit;
+        if self.level & bit != 0 {
+            self.pending |= bit;
+        }
+    }
+
+    // レジスタのオフセットからコンテキストを割り出す
+    fn context(offset: u32, base: u32, stride: u32) -> Option<(usize, u32)> {
+        let context = ((offset - base) / stride) as usize;
+        (context < CONTEXTS).then_some((context, (offset - base) % stride))
+    }
+
+    // claimレジスタの読み出しは割り込みを取り出す
+    pub fn read(&mut self, offset: u32, width: u8) -> Option<u32> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        match offset {
+            o if o < PRIORITY + 4 * SOURCES as u32 => Some(self.priority[o as usize / 4]),
+            PENDING => Some(self.pending),
+            o if o >= CONTEXT => match Self::context(o, CONTEXT, CONTEXT_STRIDE)? {
+                (c, 0) => Some(self.threshold[c]),
+                (c, 4) => Some(self.claim(c)),
+                _ => None,
+            },
+            o if o >= ENABLE => match Self::context(o, ENABLE, ENABLE_STRIDE)? {
+                (c, 0) => Some(self.enable[c]),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, width: u8, val: u32) -> bool {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return false;
+        }
+        match offset {
+            // ソース0は存在しない
+            0 => {}
+            o if o < PRIORITY + 4 * SOURCES as u32 => {
+                self.priority[o as usize / 4] = val & PRIORITY_MASK;
+            }
+            o if o >= CONTEXT => match Self::context(o, CONTEXT, CONTEXT_STRIDE) {
+                Some((c, 0)) => self.threshold[c] = val & PRIORITY_MASK,
+                Some((_, 4)) => self.complete(val),
+                _ => return false,
+            },
+            o if o >= ENABLE => match Self::context(o, ENABLE, ENABLE_STRIDE) {
+                Some((c, 0)) => self.enable[c] = val & !1,
+                _ => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        for p in self.priority {
+            w.u32(p);
+        }
+        w.u32(self.pending);
+        for c in 0..CONTEXTS {
+            w.u32(self.enable[c]);
+            w.u32(self.threshold[c]);
+        }
+        w.u32(self.claimed);
+        w.u32(self.level);
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self> {
+        let mut plic = Self::new();
+        for p in &mut plic.priority {
+            *p = r.u32()?;
+            if *p & !PRIORITY_MASK != 0 {
+                return Err(SnapshotError::Invalid("plic priority"));
+            }
+        }
+        plic.pending = r.u32()?;
+        for c in 0..CONTEXTS {
+            plic.enable[c] = r.u32()?;
+            plic.threshold[c] = r.u32()?;
+        }
+        plic.claimed = r.u32()?;
+        plic.level = r.u32()?;
+        Ok(plic)
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+
+use crate::snapshot::{Reader, Result, Writer};
+
+// 16550互換のUART
+// 送信したバイトはホストが取り出すまでtxに溜める
+const RBR_THR: u32 = 0;
+const IER: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+pub const SIZE: u32 = 0x100;
+
+#[derive(Clone)]
+pub struct Uart {
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    // ボーレートの分周比
+    dll: u8,
+    dlm: u8,
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uart {
+    pub fn new() -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+        }
+    }
+
+    // ホストからの入力
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.rx.extend(data);
+    }
+
+    // ゲストが送信したバイトを取り出す
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.tx.drain(..).collect()
+    }
+
+    // SBIのコンソールはレジスタを通さずに送受信する
+    pub(crate) fn transmit(&mut self, data: &[u8]) {
+        self.tx.extend(data);
+    }
+
+    pub(crate) fn receive(&mut self) -> Option<u8> {
+        self.rx.pop_front()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.iir() != IIR_NONE
+    }
+
+    fn iir(&self) -> u8 {
+        if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 {
+            // 送信は即座に終わるので常に空
+            IIR_THRI
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    // RBRの読み出しは受信FIFOから取り出す
+    pub fn read(&mut self, offset: u32, _width: u8) -> Option<u32> {
+        let val = match offset {
+            RBR_THR if self.dlab() => self.dll,
+            RBR_THR => self.rx.pop_front().unwrap_or(0),
+            IER if self.dlab() => self.dlm,
+            IER => self.ier,
+            IIR_FCR => {
+                let fifo = if self.fcr & FCR_ENABLE != 0 {
+                    IIR_FIFO
+                } else {
+                    0
+                };
+                self.iir() | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+                dr | LSR_THRE | LSR_TEMT
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => return None,
+        };
+        Some(val as u32)
+    }
+
+    pub fn write(&mut self, offset: u32, _width: u8, val: u32) -> bool {
+        let val = val as u8;
+        match offset {
+            RBR_THR if self.dlab() => self.dll = val,
+            RBR_THR => self.tx.push_back(val),
+            IER if self.dlab() => self.dlm = val,
+            IER => self.ier = val & 0x0F,
+            IIR_FCR => {
+                if val & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = val & FCR_ENABLE;
+            }
+            LCR => self.lcr = val,
+            MCR => self.mcr = val,
+            // LSRとMSRへの書き込みは無視する
+            LSR | MSR => {}
+            SCR => self.scr = val,
+            _ => return false,
+        }
+        true
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        for fifo in [&self.rx, &self.tx] {
+            w.bytes(&fifo.iter().copied().collect::<Vec<_>>());
+        }
+        for reg in [
+            self.ier, self.fcr, self.lcr, self.mcr, self.scr, self.dll, self.dlm,
+        ] {
+            w.u8(reg);
+        }
+    }
+
+    pub(crate) fn load(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            rx: r.bytes()?.iter().copied().collect(),
+            tx: r.bytes()?.iter().copied().collect(),
+            ier: r.u8()?,
+            fcr: r.u8()?,
+            lcr: r.u8()?,
+            mcr: r.u8()?,
+            scr: r.u8()?,
+            dll: r.u8()?,
+            dlm: r.u8()?,
+        })
+    }
+}
@@ -0,0 +1,709 @@
+use crate::bus::Bus;
+use crate::error;
+use crate::snapshot::{Reader, Result, SnapshotError, Writer};
+
+pub mod blk;
+pub mod console;
+pub mod net;
+pub mod rng;
+
+pub use blk::Blk;
+pub use console::Console;
+pub use net::Net;
+pub use rng::Rng;
+
+// virtio-mmio (バージョン2) のトランスポート
+// キューはsplit virtqueueで、通知を受けたらその場で全部処理する
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID: u32 = 0x00C;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0A0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0A4;
+const CONFIG_GENERATION: u32 = 0x0FC;
+const CONFIG: u32 = 0x100;
+
+const MAGIC: u32 = 0x7472_6976;
+const VENDOR: u32 = 0x6468_6A6D;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+const STATUS_NEEDS_RESET: u32 = 1 << 6;
+
+// InterruptStatusのビット
+const INT_USED: u32 = 1 << 0;
+const INT_CONFIG: u32 = 1 << 1;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+// ドライバが選べるキューの長さの上限
+pub const QUEUE_SIZE_MAX: u32 = 256;
+
+pub const SIZE: u32 = 0x1000;
+
+// トランスポートにつなぐデバイス
+#[derive(Clone)]
+pub enum Device {
+    Blk(Blk),
+    Net(Net),
+    Console(Console),
+    Rng(Rng),
+}
+
+impl Device {
+    pub fn id(&self) -> u32 {
+        match self {
+            Device::Blk(_) => blk::DEVICE_ID,
+            Device::Net(_) => net::DEVICE_ID,
+            Device::Console(_) => console::DEVICE_ID,
+            Device::Rng(_) => rng::DEVICE_ID,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        match self {
+            Device::Blk(blk) => blk.features(),
+            Device::Net(net) => net.features(),
+            Device::Console(console) => console.features(),
+            Device::Rng(_) => 0,
+        }
+    }
+
+    fn queues(&self) -> usize {
+        match self {
+            Device::Blk(_) => 1,
+            Device::Net(_) => 2,
+            Device::Console(console) => console.queues(),
+            Device::Rng(_) => 1,
+        }
+    }
+
+    fn config(&self) -> Vec<u8> {
+        match self {
+            Device::Blk(blk) => blk.config(),
+            Device::Net(net) => net.config(),
+            Device::Console(console) => console.config(),
+            Device::Rng(_) => Vec::new(),
+        }
+    }
+
+    // チェーン1本分の要求を処理し、書き込んだバイト数を返す
+    // 渡すデータがまだなければNoneを返し、チェーンはavailリングに残す
+    fn handle(&mut self, queue: usize, chain: &Chain, bus: &mut Bus) -> error::Result<Option<u32>> {
+        match self {
+            Device::Blk(blk) => blk.handle(queue, chain, bus).map(Some),
+            Device::Net(net) => net.handle(queue, chain, bus),
+            Device::Console(console) => console.handle(queue, chain, bus),
+            Device::Rng(rng) => rng.handle(chain, bus).map(Some),
+        }
+    }
+
+    // ホストから届いたデータを (ポート, データ) で取り出す
+    fn receive(&mut self) -> Vec<(u32, Vec<u8>)> {
+        match self {
+            Device::Net(net) => net.receive().into_iter().map(|f| (0, f)).collect(),
+            Device::Console(console) => console.receive(),
+            Device::Blk(_) | Device::Rng(_) => Vec::new(),
+        }
+    }
+
+    // 受け取ったデータを取り込む
+    fn deliver(&mut self, port: u32, data: &[u8]) -> bool {
+        match self {
+            Device::Net(net) if port == 0 => {
+                net.deliver(data);
+                true
+            }
+            Device::Console(console) => console.deliver(port, data),
+            _ => false,
+        }
+    }
+
+    // ゲストに渡すものが残っているか
+    fn pending(&self) -> bool {
+        match self {
+            Device::Blk(_) | Device::Rng(_) => false,
+            Device::Net(net) => net.pending(),
+            Device::Console(console) => console.pending(),
+        }
+    }
+
+    // ドライバがデバイスをリセットした
+    fn reset(&mut self) {
+        if let Device::Console(console) = self {
+            console.reset();
+        }
+    }
+
+    // ゲストから見える状態のうちトランスポートにないもの
+    fn save(&self, w: &mut Writer) {
+        match self {
+            Device::Blk(_) => {}
+            Device::Net(net) => net.save(w),
+            Device::Console(console) => console.save(w),
+            Device::Rng(rng) => rng.save(w),
+        }
+    }
+
+    fn load(&mut self, r: &mut Reader) -> Result<()> {
+        match self {
+            Device::Blk(_) => Ok(()),
+            Device::Net(net) => net.load(r),
+            Device::Console(console) => console.load(r),
+            Device::Rng(rng) => rng.load(r),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Queue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    // 次に取り出すavailリングの位置
+    last_avail: u16,
+}
+
+impl Queue {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.num);
+        w.bool(self.ready);
+        w.u64(self.desc);
+        w.u64(self.driver);
+        w.u64(self.device);
+        w.u32(self.last_avail as u32);
+    }
+
+    fn load(r: &mut Reader) -> Result<Self> {
+        Ok(Self {
+            num: r.u32()?,
+            ready: r.bool()?,
+            desc: r.u64()?,
+            driver: r.u64()?,
+            device: r.u64()?,
+            last_avail: r.u32()? as u16,
+        })
+    }
+}
+
+// ディスクリプタチェーンのバッファ (アドレス, 長さ)
+// デバイスが読む側と書く側に分けておく
+pub struct Chain {
+    head: u16,
+    readable: Vec<(u32, u32)>,
+    writable: Vec<(u32, u32)>,
+}
+
+impl Chain {
+    pub fn readable_len(&self) -> u32 {
+        self.readable.iter().map(|&(_, len)| len).sum()
+    }
+
+    pub fn writable_len(&self) -> u32 {
+        self.writable.iter().map(|&(_, len)| len).sum()
+    }
+
+    // 読む側のバッファを全部つなげて読む
+    pub fn read(&self, bus: &Bus) -> error::Result<Vec<u8>> {
+        let mut data = vec![0; self.readable_len() as usize];
+        let mut pos = 0;
+        for &(addr, len) in &self.readable {
+            bus.read_bytes(addr, &mut data[pos..pos + len as usize])?;
+            pos += len as usize;
+        }
+        Ok(data)
+    }
+
+    // 書く側のバッファに先頭から詰めて書き、書いたバイト数を返す
+    pub fn write(&self, bus: &mut Bus, data: &[u8]) -> error::Result<u32> {
+        self.write_at(bus, 0, data)
+    }
+
+    // 書く側のバッファのoffsetバイト目から書く
+    pub fn write_at(&self, bus: &mut Bus, offset: u32, data: &[u8]) -> error::Result<u32> {
+        let mut skip = offset as usize;
+        let mut pos = 0;
+        for &(addr, len) in &self.writable {
+            if pos == data.len() {
+                break;
+            }
+            if skip >= len as usize {
+                skip -= len as usize;
+                continue;
+            }
+            let n = (len as usize - skip).min(data.len() - pos);
+            bus.dma_write(addr + skip as u32, &data[pos..pos + n])?;
+            skip = 0;
+            pos += n;
+        }
+        Ok(pos as u32)
+    }
+}
+
+#[derive(Clone)]
+pub struct VirtioMmio {
+    device: Device,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Device) -> Self {
+        let queues = vec![Queue::default(); device.queues()];
+        Self {
+            device,
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+
+    pub fn irq(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        let mut device = self.device.clone();
+        device.reset();
+        *self = Self::new(device);
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    pub fn read(&mut self, offset: u32, width: u8) -> Option<u32> {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            let bytes = config.get(start..start + width as usize)?;
+            return Some(bytes.iter().rev().fold(0, |v, &b| (v << 8) | b as u32));
+        }
+        if width != 4 {
+            return None;
+        }
+        let val = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => match self.queue() {
+                Some(_) => QUEUE_SIZE_MAX,
+                None => 0,
+            },
+            QUEUE_READY => self.queue().is_some_and(|q| q.ready) as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            // 設定領域は変わらない
+            CONFIG_GENERATION => 0,
+            _ => return None,
+        };
+        Some(val)
+    }
+
+    // キューへの通知はbusのRAMを読み書きして処理する
+    pub fn write(&mut self, offset: u32, width: u8, val: u32, bus: &mut Bus) -> bool {
+        if width != 4 {
+            return false;
+        }
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | val as u64,
+                1 => {
+                    self.driver_features = (self.driver_features & 0xFFFF_FFFF) | (val as u64) << 32
+                }
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            QUEUE_SEL => self.queue_sel = val,
+            // リングの大きさは2の累乗でなければならない
+            QUEUE_NUM => {
+                if !valid_queue_num(val) {
+                    self.needs_reset();
+                } else if let Some(q) = self.queue() {
+                    q.num = val;
+                }
+            }
+            QUEUE_READY => {
+                if let Some(q) = self.queue() {
+                    q.ready = val & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => self.notify(val as usize, bus),
+            INTERRUPT_ACK => self.interrupt_status &= !val,
+            STATUS => self.set_status(val),
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                let Some(q) = self.queue() else {
+                    return true;
+                };
+                let reg = match offset & !0xF {
+                    QUEUE_DESC_LOW => &mut q.desc,
+                    QUEUE_DRIVER_LOW => &mut q.driver,
+                    _ => &mut q.device,
+                };
+                *reg = if offset & 0x4 == 0 {
+                    (*reg & !0xFFFF_FFFF) | val as u64
+                } else {
+                    (*reg & 0xFFFF_FFFF) | (val as u64) << 32
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn set_status(&mut self, val: u32) {
+        if val == 0 {
+            self.reset();
+            return;
+        }
+        let mut val = val;
+        // 知らない機能を選んだら受け付けない
+        let supported = self.driver_features & !self.device_features() == 0
+            && self.driver_features & F_VERSION_1 != 0;
+        if val & STATUS_FEATURES_OK != 0 && !supported {
+            val &= !STATUS_FEATURES_OK;
+        }
+        self.status = val | (self.status & STATUS_NEEDS_RESET);
+    }
+
+    fn notify(&mut self, queue: usize, bus: &mut Bus) {
+        self.kick(queue, bus);
+        // 応答を別のキューで返すデバイス (consoleの制御メッセージ) もあるので他のキューも見る
+        if self.device.pending() {
+            for queue in 0..self.queues.len() {
+                self.kick(queue, bus);
+            }
+        }
+    }
+
+    fn kick(&mut self, queue: usize, bus: &mut Bus) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_NEEDS_RESET != 0 {
+            return;
+        }
+        if !self
+            .queues
+            .get(queue)
+            .is_some_and(|q| q.ready && q.num != 0)
+        {
+            return;
+        }
+        if self.process(queue, bus).is_err() {
+            self.needs_reset();
+        }
+    }
+
+    // 壊れたキューはドライバにリセットしてもらう
+    fn needs_reset(&mut self) {
+        self.status |= STATUS_NEEDS_RESET;
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.interrupt_status |= INT_CONFIG;
+        }
+    }
+
+    // availリングに積まれたチェーンを全部処理してusedリングに返す
+    fn process(&mut self, queue: usize, bus: &mut Bus) -> error::Result<()> {
+        let q = &self.queues[queue];
+        let (desc, driver, device) = (addr(q.desc)?, addr(q.driver)?, addr(q.device)?);
+        let num = q.num as u16;
+        let mut last_avail = q.last_avail;
+
+        let avail_flags = read16(bus, driver)?;
+        let avail_idx = read16(bus, driver + 2)?;
+        let mut used = false;
+        while last_avail != avail_idx {
+            let head = read16(bus, driver + 4 + 2 * (last_avail % num) as u32)?;
+            let chain = read_chain(bus, desc, num, head)?;
+            let Some(len) = self.device.handle(queue, &chain, bus)? else {
+                break;
+            };
+
+            let used_idx = read16(bus, device + 2)?;
+            let elem = device + 4 + 8 * (used_idx % num) as u32;
+            bus.dma_write(elem, &(chain.head as u32).to_le_bytes())?;
+            bus.dma_write(elem + 4, &len.to_le_bytes())?;
+            bus.dma_write(device + 2, &used_idx.wrapping_add(1).to_le_bytes())?;
+
+            last_avail = last_avail.wrapping_add(1);
+            self.queues[queue].last_avail = last_avail;
+            used = true;
+        }
+        if used && avail_flags & AVAIL_F_NO_INTERRUPT == 0 {
+            self.interrupt_status |= INT_USED;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn receive(&mut self) -> Vec<(u32, Vec<u8>)> {
+        self.device.receive()
+    }
+
+    // ホスト側のデータを受け取れるキューに流す
+    pub(crate) fn deliver(&mut self, port: u32, data: &[u8], bus: &mut Bus) -> bool {
+        if !self.device.deliver(port, data) {
+            return false;
+        }
+        for queue in 0..self.queues.len() {
+            self.kick(queue, bus);
+        }
+        true
+    }
+
+    pub(crate) fn save(&self, w: &mut Writer) {
+        w.u32(self.device.id());
+        w.u32(self.status);
+        w.u32(self.device_features_sel);
+        w.u64(self.driver_features);
+        w.u32(self.driver_features_sel);
+        w.u32(self.queue_sel);
+        for q in &self.queues {
+            q.save(w);
+        }
+        w.u32(self.interrupt_status);
+        self.device.save(w);
+    }
+
+    // デバイスのホスト側 (ディスクイメージなど) はそのまま使い、ゲストから見える状態だけ戻す
+    pub(crate) fn load(&mut self, r: &mut Reader) -> Result<()> {
+        if r.u32()? != self.device.id() {
+            return Err(SnapshotError::Invalid("virtio device"));
+        }
+        self.status = r.u32()?;
+        self.device_features_sel = r.u32()?;
+        self.driver_features = r.u64()?;
+        self.driver_features_sel = r.u32()?;
+        self.queue_sel = r.u32()?;
+        for q in &mut self.queues {
+            *q = Queue::load(r)?;
+            if !valid_queue_num(q.num) {
+                return Err(SnapshotError::Invalid("virtio queue size"));
+            }
+        }
+        self.interrupt_status = r.u32()?;
+        self.device.load(r)
+    }
+}
+
+// 0はキューを使わない
+fn valid_queue_num(num: u32) -> bool {
+    num == 0 || (num.is_power_of_two() && num <= QUEUE_SIZE_MAX)
+}
+
+// 32ビットのバスに収まらないアドレスはRAMの外として扱う
+fn addr(addr: u64) -> error::Result<u32> {
+    u32::try_from(addr).map_err(|_| error::Error::BusFault {
+        addr: u32::MAX,
+        width: 1,
+        access: error::Access::Load,
+    })
+}
+
+fn read16(bus: &Bus, addr: u32) -> error::Result<u16> {
+    let mut buf = [0; 2];
+    bus.read_bytes(addr, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+// headから始まるチェーンを読む (ループしていたら長さの上限で止める)
+// バッファは全部RAMに収まり、合わせてもRAMより長くないものだけを受け付ける
+fn read_chain(bus: &Bus, desc: u32, num: u16, head: u16) -> error::Result<Chain> {
+    let mut chain = Chain {
+        head,
+        readable: Vec::new(),
+        writable: Vec::new(),
+    };
+    let fault = |addr, access| error::Error::BusFault {
+        addr,
+        width: 16,
+        access,
+    };
+    let mut total: u32 = 0;
+    let mut i = head;
+    for _ in 0..num {
+        if i >= num {
+            break;
+        }
+        let entry = desc
+            .checked_add(16 * i as u32)
+            .ok_or(fault(desc, error::Access::Load))?;
+        let mut buf = [0; 16];
+        bus.read_bytes(entry, &mut buf)?;
+        let buf_addr = addr(u64::from_le_bytes(buf[0..8].try_into().unwrap()))?;
+        let len = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        let flags = u16::from_le_bytes(buf[12..14].try_into().unwrap());
+        let next = u16::from_le_bytes(buf[14..16].try_into().unwrap());
+        let access = if flags & DESC_F_WRITE != 0 {
+            error::Access::Store
+        } else {
+            error::Access::Load
+        };
+        if !bus.in_ram(buf_addr, len) {
+            return Err(error::Error::BusFault {
+                addr: buf_addr,
+                width: 1,
+                access,
+            });
+        }
+        total = match total.checked_add(len) {
+            Some(total) if total <= bus.ram_size() => total,
+            _ => return Err(fault(entry, error::Access::Load)),
+        };
+        if flags & DESC_F_WRITE != 0 {
+            chain.writable.push((buf_addr, len));
+        } else {
+            chain.readable.push((buf_addr, len));
+        }
+        if flags & DESC_F_NEXT == 0 {
+            return Ok(chain);
+        }
+        i = next;
+    }
+    Err(fault(desc, error::Access::Load))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_chain, Device, Rng, DESC_F_NEXT, DESC_F_WRITE, INTERRUPT_STATUS, INT_CONFIG,
+        QUEUE_NUM, QUEUE_SEL, STATUS, STATUS_DRIVER_OK, STATUS_NEEDS_RESET,
+    };
+    use crate::bus::Bus;
+
+    const DESC: u32 = 0x100;
+
+    fn desc(bus: &mut Bus, i: u32, addr: u64, len: u32, flags: u16, next: u16) {
+        let mut d = Vec::new();
+        d.extend_from_slice(&addr.to_le_bytes());
+        d.extend_from_slice(&len.to_le_bytes());
+        d.extend_from_slice(&flags.to_le_bytes());
+        d.extend_from_slice(&next.to_le_bytes());
+        bus.write_bytes(DESC + 16 * i, &d).unwrap();
+    }
+
+    #[test]
+    fn chain_splits_readable_and_writable() {
+        let mut bus = Bus::new();
+        desc(&mut bus, 0, 0x1000, 16, DESC_F_NEXT, 2);
+        desc(&mut bus, 2, 0x2000, 0x200, DESC_F_WRITE, 0);
+        let chain = read_chain(&bus, DESC, 4, 0).unwrap();
+        assert_eq!(chain.head, 0);
+        assert_eq!(chain.readable, [(0x1000, 16)]);
+        assert_eq!(chain.writable, [(0x2000, 0x200)]);
+        assert_eq!(chain.readable_len(), 16);
+        assert_eq!(chain.writable_len(), 0x200);
+    }
+
+    // バッファがRAMからはみ出すチェーンは読まない
+    #[test]
+    fn chain_outside_ram_is_rejected() {
+        let mut bus = Bus::new();
+        let size = bus.ram_size();
+        for (addr, len) in [
+            (0x1000, u32::MAX),
+            (size as u64 - 8, 16),
+            (0x1_0000_0000, 1),
+            (0xFFFF_FFF0, 0x20),
+        ] {
+            desc(&mut bus, 0, addr, len, 0, 0);
+            assert!(
+                read_chain(&bus, DESC, 4, 0).is_err(),
+                "{:x} {:x}",
+                addr,
+                len
+            );
+        }
+    }
+
+    // 1つずつはRAMに収まっても、合わせてRAMより長いチェーンは壊れている
+    #[test]
+    fn chain_longer_than_ram_is_rejected() {
+        let mut bus = Bus::new();
+        let size = bus.ram_size();
+        desc(&mut bus, 0, 0, size, DESC_F_NEXT, 1);
+        desc(&mut bus, 1, 0, size, DESC_F_WRITE, 0);
+        assert!(read_chain(&bus, DESC, 4, 0).is_err());
+    }
+
+    #[test]
+    fn looping_chain_is_rejected() {
+        let mut bus = Bus::new();
+        desc(&mut bus, 0, 0x1000, 4, DESC_F_NEXT, 1);
+        desc(&mut bus, 1, 0x1000, 4, DESC_F_NEXT, 0);
+        assert!(read_chain(&bus, DESC, 4, 0).is_err());
+        // テーブルがアドレスの終わりを越える
+        assert!(read_chain(&bus, 0xFFFF_FFF0, 4, 1).is_err());
+    }
+
+    // 2の累乗でないリングの大きさを書くとリセットが必要になる
+    #[test]
+    fn bad_queue_num_needs_reset() {
+        let mut bus = Bus::new();
+        let base = bus.add_virtio(Device::Rng(Rng::seeded(1))).unwrap();
+        bus.write32(base + STATUS, STATUS_DRIVER_OK).unwrap();
+        bus.write32(base + QUEUE_SEL, 0).unwrap();
+        bus.write32(base + QUEUE_NUM, 8).unwrap();
+        assert_eq!(bus.read32(base + STATUS).unwrap() & STATUS_NEEDS_RESET, 0);
+
+        bus.write32(base + QUEUE_NUM, 6).unwrap();
+        assert_ne!(bus.read32(base + STATUS).unwrap() & STATUS_NEEDS_RESET, 0);
+        assert_ne!(bus.read32(base + INTERRUPT_STATUS).unwrap() & INT_CONFIG, 0);
+        assert_eq!(bus.virtio()[0].queues[0].num, 8);
+
+        // リセットすれば使える
+        bus.write32(base + STATUS, 0).unwrap();
+        assert_eq!(bus.read32(base + STATUS).unwrap(), 0);
+        bus.write32(base + QUEUE_NUM, 512).unwrap();
+        assert_ne!(bus.read32(base + STATUS).unwrap() & STATUS_NEEDS_RESET, 0);
+    }
+}
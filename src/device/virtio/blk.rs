@@ -0,0 +1,143 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::Chain;
+use crate::bus::Bus;
+use crate::error::Result;
+
+// virtio-blk (ホストの生のディスクイメージをそのまま読み書きする)
+pub const DEVICE_ID: u32 = 2;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: u64 = 512;
+// 要求の先頭 (type, reserved, sector)
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+// スナップショットやJITの検証で複製してもイメージのファイルは共有する
+#[derive(Clone)]
+pub struct Blk {
+    file: Rc<File>,
+    // 512バイト単位の容量 (端数は読み書きできない)
+    sectors: u64,
+    read_only: bool,
+    id: [u8; ID_SIZE],
+}
+
+impl Blk {
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let sectors = file.metadata()?.len() / SECTOR_SIZE;
+        // GET_IDにはファイル名を返す
+        let mut id = [0; ID_SIZE];
+        let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+        let n = name.len().min(ID_SIZE);
+        id[..n].copy_from_slice(&name[..n]);
+        Ok(Self {
+            file: Rc::new(file),
+            sectors,
+            read_only,
+            id,
+        })
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub(super) fn features(&self) -> u64 {
+        let ro = if self.read_only { F_RO } else { 0 };
+        F_FLUSH | ro
+    }
+
+    // 設定領域はcapacityだけ
+    pub(super) fn config(&self) -> Vec<u8> {
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    pub(super) fn handle(&mut self, _queue: usize, chain: &Chain, bus: &mut Bus) -> Result<u32> {
+        let req = chain.read(bus)?;
+        // 最後の1バイトがステータス
+        let writable = chain.writable_len() as usize;
+        if req.len() < HEADER_SIZE || writable == 0 {
+            return Ok(0);
+        }
+        let kind = u32::from_le_bytes(req[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(req[8..16].try_into().unwrap());
+
+        let mut data = Vec::new();
+        let status = match kind {
+            // 容量を超える読み出しは、バッファを確保する前に断って最後にステータスだけ書く
+            T_IN if !self.in_range(sector, writable - 1) => {
+                chain.write_at(bus, writable as u32 - 1, &[S_IOERR])?;
+                return Ok(writable as u32);
+            }
+            T_IN => {
+                data = vec![0; writable - 1];
+                self.status(self.read_at(sector, &mut data))
+            }
+            T_OUT if self.read_only => S_IOERR,
+            T_OUT => self.status(self.write_at(sector, &req[HEADER_SIZE..])),
+            T_FLUSH => self.status(self.file.sync_data()),
+            T_GET_ID => {
+                data = self.id[..ID_SIZE.min(writable - 1)].to_vec();
+                data.resize(writable - 1, 0);
+                S_OK
+            }
+            _ => S_UNSUPP,
+        };
+        data.push(status);
+        chain.write(bus, &data)
+    }
+
+    fn status(&self, result: io::Result<()>) -> u8 {
+        match result {
+            Ok(()) => S_OK,
+            Err(_) => S_IOERR,
+        }
+    }
+
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        let end = sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|start| start.checked_add(len as u64));
+        matches!(end, Some(end) if end <= self.sectors * SECTOR_SIZE)
+    }
+
+    // 容量を超える範囲はエラーにする
+    fn seek(&self, sector: u64, len: usize) -> io::Result<()> {
+        if !self.in_range(sector, len) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        (&*self.file).seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        Ok(())
+    }
+
+    fn read_at(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(sector, buf.len())?;
+        (&*self.file).read_exact(buf)
+    }
+
+    fn write_at(&self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.seek(sector, data.len())?;
+        (&*self.file).write_all(data)
+    }
+}
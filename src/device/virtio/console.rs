@@ -0,0 +1,537 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use super::Chain;
+use crate::bus::Bus;
+use crate::error::Result;
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+
+// virtio-console (MULTIPORTで複数のポートを持てる)
+// ポート0がコンソールで、入出力はポートごとのBackendに任せる
+pub const DEVICE_ID: u32 = 3;
+
+const F_MULTIPORT: u64 = 1 << 1;
+
+// ポート0のキューと制御キュー
+// ポート1以降は4番から受信、送信の順に並ぶ
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+
+// 制御メッセージ (id, event, value) のevent
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+const CONTROL_SIZE: usize = 8;
+
+// ホスト側の入出力の相手
+pub trait Backend {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    // 届いている入力を取り出す (なければNone)
+    fn read(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+#[derive(Clone)]
+struct Port {
+    name: String,
+    backend: Rc<RefCell<dyn Backend>>,
+    // ゲストにまだ渡していない入力
+    input: VecDeque<u8>,
+}
+
+// スナップショットやJITの検証で複製してもBackendは共有する
+#[derive(Clone)]
+pub struct Console {
+    ports: Vec<Port>,
+    // ゲストに送る制御メッセージ
+    control: VecDeque<Vec<u8>>,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            ports: Vec::new(),
+            control: VecDeque::new(),
+        }
+    }
+
+    // 最初に足したポートがコンソールになる
+    pub fn add_port(&mut self, name: &str, backend: impl Backend + 'static) {
+        self.ports.push(Port {
+            name: name.to_string(),
+            backend: Rc::new(RefCell::new(backend)),
+            input: VecDeque::new(),
+        });
+    }
+
+    pub fn ports(&self) -> usize {
+        self.ports.len()
+    }
+
+    pub(super) fn features(&self) -> u64 {
+        F_MULTIPORT
+    }
+
+    pub(super) fn queues(&self) -> usize {
+        2 * self.ports.len().max(1) + 2
+    }
+
+    // cols, rows, max_nr_ports, emerg_wr
+    pub(super) fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.extend_from_slice(&[0; 4]);
+        config
+    }
+
+    pub(super) fn reset(&mut self) {
+        self.control.clear();
+    }
+
+    // ポートに届いている入力を全部読む (まだゲストには渡さない)
+    pub(super) fn receive(&mut self) -> Vec<(u32, Vec<u8>)> {
+        let mut inputs = Vec::new();
+        for (i, port) in self.ports.iter().enumerate() {
+            while let Ok(Some(data)) = port.backend.borrow_mut().read() {
+                inputs.push((i as u32, data));
+            }
+        }
+        inputs
+    }
+
+    pub(super) fn deliver(&mut self, port: u32, data: &[u8]) -> bool {
+        match self.ports.get_mut(port as usize) {
+            Some(port) => {
+                port.input.extend(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn pending(&self) -> bool {
+        !self.control.is_empty() || self.ports.iter().any(|p| !p.input.is_empty())
+    }
+
+    // キューの番号から (ポート, 送信か) を割り出す
+    fn port_queue(queue: usize) -> Option<(usize, bool)> {
+        match queue {
+            0 | 1 => Some((0, queue == 1)),
+            CONTROL_RX | CONTROL_TX => None,
+            _ => Some((queue / 2 - 1, queue % 2 == 1)),
+        }
+    }
+
+    pub(super) fn handle(
+        &mut self,
+        queue: usize,
+        chain: &Chain,
+        bus: &mut Bus,
+    ) -> Result<Option<u32>> {
+        match (queue, Self::port_queue(queue)) {
+            (CONTROL_RX, _) => match self.control.pop_front() {
+                Some(msg) => chain.write(bus, &msg).map(Some),
+                None => Ok(None),
+            },
+            (CONTROL_TX, _) => {
+                let msg = chain.read(bus)?;
+                if msg.len() >= CONTROL_SIZE {
+                    self.control_message(&msg);
+                }
+                Ok(Some(0))
+            }
+            (_, Some((i, false))) => {
+                let Some(port) = self.ports.get_mut(i) else {
+                    return Ok(None);
+                };
+                if port.input.is_empty() {
+                    return Ok(None);
+                }
+                let n = port.input.len().min(chain.writable_len() as usize);
+                let data: Vec<u8> = port.input.drain(..n).collect();
+                chain.write(bus, &data).map(Some)
+            }
+            (_, Some((i, true))) => {
+                let data = chain.read(bus)?;
+                if let Some(port) = self.ports.get(i) {
+                    // 相手がいないなどで書けなかった出力は捨てる
+                    let _ = port.backend.borrow_mut().write(&data);
+                }
+                Ok(Some(0))
+            }
+            _ => Ok(Some(0)),
+        }
+    }
+
+    // ドライバからの制御メッセージに応える
+    fn control_message(&mut self, msg: &[u8]) {
+        let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() as u32 {
+                    self.send_control(id, DEVICE_ADD, 0, &[]);
+                }
+            }
+            PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                if id == 0 {
+                    self.send_control(id, CONSOLE_PORT, 1, &[]);
+                }
+                let name = self.ports[id as usize].name.clone();
+                if !name.is_empty() {
+                    self.send_control(id, PORT_NAME, 0, name.as_bytes());
+                }
+                self.send_control(id, PORT_OPEN, 1, &[]);
+            }
+            // ゲスト側でポートを開いたかどうかは気にしない
+            _ => {}
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut msg = Vec::with_capacity(CONTROL_SIZE + extra.len());
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg.extend_from_slice(extra);
+        self.control.push_back(msg);
+    }
+
+    // 返していない制御メッセージと入力はゲストから見える状態なので残す
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.u32(self.control.len() as u32);
+        for msg in &self.control {
+            w.bytes(msg);
+        }
+        w.u32(self.ports.len() as u32);
+        for port in &self.ports {
+            w.bytes(&port.input.iter().copied().collect::<Vec<_>>());
+        }
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> snapshot::Result<()> {
+        self.control.clear();
+        for _ in 0..r.u32()? {
+            let msg = r.bytes()?;
+            if msg.len() < CONTROL_SIZE {
+                return Err(SnapshotError::Invalid("virtio-console control"));
+            }
+            self.control.push_back(msg.to_vec());
+        }
+        if r.u32()? as usize != self.ports.len() {
+            return Err(SnapshotError::Invalid("virtio-console ports"));
+        }
+        for port in &mut self.ports {
+            port.input = r.bytes()?.iter().copied().collect();
+        }
+        Ok(())
+    }
+}
+
+// ホストの標準入出力
+// 標準入力はスレッドで読んで溜めておく
+pub struct Stdio {
+    input: Receiver<Vec<u8>>,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stdio {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Self { input: rx }
+    }
+}
+
+impl Backend for Stdio {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(data)?;
+        stdout.flush()
+    }
+
+    fn read(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.input.try_recv() {
+            Ok(data) => Ok(Some(data)),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+// Unixドメインソケットで待ち受け、つないできた相手と入出力する
+// 相手がいない間の出力は捨てる
+#[cfg(unix)]
+pub struct Socket {
+    listener: UnixListener,
+    stream: Option<UnixStream>,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Socket {
+    pub fn listen<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let path = path.as_ref().to_path_buf();
+        // 前回のソケットが残っていれば消す
+        if std::fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            stream: None,
+            path,
+        })
+    }
+
+    fn accept(&mut self) {
+        if self.stream.is_some() {
+            return;
+        }
+        if let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.stream = Some(stream);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+impl Backend for Socket {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.accept();
+        let Some(stream) = &mut self.stream else {
+            return Ok(());
+        };
+        let result = stream.write_all(data);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn read(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.accept();
+        let Some(stream) = &mut self.stream else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; 4096];
+        match stream.read(&mut buf) {
+            // 相手が切った
+            Ok(0) => {
+                self.stream = None;
+                Ok(None)
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Some(buf))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => {
+                self.stream = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::{Backend, Console, CONTROL_RX, CONTROL_TX};
+    use crate::bus::Bus;
+    use crate::device::virtio::Chain;
+    use crate::snapshot::{Reader, SnapshotError, Writer};
+
+    #[derive(Clone, Default)]
+    struct Pipe {
+        input: Rc<RefCell<VecDeque<Vec<u8>>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Backend for Pipe {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.output.borrow_mut().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.input.borrow_mut().pop_front())
+        }
+    }
+
+    fn chain(readable: &[(u32, u32)], writable: &[(u32, u32)]) -> Chain {
+        Chain {
+            head: 0,
+            readable: readable.to_vec(),
+            writable: writable.to_vec(),
+        }
+    }
+
+    fn control(id: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut msg = id.to_le_bytes().to_vec();
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg
+    }
+
+    // ドライバ側から制御メッセージを送り、返ってきたものを全部読む
+    fn exchange(console: &mut Console, bus: &mut Bus, msg: &[u8]) -> Vec<Vec<u8>> {
+        bus.write_bytes(0x1000, msg).unwrap();
+        let tx = chain(&[(0x1000, msg.len() as u32)], &[]);
+        assert_eq!(console.handle(CONTROL_TX, &tx, bus).unwrap(), Some(0));
+        let rx = chain(&[], &[(0x2000, 64)]);
+        let mut replies = Vec::new();
+        while let Some(n) = console.handle(CONTROL_RX, &rx, bus).unwrap() {
+            let mut reply = vec![0; n as usize];
+            bus.read_bytes(0x2000, &mut reply).unwrap();
+            replies.push(reply);
+        }
+        replies
+    }
+
+    #[test]
+    fn multiport_handshake() {
+        let mut console = Console::new();
+        console.add_port("", Pipe::default());
+        console.add_port("org.test.0", Pipe::default());
+        assert_eq!(console.queues(), 6);
+        assert_eq!(console.config(), [0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut bus = Bus::new();
+        assert_eq!(
+            exchange(&mut console, &mut bus, &control(0, 0, 1)),
+            [control(0, 1, 0), control(1, 1, 0)]
+        );
+        // ポート0はコンソール、名前のあるポートは名前を知らせる
+        assert_eq!(
+            exchange(&mut console, &mut bus, &control(0, 3, 1)),
+            [control(0, 4, 1), control(0, 6, 1)]
+        );
+        let mut named = control(1, 7, 0);
+        named.extend_from_slice(b"org.test.0");
+        assert_eq!(
+            exchange(&mut console, &mut bus, &control(1, 3, 1)),
+            [named, control(1, 6, 1)]
+        );
+        // 知らないポートや短すぎるメッセージは無視する
+        assert!(exchange(&mut console, &mut bus, &control(2, 3, 1)).is_empty());
+        assert!(exchange(&mut console, &mut bus, &[0; 4]).is_empty());
+        assert!(!console.pending());
+    }
+
+    #[test]
+    fn port_queues() {
+        let first = Pipe::default();
+        let second = Pipe::default();
+        let mut console = Console::new();
+        console.add_port("", first.clone());
+        console.add_port("", second.clone());
+        second.input.borrow_mut().push_back(b"hello".to_vec());
+        for (port, data) in console.receive() {
+            assert!(console.deliver(port, &data));
+        }
+        assert!(!console.deliver(2, b"lost"));
+
+        let mut bus = Bus::new();
+        let rx = chain(&[], &[(0x2000, 3)]);
+        assert_eq!(console.handle(0, &rx, &mut bus).unwrap(), None);
+        // ポート1の受信キューは4番、バッファに入る分だけ渡す
+        assert_eq!(console.handle(4, &rx, &mut bus).unwrap(), Some(3));
+        assert_eq!(console.handle(4, &rx, &mut bus).unwrap(), Some(2));
+        let mut data = [0; 2];
+        bus.read_bytes(0x2000, &mut data).unwrap();
+        assert_eq!(&data, b"lo");
+        assert!(!console.pending());
+
+        bus.write_bytes(0x1000, b"out").unwrap();
+        let tx = chain(&[(0x1000, 3)], &[]);
+        assert_eq!(console.handle(1, &tx, &mut bus).unwrap(), Some(0));
+        assert_eq!(console.handle(5, &tx, &mut bus).unwrap(), Some(0));
+        assert_eq!(console.handle(7, &tx, &mut bus).unwrap(), Some(0));
+        assert_eq!(*first.output.borrow(), b"out");
+        assert_eq!(*second.output.borrow(), b"out");
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut console = Console::new();
+        console.add_port("", Pipe::default());
+        console.add_port("", Pipe::default());
+        let mut bus = Bus::new();
+        bus.write_bytes(0x1000, &control(0, 0, 1)).unwrap();
+        console
+            .handle(CONTROL_TX, &chain(&[(0x1000, 8)], &[]), &mut bus)
+            .unwrap();
+        console.deliver(1, b"typed");
+        let mut w = Writer::new();
+        console.save(&mut w);
+        let data = w.into_bytes();
+
+        let mut restored = Console::new();
+        restored.add_port("", Pipe::default());
+        restored.add_port("", Pipe::default());
+        restored.load(&mut Reader::new(&data)).unwrap();
+        let rx = chain(&[], &[(0x2000, 64)]);
+        assert_eq!(restored.handle(CONTROL_RX, &rx, &mut bus).unwrap(), Some(8));
+        assert_eq!(restored.handle(CONTROL_RX, &rx, &mut bus).unwrap(), Some(8));
+        assert_eq!(restored.handle(CONTROL_RX, &rx, &mut bus).unwrap(), None);
+        assert_eq!(restored.handle(4, &rx, &mut bus).unwrap(), Some(5));
+
+        // ポートの数が違うスナップショットは読めない
+        let mut single = Console::new();
+        single.add_port("", Pipe::default());
+        assert!(matches!(
+            single.load(&mut Reader::new(&data)),
+            Err(SnapshotError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Chain;
+use crate::bus::Bus;
+use crate::error::Result;
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+
+// virtio-net (フレームの送受信はホスト側のBackendに任せる)
+pub const DEVICE_ID: u32 = 1;
+
+const F_MAC: u64 = 1 << 5;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+// フレームの前に付くvirtio_net_hdr (VERSION_1ではnum_buffersまでの12バイト)
+const HEADER_SIZE: usize = 12;
+// ゲストが受け取る前に溜めておけるフレームの数
+const MAX_PENDING: usize = 256;
+const MAX_FRAME: usize = 65536;
+
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+// ホスト側でフレームをやり取りする相手
+pub trait Backend {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+    // 届いているフレームを1つ取り出す (なければNone)
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+// スナップショットやJITの検証で複製してもBackendは共有する
+#[derive(Clone)]
+pub struct Net {
+    backend: Rc<RefCell<dyn Backend>>,
+    mac: [u8; 6],
+    // 届いてまだゲストに渡していないフレーム
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Net {
+    pub fn new(backend: impl Backend + 'static, mac: [u8; 6]) -> Self {
+        Self {
+            backend: Rc::new(RefCell::new(backend)),
+            mac,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub(super) fn features(&self) -> u64 {
+        F_MAC
+    }
+
+    pub(super) fn config(&self) -> Vec<u8> {
+        self.mac.to_vec()
+    }
+
+    // 届いているフレームを全部読む (まだゲストには渡さない)
+    pub(super) fn receive(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = self.backend.borrow_mut().recv() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    pub(super) fn deliver(&mut self, frame: &[u8]) {
+        // 溢れたら古いものから捨てる
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(frame.to_vec());
+    }
+
+    pub(super) fn pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub(super) fn handle(
+        &mut self,
+        queue: usize,
+        chain: &Chain,
+        bus: &mut Bus,
+    ) -> Result<Option<u32>> {
+        match queue {
+            RX => {
+                let Some(frame) = self.pending.front() else {
+                    return Ok(None);
+                };
+                // 入りきらないフレームは捨ててバッファは返さない
+                if HEADER_SIZE + frame.len() > chain.writable_len() as usize {
+                    self.pending.pop_front();
+                    return Ok(None);
+                }
+                let mut data = vec![0; HEADER_SIZE];
+                // num_buffers
+                data[10] = 1;
+                data.extend_from_slice(frame);
+                self.pending.pop_front();
+                chain.write(bus, &data).map(Some)
+            }
+            TX => {
+                let data = chain.read(bus)?;
+                if data.len() > HEADER_SIZE {
+                    // 相手がいないなどで送れなかったフレームは落とす
+                    let _ = self.backend.borrow_mut().send(&data[HEADER_SIZE..]);
+                }
+                Ok(Some(0))
+            }
+            _ => Ok(Some(0)),
+        }
+    }
+
+    // 届いてまだ渡していないフレームはゲストから見える状態なので残す
+    pub(super) fn save(&self, w: &mut Writer) {
+        w.u32(self.pending.len() as u32);
+        for frame in &self.pending {
+            w.bytes(frame);
+        }
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> snapshot::Result<()> {
+        let n = r.u32()? as usize;
+        if n > MAX_PENDING {
+            return Err(SnapshotError::Invalid("virtio-net pending"));
+        }
+        self.pending.clear();
+        for _ in 0..n {
+            self.pending.push_back(r.bytes()?.to_vec());
+        }
+        Ok(())
+    }
+}
+
+// 送ったフレームをpcap形式で書き出すだけの相手
+pub struct Pcap {
+    file: File,
+}
+
+impl Pcap {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xA1B2_C3D4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // thiszone, sigfigs
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&(MAX_FRAME as u32).to_le_bytes());
+        // LINKTYPE_ETHERNET
+        header.extend_from_slice(&1u32.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self { file })
+    }
+}
+
+impl Backend for Pcap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::new();
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        self.file.write_all(&record)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+// Unixドメインのデータグラムソケットで別のエミュレータとつなぐ
+// 互いにlocalとpeerを入れ替えて起動する
+#[cfg(unix)]
+pub struct Socket {
+    socket: UnixDatagram,
+    local: PathBuf,
+    peer: PathBuf,
+}
+
+#[cfg(unix)]
+impl Socket {
+    pub fn bind<P: AsRef<Path>, Q: AsRef<Path>>(local: P, peer: Q) -> io::Result<Self> {
+        let local = local.as_ref().to_path_buf();
+        // 前回のソケットが残っていれば消す
+        if fs::metadata(&local).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(&local)?;
+        }
+        let socket = UnixDatagram::bind(&local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            local,
+            peer: peer.as_ref().to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.local);
+    }
+}
+
+#[cfg(unix)]
+impl Backend for Socket {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.socket.send_to(frame, &self.peer).map(|_| ())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; MAX_FRAME];
+        match self.socket.recv(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Some(buf))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Linuxのtapデバイス (作る権限がある時だけ使える)
+#[cfg(target_os = "linux")]
+pub struct Tap {
+    file: File,
+}
+
+#[cfg(target_os = "linux")]
+impl Tap {
+    pub fn open(name: &str) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        const O_NONBLOCK: i32 = 0o4000;
+        const TUNSETIFF: u64 = 0x4004_54CA;
+        const IFF_TAP: u16 = 0x0002;
+        const IFF_NO_PI: u16 = 0x1000;
+        const IFNAMSIZ: usize = 16;
+
+        extern "C" {
+            fn ioctl(fd: i32, request: u64, ...) -> i32;
+        }
+
+        if name.len() >= IFNAMSIZ {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NONBLOCK)
+            .open("/dev/net/tun")?;
+        // struct ifreq (名前と後ろの共用体)
+        let mut ifreq = [0u8; 40];
+        ifreq[..name.len()].copy_from_slice(name.as_bytes());
+        ifreq[IFNAMSIZ..IFNAMSIZ + 2].copy_from_slice(&(IFF_TAP | IFF_NO_PI).to_ne_bytes());
+        // SAFETY: ifreqはカーネルが読み書きする大きさを満たしている
+        if unsafe { ioctl(file.as_raw_fd(), TUNSETIFF, ifreq.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { file })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Backend for Tap {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.file.write_all(frame)
+    }
+
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; MAX_FRAME];
+        match self.file.read(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Some(buf))
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::{Backend, Net, DEFAULT_MAC, HEADER_SIZE, MAX_PENDING, RX, TX};
+    use crate::bus::Bus;
+    use crate::device::virtio::Chain;
+    use crate::snapshot::{Reader, SnapshotError, Writer};
+
+    // 届くフレームと送ったフレームをテストから見られる相手
+    #[derive(Clone, Default)]
+    struct Loopback {
+        inbox: Rc<RefCell<VecDeque<Vec<u8>>>>,
+        sent: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl Backend for Loopback {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.sent.borrow_mut().push(frame.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.inbox.borrow_mut().pop_front())
+        }
+    }
+
+    fn chain(readable: &[(u32, u32)], writable: &[(u32, u32)]) -> Chain {
+        Chain {
+            head: 0,
+            readable: readable.to_vec(),
+            writable: writable.to_vec(),
+        }
+    }
+
+    #[test]
+    fn receive_into_rx_buffers() {
+        let backend = Loopback::default();
+        let mut net = Net::new(backend.clone(), DEFAULT_MAC);
+        assert_eq!(net.config(), DEFAULT_MAC);
+        backend
+            .inbox
+            .borrow_mut()
+            .extend([vec![1; 100], vec![2; 20]]);
+        for frame in net.receive() {
+            net.deliver(&frame);
+        }
+        assert!(net.pending());
+
+        let mut bus = Bus::new();
+        // 入りきらないフレームは捨てる
+        let small = chain(&[], &[(0x1000, 64)]);
+        assert_eq!(net.handle(RX, &small, &mut bus).unwrap(), None);
+        // 2つに分かれたバッファに詰めて書く
+        let split = chain(&[], &[(0x1000, 16), (0x2000, 64)]);
+        assert_eq!(net.handle(RX, &split, &mut bus).unwrap(), Some(32));
+        let mut header = [0; HEADER_SIZE];
+        bus.read_bytes(0x1000, &mut header).unwrap();
+        assert_eq!(header, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let mut data = [0; 4];
+        bus.read_bytes(0x1000 + HEADER_SIZE as u32, &mut data)
+            .unwrap();
+        assert_eq!(data, [2; 4]);
+        bus.read_bytes(0x2000, &mut data).unwrap();
+        assert_eq!(data, [2; 4]);
+        assert!(!net.pending());
+        assert_eq!(net.handle(RX, &split, &mut bus).unwrap(), None);
+    }
+
+    #[test]
+    fn transmit_strips_header() {
+        let backend = Loopback::default();
+        let mut net = Net::new(backend.clone(), DEFAULT_MAC);
+        let mut bus = Bus::new();
+        bus.write_bytes(0x1000, &[0; HEADER_SIZE]).unwrap();
+        bus.write_bytes(0x2000, b"frame").unwrap();
+        let tx = chain(&[(0x1000, HEADER_SIZE as u32), (0x2000, 5)], &[]);
+        assert_eq!(net.handle(TX, &tx, &mut bus).unwrap(), Some(0));
+        // ヘッダだけなら送らない
+        let empty = chain(&[(0x1000, HEADER_SIZE as u32)], &[]);
+        assert_eq!(net.handle(TX, &empty, &mut bus).unwrap(), Some(0));
+        assert_eq!(*backend.sent.borrow(), [b"frame".to_vec()]);
+    }
+
+    // 溢れたら古いものから捨てる
+    #[test]
+    fn pending_limit() {
+        let mut net = Net::new(Loopback::default(), DEFAULT_MAC);
+        for i in 0..MAX_PENDING + 2 {
+            net.deliver(&(i as u32).to_le_bytes());
+        }
+        assert_eq!(net.pending.len(), MAX_PENDING);
+        assert_eq!(net.pending[0], 2u32.to_le_bytes());
+    }
+
+    // まだゲストに渡していないフレームもスナップショットに入る
+    #[test]
+    fn save_and_load_pending() {
+        let mut net = Net::new(Loopback::default(), DEFAULT_MAC);
+        net.deliver(b"first");
+        net.deliver(b"second");
+        let mut w = Writer::new();
+        net.save(&mut w);
+        let data = w.into_bytes();
+
+        let mut restored = Net::new(Loopback::default(), DEFAULT_MAC);
+        restored.deliver(b"stale");
+        restored.load(&mut Reader::new(&data)).unwrap();
+        assert_eq!(restored.pending, [b"first".to_vec(), b"second".to_vec()]);
+
+        assert!(matches!(
+            restored.load(&mut Reader::new(&data[..data.len() - 1])),
+            Err(SnapshotError::Truncated)
+        ));
+        let mut w = Writer::new();
+        w.u32(MAX_PENDING as u32 + 1);
+        assert!(matches!(
+            restored.load(&mut Reader::new(&w.into_bytes())),
+            Err(SnapshotError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use super::Chain;
+use crate::bus::Bus;
+use crate::error::Result;
+use crate::snapshot::{self, Reader, SnapshotError, Writer};
+
+// virtio-rng (ゲストが出したバッファを乱数で埋める)
+pub const DEVICE_ID: u32 = 4;
+
+// 乱数の出どころ
+// シードから作る乱数は状態をスナップショットに含めるので、記録と再生で同じ列になる
+#[derive(Clone)]
+enum Source {
+    // splitmix64の状態
+    Seeded(u64),
+    Host(Rc<File>),
+}
+
+#[derive(Clone)]
+pub struct Rng {
+    source: Source,
+}
+
+impl Rng {
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            source: Source::Seeded(seed),
+        }
+    }
+
+    // ホストのエントロピー (/dev/urandom) を使う
+    pub fn host() -> io::Result<Self> {
+        Ok(Self {
+            source: Source::Host(Rc::new(File::open("/dev/urandom")?)),
+        })
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        match &mut self.source {
+            Source::Seeded(state) => {
+                for chunk in buf.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+            // 読めなければ0のまま返す
+            Source::Host(file) => {
+                let _ = (&**file).read_exact(buf);
+            }
+        }
+    }
+
+    pub(super) fn handle(&mut self, chain: &Chain, bus: &mut Bus) -> Result<u32> {
+        let mut data = vec![0; chain.writable_len() as usize];
+        self.fill(&mut data);
+        chain.write(bus, &data)
+    }
+
+    pub(super) fn save(&self, w: &mut Writer) {
+        match self.source {
+            Source::Seeded(state) => {
+                w.bool(true);
+                w.u64(state);
+            }
+            Source::Host(_) => w.bool(false),
+        }
+    }
+
+    pub(super) fn load(&mut self, r: &mut Reader) -> snapshot::Result<()> {
+        match (&mut self.source, r.bool()?) {
+            (Source::Seeded(state), true) => *state = r.u64()?,
+            (Source::Host(_), false) => {}
+            _ => return Err(SnapshotError::Invalid("virtio-rng source")),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+    use crate::bus::Bus;
+    use crate::device::virtio::Chain;
+    use crate::snapshot::{Reader, SnapshotError, Writer};
+
+    fn fill(rng: &mut Rng, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        rng.fill(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let mut a = Rng::seeded(1);
+        let mut b = Rng::seeded(1);
+        let first = fill(&mut a, 13);
+        assert_eq!(first, fill(&mut b, 13));
+        assert_ne!(first, fill(&mut Rng::seeded(2), 13));
+        assert_ne!(fill(&mut a, 13), first);
+
+        // ゲストのバッファを全部埋める
+        let mut bus = Bus::new();
+        let chain = Chain {
+            head: 0,
+            readable: Vec::new(),
+            writable: vec![(0x1000, 5), (0x2000, 11)],
+        };
+        let mut rng = Rng::seeded(1);
+        assert_eq!(rng.handle(&chain, &mut bus).unwrap(), 16);
+        let mut data = [0; 5];
+        bus.read_bytes(0x1000, &mut data).unwrap();
+        assert_eq!(data, fill(&mut Rng::seeded(1), 5)[..]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut rng = Rng::seeded(7);
+        fill(&mut rng, 8);
+        let mut w = Writer::new();
+        rng.save(&mut w);
+        let data = w.into_bytes();
+        let expected = fill(&mut rng, 16);
+
+        let mut restored = Rng::seeded(0);
+        restored.load(&mut Reader::new(&data)).unwrap();
+        assert_eq!(fill(&mut restored, 16), expected);
+
+        // ホストの乱数とは混ぜられない
+        if let Ok(mut host) = Rng::host() {
+            assert!(matches!(
+                host.load(&mut Reader::new(&data)),
+                Err(SnapshotError::Invalid(_))
+            ));
+        }
+    }
+}
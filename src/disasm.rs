@@ -0,0 +1,265 @@
+// RV32IMA + Zicsr + 特権命令の逆アセンブラ
+
+// ABIでのレジスタ名
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// 名前の付いたCSR
+pub const CSR_NAMES: [(u16, &str); 45] = [
+    (0x000, "ustatus"),
+    (0x004, "uie"),
+    (0x005, "utvec"),
+    (0x040, "uscratch"),
+    (0x041, "uepc"),
+    (0x042, "ucause"),
+    (0x043, "utval"),
+    (0x044, "uip"),
+    (0x100, "sstatus"),
+    (0x104, "sie"),
+    (0x105, "stvec"),
+    (0x106, "scounteren"),
+    (0x140, "sscratch"),
+    (0x141, "sepc"),
+    (0x142, "scause"),
+    (0x143, "stval"),
+    (0x144, "sip"),
+    (0x180, "satp"),
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x302, "medeleg"),
+    (0x303, "mideleg"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x306, "mcounteren"),
+    (0x320, "mcountinhibit"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0xB00, "mcycle"),
+    (0xB02, "minstret"),
+    (0xB80, "mcycleh"),
+    (0xB82, "minstreth"),
+    (0xC00, "cycle"),
+    (0xC01, "time"),
+    (0xC02, "instret"),
+    (0xC80, "cycleh"),
+    (0xC81, "timeh"),
+    (0xC82, "instreth"),
+    (0xF11, "mvendorid"),
+    (0xF12, "marchid"),
+    (0xF13, "mimpid"),
+    (0xF14, "mhartid"),
+];
+
+pub fn csr_name(no: u16) -> Option<&'static str> {
+    CSR_NAMES
+        .iter()
+        .find(|(n, _)| *n == no)
+        .map(|(_, name)| *name)
+}
+
+// レジスタ名 (ABI名かxN, fp) から番号を引く
+pub fn reg_index(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(i) = REG_NAMES.iter().position(|n| *n == name) {
+        return Some(i);
+    }
+    match name.strip_prefix('x')?.parse() {
+        Ok(i) if i < 32 => Some(i),
+        _ => None,
+    }
+}
+
+fn reg(i: u32) -> &'static str {
+    REG_NAMES[(i & 0x1F) as usize]
+}
+
+fn csr(no: u32) -> String {
+    let n = no & 0x1F;
+    match (csr_name(no as u16), no) {
+        (Some(name), _) => name.to_string(),
+        (None, 0xC03..=0xC1F) => format!("hpmcounter{}", n),
+        (None, 0xC83..=0xC9F) => format!("hpmcounter{}h", n),
+        (None, 0xB03..=0xB1F) => format!("mhpmcounter{}", n),
+        (None, 0xB83..=0xB9F) => format!("mhpmcounter{}h", n),
+        (None, 0x323..=0x33F) => format!("mhpmevent{}", n),
+        (None, _) => format!("0x{:03x}", no),
+    }
+}
+
+// 命令のニーモニック。デコードできなければNone
+pub fn mnemonic(ir: u32) -> Option<&'static str> {
+    let rd = (ir >> 7) & 0x1F;
+    let rs1 = (ir >> 15) & 0x1F;
+    let rs2 = (ir >> 20) & 0x1F;
+    let funct3 = (ir >> 12) & 0x7;
+    let funct7 = ir >> 25;
+
+    let op = match ir & 0x7F {
+        0x37 => "lui",
+        0x17 => "auipc",
+        0x6F => "jal",
+        0x67 if funct3 == 0 => "jalr",
+        0x63 => match funct3 {
+            0b000 => "beq",
+            0b001 => "bne",
+            0b100 => "blt",
+            0b101 => "bge",
+            0b110 => "bltu",
+            0b111 => "bgeu",
+            _ => return None,
+        },
+        0x03 => match funct3 {
+            0b000 => "lb",
+            0b001 => "lh",
+            0b010 => "lw",
+            0b100 => "lbu",
+            0b101 => "lhu",
+            _ => return None,
+        },
+        0x23 => match funct3 {
+            0b000 => "sb",
+            0b001 => "sh",
+            0b010 => "sw",
+            _ => return None,
+        },
+        0x13 => match (funct3, funct7) {
+            (0b000, _) => "addi",
+            (0b010, _) => "slti",
+            (0b011, _) => "sltiu",
+            (0b100, _) => "xori",
+            (0b110, _) => "ori",
+            (0b111, _) => "andi",
+            (0b001, 0b0000000) => "slli",
+            (0b101, 0b0000000) => "srli",
+            (0b101, 0b0100000) => "srai",
+            _ => return None,
+        },
+        0x33 => match (funct7, funct3) {
+            (0b0000000, 0b000) => "add",
+            (0b0100000, 0b000) => "sub",
+            (0b0000000, 0b001) => "sll",
+            (0b0000000, 0b010) => "slt",
+            (0b0000000, 0b011) => "sltu",
+            (0b0000000, 0b100) => "xor",
+            (0b0000000, 0b101) => "srl",
+            (0b0100000, 0b101) => "sra",
+            (0b0000000, 0b110) => "or",
+            (0b0000000, 0b111) => "and",
+            (0b0000001, 0b000) => "mul",
+            (0b0000001, 0b001) => "mulh",
+            (0b0000001, 0b010) => "mulhsu",
+            (0b0000001, 0b011) => "mulhu",
+            (0b0000001, 0b100) => "div",
+            (0b0000001, 0b101) => "divu",
+            (0b0000001, 0b110) => "rem",
+            (0b0000001, 0b111) => "remu",
+            _ => return None,
+        },
+        0x0F => match funct3 {
+            0b000 => "fence",
+            0b001 => "fence.i",
+            _ => return None,
+        },
+        0x73 => match funct3 {
+            0b000 if funct7 == 0b0001001 && rd == 0 => "sfence.vma",
+            0b000 if rd == 0 && rs1 == 0 => match ir >> 20 {
+                0x000 => "ecall",
+                0x001 => "ebreak",
+                0x102 => "sret",
+                0x302 => "mret",
+                0x105 => "wfi",
+                _ => return None,
+            },
+            0b001 => "csrrw",
+            0b010 => "csrrs",
+            0b011 => "csrrc",
+            0b101 => "csrrwi",
+            0b110 => "csrrsi",
+            0b111 => "csrrci",
+            _ => return None,
+        },
+        0x2F if funct3 == 0b010 => match ir >> 27 {
+            0b00010 if rs2 == 0 => "lr.w",
+            0b00011 => "sc.w",
+            0b00001 => "amoswap.w",
+            0b00000 => "amoadd.w",
+            0b00100 => "amoxor.w",
+            0b01100 => "amoand.w",
+            0b01000 => "amoor.w",
+            0b10000 => "amomin.w",
+            0b10100 => "amomax.w",
+            0b11000 => "amominu.w",
+            0b11100 => "amomaxu.w",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(op)
+}
+
+// 命令の属する拡張
+pub fn extension(ir: u32) -> Option<&'static str> {
+    let op = mnemonic(ir)?;
+    let ext = match ir & 0x7F {
+        0x33 if ir >> 25 == 0b0000001 => "M",
+        0x2F => "A",
+        0x0F if op == "fence.i" => "Zifencei",
+        0x73 if op.starts_with("csr") => "Zicsr",
+        0x73 if matches!(op, "sret" | "mret" | "wfi" | "sfence.vma") => "Priv",
+        _ => "I",
+    };
+    Some(ext)
+}
+
+// pcにある命令irを逆アセンブルする
+// 分岐先は絶対アドレスで表示する
+pub fn disassemble(ir: u32, pc: u32) -> String {
+    let Some(op) = mnemonic(ir) else {
+        return format!(".word   0x{:08x}", ir);
+    };
+    let rd = (ir >> 7) & 0x1F;
+    let rs1 = (ir >> 15) & 0x1F;
+    let rs2 = (ir >> 20) & 0x1F;
+    let imm_i = (ir as i32) >> 20;
+    let imm_s = ((ir as i32) >> 25 << 5) | ((ir >> 7) & 0x1F) as i32;
+    let imm_b = ((ir as i32) >> 31 << 12)
+        | (((ir >> 7) & 1) << 11) as i32
+        | (((ir >> 25) & 0x3F) << 5) as i32
+        | (((ir >> 8) & 0xF) << 1) as i32;
+    let imm_j = ((ir as i32) >> 31 << 20)
+        | (ir & 0xF_F000) as i32
+        | (((ir >> 20) & 1) << 11) as i32
+        | (((ir >> 21) & 0x3FF) << 1) as i32;
+    let target = |imm: i32| pc.wrapping_add(imm as u32);
+
+    let args = match ir & 0x7F {
+        0x37 | 0x17 => format!("{}, 0x{:x}", reg(rd), ir >> 12),
+        0x6F => format!("{}, 0x{:x}", reg(rd), target(imm_j)),
+        0x67 | 0x03 => format!("{}, {}({})", reg(rd), imm_i, reg(rs1)),
+        0x63 => format!("{}, {}, 0x{:x}", reg(rs1), reg(rs2), target(imm_b)),
+        0x23 => format!("{}, {}({})", reg(rs2), imm_s, reg(rs1)),
+        0x13 if matches!(op, "slli" | "srli" | "srai") => {
+            format!("{}, {}, {}", reg(rd), reg(rs1), rs2)
+        }
+        0x13 => format!("{}, {}, {}", reg(rd), reg(rs1), imm_i),
+        0x33 => format!("{}, {}, {}", reg(rd), reg(rs1), reg(rs2)),
+        0x73 if op == "sfence.vma" => format!("{}, {}", reg(rs1), reg(rs2)),
+        0x73 if op.starts_with("csr") && op.ends_with('i') => {
+            format!("{}, {}, {}", reg(rd), csr(ir >> 20), rs1)
+        }
+        0x73 if op.starts_with("csr") => format!("{}, {}, {}", reg(rd), csr(ir >> 20), reg(rs1)),
+        0x2F if op == "lr.w" => format!("{}, ({})", reg(rd), reg(rs1)),
+        0x2F => format!("{}, {}, ({})", reg(rd), reg(rs2), reg(rs1)),
+        // fence, ecallなど
+        _ => return op.to_string(),
+    };
+    format!("{:<7} {}", op, args)
+}
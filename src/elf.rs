@@ -0,0 +1,391 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cpu::Cpu;
+use crate::error::{Access, Error};
+use crate::symbols::Symbols;
+
+// RV32のリトルエンディアンのELFだけを扱う
+const MAGIC: &[u8; 4] = b"\x7FELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Io(io::Error),
+    // ELFファイルではない
+    BadMagic,
+    // RV32以外など
+    Unsupported(&'static str),
+    // 途中で終わっている
+    Truncated,
+    // セグメントをメモリに置けない
+    Load(Error),
+}
+
+pub type Result<T> = std::result::Result<T, ElfError>;
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "elf io error: {}", e),
+            ElfError::BadMagic => write!(f, "not an elf file"),
+            ElfError::Unsupported(what) => write!(f, "unsupported elf: {}", what),
+            ElfError::Truncated => write!(f, "elf is truncated"),
+            ElfError::Load(e) => write!(f, "cannot load elf: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<io::Error> for ElfError {
+    fn from(e: io::Error) -> Self {
+        ElfError::Io(e)
+    }
+}
+
+// PT_LOADのセグメント
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub paddr: u32,
+    pub vaddr: u32,
+    pub offset: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Section {
+    name: String,
+    offset: u32,
+    size: u32,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u32,
+    segments: Vec<Segment>,
+    sections: Vec<Section>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < 4 || &data[..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data.len() < 52 {
+            return Err(ElfError::Truncated);
+        }
+        if data[4] != ELFCLASS32 {
+            return Err(ElfError::Unsupported("not 32-bit"));
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::Unsupported("not little endian"));
+        }
+        if u16_at(data, 18)? != EM_RISCV {
+            return Err(ElfError::Unsupported("not risc-v"));
+        }
+
+        let entry = u32_at(data, 24)?;
+        let phoff = u32_at(data, 28)? as usize;
+        let shoff = u32_at(data, 32)? as usize;
+        let phentsize = u16_at(data, 42)? as usize;
+        let phnum = u16_at(data, 44)? as usize;
+        let shentsize = u16_at(data, 46)? as usize;
+        let shnum = u16_at(data, 48)? as usize;
+        let shstrndx = u16_at(data, 50)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if u32_at(data, ph)? != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                offset: u32_at(data, ph + 4)?,
+                vaddr: u32_at(data, ph + 8)?,
+                paddr: u32_at(data, ph + 12)?,
+                filesz: u32_at(data, ph + 16)?,
+                memsz: u32_at(data, ph + 20)?,
+            };
+            slice(data, segment.offset, segment.filesz)?;
+            segments.push(segment);
+        }
+
+        // セクション名は後でshstrtabから引く
+        let mut headers = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            let name = u32_at(data, sh)?;
+            let offset = u32_at(data, sh + 16)?;
+            let size = u32_at(data, sh + 20)?;
+            // SHT_NOBITSはファイル上に中身がない
+            let size = if u32_at(data, sh + 4)? == 8 { 0 } else { size };
+            headers.push((name, offset, size));
+        }
+        let mut sections = Vec::new();
+        if let Some(&(_, offset, size)) = headers.get(shstrndx) {
+            let shstrtab = slice(data, offset, size)?;
+            for (name, offset, size) in headers {
+                sections.push(Section {
+                    name: cstr(shstrtab, name as usize).unwrap_or("").to_string(),
+                    offset,
+                    size,
+                });
+            }
+        }
+
+        Ok(Self {
+            data,
+            entry,
+            segments,
+            sections,
+        })
+    }
+
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    // 名前でセクションの中身を引く
+    pub fn section(&self, name: &str) -> Option<&'a [u8]> {
+        let section = self.sections.iter().find(|s| s.name == name)?;
+        slice(self.data, section.offset, section.size).ok()
+    }
+
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        // parseで範囲は確かめている
+        slice(self.data, segment.offset, segment.filesz).unwrap()
+    }
+}
+
+impl Cpu {
+    // ELFのセグメントを物理アドレスに置き、pcをエントリポイントにする
+    // シンボルと行番号の情報があれば読み込む
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<()> {
+        let elf = Elf::parse(data)?;
+        for segment in elf.segments() {
+            // 書き始める前にセグメント全体がRAMに収まるか確かめる
+            let start = segment.paddr as u64;
+            let end = start + segment.memsz.max(segment.filesz) as u64;
+            if end == start {
+                continue;
+            }
+            let ram_start = self.bus().ram_base() as u64;
+            let ram_end = ram_start + self.bus().ram_size() as u64;
+            if start < ram_start || end > ram_end {
+                return Err(ElfError::Load(Error::BusFault {
+                    addr: start.max(ram_end).min(end - 1) as u32,
+                    width: 1,
+                    access: Access::Store,
+                }));
+            }
+            self.write_phys(segment.paddr, elf.segment_data(segment))
+                .map_err(ElfError::Load)?;
+            // bssは少しずつ0で埋める
+            let zeros = [0; 4096];
+            let mut addr = segment.paddr.wrapping_add(segment.filesz);
+            let mut rest = segment.memsz.saturating_sub(segment.filesz);
+            while rest > 0 {
+                let n = rest.min(zeros.len() as u32);
+                self.write_phys(addr, &zeros[..n as usize])
+                    .map_err(ElfError::Load)?;
+                addr = addr.wrapping_add(n);
+                rest -= n;
+            }
+        }
+        self.set_pc(elf.entry());
+        self.set_symbols(Symbols::from_elf(&elf));
+        Ok(())
+    }
+
+    pub fn load_elf_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.load_elf(&fs::read(path)?)
+    }
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn slice(data: &[u8], offset: u32, size: u32) -> Result<&[u8]> {
+    let start = offset as usize;
+    let end = start
+        .checked_add(size as usize)
+        .ok_or(ElfError::Truncated)?;
+    data.get(start..end).ok_or(ElfError::Truncated)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// NUL終端の文字列
+pub(crate) fn cstr(data: &[u8], offset: usize) -> Option<&str> {
+    let rest = data.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&rest[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Elf, ElfError};
+    use crate::cpu::testing::machine;
+    use crate::error::{Access, Error};
+
+    // PT_LOADのセグメント (paddr, 中身, memsz) とセクション (名前, 中身) からELFを作る
+    fn build(entry: u32, segments: &[(u32, &[u8], u32)], sections: &[(&str, &[u8])]) -> Vec<u8> {
+        let phoff = 52;
+        let mut data = vec![0; phoff + segments.len() * 32];
+        data[..4].copy_from_slice(b"\x7FELF");
+        data[4] = 1;
+        data[5] = 1;
+        data[6] = 1;
+        put16(&mut data, 16, 2);
+        put16(&mut data, 18, 243);
+        put32(&mut data, 20, 1);
+        put32(&mut data, 24, entry);
+        put32(&mut data, 28, phoff as u32);
+        put16(&mut data, 40, 52);
+        put16(&mut data, 42, 32);
+        put16(&mut data, 44, segments.len() as u16);
+
+        for (i, &(paddr, contents, memsz)) in segments.iter().enumerate() {
+            let ph = phoff + i * 32;
+            let offset = data.len() as u32;
+            put32(&mut data, ph, 1);
+            put32(&mut data, ph + 4, offset);
+            put32(&mut data, ph + 8, paddr);
+            put32(&mut data, ph + 12, paddr);
+            put32(&mut data, ph + 16, contents.len() as u32);
+            put32(&mut data, ph + 20, memsz);
+            data.extend_from_slice(contents);
+        }
+
+        // 0番は空のセクションで、最後がshstrtab
+        let mut shstrtab = vec![0];
+        let mut headers = vec![(0, 0, 0)];
+        for &(name, contents) in sections.iter().chain([(".shstrtab", &[][..])].iter()) {
+            let name_off = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+            let contents = if name == ".shstrtab" {
+                &shstrtab[..]
+            } else {
+                contents
+            };
+            headers.push((name_off, data.len() as u32, contents.len() as u32));
+            data.extend_from_slice(contents);
+        }
+        let shoff = data.len();
+        for (name, offset, size) in &headers {
+            let mut sh = [0; 40];
+            put32(&mut sh, 0, *name);
+            put32(&mut sh, 16, *offset);
+            put32(&mut sh, 20, *size);
+            data.extend_from_slice(&sh);
+        }
+        put32(&mut data, 32, shoff as u32);
+        put16(&mut data, 46, 40);
+        put16(&mut data, 48, headers.len() as u16);
+        put16(&mut data, 50, headers.len() as u16 - 1);
+        data
+    }
+
+    fn put16(data: &mut [u8], offset: usize, val: u16) {
+        data[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn put32(data: &mut [u8], offset: usize, val: u32) {
+        data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    #[test]
+    fn loads_segments_and_clears_bss() {
+        let data = build(
+            0x104,
+            &[(0x100, &[1, 2, 3, 4], 0x2000), (0x3000, &[5], 1)],
+            &[(".comment", b"hello")],
+        );
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry(), 0x104);
+        assert_eq!(elf.segments().len(), 2);
+        assert_eq!(elf.section(".comment"), Some(&b"hello"[..]));
+        assert_eq!(elf.section(".text"), None);
+
+        let mut cpu = machine(&[]);
+        cpu.write_phys(0x1000, &[0xFF; 0x1200]).unwrap();
+        cpu.load_elf(&data).unwrap();
+        assert_eq!(cpu.pc(), 0x104);
+        let mut buf = [0xAA; 8];
+        cpu.read_phys(0x100, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 0, 0, 0, 0]);
+        cpu.read_phys(0x20FC, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        cpu.read_phys(0x3000, &mut buf[..1]).unwrap();
+        assert_eq!(buf[0], 5);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let good = build(0, &[(0x100, &[1, 2, 3, 4], 4)], &[]);
+        assert!(Elf::parse(&good).is_ok());
+        assert!(matches!(Elf::parse(b"\x7FEL"), Err(ElfError::BadMagic)));
+        assert!(matches!(Elf::parse(&good[..40]), Err(ElfError::Truncated)));
+
+        let patched = |offset: usize, bytes: &[u8]| {
+            let mut data = good.clone();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            data
+        };
+        for (offset, bytes) in [(4, &[2][..]), (5, &[2]), (18, &[0x3E, 0])] {
+            assert!(matches!(
+                Elf::parse(&patched(offset, bytes)),
+                Err(ElfError::Unsupported(_))
+            ));
+        }
+        // セグメントがファイルの外を指す
+        for (offset, val) in [(28, 0xFFFF_FFF0u32), (52 + 4, 0x1000), (52 + 16, u32::MAX)] {
+            let data = patched(offset, &val.to_le_bytes());
+            assert!(matches!(Elf::parse(&data), Err(ElfError::Truncated)));
+        }
+        // セクションヘッダがファイルの外にある
+        let data = patched(32, &0x1000u32.to_le_bytes());
+        assert!(matches!(Elf::parse(&data), Err(ElfError::Truncated)));
+    }
+
+    // RAMからはみ出すセグメントは書き始める前に断る
+    #[test]
+    fn segment_outside_ram() {
+        let mut cpu = machine(&[]);
+        let data = build(0, &[(0x3F00, &[1; 0x10], 0x200)], &[]);
+        let err = cpu.load_elf(&data).unwrap_err();
+        assert!(matches!(
+            err,
+            ElfError::Load(Error::BusFault {
+                addr: 0x4000,
+                width: 1,
+                access: Access::Store
+            })
+        ));
+        let mut buf = [0xAA; 4];
+        cpu.read_phys(0x3F00, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(cpu.pc(), 0);
+    }
+}
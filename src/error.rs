@@ -0,0 +1,124 @@
+use std::fmt;
+
+// バスアクセスの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // デコードできない命令
+    IllegalInstruction {
+        ir: u32,
+        pc: u32,
+    },
+    // マップされていないアドレスへのアクセス
+    BusFault {
+        addr: u32,
+        width: u8,
+        access: Access,
+    },
+    // 未実装のCSR
+    UnimplementedCsr {
+        no: u16,
+        pc: u32,
+    },
+    // ページテーブルで変換できないアクセス
+    // 常にゲストの例外として処理される
+    PageFault {
+        addr: u32,
+        access: Access,
+    },
+    // アラインされていないアドレスへのアクセスかジャンプ
+    // 常にゲストの例外として処理される
+    Misaligned {
+        addr: u32,
+        access: Access,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // 対応する例外のmcause
+    pub fn cause(&self) -> u32 {
+        match self {
+            Error::IllegalInstruction { .. } | Error::UnimplementedCsr { .. } => 2,
+            Error::BusFault { access, .. } => match access {
+                Access::Fetch => 1,
+                Access::Load => 5,
+                Access::Store => 7,
+            },
+            Error::PageFault { access, .. } => match access {
+                Access::Fetch => 12,
+                Access::Load => 13,
+                Access::Store => 15,
+            },
+            Error::Misaligned { access, .. } => match access {
+                Access::Fetch => 0,
+                Access::Load => 4,
+                Access::Store => 6,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IllegalInstruction { ir, pc } => {
+                write!(f, "illegal instruction {:08X} at pc {:08X}", ir, pc)
+            }
+            Error::BusFault {
+                addr,
+                width,
+                access,
+            } => write!(
+                f,
+                "bus fault on {:?} of {} bytes at {:08X}",
+                access, width, addr
+            ),
+            Error::UnimplementedCsr { no, pc } => {
+                write!(f, "unimplemented csr {:03X} at pc {:08X}", no, pc)
+            }
+            Error::PageFault { addr, access } => {
+                write!(f, "page fault on {:?} at {:08X}", access, addr)
+            }
+            Error::Misaligned { addr, access } => {
+                write!(f, "misaligned {:?} at {:08X}", access, addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Error};
+
+    // 例外にした時のmcauseと表示
+    #[test]
+    fn causes_and_display() {
+        let fault = |access| Error::BusFault {
+            addr: 0,
+            width: 4,
+            access,
+        };
+        assert_eq!(fault(Access::Fetch).cause(), 1);
+        assert_eq!(fault(Access::Load).cause(), 5);
+        assert_eq!(fault(Access::Store).cause(), 7);
+        let misaligned = Error::Misaligned {
+            addr: 1,
+            access: Access::Store,
+        };
+        assert_eq!(misaligned.cause(), 6);
+        assert_eq!(
+            Error::UnimplementedCsr { no: 0x7C0, pc: 4 }.to_string(),
+            "unimplemented csr 7C0 at pc 00000004"
+        );
+    }
+}
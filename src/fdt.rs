@@ -0,0 +1,394 @@
+use crate::bus::{self, Bus};
+use crate::cpu::Cpu;
+use crate::device::{clint, plic, uart, virtio};
+
+// エミュレータの構成をFlattened Device Tree (DTB) にする
+// アドレスや割り込み番号はBusの定数から取るので、Busの配置を変えればここも追従する
+const MAGIC: u32 = 0xD00D_FEED;
+const VERSION: u32 = 17;
+const LAST_COMP_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const PHANDLE_INTC: u32 = 1;
+const PHANDLE_PLIC: u32 = 2;
+
+// mipのビット番号 (CLINTとPLICがどの割り込みを立てるか)
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+// --hzの指定が無い時のmtimeの周波数
+pub const DEFAULT_TIMEBASE_FREQUENCY: u32 = 10_000_000;
+const UART_CLOCK: u32 = 3_686_400;
+
+#[derive(Debug, Clone)]
+pub struct FdtConfig {
+    pub bootargs: Option<String>,
+    // initrdを置いた範囲 (start, end)
+    pub initrd: Option<(u32, u32)>,
+    pub timebase_frequency: u32,
+}
+
+impl Default for FdtConfig {
+    fn default() -> Self {
+        Self {
+            bootargs: None,
+            initrd: None,
+            timebase_frequency: DEFAULT_TIMEBASE_FREQUENCY,
+        }
+    }
+}
+
+struct Builder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    // 4バイト境界まで0で埋める
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    fn end(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    // プロパティ名は文字列ブロックで共有する
+    fn name_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let offset = self.name_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_u32(&mut self, name: &str, val: u32) {
+        self.prop(name, &val.to_be_bytes());
+    }
+
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    fn prop_str(&mut self, name: &str, val: &str) {
+        self.prop_strs(name, &[val]);
+    }
+
+    fn prop_strs(&mut self, name: &str, vals: &[&str]) {
+        let mut value = Vec::new();
+        for val in vals {
+            value.extend_from_slice(val.as_bytes());
+            value.push(0);
+        }
+        self.prop(name, &value);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        // ヘッダの後に空の予約領域 (終端の16バイト) を置く
+        let off_mem_rsvmap = HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total = off_dt_strings + self.strings.len();
+
+        let mut dtb = Vec::with_capacity(total);
+        for val in [
+            MAGIC,
+            total as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            VERSION,
+            LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            dtb.extend_from_slice(&val.to_be_bytes());
+        }
+        dtb.extend_from_slice(&[0; 16]);
+        dtb.extend_from_slice(&self.structure);
+        dtb.extend_from_slice(&self.strings);
+        dtb
+    }
+}
+
+// misaからriscv,isaの文字列を作る
+fn isa_string(misa: u32) -> String {
+    let mut isa = String::from("rv32");
+    for ext in "imafdqc".chars() {
+        if misa & (1 << (ext as u32 - 'a' as u32)) != 0 {
+            isa.push(ext);
+        }
+    }
+    isa.push_str("_zicsr_zifencei");
+    isa
+}
+
+pub fn build(bus: &Bus, misa: u32, hartid: u32, config: &FdtConfig) -> Vec<u8> {
+    let mut fdt = Builder::new();
+    fdt.begin("");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "mj-hd,risc-v");
+    fdt.prop_str("model", "mj-hd,risc-v");
+
+    fdt.begin("chosen");
+    if let Some(bootargs) = &config.bootargs {
+        fdt.prop_str("bootargs", bootargs);
+    }
+    if let Some((start, end)) = config.initrd {
+        fdt.prop_u32("linux,initrd-start", start);
+        fdt.prop_u32("linux,initrd-end", end);
+    }
+    fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", bus::UART_BASE));
+    fdt.end();
+
+    fdt.begin(&format!("memory@{:x}", bus.ram_base()));
+    fdt.prop_str("device_type", "memory");
+    fdt.prop_cells("reg", &[bus.ram_base(), bus.ram_size()]);
+    fdt.end();
+
+    fdt.begin("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", config.timebase_frequency);
+    fdt.begin(&format!("cpu@{:x}", hartid));
+    fdt.prop_str("device_type", "cpu");
+    fdt.prop_u32("reg", hartid);
+    fdt.prop_str("status", "okay");
+    fdt.prop_str("compatible", "riscv");
+    fdt.prop_str("riscv,isa", &isa_string(misa));
+    fdt.prop_str("mmu-type", "riscv,sv32");
+    fdt.begin("interrupt-controller");
+    fdt.prop_u32("#interrupt-cells", 1);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_str("compatible", "riscv,cpu-intc");
+    fdt.prop_u32("phandle", PHANDLE_INTC);
+    fdt.end();
+    fdt.end();
+    fdt.end();
+
+    fdt.begin("soc");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+
+    fdt.begin(&format!("clint@{:x}", bus::CLINT_BASE));
+    fdt.prop_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.prop_cells("reg", &[bus::CLINT_BASE, clint::SIZE]);
+    fdt.prop_cells(
+        "interrupts-extended",
+        &[PHANDLE_INTC, IRQ_M_SOFT, PHANDLE_INTC, IRQ_M_TIMER],
+    );
+    fdt.end();
+
+    // コンテキスト0がM-mode、1がS-mode
+    fdt.begin(&format!("plic@{:x}", bus::PLIC_BASE));
+    fdt.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.prop_cells("reg", &[bus::PLIC_BASE, plic::SIZE]);
+    fdt.prop_u32("#address-cells", 0);
+    fdt.prop_u32("#interrupt-cells", 1);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_u32("riscv,ndev", plic::SOURCES as u32 - 1);
+    fdt.prop_cells(
+        "interrupts-extended",
+        &[PHANDLE_INTC, IRQ_M_EXT, PHANDLE_INTC, IRQ_S_EXT],
+    );
+    fdt.prop_u32("phandle", PHANDLE_PLIC);
+    fdt.end();
+
+    fdt.begin(&format!("serial@{:x}", bus::UART_BASE));
+    fdt.prop_str("compatible", "ns16550a");
+    fdt.prop_cells("reg", &[bus::UART_BASE, uart::SIZE]);
+    fdt.prop_u32("clock-frequency", UART_CLOCK);
+    fdt.prop_u32("interrupt-parent", PHANDLE_PLIC);
+    fdt.prop_u32("interrupts", bus::UART_IRQ as u32);
+    fdt.end();
+
+    // つないだスロットだけ載せる
+    for slot in 0..bus.virtio().len() {
+        let base = bus::VIRTIO_BASE + slot as u32 * virtio::SIZE;
+        fdt.begin(&format!("virtio_mmio@{:x}", base));
+        fdt.prop_str("compatible", "virtio,mmio");
+        fdt.prop_cells("reg", &[base, virtio::SIZE]);
+        fdt.prop_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.prop_u32("interrupts", (bus::VIRTIO_IRQ + slot) as u32);
+        fdt.end();
+    }
+    fdt.end();
+
+    fdt.end();
+    fdt.finish()
+}
+
+impl Cpu {
+    // 今の構成のDTBを作る
+    pub fn fdt(&self, config: &FdtConfig) -> Vec<u8> {
+        // misaとmhartidは読み出しで失敗しない
+        let misa = self.read_csr(0x301).unwrap_or(0);
+        let hartid = self.read_csr(0xF14).unwrap_or(0);
+        build(self.bus(), misa, hartid, config)
+    }
+
+    // DTBをRAMの最後 (initrdがあればその手前) に置き、起動時の引数としてa0にhartid、a1にアドレスを入れる
+    // 入りきらなければNone
+    pub fn load_fdt(&mut self, config: &FdtConfig) -> Option<u32> {
+        let dtb = self.fdt(config);
+        let top = match config.initrd {
+            Some((start, _)) => start,
+            None => self.bus().ram_base() + self.bus().ram_size(),
+        };
+        // DTBは8バイト境界に置く
+        let addr = top.checked_sub(dtb.len() as u32)? & !7;
+        self.write_phys(addr, &dtb).ok()?;
+        let hartid = self.read_csr(0xF14).unwrap_or(0);
+        self.set_x(10, hartid);
+        self.set_x(11, addr);
+        Some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::cstr;
+
+    fn be32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    // 構造ブロックをたどり、(ノードのパス, プロパティ名, 値) を集める
+    fn walk(dtb: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let off_struct = be32(dtb, 8) as usize;
+        let off_strings = be32(dtb, 12) as usize;
+        let size_strings = be32(dtb, 32) as usize;
+        let size_struct = be32(dtb, 36) as usize;
+        assert_eq!(off_struct + size_struct, off_strings);
+        let strings = &dtb[off_strings..off_strings + size_strings];
+
+        let mut props = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut pos = off_struct;
+        loop {
+            assert_eq!(pos % 4, 0);
+            let token = be32(dtb, pos);
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(dtb, pos).unwrap();
+                    pos = (pos + name.len() + 1).next_multiple_of(4);
+                    path.push(name.to_string());
+                }
+                FDT_END_NODE => {
+                    path.pop().unwrap();
+                }
+                FDT_PROP => {
+                    let len = be32(dtb, pos) as usize;
+                    let name = cstr(strings, be32(dtb, pos + 4) as usize).unwrap();
+                    let value = dtb[pos + 8..pos + 8 + len].to_vec();
+                    pos = (pos + 8 + len).next_multiple_of(4);
+                    props.push((path.join("/"), name.to_string(), value));
+                }
+                FDT_END => break,
+                _ => panic!("bad token {} at {}", token, pos - 4),
+            }
+        }
+        assert!(path.is_empty());
+        assert_eq!(pos, off_strings);
+        props
+    }
+
+    #[test]
+    fn header_and_structure_are_consistent() {
+        let mut bus = Bus::with_ram(0x8000_0000, 0x10_0000).unwrap();
+        bus.add_virtio(virtio::Device::Rng(virtio::Rng::seeded(1)))
+            .unwrap();
+        let config = FdtConfig {
+            bootargs: Some("console=hvc0".to_string()),
+            initrd: Some((0x800F_0000, 0x800F_8000)),
+            ..FdtConfig::default()
+        };
+        let dtb = build(&bus, (1 << 30) | (1 << 8) | (1 << 12), 0, &config);
+
+        assert_eq!(be32(&dtb, 0), MAGIC);
+        assert_eq!(be32(&dtb, 4) as usize, dtb.len());
+        assert_eq!(be32(&dtb, 20), VERSION);
+        assert_eq!(be32(&dtb, 24), LAST_COMP_VERSION);
+        // 予約領域は終端だけ
+        let rsvmap = be32(&dtb, 16) as usize;
+        assert_eq!(dtb[rsvmap..rsvmap + 16], [0; 16]);
+
+        let props = walk(&dtb);
+        let find = |path: &str, name: &str| {
+            props
+                .iter()
+                .find(|(p, n, _)| p == path && n == name)
+                .map(|(_, _, v)| v.clone())
+                .unwrap_or_else(|| panic!("{} {}", path, name))
+        };
+        assert_eq!(find("/chosen", "bootargs"), b"console=hvc0\0");
+        assert_eq!(
+            find("/chosen", "linux,initrd-start"),
+            0x800F_0000u32.to_be_bytes()
+        );
+        assert_eq!(
+            find("/memory@80000000", "reg"),
+            [0x8000_0000u32.to_be_bytes(), 0x10_0000u32.to_be_bytes()].concat()
+        );
+        assert_eq!(find("/cpus/cpu@0", "riscv,isa"), b"rv32im_zicsr_zifencei\0");
+        assert_eq!(
+            find("/soc/virtio_mmio@10001000", "interrupts"),
+            (bus::VIRTIO_IRQ as u32).to_be_bytes()
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use crate::cpu::{Cpu, Privilege};
+
+// メモリアクセスの内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub vaddr: u32,
+    pub paddr: u32,
+    pub width: u8,
+    pub val: u32,
+}
+
+// ゲストからのCSRアクセス
+// writeは書き込みが起きなかった時はNone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrAccess {
+    pub no: u16,
+    pub read: u32,
+    pub write: Option<u32>,
+}
+
+// トラップ (例外と割り込み)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapEvent {
+    pub cause: u32,
+    pub tval: u32,
+    pub epc: u32,
+    pub from: Privilege,
+    pub to: Privilege,
+}
+
+pub type InstHook = Box<dyn FnMut(&Cpu, u32, u32)>;
+pub type MemHook = Box<dyn FnMut(MemAccess)>;
+pub type CsrHook = Box<dyn FnMut(CsrAccess)>;
+pub type TrapHook = Box<dyn FnMut(TrapEvent)>;
+pub type PrivilegeHook = Box<dyn FnMut(Privilege, Privilege)>;
+
+// Cpuのイベントに登録するコールバック
+// 登録されていないフックはOptionの判定だけで済む
+#[derive(Default)]
+pub struct Hooks {
+    pub(crate) before_inst: Option<InstHook>,
+    pub(crate) after_inst: Option<InstHook>,
+    pub(crate) mem_read: Option<MemHook>,
+    pub(crate) mem_write: Option<MemHook>,
+    pub(crate) csr: Option<CsrHook>,
+    pub(crate) trap: Option<TrapHook>,
+    pub(crate) privilege: Option<PrivilegeHook>,
+}
+
+impl Hooks {
+    // 命令の実行前に (cpu, pc, 命令) で呼ばれる
+    pub fn on_before_inst(&mut self, f: impl FnMut(&Cpu, u32, u32) + 'static) {
+        self.before_inst = Some(Box::new(f));
+    }
+
+    // 命令の実行後に (cpu, 実行したpc, 命令) で呼ばれる
+    pub fn on_after_inst(&mut self, f: impl FnMut(&Cpu, u32, u32) + 'static) {
+        self.after_inst = Some(Box::new(f));
+    }
+
+    pub fn on_mem_read(&mut self, f: impl FnMut(MemAccess) + 'static) {
+        self.mem_read = Some(Box::new(f));
+    }
+
+    pub fn on_mem_write(&mut self, f: impl FnMut(MemAccess) + 'static) {
+        self.mem_write = Some(Box::new(f));
+    }
+
+    pub fn on_csr(&mut self, f: impl FnMut(CsrAccess) + 'static) {
+        self.csr = Some(Box::new(f));
+    }
+
+    pub fn on_trap(&mut self, f: impl FnMut(TrapEvent) + 'static) {
+        self.trap = Some(Box::new(f));
+    }
+
+    // 特権レベルが変わった時に (変更前, 変更後) で呼ばれる
+    pub fn on_privilege(&mut self, f: impl FnMut(Privilege, Privilege) + 'static) {
+        self.privilege = Some(Box::new(f));
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{CsrAccess, MemAccess, TrapEvent};
+    use crate::cpu::testing::machine;
+    use crate::cpu::Privilege;
+
+    // U-modeで読み書きしてecallし、M-modeのハンドラでCSRを書く
+    const PROGRAM: [u32; 7] = [
+        0x1000_0293, // li    t0, 256
+        0x02A0_0313, // li    t1, 42
+        0x0062_A023, // sw    t1, 0(t0)
+        0x0002_A383, // lw    t2, 0(t0)
+        0x0000_0073, // ecall
+        0x3403_1E73, // csrrw t3, mscratch, t1
+        0x0000_006F, // j     .
+    ];
+
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        Before(u32, u32),
+        After(u32, u32),
+        Read(MemAccess),
+        Write(MemAccess),
+        Csr(CsrAccess),
+        Trap(TrapEvent),
+        Privilege(Privilege, Privilege),
+    }
+
+    #[test]
+    fn hooks_see_events_in_order() {
+        let mut cpu = machine(&PROGRAM);
+        cpu.write_csr(0x305, 0x14).unwrap();
+        cpu.write_csr(0x340, 7).unwrap();
+        cpu.set_privilege(Privilege::User);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let hooks = cpu.hooks_mut();
+        let s = seen.clone();
+        hooks.on_before_inst(move |_, pc, inst| s.borrow_mut().push(Seen::Before(pc, inst)));
+        let s = seen.clone();
+        hooks.on_after_inst(move |_, pc, inst| s.borrow_mut().push(Seen::After(pc, inst)));
+        let s = seen.clone();
+        hooks.on_mem_read(move |a| s.borrow_mut().push(Seen::Read(a)));
+        let s = seen.clone();
+        hooks.on_mem_write(move |a| s.borrow_mut().push(Seen::Write(a)));
+        let s = seen.clone();
+        hooks.on_csr(move |a| s.borrow_mut().push(Seen::Csr(a)));
+        let s = seen.clone();
+        hooks.on_trap(move |t| s.borrow_mut().push(Seen::Trap(t)));
+        let s = seen.clone();
+        hooks.on_privilege(move |from, to| s.borrow_mut().push(Seen::Privilege(from, to)));
+
+        cpu.step(6);
+        let access = MemAccess {
+            vaddr: 0x100,
+            paddr: 0x100,
+            width: 4,
+            val: 42,
+        };
+        let expected = [
+            Seen::Before(0x00, PROGRAM[0]),
+            Seen::After(0x00, PROGRAM[0]),
+            Seen::Before(0x04, PROGRAM[1]),
+            Seen::After(0x04, PROGRAM[1]),
+            Seen::Before(0x08, PROGRAM[2]),
+            Seen::Write(access),
+            Seen::After(0x08, PROGRAM[2]),
+            Seen::Before(0x0C, PROGRAM[3]),
+            Seen::Read(access),
+            Seen::After(0x0C, PROGRAM[3]),
+            Seen::Before(0x10, PROGRAM[4]),
+            Seen::Privilege(Privilege::User, Privilege::Machine),
+            Seen::Trap(TrapEvent {
+                cause: 8,
+                tval: 0,
+                epc: 0x10,
+                from: Privilege::User,
+                to: Privilege::Machine,
+            }),
+            Seen::After(0x10, PROGRAM[4]),
+            Seen::Before(0x14, PROGRAM[5]),
+            Seen::Csr(CsrAccess {
+                no: 0x340,
+                read: 7,
+                write: Some(42),
+            }),
+            Seen::After(0x14, PROGRAM[5]),
+        ];
+        assert_eq!(*seen.borrow(), expected);
+    }
+
+    #[test]
+    fn clear_removes_hooks() {
+        let mut cpu = machine(&PROGRAM);
+        let count = Rc::new(RefCell::new(0));
+        let c = count.clone();
+        cpu.hooks_mut()
+            .on_after_inst(move |_, _, _| *c.borrow_mut() += 1);
+        cpu.step(2);
+        cpu.hooks_mut().clear();
+        cpu.step(2);
+        assert_eq!(*count.borrow(), 2);
+        assert_eq!(cpu.pc(), 0x10);
+    }
+}
@@ -0,0 +1,20 @@
+pub mod boot;
+pub mod branch;
+pub mod bus;
+pub mod cache;
+pub mod cpu;
+mod decode_cache;
+pub mod device;
+pub mod disasm;
+pub mod elf;
+pub mod error;
+pub mod fdt;
+pub mod hooks;
+pub mod mmu;
+pub mod monitor;
+pub mod pipeline;
+pub mod profile;
+pub mod snapshot;
+pub mod stats;
+pub mod symbols;
+pub mod timing;
@@ -1,18 +1,906 @@
-use std::{thread, time::Duration};
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
 
-use bus::Bus;
-use cpu::Cpu;
+use risc_v::boot::{self, BootConfig};
+use risc_v::branch::{BranchConfig, BranchUnit};
+use risc_v::bus::Bus;
+use risc_v::cache::{CacheConfig, Caches, CachesConfig};
+#[cfg(feature = "jit")]
+use risc_v::cpu::JitMode;
+use risc_v::cpu::{Cpu, HaltReason, MisalignedPolicy, ReplayLog};
+use risc_v::device::virtio::console::{self, Backend};
+use risc_v::device::virtio::{net, Blk, Console, Device, Net, Rng};
+use risc_v::disasm;
+use risc_v::elf;
+use risc_v::fdt::{self, FdtConfig};
+use risc_v::monitor::{self, Monitor};
+use risc_v::pipeline::{Pipeline, PipelineConfig, TraceFormat};
+use risc_v::profile::{Profiler, Weight};
+use risc_v::stats::Stats;
+use risc_v::timing::{FixedLatency, Latencies};
 
-pub mod bus;
-pub mod cpu;
+const USAGE: &str = concat!(
+    "usage: risc-v [--monitor] [--trace] [--profile out.folded] [--stats table|json]\n",
+    "              [--timing alu=1,div=34,...] [--timebase cycles] [--hz freq]\n",
+    "              [--predictor btfn|bimodal|gshare|tage[,bits=10,ras=8,btb=0,penalty=3]]\n",
+    "              [--pipeline forwarding=1,mul=3,div=34,trap=1] [--pipetrace konata|o3:file]\n",
+    "              [--cache l1i|l1d|l2[:size=4k,ways=2,line=32,policy=lru,write=back,latency=0]]\n",
+    "              [--mem-latency cycles] [--cache-region name=start-end]\n",
+    "              [--misaligned trap|emulate|split] [--disk image[,ro]]\n",
+    "              [--net pcap:file|socket:local,peer|tap:name[,mac=52:54:00:12:34:56]]\n",
+    "              [--console stdio|socket:path[,name=port]] [--rng seed=N|host]\n",
+    "              [--dtb] [--dump-dtb file] [--bootargs args] [--initrd file]\n",
+    "              [--memory size[@base]] [--reset-vector addr]\n",
+    "              [--firmware fw_jump|fw_dynamic] [--kernel Image]\n",
+    "              [--record file] [--replay file] [--jit off|on|check] [image]\n",
+    "              (--kernel without --firmware boots in S-mode with the built-in SBI)"
+);
+
+// 一度に実行する命令数 (この間隔でUARTの出力とCtrl-Cを見る)
+const CHUNK: u64 = 100_000;
+
+// 終了時に表示する関数の数
+const PROFILE_TOP: usize = 20;
+// 終了時に表示する分岐命令と関数の数
+const BRANCH_TOP: usize = 20;
 
 fn main() {
-    let bus = Bus::new();
+    let mut monitor = false;
+    let mut trace = false;
+    let mut profile = None;
+    let mut stats_format = None;
+    let mut latencies = None;
+    let mut predictor = None;
+    let mut pipeline = None;
+    let mut pipetrace = None;
+    let mut timebase = 1;
+    let mut hz = None;
+    let mut caches = None;
+    let mut regions = Vec::new();
+    let mut misaligned = MisalignedPolicy::Emulate;
+    let mut disks = Vec::new();
+    let mut nets = Vec::new();
+    let mut consoles = Vec::new();
+    let mut rng = None;
+    let mut dtb = false;
+    let mut dump_dtb = None;
+    let mut bootargs = None;
+    let mut initrd = None;
+    let mut memory = None;
+    let mut reset_vector = None;
+    let mut firmware = None;
+    let mut kernel = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut jit = None;
+    let mut image = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--monitor" | "-m" => monitor = true,
+            "--trace" | "-t" => trace = true,
+            "--profile" | "-p" => match args.next() {
+                Some(path) => profile = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--stats" | "-s" => match args.next().as_deref() {
+                Some(format @ ("table" | "json")) => stats_format = Some(format.to_string()),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--timing" => match args.next().map(|spec| Latencies::parse(&spec)) {
+                Some(Ok(l)) => latencies = Some(l),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--predictor" => match args.next().map(|spec| BranchConfig::parse(&spec)) {
+                Some(Ok(config)) => predictor = Some(config),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--pipeline" => match args.next().map(|spec| PipelineConfig::parse(&spec)) {
+                Some(Ok(config)) => pipeline = Some(config),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--pipetrace" => match args.next().as_deref().and_then(parse_pipetrace) {
+                Some(trace) => pipetrace = Some(trace),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--timebase" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => timebase = n,
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--misaligned" => match args.next().as_deref() {
+                Some("trap") => misaligned = MisalignedPolicy::Trap,
+                Some("emulate") => misaligned = MisalignedPolicy::Emulate,
+                Some("split") => misaligned = MisalignedPolicy::Split,
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--hz" => match args.next().and_then(|f| f.parse::<f64>().ok()) {
+                Some(f) if f > 0.0 => hz = Some(f),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--cache" => {
+                let config = caches.get_or_insert_with(CachesConfig::default);
+                match args.next().map(|spec| parse_cache(config, &spec)) {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                    None => {
+                        eprintln!("{}", USAGE);
+                        process::exit(1);
+                    }
+                }
+            }
+            "--mem-latency" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => {
+                    caches
+                        .get_or_insert_with(CachesConfig::default)
+                        .memory_latency = n
+                }
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--cache-region" => match args.next().as_deref().and_then(parse_region) {
+                Some(region) => regions.push(region),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--disk" => match args.next() {
+                Some(spec) => disks.push(parse_disk(&spec)),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--net" => match args.next().as_deref().and_then(parse_net) {
+                Some(net) => nets.push(net),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--console" => match args.next() {
+                Some(spec) => consoles.push(spec),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--rng" => match args.next().as_deref().and_then(parse_rng) {
+                Some(source) => rng = Some(source),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--dtb" => dtb = true,
+            "--dump-dtb" => match args.next() {
+                Some(path) => dump_dtb = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--bootargs" => match args.next() {
+                Some(args) => bootargs = Some(args),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--initrd" => match args.next() {
+                Some(path) => initrd = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--memory" => match args.next().as_deref().and_then(parse_memory) {
+                Some(m) => memory = Some(m),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--reset-vector" => match args
+                .next()
+                .and_then(|a| u32::from_str_radix(a.trim_start_matches("0x"), 16).ok())
+            {
+                Some(addr) => reset_vector = Some(addr),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--firmware" => match args.next() {
+                Some(path) => firmware = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--kernel" => match args.next() {
+                Some(path) => kernel = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--record" => match args.next() {
+                Some(path) => record = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--replay" => match args.next() {
+                Some(path) => replay = Some(path),
+                None => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--jit" => match args.next().as_deref() {
+                Some(mode @ ("off" | "on" | "check")) => jit = Some(mode.to_string()),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if image.is_none() => image = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(1);
+            }
+        }
+    }
+
+    // ファームウェアが無くカーネルだけならエミュレータのSBIで起動する
+    let booting = firmware.is_some() || kernel.is_some();
+    if booting && image.is_some() {
+        eprintln!("--firmware and --kernel cannot be used with an image");
+        process::exit(1);
+    }
+    // 起動時はROMから始める
+    if booting && reset_vector.is_some() {
+        eprintln!("--reset-vector cannot be used with --firmware or --kernel");
+        process::exit(1);
+    }
+    // ファームウェアやカーネルから起動する時はQEMUのvirtと同じ位置にRAMを置く
+    let memory = match memory {
+        Some(memory) => Some(memory),
+        None if booting => Some((boot::RAM_SIZE, boot::RAM_BASE)),
+        None => None,
+    };
+    let bus = match memory {
+        Some((size, base)) => Bus::with_ram(base, size).unwrap_or_else(|| {
+            eprintln!(
+                "memory at {:08x}-{:08x} overlaps devices",
+                base,
+                base as u64 + size as u64
+            );
+            process::exit(1);
+        }),
+        None => Bus::new(),
+    };
     let mut cpu = Cpu::new(bus);
+    // 指定が無ければ1命令1サイクル。分岐予測だけなら既定のレイテンシのモデルに付ける
+    if pipetrace.is_some() && pipeline.is_none() {
+        pipeline = Some(PipelineConfig::default());
+    }
+    if pipeline.is_some() && latencies.is_some() {
+        eprintln!("--timing and --pipeline cannot be used together");
+        process::exit(1);
+    }
+    let timing = latencies.is_some() || predictor.is_some() || pipeline.is_some();
+    if let Some(config) = pipeline {
+        let mut model = Pipeline::new(config);
+        if let Some(config) = predictor {
+            model = model.with_branches(BranchUnit::new(config));
+        }
+        if let Some((format, path)) = pipetrace {
+            let file = File::create(&path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            });
+            model = model.with_trace(format, Box::new(BufWriter::new(file)));
+        }
+        cpu.set_timing_model(Some(Box::new(model)));
+    } else if timing {
+        let latencies = latencies.unwrap_or_default();
+        let model = match predictor {
+            Some(config) => FixedLatency::with_branches(latencies, BranchUnit::new(config)),
+            None => FixedLatency::new(latencies),
+        };
+        cpu.set_timing_model(Some(Box::new(model)));
+    }
+    cpu.set_timebase(timebase);
+    cpu.set_misaligned(misaligned);
+    if let Some(mode) = &jit {
+        set_jit(&mut cpu, mode);
+    }
+    // キャッシュは指定がある時だけ置く
+    let cached = caches.is_some();
+    if let Some(config) = caches {
+        let mut caches = Caches::new(config).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        for (name, start, end) in &regions {
+            caches.add_region(name, *start, *end);
+        }
+        cpu.bus_mut().set_caches(Some(caches));
+    }
+
+    for (path, read_only) in &disks {
+        let blk = Blk::open(path, *read_only).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+        if cpu.bus_mut().add_virtio(Device::Blk(blk)).is_none() {
+            eprintln!("{}: too many virtio devices", path);
+            process::exit(1);
+        }
+    }
+
+    for (backend, mac) in nets {
+        let net = open_net(&backend, mac).unwrap_or_else(|e| {
+            eprintln!("{}: {}", backend, e);
+            process::exit(1);
+        });
+        if cpu.bus_mut().add_virtio(Device::Net(net)).is_none() {
+            eprintln!("{}: too many virtio devices", backend);
+            process::exit(1);
+        }
+    }
+
+    // モニタも標準入力を読むので一緒には使えない
+    if monitor && consoles.iter().any(|spec| spec.starts_with("stdio")) {
+        eprintln!("--console stdio cannot be used with --monitor");
+        process::exit(1);
+    }
+    // --consoleを重ねるとひとつのデバイスのポートが増える
+    if !consoles.is_empty() {
+        let mut device = Console::new();
+        for spec in &consoles {
+            add_console_port(&mut device, spec).unwrap_or_else(|e| {
+                eprintln!("{}: {}", spec, e);
+                process::exit(1);
+            });
+        }
+        if cpu.bus_mut().add_virtio(Device::Console(device)).is_none() {
+            eprintln!("console: too many virtio devices");
+            process::exit(1);
+        }
+    }
+
+    if let Some(seed) = rng {
+        let device = match seed {
+            Some(seed) => Rng::seeded(seed),
+            None => Rng::host().unwrap_or_else(|e| {
+                eprintln!("rng: {}", e);
+                process::exit(1);
+            }),
+        };
+        if cpu.bus_mut().add_virtio(Device::Rng(device)).is_none() {
+            eprintln!("rng: too many virtio devices");
+            process::exit(1);
+        }
+    }
+
+    // ELFならセグメントを置き、それ以外は生のバイナリとしてRAMの先頭に置く
+    if let Some(path) = image {
+        let data = fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+        let result = if elf::is_elf(&data) {
+            cpu.load_elf(&data).map_err(|e| e.to_string())
+        } else {
+            let base = cpu.bus().ram_base();
+            cpu.set_pc(base);
+            cpu.write_phys(base, &data).map_err(|e| e.to_string())
+        };
+        if let Err(e) = result {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    // 指定が無ければイメージの入口をリセットベクタにする
+    if let Some(addr) = reset_vector {
+        cpu.set_pc(addr);
+    }
+    let pc = cpu.pc();
+    cpu.set_reset_vector(pc);
+
+    // initrdはRAMの最後にページ境界で置き、DTBはその手前に置く
+    let mut config = FdtConfig {
+        bootargs,
+        initrd: None,
+        timebase_frequency: hz.map_or(fdt::DEFAULT_TIMEBASE_FREQUENCY, |hz| {
+            (hz / timebase as f64) as u32
+        }),
+    };
+    let read = |path: &String| {
+        fs::read(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    };
+    if booting {
+        let firmware = firmware.as_ref().map(read);
+        let kernel = kernel.as_ref().map(read);
+        let initrd = initrd.as_ref().map(read);
+        let boot = BootConfig {
+            firmware: firmware.as_deref(),
+            kernel: kernel.as_deref(),
+            initrd: initrd.as_deref(),
+            fdt: config.clone(),
+        };
+        match cpu.boot(&boot) {
+            Ok(info) => config.initrd = info.initrd,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        if let Some(path) = &initrd {
+            config.initrd = Some(cpu.load_initrd(&read(path)).unwrap_or_else(|| {
+                eprintln!("{}: initrd does not fit in RAM", path);
+                process::exit(1);
+            }));
+        }
+        if (dtb || config.bootargs.is_some() || initrd.is_some()) && cpu.load_fdt(&config).is_none()
+        {
+            eprintln!("dtb does not fit in RAM");
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &dump_dtb {
+        if let Err(e) = fs::write(path, cpu.fdt(&config)) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+
+    // トレースは実行する命令を標準エラーに出す
+    let profiler = profile
+        .as_ref()
+        .map(|_| Rc::new(RefCell::new(Profiler::new(&cpu))));
+    if trace || profiler.is_some() {
+        let profiler = profiler.clone();
+        cpu.hooks_mut().on_before_inst(move |cpu, pc, ir| {
+            if trace {
+                let location = cpu.symbols().describe(pc).unwrap_or_default();
+                eprintln!(
+                    "{:08x}  {:08x}  {:<32}  {}",
+                    pc,
+                    ir,
+                    disasm::disassemble(ir, pc),
+                    location
+                );
+            }
+            if let Some(profiler) = &profiler {
+                profiler.borrow_mut().on_inst(cpu, pc, ir);
+            }
+        });
+    }
+
+    let stats = stats_format
+        .as_ref()
+        .map(|_| Rc::new(RefCell::new(Stats::new())));
+    if let Some(stats) = &stats {
+        let hooks = cpu.hooks_mut();
+        let s = stats.clone();
+        hooks.on_after_inst(move |cpu, pc, ir| s.borrow_mut().on_inst(cpu, pc, ir));
+        let s = stats.clone();
+        hooks.on_mem_read(move |access| s.borrow_mut().on_mem_read(access));
+        let s = stats.clone();
+        hooks.on_mem_write(move |access| s.borrow_mut().on_mem_write(access));
+        let s = stats.clone();
+        hooks.on_trap(move |trap| s.borrow_mut().on_trap(trap));
+    }
+
+    // 再生する時は記録を始めた時の状態に戻す
+    // デバイスやメモリの構成は記録した時と同じにしておく必要がある
+    if record.is_some() && replay.is_some() {
+        eprintln!("--record and --replay cannot be used together");
+        process::exit(1);
+    }
+    if let Some(path) = &replay {
+        let result = ReplayLog::load(path).and_then(|log| cpu.start_replay(log));
+        if let Err(e) = result {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    if let Err(e) = record.as_ref().map_or(Ok(()), |_| cpu.start_recording()) {
+        eprintln!("--record: {}", e);
+        process::exit(1);
+    }
+
+    // プロファイルや統計を取っている時はCtrl-Cで止めて結果を書き出す
+    if monitor || profiler.is_some() || stats.is_some() {
+        catch_interrupt();
+    }
 
-    loop {
-        cpu.tick().unwrap();
+    if monitor {
+        let mut monitor = Monitor::new(cpu);
+        monitor
+            .run(io::stdin().lock(), &mut io::stdout().lock())
+            .unwrap();
+        if let Some(path) = &record {
+            save_recording(path, monitor.cpu_mut());
+        }
+        if let (Some(path), Some(profiler)) = (&profile, &profiler) {
+            write_profile(path, profiler, monitor.cpu());
+        }
+        if let (Some(format), Some(stats)) = (&stats_format, &stats) {
+            write_stats(format, &mut stats.borrow_mut());
+        }
+        if timing || cached {
+            write_cycles(monitor.cpu_mut(), hz);
+        }
+        return;
+    }
+
+    // 標準入力を他で使っていなければUARTの入力にする
+    let stdio_console = consoles.iter().any(|spec| spec.starts_with("stdio"));
+    let mut input = (!stdio_console).then(console::Stdio::new);
+    let failed = run(&mut cpu, &mut input);
+    if let Some(path) = &record {
+        save_recording(path, &mut cpu);
+    }
+    if let (Some(path), Some(profiler)) = (&profile, &profiler) {
+        write_profile(path, profiler, &cpu);
+    }
+    if let (Some(format), Some(stats)) = (&stats_format, &stats) {
+        write_stats(format, &mut stats.borrow_mut());
+    }
+    if timing || cached {
+        write_cycles(&mut cpu, hz);
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+// エラーかCtrl-Cまで実行する。エラーならtrueを返す
+fn run(cpu: &mut Cpu, input: &mut Option<console::Stdio>) -> bool {
+    while !monitor::INTERRUPTED.load(Ordering::Relaxed) {
+        if let Some(Ok(Some(data))) = input.as_mut().map(|input| input.read()) {
+            cpu.push_input(&data);
+        }
+        cpu.poll_devices();
+        // 記録の最後まで再生したら、そこからは普通に実行する
+        if cpu.replay_finished() {
+            cpu.stop_replay();
+        }
+        let reason = cpu.step(CHUNK);
 
-        thread::sleep(Duration::from_millis(16));
+        let output = cpu.bus_mut().uart_mut().take_output();
+        if !output.is_empty() {
+            let mut stdout = io::stdout();
+            stdout.write_all(&output).unwrap();
+            stdout.flush().unwrap();
+        }
+
+        // ゲストがSBIでシャットダウンした。失敗による時は異常終了にする
+        // 再起動 (コールドとウォーム) ならイメージを置き直してリセットベクタから始め直す
+        if let HaltReason::SystemReset(kind, reason) = reason {
+            if kind == 0 {
+                return reason != 0;
+            }
+            if cpu.recording() {
+                eprintln!("warning: recording stopped by the reset");
+            }
+            cpu.reboot();
+        }
+        if let HaltReason::JitMismatch(diff) = &reason {
+            eprintln!("jit mismatch {}", diff);
+            return true;
+        }
+        if let HaltReason::Error(e) = reason {
+            match cpu.symbols().describe(cpu.pc()) {
+                Some(location) => eprintln!("error: {} in {}", e, location),
+                None => eprintln!("error: {}", e),
+            }
+            return true;
+        }
     }
+    false
 }
+
+// 記録を終えてファイルに書く (リセットすると記録は途切れる)
+fn save_recording(path: &str, cpu: &mut Cpu) {
+    let Some(log) = cpu.stop_recording() else {
+        eprintln!("{}: recording was stopped by a reset", path);
+        return;
+    };
+    if let Err(e) = log.save(path) {
+        eprintln!("{}: {}", path, e);
+    }
+}
+
+// タイミングモデルで数えたサイクル数と、その周波数での実行時間を標準エラーに出す
+fn write_cycles(cpu: &mut Cpu, hz: Option<f64>) {
+    if let Some(Err(e)) = cpu.timing_model_mut().map(|m| m.finish()) {
+        eprintln!("pipetrace: {}", e);
+    }
+    let cycles = cpu.cycles();
+    let insts = cpu.icount();
+    let cpi = if insts > 0 {
+        cycles as f64 / insts as f64
+    } else {
+        0.0
+    };
+    eprintln!(
+        "cycles           {} ({} insts, CPI {:.3})",
+        cycles, insts, cpi
+    );
+    if let Some(hz) = hz {
+        eprintln!("guest time       {:.6} s at {} Hz", cycles as f64 / hz, hz);
+    }
+    if let Some(caches) = cpu.bus().caches() {
+        eprintln!();
+        caches.write_table(&mut io::stderr().lock()).unwrap();
+    }
+    if let Some(model) = cpu.timing_model() {
+        let mut out = Vec::new();
+        model.report(&mut out).unwrap();
+        if !out.is_empty() {
+            eprintln!();
+            io::stderr().write_all(&out).unwrap();
+        }
+    }
+    if let Some(branches) = cpu.timing_model().and_then(|m| m.branches()) {
+        eprintln!();
+        branches
+            .write_table(cpu.symbols(), BRANCH_TOP, &mut io::stderr().lock())
+            .unwrap();
+    }
+}
+
+// "konata:pipe.log" や "o3:pipe.txt"
+fn parse_pipetrace(spec: &str) -> Option<(TraceFormat, String)> {
+    let (format, path) = spec.split_once(':')?;
+    let format = match format {
+        "konata" => TraceFormat::Konata,
+        "o3" => TraceFormat::O3PipeView,
+        _ => return None,
+    };
+    Some((format, path.to_string()))
+}
+
+// "l1d:size=8k,ways=4" のような指定でキャッシュの構成を変える
+fn parse_cache(config: &mut CachesConfig, spec: &str) -> Result<(), String> {
+    let (level, spec) = spec.split_once(':').unwrap_or((spec, ""));
+    let result = match level {
+        "l1i" => config.l1i.clone().parse(spec).map(|c| config.l1i = c),
+        "l1d" => config.l1d.clone().parse(spec).map(|c| config.l1d = c),
+        "l2" => {
+            let l2 = config.l2.clone().unwrap_or_else(CacheConfig::l2);
+            l2.parse(spec).map(|c| config.l2 = Some(c))
+        }
+        _ => return Err(format!("unknown cache: {}", level)),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+// image[,ro]
+fn parse_disk(spec: &str) -> (String, bool) {
+    match spec.strip_suffix(",ro") {
+        Some(path) => (path.to_string(), true),
+        None => (spec.to_string(), false),
+    }
+}
+
+// backend[,mac=xx:xx:xx:xx:xx:xx]
+fn parse_net(spec: &str) -> Option<(String, [u8; 6])> {
+    let Some((backend, mac)) = spec.rsplit_once(",mac=") else {
+        return Some((spec.to_string(), net::DEFAULT_MAC));
+    };
+    let bytes = mac
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some((backend.to_string(), bytes.try_into().ok()?))
+}
+
+fn open_net(backend: &str, mac: [u8; 6]) -> io::Result<Net> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "unknown network backend");
+    let (kind, arg) = backend.split_once(':').ok_or_else(invalid)?;
+    match kind {
+        "pcap" => Ok(Net::new(net::Pcap::create(arg)?, mac)),
+        #[cfg(unix)]
+        "socket" => {
+            let (local, peer) = arg.split_once(',').ok_or_else(invalid)?;
+            Ok(Net::new(net::Socket::bind(local, peer)?, mac))
+        }
+        #[cfg(target_os = "linux")]
+        "tap" => Ok(Net::new(net::Tap::open(arg)?, mac)),
+        _ => Err(invalid()),
+    }
+}
+
+// backend[,name=port]
+fn add_console_port(device: &mut Console, spec: &str) -> io::Result<()> {
+    let (backend, name) = spec.rsplit_once(",name=").unwrap_or((spec, ""));
+    match backend.split_once(':') {
+        None if backend == "stdio" => device.add_port(name, console::Stdio::new()),
+        #[cfg(unix)]
+        Some(("socket", path)) => device.add_port(name, console::Socket::listen(path)?),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unknown console backend",
+            ))
+        }
+    }
+    Ok(())
+}
+
+// seed=N ならシードから作る乱数、hostならホストの乱数 (None)
+fn parse_rng(spec: &str) -> Option<Option<u64>> {
+    match spec {
+        "host" => Some(None),
+        _ => {
+            let seed = spec.strip_prefix("seed=")?;
+            let seed = match seed.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => seed.parse().ok()?,
+            };
+            Some(Some(seed))
+        }
+    }
+}
+
+// "128M@80000000" のようなRAMの大きさと位置 (位置は16進数、省略すると0)
+fn parse_memory(spec: &str) -> Option<(u32, u32)> {
+    let (size, base) = spec.split_once('@').unwrap_or((spec, "0"));
+    let (num, unit) = match size.strip_suffix(['K', 'k', 'M', 'm', 'G', 'g']) {
+        Some(num) => (num, size.as_bytes()[size.len() - 1].to_ascii_uppercase()),
+        None => (size, b'B'),
+    };
+    let shift = match unit {
+        b'K' => 10,
+        b'M' => 20,
+        b'G' => 30,
+        _ => 0,
+    };
+    let size = num.parse::<u64>().ok()? << shift;
+    let base = u32::from_str_radix(base.trim_start_matches("0x"), 16).ok()?;
+    Some((size.try_into().ok()?, base))
+}
+
+// "stack=3000-4000" のような範囲 (16進数)
+fn parse_region(spec: &str) -> Option<(String, u32, u32)> {
+    let (name, range) = spec.split_once('=')?;
+    let (start, end) = range.split_once('-')?;
+    let hex = |s: &str| u32::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    Some((name.to_string(), hex(start)?, hex(end)?))
+}
+
+// フォールデッド形式をファイルに書き、重い関数を標準エラーに出す
+fn write_profile(path: &str, profiler: &RefCell<Profiler>, cpu: &Cpu) {
+    let mut profiler = profiler.borrow_mut();
+    profiler.finish(cpu);
+    let result = File::create(path).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        profiler.write_folded(cpu.symbols(), Weight::Cycles, &mut out)?;
+        out.flush()
+    });
+    if let Err(e) = result {
+        eprintln!("{}: {}", path, e);
+    }
+
+    eprintln!(
+        "{:<32} {:>8} {:>12} {:>12} {:>12}",
+        "function", "calls", "self insts", "self cycles", "total cycles"
+    );
+    for f in profiler.functions(cpu.symbols()).iter().take(PROFILE_TOP) {
+        eprintln!(
+            "{:<32} {:>8} {:>12} {:>12} {:>12}",
+            f.name, f.calls, f.self_insts, f.self_cycles, f.total_cycles
+        );
+    }
+}
+
+// 統計を標準エラーに出す
+fn write_stats(format: &str, stats: &mut Stats) {
+    stats.finish();
+    let mut out = io::stderr().lock();
+    let result = match format {
+        "json" => stats.write_json(&mut out),
+        _ => stats.write_table(&mut out),
+    };
+    result.unwrap();
+}
+
+// checkはブロックごとにインタプリタでも実行して結果を比べる
+#[cfg(feature = "jit")]
+fn set_jit(cpu: &mut Cpu, mode: &str) {
+    cpu.set_jit(match mode {
+        "on" => JitMode::On,
+        "check" => JitMode::Check,
+        _ => JitMode::Off,
+    });
+}
+
+#[cfg(not(feature = "jit"))]
+fn set_jit(_: &mut Cpu, _: &str) {
+    eprintln!("--jit needs a build with the jit feature");
+    process::exit(1);
+}
+
+// Ctrl-Cで実行中のcontinueや実行を止める
+#[cfg(unix)]
+fn catch_interrupt() {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    extern "C" fn handler(_: i32) {
+        monitor::INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: ハンドラはアトミック変数に書くだけ
+    unsafe {
+        signal(SIGINT, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_interrupt() {}
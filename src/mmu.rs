@@ -0,0 +1,243 @@
+use crate::bus::Bus;
+use crate::cpu::Privilege;
+use crate::error::{Access, Error, Result};
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+const PAGE_SIZE: u64 = 4096;
+
+// アドレス変換に効くmstatusのビットと実効特権レベル
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub prv: Privilege,
+    pub sum: bool,
+    pub mxr: bool,
+}
+
+// Sv32のページテーブルを引いて物理アドレスを返す
+// debugの時は権限チェックとA/Dビットの更新をしない
+pub fn translate(
+    bus: &mut Bus,
+    satp: u32,
+    addr: u32,
+    access: Access,
+    ctx: Context,
+    debug: bool,
+) -> Result<u32> {
+    if satp >> 31 == 0 || ctx.prv == Privilege::Machine {
+        return Ok(addr);
+    }
+
+    let fault = Error::PageFault { addr, access };
+    let vpn = [(addr >> 12) & 0x3FF, addr >> 22];
+
+    let mut table = (satp & 0x003F_FFFF) as u64 * PAGE_SIZE;
+    let mut level = 1;
+    let (pte_addr, pte) = loop {
+        let pte_addr = table + vpn[level] as u64 * 4;
+        let pte_addr = bus_addr(pte_addr, access)?;
+        if !debug {
+            bus.cache_access(pte_addr, 4, Access::Load);
+        }
+        let pte = bus.read32(pte_addr).map_err(|_| Error::BusFault {
+            addr: pte_addr,
+            width: 4,
+            access,
+        })?;
+
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err(fault);
+        }
+        if pte & (PTE_R | PTE_X) != 0 {
+            break (pte_addr, pte);
+        }
+        if level == 0 {
+            return Err(fault);
+        }
+        level -= 1;
+        table = (pte >> 10) as u64 * PAGE_SIZE;
+    };
+
+    if !debug {
+        let permitted = match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (ctx.mxr && pte & PTE_X != 0),
+            Access::Store => pte & PTE_W != 0,
+        };
+        let user_ok = match ctx.prv {
+            Privilege::User => pte & PTE_U != 0,
+            _ => pte & PTE_U == 0 || (access != Access::Fetch && ctx.sum),
+        };
+        if !permitted || !user_ok {
+            return Err(fault);
+        }
+    }
+
+    // メガページのPPN[0]は0でなければならない
+    let ppn = (pte >> 10) as u64;
+    if level == 1 && ppn & 0x3FF != 0 {
+        return Err(fault);
+    }
+
+    if !debug {
+        let mut updated = pte | PTE_A;
+        if access == Access::Store {
+            updated |= PTE_D;
+        }
+        if updated != pte {
+            bus.cache_access(pte_addr, 4, Access::Store);
+            bus.write32(pte_addr, updated)?;
+        }
+    }
+
+    let paddr = if level == 1 {
+        ((ppn >> 10) << 22) | (addr & 0x003F_FFFF) as u64
+    } else {
+        (ppn << 12) | (addr & 0x0FFF) as u64
+    };
+    bus_addr(paddr, access)
+}
+
+// 34bitの物理アドレスのうちバスに載る範囲だけを通す
+fn bus_addr(addr: u64, access: Access) -> Result<u32> {
+    u32::try_from(addr).map_err(|_| Error::BusFault {
+        addr: addr as u32,
+        width: 4,
+        access,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{translate, Context, PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+    use crate::bus::Bus;
+    use crate::cpu::Privilege;
+    use crate::error::{Access, Error};
+
+    // ルートを0x1000、2段目を0x2000に置く
+    const SATP: u32 = 1 << 31 | 1;
+    const VA: u32 = 0x4000_0000;
+
+    fn pte(bus: &mut Bus, addr: u32, ppn: u32, flags: u32) {
+        bus.write32(addr, ppn << 10 | flags).unwrap();
+    }
+
+    // VAの1ページ目を0x3000 (読み書き)、2ページ目を0x3000 (読むだけ)、3ページ目をユーザーページにする
+    fn tables() -> Bus {
+        let mut bus = Bus::new();
+        pte(&mut bus, 0x1000 + (VA >> 22) * 4, 2, PTE_V);
+        pte(&mut bus, 0x2000, 3, PTE_V | PTE_R | PTE_W);
+        pte(&mut bus, 0x2004, 3, PTE_V | PTE_R);
+        pte(&mut bus, 0x2008, 3, PTE_V | PTE_R | PTE_W | PTE_U);
+        bus
+    }
+
+    fn ctx(prv: Privilege, sum: bool) -> Context {
+        Context {
+            prv,
+            sum,
+            mxr: false,
+        }
+    }
+
+    fn flags(bus: &mut Bus, addr: u32) -> u32 {
+        bus.read32(addr).unwrap() & 0x3FF
+    }
+
+    #[test]
+    fn translates_and_sets_accessed_and_dirty() {
+        let mut bus = tables();
+        let s = ctx(Privilege::Supervisor, false);
+        assert_eq!(
+            translate(&mut bus, SATP, VA + 0x123, Access::Load, s, false),
+            Ok(0x3123)
+        );
+        assert_eq!(flags(&mut bus, 0x2000), PTE_V | PTE_R | PTE_W | PTE_A);
+        translate(&mut bus, SATP, VA, Access::Store, s, false).unwrap();
+        assert_eq!(
+            flags(&mut bus, 0x2000),
+            PTE_V | PTE_R | PTE_W | PTE_A | PTE_D
+        );
+        // M-modeとBareは変換しない
+        let m = ctx(Privilege::Machine, false);
+        assert_eq!(
+            translate(&mut bus, SATP, VA, Access::Load, m, false),
+            Ok(VA)
+        );
+        assert_eq!(translate(&mut bus, 0, VA, Access::Load, s, false), Ok(VA));
+    }
+
+    #[test]
+    fn permission_faults() {
+        let mut bus = tables();
+        let s = ctx(Privilege::Supervisor, false);
+        let fault = |addr, access| Err(Error::PageFault { addr, access });
+        // 読むだけのページへの書き込み、実行できないページからのフェッチ
+        assert_eq!(
+            translate(&mut bus, SATP, VA + 0x1000, Access::Store, s, false),
+            fault(VA + 0x1000, Access::Store)
+        );
+        assert_eq!(
+            translate(&mut bus, SATP, VA, Access::Fetch, s, false),
+            fault(VA, Access::Fetch)
+        );
+        // ユーザーページはSUMが無ければS-modeから触れず、U-modeからは触れる
+        assert_eq!(
+            translate(&mut bus, SATP, VA + 0x2000, Access::Load, s, false),
+            fault(VA + 0x2000, Access::Load)
+        );
+        let sum = ctx(Privilege::Supervisor, true);
+        assert_eq!(
+            translate(&mut bus, SATP, VA + 0x2000, Access::Load, sum, false),
+            Ok(0x3000)
+        );
+        let u = ctx(Privilege::User, false);
+        assert_eq!(
+            translate(&mut bus, SATP, VA, Access::Load, u, false),
+            fault(VA, Access::Load)
+        );
+        // 無効なPTE
+        assert_eq!(
+            translate(&mut bus, SATP, VA + 0x3000, Access::Load, s, false),
+            fault(VA + 0x3000, Access::Load)
+        );
+    }
+
+    // デバッグ用の変換は権限を見ず、A/Dビットも変えない
+    #[test]
+    fn debug_translation_has_no_side_effects() {
+        let mut bus = tables();
+        let u = ctx(Privilege::User, false);
+        assert_eq!(
+            translate(&mut bus, SATP, VA + 0x1004, Access::Store, u, true),
+            Ok(0x3004)
+        );
+        assert_eq!(flags(&mut bus, 0x2004), PTE_V | PTE_R);
+    }
+
+    // メガページはPPN[0]が0でなければならない
+    #[test]
+    fn misaligned_megapage_faults() {
+        let mut bus = Bus::new();
+        pte(&mut bus, 0x1000, 1, PTE_V | PTE_R | PTE_X);
+        let s = ctx(Privilege::Supervisor, false);
+        assert_eq!(
+            translate(&mut bus, SATP, 0x10, Access::Load, s, false),
+            Err(Error::PageFault {
+                addr: 0x10,
+                access: Access::Load
+            })
+        );
+        pte(&mut bus, 0x1000, 0, PTE_V | PTE_R | PTE_X);
+        assert_eq!(
+            translate(&mut bus, SATP, 0x3010, Access::Load, s, false),
+            Ok(0x3010)
+        );
+    }
+}
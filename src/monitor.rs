@@ -0,0 +1,451 @@
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu::{Cpu, HaltReason};
+use crate::disasm::{self, CSR_NAMES, REG_NAMES};
+
+// 実行中に立てると次の命令の後で止まる (SIGINTのハンドラなどから立てる)
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// continueでUARTの出力を表示する間隔 (命令数)
+const CHUNK: u64 = 1_000_000;
+
+const HELP: &str = "\
+step [n]              n命令実行する (s)
+continue              ブレークポイントまで実行する (c)
+break <addr>          ブレークポイントを置く (b)
+delete <addr>         ブレークポイントを消す
+watch <addr> [len]    書き込みを監視する
+unwatch <addr>        監視をやめる
+info                  ブレークポイントとウォッチポイントの一覧
+regs                  レジスタを表示する (r)
+set <reg> <value>     レジスタを書き換える (pcも可)
+x <addr> [len]        メモリをダンプする
+dis [addr] [n]        逆アセンブルする
+write <addr> <value> [width]
+                      メモリを書き換える (widthは1, 2, 4)
+csrs                  CSRを表示する
+input <text>          UARTに入力する (改行を付ける)
+record                記録を始める (巻き戻しに必要)
+rstep [n]             n命令巻き戻す
+rcontinue             前のブレークポイントまで巻き戻す
+reset                 リセットベクタから始め直す (メモリはそのまま)
+quit                  終了する (q)
+addrにはシンボル名も書ける";
+
+// 対話的にCpuを操作するモニタ
+pub struct Monitor {
+    cpu: Cpu,
+}
+
+impl Monitor {
+    pub fn new(cpu: Cpu) -> Self {
+        Self { cpu }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    // 入力が尽きるかquitまでコマンドを実行する
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        write!(out, "(rv) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, out)? {
+                break;
+            }
+            write!(out, "(rv) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    // 1行分のコマンドを実行する。quitならfalseを返す
+    pub fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = args.split_first() else {
+            return Ok(true);
+        };
+
+        match self.dispatch(name, args, line, out) {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
+            Err(Error::Io(e)) => return Err(e),
+            Err(Error::Usage(msg)) => writeln!(out, "error: {}", msg)?,
+        }
+        self.flush_uart(out)?;
+        Ok(true)
+    }
+
+    fn dispatch<W: Write>(
+        &mut self,
+        name: &str,
+        args: &[&str],
+        line: &str,
+        out: &mut W,
+    ) -> Result<bool, Error> {
+        match name {
+            "step" | "s" => {
+                let n = opt_num(args.first(), 1)?;
+                let reason = self.cpu.step(n);
+                self.report(&reason, out)?;
+            }
+            "continue" | "c" => {
+                let reason = self.cont(out)?;
+                self.report(&reason, out)?;
+            }
+            "break" | "b" => {
+                let addr = self.addr(args.first())?;
+                self.cpu.add_breakpoint(addr);
+            }
+            "delete" => {
+                let addr = self.addr(args.first())?;
+                if !self.cpu.remove_breakpoint(addr) {
+                    return Err(Error::Usage(format!("no breakpoint at 0x{:08x}", addr)));
+                }
+            }
+            "watch" => {
+                let addr = self.addr(args.first())?;
+                let len = opt_num(args.get(1), 4)?;
+                self.cpu.add_watchpoint(addr, len as u32);
+            }
+            "unwatch" => {
+                let addr = self.addr(args.first())?;
+                if !self.cpu.remove_watchpoint(addr) {
+                    return Err(Error::Usage(format!("no watchpoint at 0x{:08x}", addr)));
+                }
+            }
+            "info" => {
+                for pc in self.cpu.breakpoints() {
+                    writeln!(out, "break 0x{:08x}{}", pc, self.location(pc))?;
+                }
+                for (addr, len) in self.cpu.watchpoints() {
+                    writeln!(out, "watch 0x{:08x} {}", addr, len)?;
+                }
+            }
+            "regs" | "r" => self.regs(out)?,
+            "set" => {
+                let name = args.first().ok_or(usage("set <reg> <value>"))?;
+                let val = num(args.get(1))? as u32;
+                match *name {
+                    "pc" => self.cpu.set_pc(val),
+                    name => match disasm::reg_index(name) {
+                        Some(i) => self.cpu.set_x(i, val),
+                        None => return Err(Error::Usage(format!("unknown register {}", name))),
+                    },
+                }
+            }
+            "x" => {
+                let addr = self.addr(args.first())?;
+                let len = opt_num(args.get(1), 64)?;
+                self.dump(addr, len, out)?;
+            }
+            "dis" => {
+                let addr = match args.first() {
+                    Some(_) => self.addr(args.first())?,
+                    None => self.cpu.pc(),
+                };
+                let n = opt_num(args.get(1), 10)?;
+                for i in 0..n as u32 {
+                    let addr = addr.wrapping_add(4 * i);
+                    // シンボルの先頭ならラベルを付ける
+                    if let Some((name, 0)) = self.cpu.symbols().symbolize(addr) {
+                        writeln!(out, "{}:", name)?;
+                    }
+                    self.disassemble(addr, out)?;
+                }
+            }
+            "write" => {
+                let addr = self.addr(args.first())?;
+                let val = num(args.get(1))? as u32;
+                let width = opt_num(args.get(2), 4)? as usize;
+                if !matches!(width, 1 | 2 | 4) {
+                    return Err(usage("width must be 1, 2 or 4"));
+                }
+                if let Err(e) = self.cpu.write_virt(addr, &val.to_le_bytes()[..width]) {
+                    writeln!(out, "{}", e)?;
+                }
+            }
+            "csrs" => {
+                for (no, name) in CSR_NAMES {
+                    if let Ok(val) = self.cpu.read_csr(no) {
+                        writeln!(out, "{:<14} 0x{:08x}", name, val)?;
+                    }
+                }
+            }
+            "input" => {
+                let text = line.trim_start().strip_prefix("input").unwrap_or("");
+                let mut data = text.strip_prefix(' ').unwrap_or(text).as_bytes().to_vec();
+                data.push(b'\n');
+                self.cpu.push_input(&data);
+            }
+            "record" => {
+                if let Err(e) = self.cpu.start_recording() {
+                    writeln!(out, "{}", e)?;
+                }
+            }
+            "rstep" => {
+                let n = opt_num(args.first(), 1)?;
+                let reason = self.cpu.reverse_step(n);
+                self.report(&reason, out)?;
+            }
+            "rcontinue" => {
+                let reason = self.cpu.reverse_continue();
+                self.report(&reason, out)?;
+            }
+            "reset" => self.cpu.reset(),
+            "help" | "h" => writeln!(out, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            name => return Err(Error::Usage(format!("unknown command {}", name))),
+        }
+        Ok(true)
+    }
+
+    // ブレークポイントか割り込みまで、UARTの出力を流しながら実行する
+    fn cont<W: Write>(&mut self, out: &mut W) -> io::Result<HaltReason> {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        loop {
+            let end = self.cpu.icount() + CHUNK;
+            self.cpu.poll_devices();
+            let reason = self
+                .cpu
+                .run_until(|cpu| cpu.icount() >= end || INTERRUPTED.load(Ordering::Relaxed));
+            self.flush_uart(out)?;
+            if reason != HaltReason::Condition || INTERRUPTED.load(Ordering::Relaxed) {
+                return Ok(reason);
+            }
+        }
+    }
+
+    fn report<W: Write>(&mut self, reason: &HaltReason, out: &mut W) -> io::Result<()> {
+        match reason {
+            HaltReason::Step | HaltReason::Condition => {}
+            HaltReason::Breakpoint(pc) => writeln!(out, "breakpoint at 0x{:08x}", pc)?,
+            HaltReason::Watchpoint(addr) => writeln!(out, "watchpoint: write to 0x{:08x}", addr)?,
+            HaltReason::Pc(pc) => writeln!(out, "reached 0x{:08x}", pc)?,
+            HaltReason::Error(e) => writeln!(out, "error: {}", e)?,
+            HaltReason::Diverged(icount) => writeln!(out, "replay diverged at {}", icount)?,
+            HaltReason::HistoryStart => writeln!(out, "reached the start of history")?,
+            HaltReason::BadCheckpoint(icount) => {
+                writeln!(out, "cannot restore the checkpoint at {}", icount)?
+            }
+            // 再起動ならイメージを置き直してリセットしておき、続けて実行すると始めから動く
+            HaltReason::SystemReset(kind, reason) => {
+                writeln!(out, "system reset (type {}, reason {})", kind, reason)?;
+                if *kind != 0 {
+                    if self.cpu.recording() {
+                        writeln!(out, "recording stopped by the reset")?;
+                    }
+                    self.cpu.reboot();
+                }
+            }
+            HaltReason::JitMismatch(diff) => writeln!(out, "jit mismatch {}", diff)?,
+        }
+        let pc = self.cpu.pc();
+        let location = self.location(pc);
+        if !location.is_empty() {
+            writeln!(out, "{}", location.trim_start())?;
+        }
+        self.disassemble(pc, out)
+    }
+
+    // アドレスかシンボル名
+    fn addr(&self, arg: Option<&&str>) -> Result<u32, Error> {
+        let arg = arg.ok_or(usage("missing argument"))?;
+        match self.cpu.symbols().lookup(arg) {
+            Some(addr) => Ok(addr),
+            None => num(Some(arg)).map(|addr| addr as u32),
+        }
+    }
+
+    // シンボルが分かれば " in 関数+オフセット (ファイル:行)"
+    fn location(&self, pc: u32) -> String {
+        match self.cpu.symbols().describe(pc) {
+            Some(loc) => format!(" in {}", loc),
+            None => String::new(),
+        }
+    }
+
+    fn regs<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "pc   0x{:08x}  {:?}",
+            self.cpu.pc(),
+            self.cpu.privilege()
+        )?;
+        for row in 0..8 {
+            let line: Vec<String> = (0..4)
+                .map(|col| {
+                    let i = row * 4 + col;
+                    format!("{:<4} 0x{:08x}", REG_NAMES[i], self.cpu.get_x(i))
+                })
+                .collect();
+            writeln!(out, "{}", line.join("  "))?;
+        }
+        Ok(())
+    }
+
+    // 1行ずつ読むので、長さが大きくても読めなくなった所で止まる
+    fn dump<W: Write>(&mut self, addr: u32, len: u64, out: &mut W) -> io::Result<()> {
+        for offset in (0..len).step_by(16) {
+            let line = addr.wrapping_add(offset as u32);
+            let mut buf = vec![0; (len - offset).min(16) as usize];
+            if let Err(e) = self.cpu.read_virt(line, &mut buf) {
+                return writeln!(out, "{}", e);
+            }
+            let hex: Vec<String> = buf.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = buf
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(out, "{:08x}  {:<47}  {}", line, hex.join(" "), ascii)?;
+        }
+        Ok(())
+    }
+
+    fn disassemble<W: Write>(&mut self, addr: u32, out: &mut W) -> io::Result<()> {
+        let mut buf = [0; 4];
+        let marker = if addr == self.cpu.pc() { "=>" } else { "  " };
+        match self.cpu.read_virt(addr, &mut buf) {
+            Ok(()) => {
+                let ir = u32::from_le_bytes(buf);
+                writeln!(
+                    out,
+                    "{} 0x{:08x}:  {:08x}  {}",
+                    marker,
+                    addr,
+                    ir,
+                    disasm::disassemble(ir, addr)
+                )
+            }
+            Err(e) => writeln!(out, "{} 0x{:08x}:  {}", marker, addr, e),
+        }
+    }
+
+    fn flush_uart<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let output = self.cpu.bus_mut().uart_mut().take_output();
+        if !output.is_empty() {
+            out.write_all(&output)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+enum Error {
+    Io(io::Error),
+    Usage(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+fn usage(msg: &str) -> Error {
+    Error::Usage(msg.to_string())
+}
+
+// 0xで始まれば16進数、それ以外は10進数
+fn num(arg: Option<&&str>) -> Result<u64, Error> {
+    let arg = arg.ok_or(usage("missing argument"))?;
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| Error::Usage(format!("invalid number {}", arg)))
+}
+
+fn opt_num(arg: Option<&&str>, default: u64) -> Result<u64, Error> {
+    match arg {
+        Some(_) => num(arg),
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Monitor;
+    use crate::cpu::testing::machine;
+
+    // a0を1ずつ増やしながらUARTに送る
+    const PROGRAM: [u32; 4] = [
+        0x0015_0513, // addi a0, a0, 1
+        0x1000_02B7, // lui  t0, 0x10000
+        0x00A2_8023, // sb   a0, 0(t0)
+        0xFF5F_F06F, // j    0
+    ];
+
+    fn run(monitor: &mut Monitor, script: &str) -> String {
+        let mut out = Vec::new();
+        monitor.run(script.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut monitor = Monitor::new(machine(&PROGRAM));
+        let out = run(
+            &mut monitor,
+            "set a0 0x40\nb 8\nc\ns\ninfo\nregs\nwrite 0x100 0x41424344\nx 0x100 4\n\
+             input hi\nq\ns\n",
+        );
+        let expected = [
+            "breakpoint at 0x00000008\n=> 0x00000008:  00a28023  ",
+            // UARTの出力は次の命令の表示の後
+            "=> 0x0000000c:  ff5ff06f  jal     zero, 0x0\nA",
+            "break 0x00000008\n",
+            "a0   0x00000041",
+            "00000100  44 43 42 41",
+            "DCBA\n",
+        ];
+        let mut rest = out.as_str();
+        for s in expected {
+            let at = rest
+                .find(s)
+                .unwrap_or_else(|| panic!("{:?} in {:?}", s, out));
+            rest = &rest[at + s.len()..];
+        }
+        // quitの後は読まない
+        assert_eq!(out.matches("(rv) ").count(), 10);
+        assert_eq!(monitor.cpu().pc(), 0xC);
+        let uart = monitor.cpu_mut().bus_mut().uart_mut();
+        assert_eq!(uart.receive(), Some(b'h'));
+        assert_eq!(uart.receive(), Some(b'i'));
+        assert_eq!(uart.receive(), Some(b'\n'));
+    }
+
+    #[test]
+    fn usage_errors() {
+        let mut monitor = Monitor::new(machine(&PROGRAM));
+        let out = run(
+            &mut monitor,
+            "frob\ndelete 0x20\nunwatch 0x20\nb\nset zz 1\nwrite 0 1 3\ns x\n",
+        );
+        for msg in [
+            "error: unknown command frob\n",
+            "error: no breakpoint at 0x00000020\n",
+            "error: no watchpoint at 0x00000020\n",
+            "error: missing argument\n",
+            "error: unknown register zz\n",
+            "error: width must be 1, 2 or 4\n",
+        ] {
+            assert!(out.contains(msg), "{:?} in {:?}", msg, out);
+        }
+        assert_eq!(out.matches("error: ").count(), 7);
+        // 何も実行していない
+        assert_eq!(monitor.cpu().icount(), 0);
+    }
+}
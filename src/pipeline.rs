@@ -0,0 +1,691 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::branch::BranchUnit;
+use crate::disasm;
+use crate::timing::{Class, InstEvent, TimingModel};
+
+// IF, ID, EX, MEM, WB
+const STAGES: usize = 5;
+const STAGE_NAMES: [&str; STAGES] = ["IF", "ID", "EX", "MEM", "WB"];
+const IF: usize = 0;
+const ID: usize = 1;
+const EX: usize = 2;
+const MEM: usize = 3;
+const WB: usize = 4;
+
+// O3PipeViewのtick (gem5の既定に合わせて1サイクル1000tick)
+const O3_TICKS: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineConfig {
+    // EXとMEMの結果をEXへ回す (無ければWBを待つ)
+    pub forwarding: bool,
+    // EXを占有するサイクル数
+    pub mul: u64,
+    pub div: u64,
+    // トラップした後にハンドラのフェッチを始めるまでのサイクル数
+    pub trap: u64,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            forwarding: true,
+            mul: 3,
+            div: 34,
+            trap: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid pipeline config: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl PipelineConfig {
+    // "forwarding=0,mul=3,div=34,trap=1" のような指定で既定値を上書きする
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        let mut config = Self::default();
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let err = || ParseError(item.to_string());
+            let (name, val) = item.split_once('=').ok_or_else(err)?;
+            match name {
+                "forwarding" => {
+                    config.forwarding = match val {
+                        "1" | "on" => true,
+                        "0" | "off" => false,
+                        _ => return Err(err()),
+                    }
+                }
+                "mul" => config.mul = val.parse().map_err(|_| err())?,
+                "div" => config.div = val.parse().map_err(|_| err())?,
+                "trap" => config.trap = val.parse().map_err(|_| err())?,
+                _ => return Err(err()),
+            }
+        }
+        config.mul = config.mul.max(1);
+        config.div = config.div.max(1);
+        Ok(config)
+    }
+}
+
+// パイプライン図の形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // Konata (Kanataログ)
+    Konata,
+    // gem5のO3PipeView
+    O3PipeView,
+}
+
+// ハザードの集計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub insts: u64,
+    pub cycles: u64,
+    // ロード直後の命令が待ったサイクル数
+    pub load_use_stalls: u64,
+    // それ以外のRAWハザードで待ったサイクル数
+    pub raw_stalls: u64,
+    // 前の命令のmul/divがEXを空けるのを待ったサイクル数
+    pub occupancy_stalls: u64,
+    // キャッシュを待ったサイクル数
+    pub memory_stalls: u64,
+    // WBの前の値をフォワーディングで受け取ったオペランドの数
+    pub forwarded: u64,
+    pub branch_flushes: u64,
+    pub trap_flushes: u64,
+    // フラッシュでフェッチが遅れたサイクル数
+    pub flush_cycles: u64,
+}
+
+// 前の命令の各ステージに入ったサイクル
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    start: [u64; STAGES],
+    // EXに2サイクル以上いた (mul/div)
+    long: bool,
+}
+
+// 命令を1つずつ流す5段のインオーダーパイプライン
+// 各ステージに入るサイクルを前の命令とレジスタの準備ができるサイクルから決める
+// 分岐はEXで、jalはIDで飛び先が決まり、外れればその次のサイクルからフェッチし直す
+pub struct Pipeline {
+    config: PipelineConfig,
+    branches: Option<BranchUnit>,
+    prev: Slot,
+    // 次の命令をフェッチできる最初のサイクル
+    redirect: u64,
+    // 直前に退いた命令のWBが終わるサイクル
+    done: u64,
+    // レジスタの値をEXで使えるようになるサイクルと、それがロードの結果か
+    ready: [(u64, bool); 32],
+    // WBが終わるサイクル (これより前に使えばフォワーディング)
+    written: [u64; 32],
+    stats: PipelineStats,
+    trace: Option<Trace>,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Self {
+            config,
+            branches: None,
+            prev: Slot::default(),
+            redirect: 0,
+            done: 0,
+            ready: [(0, false); 32],
+            written: [0; 32],
+            stats: PipelineStats::default(),
+            trace: None,
+        }
+    }
+
+    // 分岐予測器を付ける (無ければ分岐は不成立、ジャンプは外れると予測する)
+    pub fn with_branches(mut self, branches: BranchUnit) -> Self {
+        self.branches = Some(branches);
+        self
+    }
+
+    // パイプライン図を書き出す
+    pub fn with_trace(mut self, format: TraceFormat, out: Box<dyn Write>) -> Self {
+        self.trace = Some(Trace::new(format, out));
+        self
+    }
+
+    pub fn stats(&self) -> &PipelineStats {
+        &self.stats
+    }
+
+    fn ex_cycles(&self, class: Class) -> u64 {
+        match class {
+            Class::Mul => self.config.mul,
+            Class::Div => self.config.div,
+            _ => 1,
+        }
+    }
+
+    // 予測が外れて飛び先が決まるステージ
+    fn resolve(&mut self, event: &InstEvent) -> Option<usize> {
+        let next = event.pc.wrapping_add(4);
+        if event.trapped {
+            return None;
+        }
+        let mispredicted = match &mut self.branches {
+            Some(unit) => unit.on_inst(event.pc, event.ir, event.next_pc),
+            None => match event.class {
+                Class::Branch | Class::Jump => Some(event.next_pc != next),
+                _ => None,
+            },
+        };
+        match (mispredicted, event.ir & 0x7F) {
+            (Some(true), 0x6F) => Some(ID),
+            (Some(true), _) => Some(EX),
+            (Some(false), _) => None,
+            // mret, sretなどでpcが変わる
+            (None, _) if event.next_pc != next => Some(MEM),
+            (None, _) => None,
+        }
+    }
+}
+
+impl TimingModel for Pipeline {
+    fn inst(&mut self, event: &InstEvent) -> u64 {
+        let (rs1, rs2, rd) = operands(event.ir);
+        let prev = self.prev;
+        let mut start = [0; STAGES];
+        let mut len = [1; STAGES];
+        len[IF] += event.fetch_stall;
+        len[EX] = self.ex_cycles(event.class);
+        len[MEM] += event.mem_stall;
+        self.stats.memory_stalls += event.fetch_stall + event.mem_stall;
+
+        // IF: 前の命令がIFを出て、フラッシュした後
+        let fetch = prev.start[ID].max(self.redirect);
+        if self.redirect > prev.start[ID] {
+            self.stats.flush_cycles += self.redirect - prev.start[ID];
+        }
+        start[IF] = fetch;
+        start[ID] = (start[IF] + len[IF]).max(prev.start[EX]);
+
+        // EX: 前の命令がEXを出て、オペランドが揃った後
+        let free = start[ID] + len[ID];
+        let occupied = free.max(prev.start[MEM]);
+        if prev.long && prev.start[MEM] > free {
+            self.stats.occupancy_stalls += prev.start[MEM] - free;
+        }
+        let mut operand = 0;
+        let mut load_use = false;
+        for r in [rs1, rs2].into_iter().flatten() {
+            let (ready, load) = self.ready[r];
+            if ready > operand {
+                operand = ready;
+                load_use = load;
+            }
+        }
+        start[EX] = occupied.max(operand);
+        if operand > occupied {
+            let wait = operand - occupied;
+            if load_use {
+                self.stats.load_use_stalls += wait;
+            } else {
+                self.stats.raw_stalls += wait;
+            }
+        }
+        if self.config.forwarding {
+            for r in [rs1, rs2].into_iter().flatten() {
+                if self.written[r] > start[EX] {
+                    self.stats.forwarded += 1;
+                }
+            }
+        }
+
+        start[MEM] = (start[EX] + len[EX]).max(prev.start[WB]);
+        start[WB] = (start[MEM] + len[MEM]).max(prev.start[WB] + 1);
+        let end = start[WB] + len[WB];
+
+        // 結果を使えるサイクル
+        if let (Some(rd), false) = (rd, event.trapped) {
+            let load = matches!(event.class, Class::Load | Class::Amo);
+            let ready = match (self.config.forwarding, load) {
+                (true, true) => start[MEM] + len[MEM],
+                (true, false) => start[EX] + len[EX],
+                // 前半で書いて後半で読む
+                (false, _) => start[WB] + 1,
+            };
+            self.ready[rd] = (ready, load);
+            self.written[rd] = end;
+        }
+
+        // 飛び先が分かるまで次の命令をフェッチしない
+        if event.trapped {
+            self.stats.trap_flushes += 1;
+            self.redirect = self.redirect.max(start[WB] + self.config.trap);
+        } else if let Some(stage) = self.resolve(event) {
+            if stage != MEM {
+                self.stats.branch_flushes += 1;
+            }
+            self.redirect = self.redirect.max(start[stage] + len[stage]);
+        }
+
+        if let Some(trace) = &mut self.trace {
+            trace.inst(event, &start, end);
+        }
+        self.prev = Slot {
+            start,
+            long: len[EX] > 1,
+        };
+        self.stats.insts += 1;
+        let cycles = end - self.done;
+        self.done = end;
+        self.stats.cycles += cycles;
+        cycles
+    }
+
+    // 割り込みは今流れている命令が退いてからハンドラをフェッチする
+    // 例外はその命令のinstでフラッシュする
+    fn trap(&mut self, _cause: u32) -> u64 {
+        self.redirect = self.redirect.max(self.done + self.config.trap);
+        0
+    }
+
+    fn max_cycles(&self) -> u64 {
+        2 * STAGES as u64 + self.config.mul.max(self.config.div) + self.config.trap
+    }
+
+    fn branches(&self) -> Option<&BranchUnit> {
+        self.branches.as_ref()
+    }
+
+    fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let s = &self.stats;
+        let cpi = match s.insts {
+            0 => 0.0,
+            n => s.cycles as f64 / n as f64,
+        };
+        writeln!(
+            out,
+            "pipeline         5-stage (forwarding {}, mul {}, div {}, trap {})",
+            if self.config.forwarding { "on" } else { "off" },
+            self.config.mul,
+            self.config.div,
+            self.config.trap
+        )?;
+        writeln!(out, "insts            {} (CPI {:.3})", s.insts, cpi)?;
+        writeln!(out, "load-use stalls  {}", s.load_use_stalls)?;
+        writeln!(out, "raw stalls       {}", s.raw_stalls)?;
+        writeln!(out, "mul/div stalls   {}", s.occupancy_stalls)?;
+        writeln!(out, "memory stalls    {}", s.memory_stalls)?;
+        writeln!(out, "forwarded        {}", s.forwarded)?;
+        writeln!(
+            out,
+            "flushes          {} (branch {}, trap {}, {} cycles)",
+            s.branch_flushes + s.trap_flushes,
+            s.branch_flushes,
+            s.trap_flushes,
+            s.flush_cycles
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.trace {
+            Some(trace) => trace.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+// 読むレジスタと書くレジスタ (x0は除く)
+fn operands(ir: u32) -> (Option<usize>, Option<usize>, Option<usize>) {
+    let reg = |r: u32| (r != 0).then_some(r as usize);
+    let rd = reg((ir >> 7) & 0x1F);
+    let rs1 = reg((ir >> 15) & 0x1F);
+    let rs2 = reg((ir >> 20) & 0x1F);
+    let funct3 = (ir >> 12) & 0b111;
+    match ir & 0x7F {
+        0x33 => (rs1, rs2, rd),
+        // lr.wはrs2を読まない
+        0x2F if ir >> 27 == 0b00010 => (rs1, None, rd),
+        0x2F => (rs1, rs2, rd),
+        0x13 | 0x03 | 0x67 => (rs1, None, rd),
+        0x23 | 0x63 => (rs1, rs2, None),
+        0x37 | 0x17 | 0x6F => (None, None, rd),
+        // csrrwi などはrs1の場所が即値
+        0x73 if funct3 & 0b100 != 0 => (None, None, rd),
+        0x73 if funct3 != 0 => (rs1, None, rd),
+        _ => (None, None, None),
+    }
+}
+
+// パイプライン図の書き出し
+struct Trace {
+    format: TraceFormat,
+    out: Box<dyn Write>,
+    // Konataはサイクル順に書くので、まだ先の命令が割り込みうる分を溜めておく
+    pending: BTreeMap<(u64, u64), String>,
+    order: u64,
+    cycle: u64,
+    id: u64,
+    retired: u64,
+    // 書けなくなったら以降は捨てる
+    error: Option<io::Error>,
+}
+
+impl Trace {
+    fn new(format: TraceFormat, out: Box<dyn Write>) -> Self {
+        let mut trace = Self {
+            format,
+            out,
+            pending: BTreeMap::new(),
+            order: 0,
+            cycle: 0,
+            id: 0,
+            retired: 0,
+            error: None,
+        };
+        if format == TraceFormat::Konata {
+            trace.write("Kanata\t0004\nC=\t0\n".to_string());
+        }
+        trace
+    }
+
+    fn write(&mut self, s: String) {
+        if self.error.is_none() {
+            if let Err(e) = self.out.write_all(s.as_bytes()) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn inst(&mut self, event: &InstEvent, start: &[u64; STAGES], end: u64) {
+        let text = disasm::disassemble(event.ir, event.pc);
+        let id = self.id;
+        self.id += 1;
+        match self.format {
+            TraceFormat::O3PipeView => {
+                let t = |c: u64| c * O3_TICKS;
+                let mut s = format!(
+                    "O3PipeView:fetch:{}:0x{:08x}:0:{}:{}\n",
+                    t(start[IF]),
+                    event.pc,
+                    id + 1,
+                    text
+                );
+                s += &format!("O3PipeView:decode:{}\n", t(start[ID]));
+                s += &format!("O3PipeView:rename:{}\n", t(start[ID]));
+                s += &format!("O3PipeView:dispatch:{}\n", t(start[ID]));
+                s += &format!("O3PipeView:issue:{}\n", t(start[EX]));
+                s += &format!("O3PipeView:complete:{}\n", t(start[MEM]));
+                let store = match event.class {
+                    Class::Store | Class::Amo => t(start[WB]),
+                    _ => 0,
+                };
+                s += &format!("O3PipeView:retire:{}:store:{}\n", t(start[WB]), store);
+                self.write(s);
+            }
+            TraceFormat::Konata => {
+                self.push(start[IF], format!("I\t{}\t{}\t0\n", id, id));
+                self.push(
+                    start[IF],
+                    format!("L\t{}\t0\t{:08x}: {}\n", id, event.pc, text),
+                );
+                for (stage, name) in STAGE_NAMES.iter().enumerate() {
+                    self.push(start[stage], format!("S\t{}\t0\t{}\n", id, name));
+                }
+                // 例外になった命令はフラッシュとして出す
+                let (retire, kind) = if event.trapped {
+                    (0, 1)
+                } else {
+                    self.retired += 1;
+                    (self.retired - 1, 0)
+                };
+                self.push(end, format!("R\t{}\t{}\t{}\n", id, retire, kind));
+                // これより後の命令はstart[IF]より後のサイクルにしか来ない
+                self.flush(start[IF]);
+            }
+        }
+    }
+
+    fn push(&mut self, cycle: u64, line: String) {
+        self.pending.insert((cycle, self.order), line);
+        self.order += 1;
+    }
+
+    // cycleまでの分を書き出す
+    fn flush(&mut self, cycle: u64) {
+        while let Some(entry) = self.pending.first_entry() {
+            let at = entry.key().0;
+            if at > cycle {
+                break;
+            }
+            let line = entry.remove();
+            if at > self.cycle {
+                self.write(format!("C\t{}\n", at - self.cycle));
+                self.cycle = at;
+            }
+            self.write(line);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush(u64::MAX);
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use super::{Pipeline, PipelineConfig, PipelineStats, TraceFormat};
+    use crate::timing::{Class, InstEvent, TimingModel};
+
+    const LW: u32 = 0x0003_2283; // lw   t0, 0(t1)
+    const ADD: u32 = 0x0052_83B3; // add  t2, t0, t0
+    const ADDI: u32 = 0x0012_8293; // addi t0, t0, 1
+    const MUL: u32 = 0x03EE_8E33; // mul  t3, t4, t5
+    const NOP: u32 = 0x0000_0013; // nop
+    const BEQ: u32 = 0x04B5_0063; // beq  a0, a1, +0x40
+
+    fn event(pc: u32, ir: u32) -> InstEvent {
+        InstEvent {
+            pc,
+            ir,
+            class: Class::of(ir),
+            next_pc: pc + 4,
+            trapped: false,
+            fetch_stall: 0,
+            mem_stall: 0,
+        }
+    }
+
+    // 順に流してかかったサイクル数の合計を返す
+    fn run(pipeline: &mut Pipeline, insts: &[u32]) -> u64 {
+        let mut pc = 0;
+        let mut cycles = 0;
+        for &ir in insts {
+            cycles += pipeline.inst(&event(pc, ir));
+            pc += 4;
+        }
+        cycles
+    }
+
+    fn pipeline(spec: &str) -> Pipeline {
+        Pipeline::new(PipelineConfig::parse(spec).unwrap())
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = PipelineConfig::parse("forwarding=off,mul=0,div=20,trap=3").unwrap();
+        assert_eq!(
+            config,
+            PipelineConfig {
+                forwarding: false,
+                mul: 1,
+                div: 20,
+                trap: 3,
+            }
+        );
+        for spec in ["forwarding=yes", "mul=-1", "depth=7", "mul"] {
+            assert!(PipelineConfig::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    // 依存のない命令は1サイクルに1つ退く
+    #[test]
+    fn independent_instructions() {
+        let mut p = pipeline("");
+        assert_eq!(run(&mut p, &[NOP; 5]), 5 + 4);
+        assert_eq!(
+            *p.stats(),
+            PipelineStats {
+                insts: 5,
+                cycles: 9,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn data_hazards() {
+        // ロードの結果はMEMの後なので1サイクル待つ
+        let mut p = pipeline("");
+        assert_eq!(run(&mut p, &[LW, ADD]), 7);
+        assert_eq!(p.stats().load_use_stalls, 1);
+        assert_eq!(p.stats().forwarded, 2);
+
+        // ALUの結果はEXの後に回せる
+        let mut p = pipeline("");
+        assert_eq!(run(&mut p, &[ADDI, ADD]), 6);
+        assert_eq!(p.stats().raw_stalls, 0);
+        assert_eq!(p.stats().forwarded, 2);
+
+        // フォワーディングが無ければWBを待つ
+        let mut p = pipeline("forwarding=off");
+        assert_eq!(run(&mut p, &[ADDI, ADD]), 8);
+        assert_eq!(p.stats().raw_stalls, 2);
+        assert_eq!(p.stats().forwarded, 0);
+        let mut p = pipeline("forwarding=off");
+        assert_eq!(run(&mut p, &[LW, ADD]), 8);
+        assert_eq!(p.stats().load_use_stalls, 2);
+    }
+
+    #[test]
+    fn mul_occupies_ex() {
+        let mut p = pipeline("mul=3");
+        assert_eq!(run(&mut p, &[MUL, NOP]), 7 + 1);
+        assert_eq!(p.stats().occupancy_stalls, 2);
+        assert_eq!(p.stats().raw_stalls, 0);
+    }
+
+    #[test]
+    fn flushes() {
+        // 予測器が無ければ成立した分岐はEXで外れる
+        let mut p = pipeline("");
+        let mut branch = event(0, BEQ);
+        branch.next_pc = 0x40;
+        assert_eq!(p.inst(&branch), 5);
+        assert_eq!(p.inst(&event(0x40, NOP)), 3);
+        assert_eq!(p.stats().branch_flushes, 1);
+        assert_eq!(p.stats().flush_cycles, 2);
+
+        // 例外はWBの後にハンドラをフェッチする
+        let mut p = pipeline("trap=2");
+        let mut fault = event(0, LW);
+        fault.trapped = true;
+        fault.next_pc = 0x100;
+        assert_eq!(p.inst(&fault), 5);
+        assert_eq!(p.inst(&event(0x100, NOP)), 6);
+        assert_eq!(p.stats().trap_flushes, 1);
+        assert_eq!(p.stats().flush_cycles, 5);
+
+        // キャッシュを待った分も足す
+        let mut p = pipeline("");
+        let mut slow = event(0, LW);
+        slow.fetch_stall = 3;
+        slow.mem_stall = 10;
+        assert_eq!(p.inst(&slow), 18);
+        assert_eq!(p.stats().memory_stalls, 13);
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(format: TraceFormat) -> String {
+        let out = Shared::default();
+        let mut p = pipeline("").with_trace(format, Box::new(out.clone()));
+        run(&mut p, &[NOP, NOP]);
+        p.finish().unwrap();
+        let text = out.0.borrow().clone();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn konata_trace() {
+        let text = trace(TraceFormat::Konata);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            &lines[..8],
+            [
+                "Kanata\t0004",
+                "C=\t0",
+                "I\t0\t0\t0",
+                "L\t0\t0\t00000000: addi    zero, zero, 0",
+                "S\t0\t0\tIF",
+                "C\t1",
+                "S\t0\t0\tID",
+                "I\t1\t1\t0",
+            ]
+        );
+        assert_eq!(lines.last(), Some(&"R\t1\t1\t0"));
+        assert_eq!(text.matches("\nR\t").count(), 2);
+    }
+
+    #[test]
+    fn o3_trace() {
+        let text = trace(TraceFormat::O3PipeView);
+        assert!(text.starts_with(
+            "O3PipeView:fetch:0:0x00000000:0:1:addi    zero, zero, 0\n\
+             O3PipeView:decode:1000\n"
+        ));
+        assert!(text.ends_with("O3PipeView:retire:5000:store:0\n"));
+    }
+
+    #[test]
+    fn report() {
+        let mut p = pipeline("");
+        run(&mut p, &[LW, ADD]);
+        let mut out = Vec::new();
+        p.report(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(
+            "pipeline         5-stage (forwarding on, mul 3, div 34, trap 1)\n\
+             insts            2 (CPI 3.500)\n\
+             load-use stalls  1\n"
+        ));
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::cpu::{Cpu, Privilege};
+use crate::symbols::Symbols;
+
+const CSR_SEPC: u16 = 0x141;
+const CSR_MEPC: u16 = 0x341;
+
+// ゲストの関数単位のプロファイラ
+// 命令フックから呼び、呼び出しと復帰を追ってシャドウコールスタックを持つ
+// jal/jalrでraかt0にリンクすれば呼び出し、raかt0へのjalrなら復帰とみなす
+// 関数の先頭へのリンクしないジャンプは末尾呼び出し、予想外の飛び先はトラップとする
+pub struct Profiler {
+    // コールスタックの木。0番は根 (プロファイルを始めた時のpc)
+    nodes: Vec<Node>,
+    stack: Vec<Frame>,
+    // 前の命令による制御の移り方
+    flow: Flow,
+    instret: u64,
+    cycles: u64,
+}
+
+struct Node {
+    // 関数の先頭アドレス
+    func: u32,
+    parent: usize,
+    children: HashMap<u32, usize>,
+    calls: u64,
+    insts: u64,
+    cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    node: usize,
+    // 戻り先
+    ret: u32,
+    // トラップハンドラのフレーム (mret/sretで戻る)
+    trap: bool,
+}
+
+// 前の命令の後に来るはずのpc
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    // 次の命令か分岐先
+    Next(u32, Option<u32>),
+    Call { target: u32, ret: u32 },
+    Return(u32),
+    // mret, sret
+    TrapReturn(u32),
+    Jump(u32),
+    // ecallなど必ずトラップする
+    Trap,
+}
+
+// 関数ごとの集計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    pub calls: u64,
+    // その関数自身で実行した分
+    pub self_insts: u64,
+    pub self_cycles: u64,
+    // 呼び出した関数の分も含む
+    pub total_insts: u64,
+    pub total_cycles: u64,
+}
+
+// フォールデッド形式で出す値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    Insts,
+    Cycles,
+}
+
+impl Profiler {
+    // cpuの今のpcを根にして始める
+    pub fn new(cpu: &Cpu) -> Self {
+        Self {
+            nodes: vec![Node::new(cpu.pc(), 0)],
+            stack: vec![Frame {
+                node: 0,
+                ret: 0,
+                trap: false,
+            }],
+            flow: Flow::Next(cpu.pc(), None),
+            instret: cpu.instret(),
+            cycles: cpu.cycles(),
+        }
+    }
+
+    // 命令の実行前フックから呼ぶ
+    pub fn on_inst(&mut self, cpu: &Cpu, pc: u32, ir: u32) {
+        self.account(cpu);
+        self.follow(cpu, pc);
+        self.flow = flow(cpu, pc, ir);
+    }
+
+    // 最後の命令の分を数える (結果を出す前に呼ぶ)
+    pub fn finish(&mut self, cpu: &Cpu) {
+        self.account(cpu);
+    }
+
+    // 前の命令から増えた命令数とサイクル数を今の関数に足す
+    fn account(&mut self, cpu: &Cpu) {
+        let current = self.current();
+        let node = &mut self.nodes[current];
+        node.insts += cpu.instret() - self.instret;
+        node.cycles += cpu.cycles() - self.cycles;
+        self.instret = cpu.instret();
+        self.cycles = cpu.cycles();
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().unwrap().node
+    }
+
+    // 前の命令の飛び先とpcを比べてスタックを更新する
+    fn follow(&mut self, cpu: &Cpu, pc: u32) {
+        match self.flow {
+            Flow::Next(next, taken) if pc == next || Some(pc) == taken => {}
+            Flow::Call { target, ret } if pc == target => self.push(pc, ret, false),
+            Flow::Return(target) if pc == target => self.pop(|frame| frame.ret == pc),
+            Flow::TrapReturn(target) if pc == target => self.pop(|frame| frame.trap),
+            Flow::Jump(target) if pc == target => {
+                // 別の関数の先頭に飛んだら末尾呼び出し
+                let current = self.nodes[self.current()].func;
+                if pc != current && is_function(cpu.symbols(), pc) {
+                    let frame = self.stack.pop().unwrap();
+                    self.push(pc, frame.ret, frame.trap);
+                }
+            }
+            // トラップハンドラをトラップした命令 (割り込みなら次の命令) から呼んだとみなす
+            _ => {
+                let epc = match cpu.privilege() {
+                    Privilege::Machine => cpu.read_csr(CSR_MEPC),
+                    _ => cpu.read_csr(CSR_SEPC),
+                };
+                self.push(pc, epc.unwrap_or(0), true);
+            }
+        }
+    }
+
+    fn push(&mut self, func: u32, ret: u32, trap: bool) {
+        let parent = self.current();
+        let next = self.nodes.len();
+        let node = *self.nodes[parent].children.entry(func).or_insert(next);
+        if node == next {
+            self.nodes.push(Node::new(func, parent));
+        }
+        self.nodes[node].calls += 1;
+        self.stack.push(Frame { node, ret, trap });
+    }
+
+    // 条件に合う一番上のフレームまで戻る
+    // 見つからなければlongjmpやブート時のmretのようなものとみなしてそのままにする
+    fn pop<F: Fn(&Frame) -> bool>(&mut self, f: F) {
+        if let Some(i) = self.stack.iter().rposition(f) {
+            if i > 0 {
+                self.stack.truncate(i);
+            }
+        }
+    }
+
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+
+    // 関数ごとの集計をサイクル数の多い順に返す
+    pub fn functions(&self, symbols: &Symbols) -> Vec<FunctionStats> {
+        let mut stats: HashMap<u32, FunctionStats> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let entry = stats
+                .entry(node.func)
+                .or_insert_with(|| FunctionStats::new(name(symbols, node.func)));
+            entry.calls += node.calls;
+            entry.self_insts += node.insts;
+            entry.self_cycles += node.cycles;
+            // 再帰していても一度だけ数える
+            let mut funcs: Vec<u32> = self.path(i).iter().map(|&n| self.nodes[n].func).collect();
+            funcs.sort_unstable();
+            funcs.dedup();
+            for func in funcs {
+                let entry = stats
+                    .entry(func)
+                    .or_insert_with(|| FunctionStats::new(name(symbols, func)));
+                entry.total_insts += node.insts;
+                entry.total_cycles += node.cycles;
+            }
+        }
+        let mut stats: Vec<FunctionStats> = stats.into_values().collect();
+        stats.sort_by(|a, b| {
+            b.self_cycles
+                .cmp(&a.self_cycles)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        stats
+    }
+
+    // flamegraph.plなどで読めるフォールデッド形式 ("a;b;c 値") で書き出す
+    pub fn write_folded<W: Write>(
+        &self,
+        symbols: &Symbols,
+        weight: Weight,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let value = match weight {
+                Weight::Insts => node.insts,
+                Weight::Cycles => node.cycles,
+            };
+            if value == 0 {
+                continue;
+            }
+            let names: Vec<String> = self
+                .path(i)
+                .iter()
+                .map(|&n| name(symbols, self.nodes[n].func))
+                .collect();
+            lines.push((names.join(";"), value));
+        }
+        lines.sort();
+        for (stack, value) in lines {
+            writeln!(out, "{} {}", stack, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    fn new(func: u32, parent: usize) -> Self {
+        Self {
+            func,
+            parent,
+            children: HashMap::new(),
+            calls: 0,
+            insts: 0,
+            cycles: 0,
+        }
+    }
+}
+
+impl FunctionStats {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            calls: 0,
+            self_insts: 0,
+            self_cycles: 0,
+            total_insts: 0,
+            total_cycles: 0,
+        }
+    }
+}
+
+fn name(symbols: &Symbols, func: u32) -> String {
+    match symbols.symbolize(func) {
+        Some((name, 0)) => name.to_string(),
+        Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+        None => format!("0x{:08x}", func),
+    }
+}
+
+fn is_function(symbols: &Symbols, addr: u32) -> bool {
+    matches!(symbols.symbolize(addr), Some((_, 0)))
+}
+
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+// 命令の後にどこへ進むか
+fn flow(cpu: &Cpu, pc: u32, ir: u32) -> Flow {
+    let rd = (ir >> 7) & 0x1F;
+    let rs1 = (ir >> 15) & 0x1F;
+    let next = pc.wrapping_add(4);
+    match ir & 0x7F {
+        // jal
+        0x6F => {
+            let imm = ((ir as i32) >> 31 << 20)
+                | (ir & 0xF_F000) as i32
+                | (((ir >> 20) & 1) << 11) as i32
+                | (((ir >> 21) & 0x3FF) << 1) as i32;
+            let target = pc.wrapping_add(imm as u32);
+            if is_link(rd) {
+                Flow::Call { target, ret: next }
+            } else {
+                Flow::Jump(target)
+            }
+        }
+        // jalr
+        0x67 => {
+            let imm = (ir as i32) >> 20;
+            let target = cpu.get_x(rs1 as usize).wrapping_add(imm as u32) & !1;
+            if is_link(rd) {
+                Flow::Call { target, ret: next }
+            } else if is_link(rs1) {
+                Flow::Return(target)
+            } else {
+                Flow::Jump(target)
+            }
+        }
+        // 分岐
+        0x63 => {
+            let imm = ((ir as i32) >> 31 << 12)
+                | (((ir >> 7) & 1) << 11) as i32
+                | (((ir >> 25) & 0x3F) << 5) as i32
+                | (((ir >> 8) & 0xF) << 1) as i32;
+            Flow::Next(next, Some(pc.wrapping_add(imm as u32)))
+        }
+        0x73 => match ir {
+            // ecall, ebreak
+            0x0000_0073 | 0x0010_0073 => Flow::Trap,
+            // sret, mret
+            0x1020_0073 => Flow::TrapReturn(cpu.read_csr(CSR_SEPC).unwrap_or(0)),
+            0x3020_0073 => Flow::TrapReturn(cpu.read_csr(CSR_MEPC).unwrap_or(0)),
+            _ => Flow::Next(next, None),
+        },
+        _ => Flow::Next(next, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{FunctionStats, Profiler, Weight};
+    use crate::cpu::testing::machine;
+    use crate::symbols::Symbols;
+
+    // 0x00から0x20を2回呼び、0x20は0x30をt0で呼ぶ
+    // ecallのハンドラは0x40
+    const PROGRAM: [u32; 20] = [
+        0x0200_00EF, // jal   ra, 0x20
+        0x01C0_00EF, // jal   ra, 0x20
+        0x0000_0073, // ecall
+        0x0000_006F, // j     .
+        0,
+        0,
+        0,
+        0,
+        0x0100_02EF, // jal   t0, 0x30
+        0x0015_0513, // addi  a0, a0, 1
+        0x0000_8067, // ret
+        0,
+        0x0015_8593, // addi  a1, a1, 1
+        0x0002_8067, // jr    t0
+        0,
+        0,
+        0x3410_2373, // csrr  t1, mepc
+        0x0043_0313, // addi  t1, t1, 4
+        0x3413_1073, // csrw  mepc, t1
+        0x3020_0073, // mret
+    ];
+
+    fn profile() -> Profiler {
+        let mut cpu = machine(&PROGRAM);
+        cpu.write_csr(0x305, 0x40).unwrap();
+        let profiler = Rc::new(RefCell::new(Profiler::new(&cpu)));
+        let p = profiler.clone();
+        cpu.hooks_mut()
+            .on_before_inst(move |cpu, pc, ir| p.borrow_mut().on_inst(cpu, pc, ir));
+        // 最後にj .を3回
+        cpu.step(20);
+        assert_eq!(cpu.pc(), 0xC);
+        cpu.hooks_mut().clear();
+        let mut profiler = Rc::try_unwrap(profiler).ok().unwrap().into_inner();
+        profiler.finish(&cpu);
+        profiler
+    }
+
+    #[test]
+    fn call_tree() {
+        let profiler = profile();
+        let stats = profiler.functions(&Symbols::default());
+        let get = |name: &str| stats.iter().find(|s| s.name == name).unwrap().clone();
+        let expected = |name: &str, calls, self_insts, total_insts| FunctionStats {
+            name: name.to_string(),
+            calls,
+            self_insts,
+            self_cycles: get(name).self_cycles,
+            total_insts,
+            total_cycles: get(name).total_cycles,
+        };
+        assert_eq!(stats.len(), 4);
+        // ecallは命令数に入らない
+        assert_eq!(get("0x00000000"), expected("0x00000000", 0, 5, 19));
+        assert_eq!(get("0x00000020"), expected("0x00000020", 2, 6, 10));
+        assert_eq!(get("0x00000030"), expected("0x00000030", 2, 4, 4));
+        assert_eq!(get("0x00000040"), expected("0x00000040", 1, 4, 4));
+    }
+
+    #[test]
+    fn folded_output() {
+        let profiler = profile();
+        let mut out = Vec::new();
+        profiler
+            .write_folded(&Symbols::default(), Weight::Insts, &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0x00000000 5\n\
+             0x00000000;0x00000020 6\n\
+             0x00000000;0x00000020;0x00000030 4\n\
+             0x00000000;0x00000040 4\n"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+
+// スナップショットのファイル形式
+// 先頭にMAGICとVERSIONを置き、以降はBus, Cpuの順に並べる (リトルエンディアン)
+const MAGIC: &[u8; 8] = b"RVSNAP\0\0";
+const VERSION: u32 = 8;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // スナップショットのファイルではない
+    BadMagic,
+    // 対応していないバージョン
+    Version(u32),
+    // 途中で終わっている
+    Truncated,
+    // 値が不正
+    Invalid(&'static str),
+    // 状態を保存できないものが付いている
+    Unsupported(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, SnapshotError>;
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot io error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::Version(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(what) => write!(f, "invalid {} in snapshot", what),
+            SnapshotError::Unsupported(what) => write!(f, "cannot snapshot with {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    // 長さを付けずに書く
+    pub fn raw(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // 長さ付きのバイト列
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn raw(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.raw(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid("bool")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.raw(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.raw(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.raw(len)
+    }
+}
+
+impl Cpu {
+    // マシン全体の状態をバイト列にする
+    // フック、ブレークポイント、ディスクイメージの中身などホスト側のものは含まない
+    // タイミングモデルとキャッシュの状態は保存できないので、付いている時は断る
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        if self.timing_model().is_some() {
+            return Err(SnapshotError::Unsupported("a timing model"));
+        }
+        if self.bus().caching() {
+            return Err(SnapshotError::Unsupported("caches"));
+        }
+        let mut w = Writer::new();
+        w.raw(MAGIC);
+        w.u32(VERSION);
+        self.bus().save(&mut w);
+        self.save_state(&mut w);
+        Ok(w.into_bytes())
+    }
+
+    // snapshotで作った状態に戻す
+    // 失敗した時は何も変更しない
+    pub fn restore(&mut self, data: &[u8]) -> Result<()> {
+        let mut r = Reader::new(data);
+        if r.raw(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+
+        let mut cpu = Cpu::new(Bus::load(&mut r, self.bus())?);
+        cpu.load_state(&mut r)?;
+        if !r.is_empty() {
+            return Err(SnapshotError::Invalid("trailing data"));
+        }
+        // timebaseはホスト側の設定なので、こちらの値で確かめる
+        if cpu.time_rem() >= self.timebase() {
+            return Err(SnapshotError::Invalid("timebase"));
+        }
+        self.replace_state(cpu);
+        Ok(())
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.snapshot()?)?;
+        Ok(())
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let data = fs::read(path)?;
+        self.restore(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotError;
+    use crate::cpu::testing::{assert_same, machine, TIMER_LOOP};
+    use crate::cpu::HaltReason;
+    use crate::timing::{FixedLatency, Latencies};
+
+    // 戻した状態から実行すると、戻さずに実行したのと同じになる
+    #[test]
+    fn restore_then_run_matches() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.step(10_000);
+        cpu.bus_mut().uart_mut().push_input(b"ab");
+        let saved = cpu.snapshot().unwrap();
+        assert_eq!(cpu.step(50_000), HaltReason::Step);
+
+        let mut other = machine(&[]);
+        other.restore(&saved).unwrap();
+        assert_eq!(other.step(50_000), HaltReason::Step);
+        assert_same(&cpu, &other);
+        assert_eq!(cpu.get_x(23), b'a' as u32 + b'b' as u32);
+        assert_eq!(cpu.snapshot().unwrap(), other.snapshot().unwrap());
+    }
+
+    // 壊れたスナップショットでは何も変えない
+    #[test]
+    fn bad_snapshot_keeps_state() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.step(1_000);
+        let saved = cpu.snapshot().unwrap();
+        cpu.step(1_000);
+        let before = cpu.snapshot().unwrap();
+        assert!(matches!(
+            cpu.restore(&saved[..saved.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+        assert!(matches!(
+            cpu.restore(b"nothing"),
+            Err(SnapshotError::Truncated)
+        ));
+        assert_eq!(cpu.snapshot().unwrap(), before);
+    }
+
+    // timebaseが1でない時も、途中のtime_remごと戻せる
+    #[test]
+    fn restore_with_timebase() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.set_timebase(3);
+        cpu.step(10_001);
+        assert_ne!(cpu.time_rem(), 0);
+        let saved = cpu.snapshot().unwrap();
+        cpu.step(20_000);
+
+        let mut other = machine(&[]);
+        other.set_timebase(3);
+        other.restore(&saved).unwrap();
+        other.step(20_000);
+        assert_same(&cpu, &other);
+
+        // 1では余りが大きすぎる
+        let mut other = machine(&[]);
+        assert!(matches!(
+            other.restore(&saved),
+            Err(SnapshotError::Invalid("timebase"))
+        ));
+    }
+
+    #[test]
+    fn refuses_timing_model() {
+        let mut cpu = machine(&TIMER_LOOP);
+        cpu.set_timing_model(Some(Box::new(FixedLatency::new(Latencies::default()))));
+        assert!(matches!(cpu.snapshot(), Err(SnapshotError::Unsupported(_))));
+        assert!(cpu.start_recording().is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::cpu::Cpu;
+use crate::disasm;
+use crate::hooks::{MemAccess, TrapEvent};
+
+// 実行した命令の統計
+// 命令の実行後フック、メモリとトラップのフックから呼ぶ
+pub struct Stats {
+    insts: u64,
+    mnemonics: BTreeMap<&'static str, u64>,
+    extensions: BTreeMap<&'static str, u64>,
+    taken: u64,
+    not_taken: u64,
+    // アクセス幅 (1, 2, 4バイト) ごとの回数
+    loads: [u64; 3],
+    stores: [u64; 3],
+    exceptions: u64,
+    interrupts: u64,
+    start: Instant,
+    elapsed: Option<Duration>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            insts: 0,
+            mnemonics: BTreeMap::new(),
+            extensions: BTreeMap::new(),
+            taken: 0,
+            not_taken: 0,
+            loads: [0; 3],
+            stores: [0; 3],
+            exceptions: 0,
+            interrupts: 0,
+            start: Instant::now(),
+            elapsed: None,
+        }
+    }
+
+    // 命令の実行後フックから呼ぶ
+    pub fn on_inst(&mut self, cpu: &Cpu, pc: u32, ir: u32) {
+        self.insts += 1;
+        let mnemonic = disasm::mnemonic(ir).unwrap_or("unknown");
+        *self.mnemonics.entry(mnemonic).or_insert(0) += 1;
+        let extension = disasm::extension(ir).unwrap_or("unknown");
+        *self.extensions.entry(extension).or_insert(0) += 1;
+        // 分岐はpcが次の命令でなければ成立
+        if ir & 0x7F == 0x63 {
+            if cpu.pc() == pc.wrapping_add(4) {
+                self.not_taken += 1;
+            } else {
+                self.taken += 1;
+            }
+        }
+    }
+
+    pub fn on_mem_read(&mut self, access: MemAccess) {
+        self.loads[width_index(access.width)] += 1;
+    }
+
+    pub fn on_mem_write(&mut self, access: MemAccess) {
+        self.stores[width_index(access.width)] += 1;
+    }
+
+    pub fn on_trap(&mut self, trap: TrapEvent) {
+        if trap.cause & 0x8000_0000 != 0 {
+            self.interrupts += 1;
+        } else {
+            self.exceptions += 1;
+        }
+    }
+
+    // 計測を終える (以降の経過時間は含めない)
+    pub fn finish(&mut self) {
+        self.elapsed.get_or_insert_with(|| self.start.elapsed());
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.elapsed.unwrap_or_else(|| self.start.elapsed())
+    }
+
+    // ホストの1秒あたりの命令数
+    pub fn insts_per_sec(&self) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            self.insts as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn write_table<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "instructions     {}", self.insts)?;
+        writeln!(
+            out,
+            "host time        {:.3} s ({:.0} inst/s)",
+            self.elapsed().as_secs_f64(),
+            self.insts_per_sec()
+        )?;
+        let branches = self.taken + self.not_taken;
+        writeln!(
+            out,
+            "branches         {} (taken {}, not taken {}, {:.1}% taken)",
+            branches,
+            self.taken,
+            self.not_taken,
+            percent(self.taken, branches)
+        )?;
+        for (name, counts) in [("loads", &self.loads), ("stores", &self.stores)] {
+            writeln!(
+                out,
+                "{:<16} {} (byte {}, half {}, word {}, {} bytes)",
+                name,
+                counts.iter().sum::<u64>(),
+                counts[0],
+                counts[1],
+                counts[2],
+                bytes(counts)
+            )?;
+        }
+        writeln!(out, "exceptions       {}", self.exceptions)?;
+        writeln!(out, "interrupts       {}", self.interrupts)?;
+
+        writeln!(out)?;
+        writeln!(out, "{:<12} {:>12} {:>7}", "extension", "count", "%")?;
+        for (name, count) in sorted(&self.extensions) {
+            let p = percent(count, self.insts);
+            writeln!(out, "{:<12} {:>12} {:>6.2}%", name, count, p)?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:<12} {:>12} {:>7}", "mnemonic", "count", "%")?;
+        for (name, count) in sorted(&self.mnemonics) {
+            let p = percent(count, self.insts);
+            writeln!(out, "{:<12} {:>12} {:>6.2}%", name, count, p)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let counts = |map: &BTreeMap<&str, u64>| {
+            let fields: Vec<String> = map
+                .iter()
+                .map(|(name, count)| format!("\"{}\": {}", name, count))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        };
+        let widths = |c: &[u64; 3]| {
+            format!(
+                "{{\"byte\": {}, \"half\": {}, \"word\": {}, \"bytes\": {}}}",
+                c[0],
+                c[1],
+                c[2],
+                bytes(c)
+            )
+        };
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"instructions\": {},", self.insts)?;
+        writeln!(
+            out,
+            "  \"host_seconds\": {:.6},",
+            self.elapsed().as_secs_f64()
+        )?;
+        writeln!(
+            out,
+            "  \"instructions_per_second\": {:.0},",
+            self.insts_per_sec()
+        )?;
+        writeln!(
+            out,
+            "  \"branches\": {{\"taken\": {}, \"not_taken\": {}}},",
+            self.taken, self.not_taken
+        )?;
+        writeln!(out, "  \"loads\": {},", widths(&self.loads))?;
+        writeln!(out, "  \"stores\": {},", widths(&self.stores))?;
+        writeln!(out, "  \"exceptions\": {},", self.exceptions)?;
+        writeln!(out, "  \"interrupts\": {},", self.interrupts)?;
+        writeln!(out, "  \"extensions\": {},", counts(&self.extensions))?;
+        writeln!(out, "  \"mnemonics\": {}", counts(&self.mnemonics))?;
+        writeln!(out, "}}")
+    }
+}
+
+fn width_index(width: u8) -> usize {
+    match width {
+        1 => 0,
+        2 => 1,
+        _ => 2,
+    }
+}
+
+fn bytes(counts: &[u64; 3]) -> u64 {
+    counts[0] + counts[1] * 2 + counts[2] * 4
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 * 100.0 / total as f64
+    }
+}
+
+// 多い順
+fn sorted<'a>(map: &BTreeMap<&'a str, u64>) -> Vec<(&'a str, u64)> {
+    let mut list: Vec<(&str, u64)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+    list.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::Stats;
+    use crate::cpu::testing::machine;
+    use crate::cpu::Privilege;
+    use crate::hooks::TrapEvent;
+
+    // 3回回るループで幅の違うロードとストアをして、最後にecallする
+    const PROGRAM: [u32; 10] = [
+        0x0030_0293, // li    t0, 3
+        0x1000_0313, // li    t1, 0x100
+        0x0053_0023, // sb    t0, 0(t1)
+        0x0053_1123, // sh    t0, 2(t1)
+        0x0003_2383, // lw    t2, 0(t1)
+        0x0253_83B3, // mul   t2, t2, t0
+        0xFFF2_8293, // addi  t0, t0, -1
+        0xFE02_96E3, // bnez  t0, 0x8
+        0x0000_0073, // ecall
+        0x0000_006F, // j     .
+    ];
+
+    fn run() -> Stats {
+        let mut cpu = machine(&PROGRAM);
+        cpu.write_csr(0x305, 0x24).unwrap();
+        let stats = Rc::new(RefCell::new(Stats::new()));
+        let hooks = cpu.hooks_mut();
+        let s = stats.clone();
+        hooks.on_after_inst(move |cpu, pc, ir| s.borrow_mut().on_inst(cpu, pc, ir));
+        let s = stats.clone();
+        hooks.on_mem_read(move |a| s.borrow_mut().on_mem_read(a));
+        let s = stats.clone();
+        hooks.on_mem_write(move |a| s.borrow_mut().on_mem_write(a));
+        let s = stats.clone();
+        hooks.on_trap(move |t| s.borrow_mut().on_trap(t));
+        cpu.step(22);
+        assert_eq!(cpu.pc(), 0x24);
+        cpu.hooks_mut().clear();
+
+        let mut stats = Rc::try_unwrap(stats).ok().unwrap().into_inner();
+        // 割り込みは直接渡す
+        stats.on_trap(TrapEvent {
+            cause: 0x8000_0007,
+            tval: 0,
+            epc: 0x24,
+            from: Privilege::Machine,
+            to: Privilege::Machine,
+        });
+        stats.finish();
+        stats
+    }
+
+    #[test]
+    fn json() {
+        let mut out = Vec::new();
+        run().write_json(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // ホストの時間は毎回違う
+        let lines: Vec<&str> = out
+            .lines()
+            .filter(|line| !line.contains("second"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "{",
+                "  \"instructions\": 22,",
+                "  \"branches\": {\"taken\": 2, \"not_taken\": 1},",
+                "  \"loads\": {\"byte\": 0, \"half\": 0, \"word\": 3, \"bytes\": 12},",
+                "  \"stores\": {\"byte\": 3, \"half\": 3, \"word\": 0, \"bytes\": 9},",
+                "  \"exceptions\": 1,",
+                "  \"interrupts\": 1,",
+                "  \"extensions\": {\"I\": 19, \"M\": 3},",
+                "  \"mnemonics\": {\"addi\": 5, \"bne\": 3, \"ecall\": 1, \"jal\": 1, \
+                 \"lw\": 3, \"mul\": 3, \"sb\": 3, \"sh\": 3}",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn table() {
+        let mut out = Vec::new();
+        run().write_table(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        for line in [
+            "instructions     22\n",
+            "branches         3 (taken 2, not taken 1, 66.7% taken)\n",
+            "loads            3 (byte 0, half 0, word 3, 12 bytes)\n",
+            "stores           6 (byte 3, half 3, word 0, 9 bytes)\n",
+            "exceptions       1\n",
+            "interrupts       1\n",
+        ] {
+            assert!(out.contains(line), "{:?} in {:?}", line, out);
+        }
+        // 多い順で、同じ数なら名前順
+        let mnemonics: Vec<&str> = out
+            .lines()
+            .skip_while(|line| !line.starts_with("mnemonic"))
+            .skip(1)
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(
+            mnemonics,
+            ["addi", "bne", "lw", "mul", "sb", "sh", "ecall", "jal"]
+        );
+        assert!(out.contains("addi                    5  22.73%\n"));
+    }
+}
@@ -0,0 +1,627 @@
+use crate::elf::{self, Elf};
+
+// ELFの.symtabと.debug_lineから引く、アドレスとシンボル、ソースの行の対応
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    // アドレス順。同じアドレスでは関数、グローバルを優先する
+    symbols: Vec<Symbol>,
+    // アドレス順の行番号表
+    rows: Vec<Row>,
+    files: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    // 0なら大きさが分からない
+    pub size: u32,
+    rank: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    addr: u32,
+    file: usize,
+    line: u32,
+    // シーケンスの終わり (ここから先は対応する行がない)
+    end: bool,
+}
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+const SHN_LORESERVE: u16 = 0xFF00;
+
+impl Symbols {
+    pub fn from_elf(elf: &Elf) -> Self {
+        let mut symbols = Self::default();
+        if let (Some(symtab), Some(strtab)) = (elf.section(".symtab"), elf.section(".strtab")) {
+            symbols.read_symtab(symtab, strtab);
+        }
+        if let Some(debug_line) = elf.section(".debug_line") {
+            let strs = Strings {
+                line_str: elf.section(".debug_line_str").unwrap_or(&[]),
+                str: elf.section(".debug_str").unwrap_or(&[]),
+            };
+            // 読めないユニットがあってもそこまでの行番号は使う
+            let _ = symbols.read_debug_line(debug_line, &strs);
+        }
+        symbols.rows.sort_by_key(|row| (row.addr, !row.end));
+        symbols
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty() && self.rows.is_empty()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // 名前からアドレスを引く
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .filter(|s| s.name == name)
+            .max_by_key(|s| s.rank)
+            .map(|s| s.addr)
+    }
+
+    // addrを含むシンボルとその先頭からのオフセット
+    pub fn symbolize(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self.symbols.partition_point(|s| s.addr <= addr);
+        let last = self.symbols.get(i.checked_sub(1)?)?;
+        let first = self.symbols.partition_point(|s| s.addr < last.addr);
+        let symbol = &self.symbols[first];
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+
+    // addrのソースファイルと行
+    pub fn line(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self.rows.partition_point(|r| r.addr <= addr);
+        let row = self.rows.get(i.checked_sub(1)?)?;
+        if row.end {
+            return None;
+        }
+        Some((&self.files[row.file], row.line))
+    }
+
+    // "関数+オフセット (ファイル:行)" の形で表す
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        let symbol = self.symbolize(addr).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+0x{:x}", name, offset),
+        });
+        let line = self
+            .line(addr)
+            .map(|(file, line)| format!("{}:{}", file, line));
+        match (symbol, line) {
+            (Some(symbol), Some(line)) => Some(format!("{} ({})", symbol, line)),
+            (symbol, line) => symbol.or(line),
+        }
+    }
+
+    fn read_symtab(&mut self, symtab: &[u8], strtab: &[u8]) {
+        for sym in symtab.chunks_exact(16) {
+            let name = u32::from_le_bytes(sym[0..4].try_into().unwrap());
+            let addr = u32::from_le_bytes(sym[4..8].try_into().unwrap());
+            let size = u32::from_le_bytes(sym[8..12].try_into().unwrap());
+            let info = sym[12];
+            let shndx = u16::from_le_bytes(sym[14..16].try_into().unwrap());
+            // 未定義と絶対値 (.equなど) はアドレスではない
+            if shndx == SHN_UNDEF || shndx >= SHN_LORESERVE {
+                continue;
+            }
+            let kind = info & 0xF;
+            let bind = info >> 4;
+            let rank = match kind {
+                STT_FUNC => 4,
+                STT_OBJECT => 2,
+                0 => 0,
+                _ => continue,
+            } + matches!(bind, STB_GLOBAL | STB_WEAK) as u8;
+            let Some(name) = elf::cstr(strtab, name as usize) else {
+                continue;
+            };
+            // アセンブラの一時ラベルとマッピングシンボル
+            if name.is_empty() || name.starts_with(".L") || name.starts_with('$') {
+                continue;
+            }
+            self.symbols.push(Symbol {
+                name: name.to_string(),
+                addr,
+                size,
+                rank,
+            });
+        }
+        self.symbols
+            .sort_by(|a, b| a.addr.cmp(&b.addr).then(b.rank.cmp(&a.rank)));
+    }
+
+    // .debug_lineの行番号プログラムを全て実行して表を作る (DWARF 2から5)
+    fn read_debug_line<'a>(&mut self, data: &'a [u8], strs: &Strings<'a>) -> Option<()> {
+        let mut r = DwarfReader::new(data);
+        while !r.is_empty() {
+            let (len, offset_size) = match r.u32()? {
+                0xFFFF_FFFF => (r.u64()? as usize, 8),
+                len => (len as usize, 4),
+            };
+            let mut unit = DwarfReader::new(r.raw(len)?);
+            unit.offset_size = offset_size;
+            self.read_line_unit(&mut unit, strs)?;
+        }
+        Some(())
+    }
+
+    fn read_line_unit<'a>(&mut self, r: &mut DwarfReader<'a>, strs: &Strings<'a>) -> Option<()> {
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+        if version >= 5 {
+            // address_sizeとsegment_selector_size
+            r.raw(2)?;
+        }
+        let header_len = r.offset()?;
+        let mut program = r.clone();
+        program.skip(header_len)?;
+
+        let min_inst_len = r.u8()? as u32;
+        if version >= 4 {
+            // maximum_operations_per_instruction (VLIW用なので無視する)
+            r.u8()?;
+        }
+        r.u8()?; // default_is_stmt
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return None;
+        }
+        let lengths = r.raw(opcode_base as usize - 1)?.to_vec();
+
+        // ファイル番号からself.filesの番号への対応
+        let mut files = Vec::new();
+        if version >= 5 {
+            // 0番はコンパイル時のディレクトリなので付けない
+            let dirs = read_entries(r, strs)?;
+            for (name, dir) in read_entries(r, strs)? {
+                let dir = dirs.get(dir as usize).filter(|_| dir != 0);
+                let dir = dir.map(|(d, _)| d.as_str());
+                files.push(self.add_file(dir, &name));
+            }
+        } else {
+            // 0番はコンパイル時のディレクトリで、ここにはない
+            let mut dirs = vec![String::new()];
+            while let Some(dir) = r.cstr().filter(|d| !d.is_empty()) {
+                dirs.push(dir.to_string());
+            }
+            // 1番から始まる
+            files.push(usize::MAX);
+            loop {
+                let name = r.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()?;
+                r.uleb()?;
+                r.uleb()?;
+                let dir = dirs.get(dir as usize).map(|d| d.as_str());
+                files.push(self.add_file(dir, name));
+            }
+        }
+
+        let r = &mut program;
+        let mut state = LineState::new(version);
+        while !r.is_empty() {
+            let op = r.u8()?;
+            if op >= opcode_base {
+                let adj = op - opcode_base;
+                state.addr = state
+                    .addr
+                    .wrapping_add(min_inst_len * (adj / line_range) as u32);
+                state.line += line_base + (adj % line_range) as i64;
+                self.emit(&state, &files, false);
+                continue;
+            }
+            match op {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let mut ext = DwarfReader::new(r.raw(len)?);
+                    match ext.u8()? {
+                        // DW_LNE_end_sequence
+                        1 => {
+                            self.emit(&state, &files, true);
+                            state = LineState::new(version);
+                        }
+                        // DW_LNE_set_address
+                        2 => {
+                            state.addr = match len - 1 {
+                                8 => ext.u64()? as u32,
+                                _ => ext.u32()?,
+                            }
+                        }
+                        // DW_LNE_define_file
+                        3 => {
+                            let name = ext.cstr()?.to_string();
+                            files.push(self.add_file(None, &name));
+                        }
+                        _ => {}
+                    }
+                }
+                // DW_LNS_copy
+                1 => self.emit(&state, &files, false),
+                // DW_LNS_advance_pc
+                2 => {
+                    state.addr = state
+                        .addr
+                        .wrapping_add(min_inst_len.wrapping_mul(r.uleb()? as u32))
+                }
+                // DW_LNS_advance_line
+                3 => state.line += r.sleb()?,
+                // DW_LNS_set_file
+                4 => state.file = r.uleb()?,
+                // DW_LNS_const_add_pc
+                8 => {
+                    let adj = 255 - opcode_base;
+                    state.addr = state
+                        .addr
+                        .wrapping_add(min_inst_len * (adj / line_range) as u32);
+                }
+                // DW_LNS_fixed_advance_pc
+                9 => state.addr = state.addr.wrapping_add(r.u16()? as u32),
+                // 引数の数だけULEB128を読み飛ばす
+                op => {
+                    for _ in 0..lengths[op as usize - 1] {
+                        r.uleb()?;
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn emit(&mut self, state: &LineState, files: &[usize], end: bool) {
+        let file = files.get(state.file as usize).copied();
+        let Some(file) = file.filter(|&f| f != usize::MAX) else {
+            return;
+        };
+        self.rows.push(Row {
+            addr: state.addr,
+            file,
+            line: state.line as u32,
+            end,
+        });
+    }
+
+    fn add_file(&mut self, dir: Option<&str>, name: &str) -> usize {
+        let path = match dir {
+            Some(dir) if !dir.is_empty() && !name.starts_with('/') => format!("{}/{}", dir, name),
+            _ => name.to_string(),
+        };
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+struct Strings<'a> {
+    line_str: &'a [u8],
+    str: &'a [u8],
+}
+
+// 行番号プログラムのレジスタ
+struct LineState {
+    addr: u32,
+    file: u64,
+    line: i64,
+}
+
+impl LineState {
+    fn new(version: u16) -> Self {
+        Self {
+            addr: 0,
+            // DWARF 5からファイル番号は0から始まる
+            file: if version >= 5 { 0 } else { 1 },
+            line: 1,
+        }
+    }
+}
+
+// DWARF 5のディレクトリとファイルの表 (パス, ディレクトリ番号)
+fn read_entries<'a>(r: &mut DwarfReader<'a>, strs: &Strings<'a>) -> Option<Vec<(String, u64)>> {
+    const DW_LNCT_PATH: u64 = 1;
+    const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+    let format_count = r.u8()?;
+    let mut format = Vec::new();
+    for _ in 0..format_count {
+        format.push((r.uleb()?, r.uleb()?));
+    }
+    let mut entries = Vec::new();
+    for _ in 0..r.uleb()? {
+        let mut path = String::new();
+        let mut dir = 0;
+        for &(content, form) in &format {
+            let value = r.form(form, strs)?;
+            match (content, value) {
+                (DW_LNCT_PATH, Value::Str(s)) => path = s.to_string(),
+                (DW_LNCT_DIRECTORY_INDEX, Value::Num(n)) => dir = n,
+                _ => {}
+            }
+        }
+        entries.push((path, dir));
+    }
+    Some(entries)
+}
+
+enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+    Other,
+}
+
+#[derive(Clone)]
+struct DwarfReader<'a> {
+    data: &'a [u8],
+    // 32-bit DWARFなら4, 64-bit DWARFなら8
+    offset_size: usize,
+}
+
+impl<'a> DwarfReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset_size: 4,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn raw(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: u64) -> Option<()> {
+        self.raw(usize::try_from(n).ok()?).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.raw(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.raw(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.raw(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.raw(8)?.try_into().unwrap()))
+    }
+
+    fn offset(&mut self) -> Option<u64> {
+        match self.offset_size {
+            8 => self.u64(),
+            _ => self.u32().map(u64::from),
+        }
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut val = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Some(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut val = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Some(val);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'a str> {
+        let s = elf::cstr(self.data, 0)?;
+        self.raw(s.len() + 1)?;
+        Some(s)
+    }
+
+    // DWARF 5のエントリの値 (行番号表で使われる形式のみ)
+    fn form(&mut self, form: u64, strs: &Strings<'a>) -> Option<Value<'a>> {
+        let value = match form {
+            // DW_FORM_string
+            0x08 => Value::Str(self.cstr()?),
+            // DW_FORM_line_strp
+            0x1F => Value::Str(elf::cstr(strs.line_str, self.offset()? as usize)?),
+            // DW_FORM_strp
+            0x0E => Value::Str(elf::cstr(strs.str, self.offset()? as usize)?),
+            // DW_FORM_data1, data2, data4, data8, udata
+            0x0B => Value::Num(self.u8()? as u64),
+            0x05 => Value::Num(self.u16()? as u64),
+            0x06 => Value::Num(self.u32()? as u64),
+            0x07 => Value::Num(self.u64()?),
+            0x0F => Value::Num(self.uleb()?),
+            // DW_FORM_data16 (MD5)
+            0x1E => {
+                self.raw(16)?;
+                Value::Other
+            }
+            // DW_FORM_block
+            0x09 => {
+                let len = self.uleb()?;
+                self.skip(len)?;
+                Value::Other
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Strings, Symbols};
+
+    // (名前, アドレス, 大きさ, st_info, st_shndx) から.symtabと.strtabを作る
+    fn symtab(entries: &[(&str, u32, u32, u8, u16)]) -> (Vec<u8>, Vec<u8>) {
+        let (mut symtab, mut strtab) = (vec![0; 16], vec![0]);
+        for &(name, addr, size, info, shndx) in entries {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&addr.to_le_bytes());
+            symtab.extend_from_slice(&size.to_le_bytes());
+            symtab.extend_from_slice(&[info, 0]);
+            symtab.extend_from_slice(&shndx.to_le_bytes());
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        (symtab, strtab)
+    }
+
+    fn symbols() -> Symbols {
+        let (symtab, strtab) = symtab(&[
+            ("_start", 0x100, 0, 0x10, 1),
+            ("main", 0x100, 0x20, 0x12, 1),
+            ("loop", 0x110, 0, 0x00, 1),
+            ("table", 0x200, 8, 0x11, 2),
+            (".L1", 0x104, 0, 0x00, 1),
+            ("$x", 0x100, 0, 0x00, 1),
+            ("printf", 0, 0, 0x12, 0),
+            ("SIZE", 0x40, 0, 0x10, 0xFFF1),
+        ]);
+        let mut symbols = Symbols::default();
+        symbols.read_symtab(&symtab, &strtab);
+        symbols
+    }
+
+    #[test]
+    fn symbolize_and_lookup() {
+        let symbols = symbols();
+        assert_eq!(symbols.symbols().len(), 4);
+        // 同じアドレスなら関数を選ぶ
+        assert_eq!(symbols.symbolize(0x100), Some(("main", 0)));
+        assert_eq!(symbols.symbolize(0x104), Some(("main", 4)));
+        // 大きさが分からなければ次のシンボルまで
+        assert_eq!(symbols.symbolize(0x1F0), Some(("loop", 0xE0)));
+        assert_eq!(symbols.symbolize(0x204), Some(("table", 4)));
+        assert_eq!(symbols.symbolize(0x208), None);
+        assert_eq!(symbols.symbolize(0xFC), None);
+
+        assert_eq!(symbols.lookup("_start"), Some(0x100));
+        assert_eq!(symbols.lookup("table"), Some(0x200));
+        for name in [".L1", "$x", "printf", "SIZE"] {
+            assert_eq!(symbols.lookup(name), None);
+        }
+    }
+
+    // DWARF 4の行番号プログラム
+    // 0x100が10行目、0x108が11行目で、0x110で終わる
+    fn debug_line() -> Vec<u8> {
+        let mut header = vec![
+            1,    // minimum_instruction_length
+            1,    // maximum_operations_per_instruction
+            1,    // default_is_stmt
+            0xFB, // line_base (-5)
+            14,   // line_range
+            13,   // opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+        ];
+        header.extend_from_slice(b"src\0\0");
+        header.extend_from_slice(b"main.c\0\x01\0\0\0");
+        let program = [
+            0x00, 5, 0x02, 0x00, 0x01, 0x00, 0x00, // DW_LNE_set_address 0x100
+            0x03, 9,    // DW_LNS_advance_line 9
+            0x01, // DW_LNS_copy
+            131,  // addr += 8, line += 1
+            0x02, 8, // DW_LNS_advance_pc 8
+            0x00, 1, 0x01, // DW_LNE_end_sequence
+        ];
+        let mut unit = 4u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&program);
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&unit);
+        data
+    }
+
+    #[test]
+    fn lines_and_describe() {
+        let mut symbols = symbols();
+        let strs = Strings {
+            line_str: &[],
+            str: &[],
+        };
+        assert_eq!(symbols.read_debug_line(&debug_line(), &strs), Some(()));
+
+        assert_eq!(symbols.line(0x100), Some(("src/main.c", 10)));
+        assert_eq!(symbols.line(0x104), Some(("src/main.c", 10)));
+        assert_eq!(symbols.line(0x10C), Some(("src/main.c", 11)));
+        assert_eq!(symbols.line(0x110), None);
+        assert_eq!(symbols.line(0xFC), None);
+
+        assert_eq!(
+            symbols.describe(0x104).as_deref(),
+            Some("main+0x4 (src/main.c:10)")
+        );
+        assert_eq!(symbols.describe(0x204).as_deref(), Some("table+0x4"));
+        assert_eq!(symbols.describe(0x20), None);
+    }
+
+    // 長さの足りないユニットは読まない。end_sequenceが無ければ最後の行が続く
+    #[test]
+    fn truncated_debug_line() {
+        let mut data = debug_line();
+        let end = data.len() - 3;
+        data.truncate(end);
+        let mut symbols = Symbols::default();
+        let strs = Strings {
+            line_str: &[],
+            str: &[],
+        };
+        assert_eq!(symbols.read_debug_line(&data, &strs), None);
+        assert!(symbols.rows.is_empty());
+        data[0] -= 3;
+        assert_eq!(symbols.read_debug_line(&data, &strs), Some(()));
+        assert_eq!(symbols.line(0x10C), Some(("src/main.c", 11)));
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::branch::BranchUnit;
+
+// タイミングモデルで区別する命令の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Alu,
+    Mul,
+    Div,
+    Load,
+    Store,
+    // AMOとLR/SC
+    Amo,
+    // 条件分岐
+    Branch,
+    // jal, jalr
+    Jump,
+    Csr,
+    // fence, ecall, mretなど
+    System,
+}
+
+impl Class {
+    pub fn of(ir: u32) -> Class {
+        match ir & 0x7F {
+            0x33 if ir >> 25 == 0b0000001 => {
+                if (ir >> 12) & 0b100 == 0 {
+                    Class::Mul
+                } else {
+                    Class::Div
+                }
+            }
+            0x03 => Class::Load,
+            0x23 => Class::Store,
+            0x2F => Class::Amo,
+            0x63 => Class::Branch,
+            0x6F | 0x67 => Class::Jump,
+            0x73 if (ir >> 12) & 0b111 != 0 => Class::Csr,
+            0x73 | 0x0F => Class::System,
+            _ => Class::Alu,
+        }
+    }
+}
+
+// 実行した命令
+#[derive(Debug, Clone, Copy)]
+pub struct InstEvent {
+    pub pc: u32,
+    pub ir: u32,
+    pub class: Class,
+    // 実際に進んだ先 (トラップした時はトラップベクタ)
+    pub next_pc: u32,
+    // 例外になった
+    pub trapped: bool,
+    // 命令フェッチとデータのアクセスでキャッシュを待ったサイクル数
+    pub fetch_stall: u64,
+    pub mem_stall: u64,
+}
+
+impl InstEvent {
+    // 条件分岐が成立したか
+    pub fn taken(&self) -> bool {
+        self.class == Class::Branch && !self.trapped && self.next_pc != self.pc.wrapping_add(4)
+    }
+}
+
+// 命令ごとのサイクル数を決めるモデル
+pub trait TimingModel {
+    // 命令の実行にかかったサイクル数 (例外になった時はトラップの分を含めない)
+    // キャッシュを待った分も含める
+    fn inst(&mut self, event: &InstEvent) -> u64;
+    // トラップ (例外と割り込み) に入るサイクル数
+    fn trap(&mut self, cause: u32) -> u64;
+    // 1命令にかかる最大のサイクル数 (トラップを含む)
+    // タイマー割り込みに間に合うように実行を区切るのに使う
+    fn max_cycles(&self) -> u64;
+    // 分岐予測を使っていればその集計
+    fn branches(&self) -> Option<&BranchUnit> {
+        None
+    }
+    // モデル独自の集計を書き出す
+    fn report(&self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+    // 書きかけの出力を書き切る
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// 命令の種類ごとのレイテンシ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Latencies {
+    pub alu: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub load: u64,
+    pub store: u64,
+    pub amo: u64,
+    // 成立しなかった条件分岐
+    pub branch: u64,
+    // 成立した条件分岐 (静的に不成立と予測した時の予測ミス)
+    // 分岐予測を使う時は使わず、外れた時にBranchConfig::penaltyを足す
+    pub branch_taken: u64,
+    pub jump: u64,
+    pub csr: u64,
+    pub system: u64,
+    pub trap: u64,
+}
+
+// 小さなインオーダーコアを想定した値
+impl Default for Latencies {
+    fn default() -> Self {
+        Self {
+            alu: 1,
+            mul: 3,
+            div: 34,
+            load: 2,
+            store: 1,
+            amo: 4,
+            branch: 1,
+            branch_taken: 3,
+            jump: 2,
+            csr: 2,
+            system: 3,
+            trap: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid latency: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Latencies {
+    // "alu=1,div=20" のような指定で既定値を上書きする
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        let mut lat = Self::default();
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let err = || ParseError(item.to_string());
+            let (name, val) = item.split_once('=').ok_or_else(err)?;
+            let val = val.parse().map_err(|_| err())?;
+            match name {
+                "alu" => lat.alu = val,
+                "mul" => lat.mul = val,
+                "div" => lat.div = val,
+                "load" => lat.load = val,
+                "store" => lat.store = val,
+                "amo" => lat.amo = val,
+                "branch" => lat.branch = val,
+                "branch_taken" => lat.branch_taken = val,
+                "jump" => lat.jump = val,
+                "csr" => lat.csr = val,
+                "system" => lat.system = val,
+                "trap" => lat.trap = val,
+                _ => return Err(err()),
+            }
+        }
+        Ok(lat)
+    }
+
+    pub fn of(&self, class: Class, taken: bool) -> u64 {
+        match class {
+            Class::Alu => self.alu,
+            Class::Mul => self.mul,
+            Class::Div => self.div,
+            Class::Load => self.load,
+            Class::Store => self.store,
+            Class::Amo => self.amo,
+            Class::Branch if taken => self.branch_taken,
+            Class::Branch => self.branch,
+            Class::Jump => self.jump,
+            Class::Csr => self.csr,
+            Class::System => self.system,
+        }
+    }
+
+    fn max(&self) -> u64 {
+        [
+            self.alu,
+            self.mul,
+            self.div,
+            self.load,
+            self.store,
+            self.amo,
+            self.branch,
+            self.branch_taken,
+            self.jump,
+            self.csr,
+            self.system,
+        ]
+        .into_iter()
+        .max()
+        .unwrap()
+    }
+}
+
+// 命令の種類だけでサイクル数が決まるモデル
+// 分岐予測器を付けると分岐とジャンプは予測が外れた時だけペナルティを足す
+#[derive(Default)]
+pub struct FixedLatency {
+    latencies: Latencies,
+    branches: Option<BranchUnit>,
+}
+
+impl FixedLatency {
+    pub fn new(latencies: Latencies) -> Self {
+        Self {
+            latencies,
+            branches: None,
+        }
+    }
+
+    pub fn with_branches(latencies: Latencies, branches: BranchUnit) -> Self {
+        Self {
+            latencies,
+            branches: Some(branches),
+        }
+    }
+
+    pub fn latencies(&self) -> &Latencies {
+        &self.latencies
+    }
+}
+
+impl TimingModel for FixedLatency {
+    fn inst(&mut self, event: &InstEvent) -> u64 {
+        event.fetch_stall + event.mem_stall + self.latency(event)
+    }
+
+    fn trap(&mut self, _cause: u32) -> u64 {
+        self.latencies.trap
+    }
+
+    fn max_cycles(&self) -> u64 {
+        let penalty = self.branches.as_ref().map_or(0, |b| b.config().penalty);
+        self.latencies.max() + self.latencies.trap + penalty
+    }
+
+    fn branches(&self) -> Option<&BranchUnit> {
+        self.branches.as_ref()
+    }
+}
+
+impl FixedLatency {
+    fn latency(&mut self, event: &InstEvent) -> u64 {
+        if let (Some(unit), false) = (&mut self.branches, event.trapped) {
+            if let Some(mispredicted) = unit.on_inst(event.pc, event.ir, event.next_pc) {
+                let base = match event.class {
+                    Class::Branch => self.latencies.branch,
+                    _ => self.latencies.jump,
+                };
+                return base
+                    + if mispredicted {
+                        unit.config().penalty
+                    } else {
+                        0
+                    };
+            }
+        }
+        self.latencies.of(event.class, event.taken())
+    }
+}